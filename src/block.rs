@@ -0,0 +1,140 @@
+//! Block device abstraction.
+//!
+//! Filesystems, partition tables and bootloader code are written against
+//! [`BlockDevice`] so they run on any backend: the SDIO [`SdCard`], the SPI
+//! [`SpiCard`], or the [`ram::RamDisk`].
+//!
+//! Borrowed devices are block devices too, and `&RefCell<D>` lets several
+//! [`Partition`](crate::part::Partition) views share one card.
+//! [`cache::BlockCache`] keeps recently used blocks in memory and
+//! [`readahead::ReadAhead`] prefetches ahead of sequential reads.
+//! [`wal::Wal`] makes multi-block writes atomic across power cuts, and
+//! [`ram::RamDisk`] simulates those cuts.
+//!
+//! [`SdCard`]: crate::sd::SdCard
+//! [`SpiCard`]: crate::sd::spi::SpiCard
+use core::cell::RefCell;
+
+use crate::sd::err::CardError;
+
+pub mod cache;
+pub mod ram;
+pub mod readahead;
+pub mod wal;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BlockError {
+    /// Block address past the end of the device
+    OutOfRange,
+    /// Buffer length is not a non zero multiple of the block size
+    InvalidBuffer,
+    /// Write or erase on a read-only device
+    ReadOnly,
+    /// The device cannot perform the operation
+    Unsupported,
+    /// An on-disk structure (partition table, filesystem) is invalid
+    Corrupted,
+    /// The partition, file or directory looked up does not exist
+    NotFound,
+    /// A journal or staging area has no room left for the request
+    NoSpace,
+    /// Error from an SD card
+    Card(CardError),
+}
+
+impl From<CardError> for BlockError {
+    fn from(value: CardError) -> Self {
+        match value {
+            CardError::AddressOutOfRange => Self::OutOfRange,
+            CardError::InvalidArgument => Self::InvalidBuffer,
+            CardError::UnsupportedCommand => Self::Unsupported,
+            err => Self::Card(err),
+        }
+    }
+}
+
+/// A device addressed in fixed-size blocks.
+pub trait BlockDevice {
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Read `buf.len() / block_size()` blocks starting at block `lba`.
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / block_size()` blocks starting at block `lba`.
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Make every completed write durable.
+    fn flush(&mut self) -> Result<(), BlockError>;
+
+    /// Erase `count` blocks starting at block `lba`, their content is
+    /// undefined afterwards.
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError>;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        (**self).write_blocks(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        (**self).flush()
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        (**self).erase(lba, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+impl<T: BlockDevice> BlockDevice for &RefCell<T> {
+    fn block_size(&self) -> usize {
+        self.borrow().block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.borrow().block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.borrow_mut().read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.borrow_mut().write_blocks(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.borrow_mut().flush()
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        self.borrow_mut().erase(lba, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.borrow().is_read_only()
+    }
+}
@@ -0,0 +1,474 @@
+//! Block cache for small, repeated accesses.
+//!
+//! Single block reads and writes go through a set of cache lines the caller
+//! provides, usually a `static` array, evicting the least recently used line
+//! when they are all taken. Multi-block transfers are streaming data: they
+//! go straight to the device and only update lines already cached, so a
+//! large file read does not flush out the filesystem metadata.
+//!
+//! Lines are keyed by the block address on the wrapped device. Wrap the card
+//! itself and put [`Partition`](crate::part::Partition) views on top, then
+//! every view sees the same cached data even where views overlap. Views
+//! placed under the cache, or accesses that bypass it, are not seen.
+//!
+//! ```no_run
+//! use core::cell::RefCell;
+//! use vf2_driver::block::cache::{BlockCache, CacheLine, WritePolicy};
+//! use vf2_driver::part::mbr::Mbr;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! static mut LINES: [CacheLine; 64] = [CacheLine::EMPTY; 64];
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! // SAFETY: the only reference to `LINES`
+//! let lines = unsafe { &mut *core::ptr::addr_of_mut!(LINES) };
+//! let cache = BlockCache::new(card, lines, WritePolicy::WriteBack).unwrap();
+//! let cache = RefCell::new(cache);
+//! let mbr = Mbr::read(&mut &cache).unwrap();
+//! let boot = mbr.get(1).unwrap().open(&cache).unwrap();
+//! // ...
+//! cache.into_inner().into_inner().unwrap();
+//! ```
+use super::{BlockDevice, BlockError};
+
+/// Size of a cache line, the block size of the devices it caches.
+pub const LINE_SIZE: usize = 512;
+
+/// When writes reach the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Written blocks stay in the cache until evicted or flushed
+    WriteBack,
+    /// Every write goes to the device before returning, the cache keeps a
+    /// clean copy
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineState {
+    Empty,
+    Clean,
+    /// Newer than the device
+    Dirty,
+}
+
+/// One cached block.
+#[derive(Clone)]
+pub struct CacheLine {
+    data: [u8; LINE_SIZE],
+    lba: u64,
+    /// Clock value of the last access
+    used: u64,
+    state: LineState,
+}
+
+impl CacheLine {
+    pub const EMPTY: Self = Self {
+        data: [0; LINE_SIZE],
+        lba: 0,
+        used: 0,
+        state: LineState::Empty,
+    };
+
+    fn holds(&self, lba: u64) -> bool {
+        self.state != LineState::Empty && self.lba == lba
+    }
+
+    fn in_range(&self, lba: u64, count: u64) -> bool {
+        self.state != LineState::Empty && self.lba >= lba && self.lba - lba < count
+    }
+}
+
+/// Counters since creation or the last [`BlockCache::reset_stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Single block reads served from a line
+    pub hits: u64,
+    /// Single block reads that went to the device
+    pub misses: u64,
+    /// Dirty lines written to the device
+    pub writebacks: u64,
+    /// Blocks of multi-block transfers, which bypass the lines
+    pub bypassed: u64,
+}
+
+impl CacheStats {
+    /// Share of single block reads served from the cache, 0 before any.
+    pub fn hit_ratio(&self) -> f32 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f32 / total as f32
+    }
+}
+
+/// An LRU cache in front of `dev`.
+pub struct BlockCache<'a, D: BlockDevice> {
+    dev: D,
+    lines: &'a mut [CacheLine],
+    policy: WritePolicy,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl<'a, D: BlockDevice> BlockCache<'a, D> {
+    /// Cache `dev` in `lines`, whatever they held is dropped. Fails with
+    /// `Unsupported` for a block size other than [`LINE_SIZE`] and
+    /// `InvalidBuffer` without lines.
+    pub fn new(
+        dev: D,
+        lines: &'a mut [CacheLine],
+        policy: WritePolicy,
+    ) -> Result<Self, BlockError> {
+        if dev.block_size() != LINE_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        if lines.is_empty() {
+            return Err(BlockError::InvalidBuffer);
+        }
+        for line in lines.iter_mut() {
+            line.state = LineState::Empty;
+        }
+        Ok(Self {
+            dev,
+            lines,
+            policy,
+            clock: 0,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Number of lines.
+    pub fn capacity(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Lines holding writes the device has not seen.
+    pub fn dirty_lines(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.state == LineState::Dirty)
+            .count()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// Write back dirty lines and drop every line, for when the device was
+    /// written without going through the cache.
+    pub fn invalidate(&mut self) -> Result<(), BlockError> {
+        self.write_back()?;
+        for line in self.lines.iter_mut() {
+            line.state = LineState::Empty;
+        }
+        Ok(())
+    }
+
+    /// Flush and give the device back.
+    pub fn into_inner(mut self) -> Result<D, BlockError> {
+        self.flush()?;
+        Ok(self.dev)
+    }
+
+    /// Write dirty lines to the device, lowest address first.
+    fn write_back(&mut self) -> Result<(), BlockError> {
+        while let Some(line) = self
+            .lines
+            .iter_mut()
+            .filter(|line| line.state == LineState::Dirty)
+            .min_by_key(|line| line.lba)
+        {
+            self.dev.write_blocks(line.lba, &line.data)?;
+            line.state = LineState::Clean;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn find(&self, lba: u64) -> Option<usize> {
+        self.lines.iter().position(|line| line.holds(lba))
+    }
+
+    /// An empty line, else the least recently used one after writing it
+    /// back when dirty.
+    fn victim(&mut self) -> Result<usize, BlockError> {
+        let index = match self
+            .lines
+            .iter()
+            .position(|line| line.state == LineState::Empty)
+        {
+            Some(index) => index,
+            None => (0..self.lines.len())
+                .min_by_key(|&i| self.lines[i].used)
+                .unwrap(),
+        };
+        let line = &mut self.lines[index];
+        if line.state == LineState::Dirty {
+            self.dev.write_blocks(line.lba, &line.data)?;
+            self.stats.writebacks += 1;
+        }
+        line.state = LineState::Empty;
+        Ok(index)
+    }
+
+    /// Blocks in a buffer of `len` bytes at `lba`, checked against the
+    /// device.
+    fn blocks(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        if len == 0 || !len.is_multiple_of(LINE_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        let count = (len / LINE_SIZE) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.dev.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    fn read_one(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let used = self.tick();
+        let index = match self.find(lba) {
+            Some(index) => {
+                self.stats.hits += 1;
+                index
+            }
+            None => {
+                self.stats.misses += 1;
+                let index = self.victim()?;
+                let line = &mut self.lines[index];
+                self.dev.read_blocks(lba, &mut line.data)?;
+                line.lba = lba;
+                line.state = LineState::Clean;
+                index
+            }
+        };
+        let line = &mut self.lines[index];
+        line.used = used;
+        buf.copy_from_slice(&line.data);
+        Ok(())
+    }
+
+    fn write_one(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let used = self.tick();
+        if self.policy == WritePolicy::WriteThrough {
+            self.dev.write_blocks(lba, buf)?;
+        }
+        let index = match self.find(lba) {
+            Some(index) => index,
+            None => self.victim()?,
+        };
+        let line = &mut self.lines[index];
+        line.data.copy_from_slice(buf);
+        line.lba = lba;
+        line.used = used;
+        line.state = match self.policy {
+            WritePolicy::WriteBack => LineState::Dirty,
+            WritePolicy::WriteThrough => LineState::Clean,
+        };
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<'_, D> {
+    fn block_size(&self) -> usize {
+        LINE_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = self.blocks(lba, buf.len())?;
+        if count == 1 {
+            return self.read_one(lba, buf);
+        }
+        self.dev.read_blocks(lba, buf)?;
+        self.stats.bypassed += count;
+        // dirty lines are newer than what was just read
+        for line in self.lines.iter() {
+            if line.state == LineState::Dirty && line.in_range(lba, count) {
+                let offset = (line.lba - lba) as usize * LINE_SIZE;
+                buf[offset..offset + LINE_SIZE].copy_from_slice(&line.data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = self.blocks(lba, buf.len())?;
+        if self.dev.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        if count == 1 {
+            return self.write_one(lba, buf);
+        }
+        self.dev.write_blocks(lba, buf)?;
+        self.stats.bypassed += count;
+        for line in self.lines.iter_mut() {
+            if line.in_range(lba, count) {
+                let offset = (line.lba - lba) as usize * LINE_SIZE;
+                line.data.copy_from_slice(&buf[offset..offset + LINE_SIZE]);
+                line.state = LineState::Clean;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.write_back()?;
+        self.dev.flush()
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        if self.dev.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        // the content is undefined afterwards, pending writes included
+        for line in self.lines.iter_mut() {
+            if line.in_range(lba, count) {
+                line.state = LineState::Empty;
+            }
+        }
+        self.dev.erase(lba, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.dev.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+    use crate::part::Partition;
+
+    fn block(fill: u8) -> [u8; LINE_SIZE] {
+        [fill; LINE_SIZE]
+    }
+
+    fn disk_block(disk: &RefCell<RamDisk>, lba: u64) -> [u8; LINE_SIZE] {
+        let mut buf = block(0);
+        disk.borrow_mut().read_blocks(lba, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn overlapping_views_share_write_back_lines() {
+        let mut image = [0u8; 32 * LINE_SIZE];
+        let disk = RefCell::new(RamDisk::new(&mut image));
+        let mut lines = [CacheLine::EMPTY; 4];
+        let cache = BlockCache::new(&disk, &mut lines, WritePolicy::WriteBack).unwrap();
+        let cache = RefCell::new(cache);
+        // blocks 8-15 are in both views
+        let mut low = Partition::new(&cache, 0, 16).unwrap();
+        let mut high = Partition::new(&cache, 8, 16).unwrap();
+
+        low.write_blocks(10, &block(1)).unwrap();
+        let mut buf = block(0);
+        high.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf, block(1));
+        assert_eq!(cache.borrow().stats().hits, 1);
+        assert_eq!(cache.borrow().dirty_lines(), 1);
+        assert_eq!(disk.borrow().block_writes(), 0);
+
+        // a streaming read sees the dirty line over the device content
+        high.write_blocks(3, &block(2)).unwrap();
+        let mut two = [0u8; 2 * LINE_SIZE];
+        low.read_blocks(10, &mut two).unwrap();
+        assert_eq!(two[..LINE_SIZE], block(1));
+        assert_eq!(two[LINE_SIZE..], block(2));
+        assert_eq!(cache.borrow().stats().bypassed, 2);
+
+        // a streaming write replaces cached data and leaves it clean
+        let mut pair = [3u8; 2 * LINE_SIZE];
+        pair[LINE_SIZE..].fill(4);
+        high.write_blocks(2, &pair).unwrap();
+        assert_eq!(cache.borrow().dirty_lines(), 0);
+        low.read_blocks(11, &mut buf).unwrap();
+        assert_eq!(buf, block(4));
+
+        low.write_blocks(0, &block(5)).unwrap();
+        high.flush().unwrap();
+        assert_eq!(cache.borrow().dirty_lines(), 0);
+        assert_eq!(disk_block(&disk, 0), block(5));
+        assert_eq!(disk_block(&disk, 10), block(3));
+        assert_eq!(disk_block(&disk, 11), block(4));
+    }
+
+    #[test]
+    fn lru_line_is_written_back_on_eviction() {
+        let mut image = [0u8; 16 * LINE_SIZE];
+        let disk = RefCell::new(RamDisk::new(&mut image));
+        let mut lines = [CacheLine::EMPTY; 2];
+        let mut cache = BlockCache::new(&disk, &mut lines, WritePolicy::WriteBack).unwrap();
+        cache.write_blocks(1, &block(1)).unwrap();
+        cache.write_blocks(2, &block(2)).unwrap();
+        let mut buf = block(0);
+        // block 1 becomes the most recently used
+        cache.read_blocks(1, &mut buf).unwrap();
+        cache.write_blocks(3, &block(3)).unwrap();
+        assert_eq!(cache.stats().writebacks, 1);
+        assert_eq!(disk_block(&disk, 2), block(2));
+        assert_eq!(disk_block(&disk, 1), block(0));
+
+        cache.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf, block(2));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_ratio(), 0.5);
+
+        cache.into_inner().unwrap();
+        assert_eq!(disk_block(&disk, 1), block(1));
+        assert_eq!(disk_block(&disk, 3), block(3));
+    }
+
+    #[test]
+    fn write_through_reaches_the_device_at_once() {
+        let mut image = [0u8; 16 * LINE_SIZE];
+        let disk = RefCell::new(RamDisk::new(&mut image));
+        let mut lines = [CacheLine::EMPTY; 2];
+        let mut cache = BlockCache::new(&disk, &mut lines, WritePolicy::WriteThrough).unwrap();
+        cache.write_blocks(5, &block(7)).unwrap();
+        assert_eq!(cache.dirty_lines(), 0);
+        assert_eq!(disk_block(&disk, 5), block(7));
+        let mut buf = block(0);
+        cache.read_blocks(5, &mut buf).unwrap();
+        assert_eq!(buf, block(7));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn erase_drops_pending_writes() {
+        let mut image = [0u8; 16 * LINE_SIZE];
+        let disk = RefCell::new(RamDisk::new(&mut image));
+        let mut lines = [CacheLine::EMPTY; 2];
+        let mut cache = BlockCache::new(&disk, &mut lines, WritePolicy::WriteBack).unwrap();
+        cache.write_blocks(4, &block(9)).unwrap();
+        cache.erase(4, 2).unwrap();
+        assert_eq!(cache.dirty_lines(), 0);
+        cache.flush().unwrap();
+        assert_eq!(disk.borrow().block_writes(), 0);
+        let mut buf = block(0);
+        cache.read_blocks(4, &mut buf).unwrap();
+        assert_eq!(buf, block(0xFF));
+    }
+}
@@ -0,0 +1,147 @@
+//! RAM disk with power loss injection.
+//!
+//! [`RamDisk`] keeps its blocks in a caller buffer and can lose power after
+//! a chosen number of block writes, optionally tearing the block being
+//! written, so code meant to survive power cuts can be run against every
+//! possible cut point:
+//!
+//! ```no_run
+//! use vf2_driver::block::ram::RamDisk;
+//!
+//! let mut image = [0u8; 64 * 512];
+//! let mut disk = RamDisk::new(&mut image);
+//! disk.cut_power_after(3, true);
+//! // ... writes fail once the cut happens ...
+//! disk.power_on();
+//! ```
+use crate::sd::err::CardError;
+
+use super::{BlockDevice, BlockError};
+
+pub const BLOCK_SIZE: usize = 512;
+
+/// A pending power cut.
+#[derive(Debug, Clone, Copy)]
+struct PowerCut {
+    /// Block writes that still complete
+    writes_left: u64,
+    /// Leave the block written at the cut half old, half new
+    tear: bool,
+}
+
+/// A block device in memory.
+pub struct RamDisk<'a> {
+    data: &'a mut [u8],
+    cut: Option<PowerCut>,
+    powered: bool,
+    block_writes: u64,
+}
+
+impl<'a> RamDisk<'a> {
+    /// Blocks of `data`, a partial block at the end is not used.
+    pub fn new(data: &'a mut [u8]) -> Self {
+        Self {
+            data,
+            cut: None,
+            powered: true,
+            block_writes: 0,
+        }
+    }
+
+    /// Lose power once `writes` more blocks have been written. Every
+    /// request afterwards fails until [`power_on`](Self::power_on).
+    pub fn cut_power_after(&mut self, writes: u64, tear: bool) {
+        self.cut = Some(PowerCut {
+            writes_left: writes,
+            tear,
+        });
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Restore power and disarm a pending cut. The content stays as the
+    /// cut left it.
+    pub fn power_on(&mut self) {
+        self.powered = true;
+        self.cut = None;
+    }
+
+    /// Blocks written since creation, to pick cut points from.
+    pub fn block_writes(&self) -> u64 {
+        self.block_writes
+    }
+
+    pub fn into_inner(self) -> &'a mut [u8] {
+        self.data
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<usize, BlockError> {
+        if !self.powered {
+            return Err(BlockError::Card(CardError::DataTransferTimeout));
+        }
+        if len == 0 || !len.is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        match lba.checked_add((len / BLOCK_SIZE) as u64) {
+            Some(end) if end <= self.block_count() => Ok(lba as usize * BLOCK_SIZE),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk<'_> {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = self.check(lba, buf.len())?;
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let start = self.check(lba, buf.len())?;
+        for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            let at = start + i * BLOCK_SIZE;
+            if let Some(cut) = &mut self.cut {
+                if cut.writes_left == 0 {
+                    if cut.tear {
+                        let half = BLOCK_SIZE / 2;
+                        self.data[at..at + half].copy_from_slice(&block[..half]);
+                    }
+                    self.powered = false;
+                    self.cut = None;
+                    return Err(BlockError::Card(CardError::DataTransferTimeout));
+                }
+                cut.writes_left -= 1;
+            }
+            self.data[at..at + BLOCK_SIZE].copy_from_slice(block);
+            self.block_writes += 1;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if !self.powered {
+            return Err(BlockError::Card(CardError::DataTransferTimeout));
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        let len = usize::try_from(count)
+            .ok()
+            .and_then(|count| count.checked_mul(BLOCK_SIZE))
+            .ok_or(BlockError::OutOfRange)?;
+        let start = self.check(lba, len)?;
+        self.data[start..start + len].fill(0xFF);
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! Sequential read-ahead.
+//!
+//! Loading a kernel reads a file front to back, often a cluster or a block
+//! at a time, and every request waits for the previous one. [`ReadAhead`]
+//! notices requests that continue where the last one ended and, from the
+//! second one on, reads a window of blocks past them into a staging buffer
+//! with a single multi-block read. Later requests are served from there.
+//!
+//! The window starts at [`MIN_WINDOW`] blocks, doubles each time a staged
+//! window is used up and halves when prefetched blocks go unused, bounded by
+//! the staging buffer. A request at least as large as the window is already
+//! efficient and goes straight to the device.
+//!
+//! ```no_run
+//! use vf2_driver::block::readahead::ReadAhead;
+//! use vf2_driver::fs::ext4::Ext4Fs;
+//! use vf2_driver::fs::FileSystem;
+//! use vf2_driver::part::gpt;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! static mut STAGING: [u8; 64 * 1024] = [0; 64 * 1024];
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let mut card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let root = gpt::find_partition(&mut card, "root").unwrap();
+//! // SAFETY: the only reference to `STAGING`
+//! let staging = unsafe { &mut *core::ptr::addr_of_mut!(STAGING) };
+//! let mut fs = Ext4Fs::mount(ReadAhead::new(root, staging).unwrap()).unwrap();
+//! let mut buf = [0u8; 4096];
+//! let mut image = fs.open("/boot/Image").unwrap();
+//! while fs.read(&mut image, &mut buf).unwrap() != 0 {}
+//! let stats = fs.unmount().stats();
+//! log::info!("read-ahead hit ratio {}", stats.hit_ratio());
+//! ```
+use super::{BlockDevice, BlockError};
+
+/// Smallest prefetch window, in blocks.
+pub const MIN_WINDOW: u64 = 8;
+
+/// Counters since creation or the last [`ReadAhead::reset_stats`], in
+/// blocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReadAheadStats {
+    /// Blocks served from the staging buffer
+    pub hits: u64,
+    /// Blocks read from the device on demand
+    pub misses: u64,
+    /// Blocks read ahead into the staging buffer
+    pub prefetched: u64,
+    /// Prefetched blocks dropped without being read
+    pub wasted: u64,
+}
+
+impl ReadAheadStats {
+    /// Share of the blocks read that came from the staging buffer, 0 before
+    /// any.
+    pub fn hit_ratio(&self) -> f32 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f32 / total as f32
+    }
+}
+
+/// A read-ahead layer in front of `dev`.
+pub struct ReadAhead<'a, D: BlockDevice> {
+    dev: D,
+    staging: &'a mut [u8],
+    /// Blocks the staging buffer holds
+    capacity: u64,
+    /// First block staged and the number staged, 0 when empty
+    start: u64,
+    len: u64,
+    /// Staged blocks read, a block read twice counts twice
+    consumed: u64,
+    window: u64,
+    /// Block after the last request
+    next: Option<u64>,
+    stats: ReadAheadStats,
+}
+
+impl<'a, D: BlockDevice> ReadAhead<'a, D> {
+    /// Stage read-ahead in `staging`, which must hold a non zero number of
+    /// blocks of `dev`.
+    pub fn new(dev: D, staging: &'a mut [u8]) -> Result<Self, BlockError> {
+        let size = dev.block_size();
+        let capacity = (staging.len() / size) as u64;
+        if capacity == 0 {
+            return Err(BlockError::InvalidBuffer);
+        }
+        Ok(Self {
+            dev,
+            staging,
+            capacity,
+            start: 0,
+            len: 0,
+            consumed: 0,
+            window: MIN_WINDOW.min(capacity),
+            next: None,
+            stats: ReadAheadStats::default(),
+        })
+    }
+
+    /// Current prefetch window, in blocks.
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub fn stats(&self) -> ReadAheadStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = ReadAheadStats::default();
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    fn blocks(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let size = self.dev.block_size();
+        if len == 0 || !len.is_multiple_of(size) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        let count = (len / size) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.dev.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    fn staged(&self, lba: u64) -> bool {
+        lba >= self.start && lba - self.start < self.len
+    }
+
+    /// Drop the staged blocks, counting the ones never read.
+    fn drop_staged(&mut self) {
+        self.stats.wasted += self.len - self.consumed;
+        self.len = 0;
+        self.consumed = 0;
+    }
+
+    /// Stage a window from `lba` on, sized from how the last one was used.
+    fn prefetch(&mut self, lba: u64) -> Result<(), BlockError> {
+        let used_up = self.len != 0 && self.consumed == self.len;
+        let wasted = self.len != 0 && self.consumed < self.len;
+        self.drop_staged();
+        if used_up {
+            self.window = (self.window * 2).min(self.capacity);
+        } else if wasted {
+            self.window = (self.window / 2).max(MIN_WINDOW.min(self.capacity));
+        }
+        let count = self.window.min(self.dev.block_count() - lba);
+        let size = self.dev.block_size();
+        self.dev
+            .read_blocks(lba, &mut self.staging[..count as usize * size])?;
+        self.start = lba;
+        self.len = count;
+        self.stats.prefetched += count;
+        Ok(())
+    }
+
+    /// Copy staged blocks from `lba` into `buf`, returning how many.
+    fn copy_staged(&mut self, lba: u64, buf: &mut [u8]) -> u64 {
+        if !self.staged(lba) {
+            return 0;
+        }
+        let size = self.dev.block_size();
+        let skip = lba - self.start;
+        let count = ((buf.len() / size) as u64).min(self.len - skip);
+        let from = skip as usize * size;
+        let n = count as usize * size;
+        buf[..n].copy_from_slice(&self.staging[from..from + n]);
+        self.consumed = (self.consumed + count).min(self.len);
+        self.stats.hits += count;
+        count
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for ReadAhead<'_, D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = self.blocks(lba, buf.len())?;
+        let size = self.dev.block_size();
+        let sequential = self.next == Some(lba);
+        if !sequential && !self.staged(lba) {
+            self.window = MIN_WINDOW.min(self.capacity);
+        }
+        self.next = Some(lba + count);
+
+        let mut done = self.copy_staged(lba, buf);
+        while done < count {
+            let at = lba + done;
+            let rest = &mut buf[done as usize * size..];
+            let left = count - done;
+            if !sequential || left >= self.window {
+                self.dev.read_blocks(at, rest)?;
+                self.stats.misses += left;
+                break;
+            }
+            self.prefetch(at)?;
+            done += self.copy_staged(at, rest);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = self.blocks(lba, buf.len())?;
+        self.dev.write_blocks(lba, buf)?;
+        // keep staged copies of the written blocks current
+        let size = self.dev.block_size();
+        for block in lba..lba + count {
+            if self.staged(block) {
+                let from = (block - lba) as usize * size;
+                let to = (block - self.start) as usize * size;
+                self.staging[to..to + size].copy_from_slice(&buf[from..from + size]);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        let end = lba.saturating_add(count);
+        if self.len != 0 && lba < self.start + self.len && end > self.start {
+            self.drop_staged();
+        }
+        self.dev.erase(lba, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.dev.is_read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ram::{RamDisk, BLOCK_SIZE};
+
+    const BLOCKS: usize = 256;
+
+    /// Block `i` filled with `i`.
+    fn image() -> [u8; BLOCKS * BLOCK_SIZE] {
+        let mut image = [0u8; BLOCKS * BLOCK_SIZE];
+        for (i, block) in image.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            block.fill(i as u8);
+        }
+        image
+    }
+
+    fn read(ra: &mut ReadAhead<RamDisk>, lba: u64) {
+        let mut buf = [0u8; BLOCK_SIZE];
+        ra.read_blocks(lba, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == lba as u8), "block {lba}");
+    }
+
+    #[test]
+    fn window_grows_while_sequential() {
+        let mut image = image();
+        let mut staging = [0u8; 32 * BLOCK_SIZE];
+        let mut ra = ReadAhead::new(RamDisk::new(&mut image), &mut staging).unwrap();
+        assert_eq!(ra.window(), MIN_WINDOW);
+        // the first read is not known to be sequential yet
+        read(&mut ra, 0);
+        assert_eq!(ra.stats().prefetched, 0);
+        for lba in 1..=57 {
+            read(&mut ra, lba);
+        }
+        // 8, 16 and 32 blocks staged, then 32 again at the staging size
+        assert_eq!(ra.window(), 32);
+        let stats = ra.stats();
+        assert_eq!((stats.hits, stats.misses), (57, 1));
+        assert_eq!((stats.prefetched, stats.wasted), (88, 0));
+        assert_eq!(stats.hit_ratio(), 57.0 / 58.0);
+    }
+
+    #[test]
+    fn window_shrinks_when_staged_blocks_go_unread() {
+        let mut image = image();
+        let mut staging = [0u8; 32 * BLOCK_SIZE];
+        let mut ra = ReadAhead::new(RamDisk::new(&mut image), &mut staging).unwrap();
+        for lba in 0..=25 {
+            read(&mut ra, lba);
+        }
+        // 25-56 staged, skip to the last of them and go on from there
+        assert_eq!(ra.window(), 32);
+        read(&mut ra, 56);
+        read(&mut ra, 57);
+        assert_eq!(ra.window(), 16);
+        assert_eq!(ra.stats().wasted, 30);
+
+        // random access starts over from the smallest window
+        read(&mut ra, 200);
+        assert_eq!(ra.window(), MIN_WINDOW);
+        assert_eq!(ra.stats().misses, 2);
+    }
+
+    #[test]
+    fn large_requests_bypass_the_staging_buffer() {
+        let mut image = image();
+        let mut staging = [0u8; 32 * BLOCK_SIZE];
+        let mut ra = ReadAhead::new(RamDisk::new(&mut image), &mut staging).unwrap();
+        read(&mut ra, 0);
+        let mut buf = [0u8; MIN_WINDOW as usize * BLOCK_SIZE];
+        ra.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(buf[buf.len() - 1], MIN_WINDOW as u8);
+        let stats = ra.stats();
+        assert_eq!((stats.misses, stats.prefetched), (1 + MIN_WINDOW, 0));
+    }
+
+    #[test]
+    fn writes_and_erases_keep_staged_blocks_current() {
+        let mut image = image();
+        let mut staging = [0u8; 32 * BLOCK_SIZE];
+        let mut ra = ReadAhead::new(RamDisk::new(&mut image), &mut staging).unwrap();
+        read(&mut ra, 0);
+        read(&mut ra, 1);
+        // 1-8 staged
+        ra.write_blocks(3, &[0xAB; BLOCK_SIZE]).unwrap();
+        let mut buf = [0u8; BLOCK_SIZE];
+        ra.read_blocks(2, &mut buf).unwrap();
+        ra.read_blocks(3, &mut buf).unwrap();
+        assert_eq!(buf, [0xAB; BLOCK_SIZE]);
+        assert_eq!(ra.stats().hits, 3);
+
+        ra.erase(6, 1).unwrap();
+        assert_eq!(ra.stats().wasted, 5);
+        ra.read_blocks(4, &mut buf).unwrap();
+        assert_eq!(ra.stats().prefetched, 2 * MIN_WINDOW);
+        ra.read_blocks(6, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF; BLOCK_SIZE]);
+    }
+}
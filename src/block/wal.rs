@@ -0,0 +1,582 @@
+//! Atomic multi-block writes through a write-ahead log.
+//!
+//! [`Wal`] reserves the first blocks of a device for a journal and exposes
+//! the rest as a block device whose writes are all-or-nothing, even across
+//! a power cut. A [`Transaction`] groups several writes; a plain
+//! `write_blocks` is a transaction of its own.
+//!
+//! The journal is a superblock followed by two halves that transactions
+//! take in turn. Staged blocks are written to the half of the transaction
+//! as they come. Commit flushes them, writes one commit record listing
+//! their home addresses with a CRC32 over the record and the staged data,
+//! flushes again and only then copies the blocks home. Until the record is
+//! on the card nothing outside the journal has changed; once it is, mounting
+//! finishes the copy. The record is a single block and torn writes of it
+//! fail its checksum, so a transaction is either replayed whole or dropped.
+//!
+//! Mounting replays the newest committed transaction, rewriting only the
+//! home blocks that differ, and drops anything staged after it. The halves
+//! alternate so a transaction being staged never overwrites the record of
+//! the last committed one.
+//!
+//! ```no_run
+//! use vf2_driver::block::wal::Wal;
+//! use vf2_driver::part::gpt;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let mut card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let part = gpt::find_partition(&mut card, "config").unwrap();
+//! let mut wal = Wal::mount(part).unwrap();
+//! let mut tx = wal.begin().unwrap();
+//! tx.write(0, &[0u8; 512]).unwrap();
+//! tx.write(7, &[1u8; 1024]).unwrap();
+//! tx.commit().unwrap();
+//! ```
+use log::warn;
+
+use crate::crc::{crc32, crc32_update};
+
+use super::{BlockDevice, BlockError};
+
+const BLOCK_SIZE: usize = 512;
+const SUPER_MAGIC: [u8; 8] = *b"VF2 WAL\0";
+const RECORD_MAGIC: [u8; 4] = *b"WALC";
+const VERSION: u32 = 1;
+/// Offset of the home addresses in a commit record
+const RECORD_LBAS: usize = 24;
+/// Offset of the CRC32 closing the superblock and commit records
+const CRC_OFFSET: usize = BLOCK_SIZE - 4;
+/// Blocks one transaction can stage, as many addresses as a record holds
+pub const MAX_TX_BLOCKS: usize = (CRC_OFFSET - RECORD_LBAS) / 8;
+/// Smallest journal: the superblock and two halves of a record and a block
+pub const MIN_JOURNAL_BLOCKS: u64 = 5;
+
+/// What mounting found in the journal.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Recovery {
+    /// Sequence number of the newest committed transaction
+    pub committed: Option<u64>,
+    /// Home blocks that had not been written yet and were replayed
+    pub replayed: u32,
+}
+
+/// A commit record read back from the journal.
+struct Record {
+    seq: u64,
+    count: usize,
+    data_crc: u32,
+    lbas: [u64; MAX_TX_BLOCKS],
+}
+
+/// A device whose writes go through a write-ahead log.
+pub struct Wal<D: BlockDevice> {
+    dev: D,
+    /// Blocks of the journal, superblock included; the data area follows
+    journal_blocks: u64,
+    half_blocks: u64,
+    /// Sequence number of the next transaction, its half is `seq % 2`
+    seq: u64,
+    staged: [u64; MAX_TX_BLOCKS],
+    staged_len: usize,
+    /// Running, not yet inverted, CRC32 of the staged data
+    data_crc: u32,
+    /// A commit failed after its record was written, the home blocks may
+    /// be behind the journal
+    needs_recovery: bool,
+    recovery: Recovery,
+}
+
+impl<D: BlockDevice> Wal<D> {
+    /// Write an empty journal of `journal_blocks` at the start of `dev`.
+    /// What the data area held is kept.
+    pub fn format(mut dev: D, journal_blocks: u64) -> Result<Self, BlockError> {
+        if dev.block_size() != BLOCK_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        if journal_blocks < MIN_JOURNAL_BLOCKS || journal_blocks >= dev.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        let mut block = [0u8; BLOCK_SIZE];
+        // no record of an earlier journal must look valid
+        let half_blocks = (journal_blocks - 1) / 2;
+        dev.write_blocks(1, &block)?;
+        dev.write_blocks(1 + half_blocks, &block)?;
+        block[..8].copy_from_slice(&SUPER_MAGIC);
+        block[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        block[12..20].copy_from_slice(&journal_blocks.to_le_bytes());
+        seal(&mut block);
+        dev.write_blocks(0, &block)?;
+        dev.flush()?;
+        Ok(Self::new(dev, journal_blocks))
+    }
+
+    /// Open the journal on `dev`, replaying the last committed transaction
+    /// where it did not reach its home blocks.
+    pub fn mount(mut dev: D) -> Result<Self, BlockError> {
+        if dev.block_size() != BLOCK_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        let mut block = [0u8; BLOCK_SIZE];
+        dev.read_blocks(0, &mut block)?;
+        if block[..8] != SUPER_MAGIC || !sealed(&block) {
+            return Err(BlockError::NotFound);
+        }
+        if u32_le(&block, 8) != VERSION {
+            return Err(BlockError::Unsupported);
+        }
+        let journal_blocks = u64_le(&block, 12);
+        if journal_blocks < MIN_JOURNAL_BLOCKS || journal_blocks >= dev.block_count() {
+            return Err(BlockError::Corrupted);
+        }
+        let mut wal = Self::new(dev, journal_blocks);
+        wal.recover()?;
+        Ok(wal)
+    }
+
+    fn new(dev: D, journal_blocks: u64) -> Self {
+        Self {
+            dev,
+            journal_blocks,
+            half_blocks: (journal_blocks - 1) / 2,
+            seq: 1,
+            staged: [0; MAX_TX_BLOCKS],
+            staged_len: 0,
+            data_crc: !0,
+            needs_recovery: false,
+            recovery: Recovery::default(),
+        }
+    }
+
+    /// What the last mount found.
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    /// Blocks one transaction can write.
+    pub fn transaction_capacity(&self) -> usize {
+        MAX_TX_BLOCKS.min((self.half_blocks - 1) as usize)
+    }
+
+    /// Start a transaction. Writes staged in it reach the data area only
+    /// when it commits.
+    pub fn begin(&mut self) -> Result<Transaction<'_, D>, BlockError> {
+        if self.needs_recovery {
+            self.recover()?;
+        }
+        self.staged_len = 0;
+        self.data_crc = !0;
+        Ok(Transaction { wal: self })
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    /// First block of the half used by transaction `seq`, holding its
+    /// record; the staged blocks follow.
+    fn half(&self, seq: u64) -> u64 {
+        1 + seq % 2 * self.half_blocks
+    }
+
+    /// Blocks in a buffer of `len` bytes at `lba` of the data area.
+    fn blocks(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        if len == 0 || !len.is_multiple_of(BLOCK_SIZE) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        let count = (len / BLOCK_SIZE) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// Read both halves, replay the newest committed transaction and pick
+    /// the sequence number after the newest record.
+    fn recover(&mut self) -> Result<(), BlockError> {
+        let mut newest = 0;
+        let mut committed: Option<(u64, Record)> = None;
+        for half in 0..2 {
+            let Some(record) = self.read_record(half)? else {
+                continue;
+            };
+            newest = newest.max(record.seq);
+            if committed
+                .as_ref()
+                .is_some_and(|(_, best)| best.seq > record.seq)
+            {
+                continue;
+            }
+            // staging of a later transaction may have overwritten the data
+            if self.staged_crc(half, &record)? == record.data_crc {
+                committed = Some((half, record));
+            }
+        }
+        self.recovery = Recovery::default();
+        if let Some((half, record)) = committed {
+            self.recovery.committed = Some(record.seq);
+            self.recovery.replayed = self.replay(half, &record)?;
+            if self.recovery.replayed != 0 {
+                warn!(
+                    "wal: replayed {} blocks of transaction {}",
+                    self.recovery.replayed, record.seq
+                );
+            }
+        }
+        self.seq = newest + 1;
+        self.staged_len = 0;
+        self.needs_recovery = false;
+        Ok(())
+    }
+
+    /// The commit record of `half`, `None` when there is no valid one.
+    fn read_record(&mut self, half: u64) -> Result<Option<Record>, BlockError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        self.dev
+            .read_blocks(1 + half * self.half_blocks, &mut block)?;
+        if block[..4] != RECORD_MAGIC || !sealed(&block) {
+            return Ok(None);
+        }
+        let seq = u64_le(&block, 4);
+        let count = u32_le(&block, 12) as usize;
+        if seq % 2 != half || count == 0 || count > self.transaction_capacity() {
+            return Ok(None);
+        }
+        let mut lbas = [0; MAX_TX_BLOCKS];
+        for (i, lba) in lbas[..count].iter_mut().enumerate() {
+            *lba = u64_le(&block, RECORD_LBAS + 8 * i);
+            if *lba >= self.block_count() {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Record {
+            seq,
+            count,
+            data_crc: u32_le(&block, 16),
+            lbas,
+        }))
+    }
+
+    /// CRC32 of the blocks staged in `half` for `record`.
+    fn staged_crc(&mut self, half: u64, record: &Record) -> Result<u32, BlockError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut crc = !0;
+        let base = 1 + half * self.half_blocks + 1;
+        for i in 0..record.count as u64 {
+            self.dev.read_blocks(base + i, &mut block)?;
+            crc = crc32_update(crc, &block);
+        }
+        Ok(!crc)
+    }
+
+    /// Copy the staged blocks of `record` home, skipping the ones already
+    /// there, and return how many were written.
+    fn replay(&mut self, half: u64, record: &Record) -> Result<u32, BlockError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut home = [0u8; BLOCK_SIZE];
+        let base = 1 + half * self.half_blocks + 1;
+        let mut written = 0;
+        for (i, &lba) in record.lbas[..record.count].iter().enumerate() {
+            self.dev.read_blocks(base + i as u64, &mut block)?;
+            let lba = self.journal_blocks + lba;
+            self.dev.read_blocks(lba, &mut home)?;
+            if home != block {
+                self.dev.write_blocks(lba, &block)?;
+                written += 1;
+            }
+        }
+        self.dev.flush()?;
+        Ok(written)
+    }
+
+    fn stage(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = self.blocks(lba, buf.len())?;
+        if self.staged_len + count as usize > self.transaction_capacity() {
+            return Err(BlockError::NoSpace);
+        }
+        let at = self.half(self.seq) + 1 + self.staged_len as u64;
+        self.dev.write_blocks(at, buf)?;
+        for (i, block) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.data_crc = crc32_update(self.data_crc, block);
+            self.staged[self.staged_len] = lba + i as u64;
+            self.staged_len += 1;
+        }
+        Ok(())
+    }
+
+    /// Read through the staged blocks, the newest copy of a block winning.
+    fn read_staged(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.blocks(lba, buf.len())?;
+        self.dev.read_blocks(self.journal_blocks + lba, buf)?;
+        let base = self.half(self.seq) + 1;
+        for (i, block) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let lba = lba + i as u64;
+            if let Some(index) = self.staged[..self.staged_len]
+                .iter()
+                .rposition(|&s| s == lba)
+            {
+                self.dev.read_blocks(base + index as u64, block)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), BlockError> {
+        if self.staged_len == 0 {
+            return Ok(());
+        }
+        let count = self.staged_len;
+        let half = self.half(self.seq);
+        self.dev.flush()?;
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..4].copy_from_slice(&RECORD_MAGIC);
+        block[4..12].copy_from_slice(&self.seq.to_le_bytes());
+        block[12..16].copy_from_slice(&(count as u32).to_le_bytes());
+        block[16..20].copy_from_slice(&(!self.data_crc).to_le_bytes());
+        for (i, lba) in self.staged[..count].iter().enumerate() {
+            let at = RECORD_LBAS + 8 * i;
+            block[at..at + 8].copy_from_slice(&lba.to_le_bytes());
+        }
+        seal(&mut block);
+        self.dev.write_blocks(half, &block)?;
+        // from here on the transaction is durable, a failure leaves the
+        // copy home to recovery
+        self.needs_recovery = true;
+        self.dev.flush()?;
+        for i in 0..count {
+            self.dev.read_blocks(half + 1 + i as u64, &mut block)?;
+            self.dev
+                .write_blocks(self.journal_blocks + self.staged[i], &block)?;
+        }
+        self.dev.flush()?;
+        self.needs_recovery = false;
+        self.seq += 1;
+        self.staged_len = 0;
+        Ok(())
+    }
+}
+
+/// Writes staged in a [`Wal`], dropped unless committed.
+pub struct Transaction<'a, D: BlockDevice> {
+    wal: &'a mut Wal<D>,
+}
+
+impl<D: BlockDevice> Transaction<'_, D> {
+    /// Stage `buf` for the blocks from `lba` of the data area. Fails with
+    /// `NoSpace` past [`Wal::transaction_capacity`] blocks.
+    pub fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.wal.stage(lba, buf)
+    }
+
+    /// Read blocks of the data area as the transaction left them.
+    pub fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.wal.read_staged(lba, buf)
+    }
+
+    /// Make the staged writes durable and copy them home. Once this fails
+    /// past writing the commit record, the next [`Wal::begin`] or mount
+    /// finishes the copy.
+    pub fn commit(self) -> Result<(), BlockError> {
+        self.wal.commit()
+    }
+
+    /// Drop the staged writes.
+    pub fn abort(self) {}
+}
+
+impl<D: BlockDevice> Drop for Transaction<'_, D> {
+    fn drop(&mut self) {
+        self.wal.staged_len = 0;
+    }
+}
+
+/// The data area; every write is a transaction of its own.
+impl<D: BlockDevice> BlockDevice for Wal<D> {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.dev.block_count() - self.journal_blocks
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.blocks(lba, buf.len())?;
+        self.dev.read_blocks(self.journal_blocks + lba, buf)
+    }
+
+    /// Fails with `NoSpace` past [`Wal::transaction_capacity`] blocks.
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let mut tx = self.begin()?;
+        tx.write(lba, buf)?;
+        tx.commit()
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+
+    /// Not journaled: the content is undefined afterwards anyway.
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => {
+                self.dev.erase(self.journal_blocks + lba, count)
+            }
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.dev.is_read_only()
+    }
+}
+
+/// Close `block` with the CRC32 of what precedes it.
+fn seal(block: &mut [u8; BLOCK_SIZE]) {
+    let crc = crc32(&block[..CRC_OFFSET]);
+    block[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn sealed(block: &[u8; BLOCK_SIZE]) -> bool {
+    crc32(&block[..CRC_OFFSET]) == u32_le(block, CRC_OFFSET)
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    const BLOCKS: usize = 64;
+    const JOURNAL: u64 = 21;
+    const DATA: usize = (BLOCKS - JOURNAL as usize) * BLOCK_SIZE;
+
+    /// Writes of one transaction: address, blocks and fill byte.
+    type Tx = [(u64, usize, u8); 4];
+
+    /// Overlapping writes, so replaying in the wrong order shows.
+    fn transactions() -> Vec<Tx> {
+        (0..6u8)
+            .map(|t| {
+                let lba = u64::from(t) % 5;
+                [
+                    (lba, 2, 0x10 + t),
+                    (30 + u64::from(t), 1, 0x20 + t),
+                    (lba + 1, 3, 0x30 + t),
+                    (40, 1, 0x40 + t),
+                ]
+            })
+            .collect()
+    }
+
+    /// Data area after each number of committed transactions.
+    fn states(txs: &[Tx]) -> Vec<Vec<u8>> {
+        let mut states = vec![vec![0xAA; DATA]];
+        for tx in txs {
+            let mut state = states.last().unwrap().clone();
+            for &(lba, blocks, fill) in tx {
+                let at = lba as usize * BLOCK_SIZE;
+                state[at..at + blocks * BLOCK_SIZE].fill(fill);
+            }
+            states.push(state);
+        }
+        states
+    }
+
+    /// Run `txs` until one fails, returning how many committed.
+    fn run<D: BlockDevice>(wal: &mut Wal<D>, txs: &[Tx]) -> usize {
+        for (committed, tx) in txs.iter().enumerate() {
+            let Ok(mut t) = wal.begin() else {
+                return committed;
+            };
+            for &(lba, blocks, fill) in tx {
+                if t.write(lba, &vec![fill; blocks * BLOCK_SIZE]).is_err() {
+                    return committed;
+                }
+            }
+            if t.commit().is_err() {
+                return committed;
+            }
+        }
+        txs.len()
+    }
+
+    fn data_area<D: BlockDevice>(wal: &mut Wal<D>) -> Vec<u8> {
+        let mut area = vec![0; DATA];
+        wal.read_blocks(0, &mut area).unwrap();
+        area
+    }
+
+    #[test]
+    fn every_power_cut_leaves_old_or_new_data() {
+        let txs = transactions();
+        let states = states(&txs);
+        let mut formatted = vec![0xAA; BLOCKS * BLOCK_SIZE];
+        Wal::format(RamDisk::new(&mut formatted), JOURNAL).unwrap();
+
+        let mut image = formatted.clone();
+        let mut wal = Wal::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(run(&mut wal, &txs), txs.len());
+        let writes = wal.into_inner().block_writes();
+        assert_eq!(image[JOURNAL as usize * BLOCK_SIZE..], states[txs.len()]);
+
+        for tear in [false, true] {
+            for cut in 0..=writes {
+                let mut image = formatted.clone();
+                let mut disk = RamDisk::new(&mut image);
+                disk.cut_power_after(cut, tear);
+                let mut wal = Wal::mount(disk).unwrap();
+                let committed = run(&mut wal, &txs);
+                let mut disk = wal.into_inner();
+                disk.power_on();
+
+                let mut wal = Wal::mount(disk).unwrap();
+                let area = data_area(&mut wal);
+                // the transaction cut during its commit may have made it
+                let state = states.iter().position(|state| *state == area);
+                assert!(
+                    state.is_some_and(|state| state == committed || state == committed + 1),
+                    "cut after {cut} writes, tear {tear}: {state:?}, {committed} committed"
+                );
+
+                // the log keeps working after recovery
+                let mut expected = states[state.unwrap()].clone();
+                let mut tx = wal.begin().unwrap();
+                tx.write(3, &[0x77; 2 * BLOCK_SIZE]).unwrap();
+                tx.commit().unwrap();
+                let mut wal = Wal::mount(wal.into_inner()).unwrap();
+                expected[3 * BLOCK_SIZE..5 * BLOCK_SIZE].fill(0x77);
+                assert!(
+                    data_area(&mut wal) == expected,
+                    "cut after {cut} writes, tear {tear}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_larger_than_a_half_is_refused() {
+        let mut image = vec![0u8; BLOCKS * BLOCK_SIZE];
+        let mut wal = Wal::format(RamDisk::new(&mut image), JOURNAL).unwrap();
+        let capacity = wal.transaction_capacity();
+        let ret = wal.write_blocks(0, &vec![1; (capacity + 1) * BLOCK_SIZE]);
+        assert!(matches!(ret, Err(BlockError::NoSpace)));
+        assert!(data_area(&mut wal).iter().all(|&b| b == 0));
+        wal.write_blocks(0, &vec![1; capacity * BLOCK_SIZE])
+            .unwrap();
+        let area = data_area(&mut wal);
+        assert!(area[..capacity * BLOCK_SIZE].iter().all(|&b| b == 1));
+    }
+}
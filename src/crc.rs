@@ -0,0 +1,37 @@
+//! CRC32 (IEEE 802.3, reflected), as GPT and the write-ahead log use it.
+
+/// CRC32 of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Feed `data` into a running, not yet inverted, CRC32.
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn update_in_pieces() {
+        let crc = crc32_update(crc32_update(!0, b"1234"), b"56789");
+        assert_eq!(!crc, crc32(b"123456789"));
+    }
+}
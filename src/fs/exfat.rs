@@ -0,0 +1,1089 @@
+//! Read-only exFAT, as SDXC cards come formatted.
+//!
+//! Only 512 byte sectors are handled. Names are compared through the
+//! volume's up-case table for code points up to U+04FF, past that they must
+//! match exactly.
+//!
+//! ```no_run
+//! use vf2_driver::fs::exfat::ExFatFs;
+//! use vf2_driver::fs::FileSystem;
+//! use vf2_driver::part::mbr::Mbr;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let mut card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let part = Mbr::read(&mut card).unwrap().get(1).unwrap().open(&mut card).unwrap();
+//! let mut fs = ExFatFs::mount(part).unwrap();
+//! let mut buf = [0u8; 4096];
+//! let len = fs.read_file("/config.txt", &mut buf).unwrap();
+//! ```
+use log::warn;
+
+use crate::block::BlockDevice;
+
+use super::fat::Attributes;
+use super::{walk, FileSystem, FsError, Lookup};
+
+const SECTOR_SIZE: usize = 512;
+const SECTOR_SHIFT: u8 = 9;
+const FS_NAME: &[u8; 8] = b"EXFAT   ";
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Sectors of a boot region covered by its checksum, the checksum sector
+/// follows them
+const BOOT_REGION_SECTORS: u64 = 11;
+const BACKUP_BOOT_REGION: u64 = 12;
+/// Volume flags
+const ACTIVE_FAT: u16 = 1 << 0;
+const VOLUME_DIRTY: u16 = 1 << 1;
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+/// Largest directory, 256 MiB
+const MAX_DIR_ENTRIES: u64 = (256 << 20) / ENTRY_SIZE as u64;
+const TYPE_END: u8 = 0x00;
+const TYPE_BITMAP: u8 = 0x81;
+const TYPE_UPCASE: u8 = 0x82;
+const TYPE_LABEL: u8 = 0x83;
+const TYPE_FILE: u8 = 0x85;
+const TYPE_STREAM: u8 = 0xC0;
+const TYPE_NAME: u8 = 0xC1;
+/// Stream extension flags: the data is contiguous, its FAT entries unused
+const NO_FAT_CHAIN: u8 = 1 << 1;
+const NAME_CHARS: usize = 15;
+/// Secondary entries of a file: a stream extension and up to 17 names
+const MIN_SECONDARY: usize = 2;
+const MAX_SECONDARY: usize = 18;
+const LABEL_CHARS: usize = 11;
+/// Code points the up-case table is kept for
+const UPCASE_LEN: usize = 0x500;
+
+/// UTF-16 units in a name.
+pub const MAX_NAME: usize = 255;
+
+/// Clusters of a file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Chain {
+    first: u32,
+    /// Consecutive clusters from `first`, the FAT is not looked at
+    contiguous: bool,
+}
+
+/// A directory handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dir {
+    chain: Chain,
+    /// Size in bytes, `None` for the root directory that ends with its chain
+    size: Option<u64>,
+}
+
+/// An open file.
+#[derive(Debug, Clone)]
+pub struct File {
+    chain: Chain,
+    size: u64,
+    /// Bytes past this read as zeros
+    valid_size: u64,
+    pos: u64,
+    /// Cluster number `cluster_index` of the chain, 0 when not looked up
+    cluster: u32,
+    cluster_index: u64,
+}
+
+impl File {
+    fn new(chain: Chain, size: u64, valid_size: u64) -> Self {
+        Self {
+            chain,
+            size,
+            valid_size: valid_size.min(size),
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Move to byte `pos`, clamped to the end of the file.
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos.min(self.size);
+    }
+}
+
+/// A file or directory read from a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u16; MAX_NAME],
+    name_len: usize,
+    pub attributes: Attributes,
+    chain: Chain,
+    size: u64,
+    valid_size: u64,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.name[..self.name_len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::directory)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Position in a directory.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    dir: Dir,
+    cluster: u32,
+    sector: u32,
+    index: usize,
+    /// Byte offset in the directory
+    pos: u64,
+}
+
+impl Cursor {
+    fn new(dir: Dir) -> Self {
+        Self {
+            dir,
+            cluster: dir.chain.first,
+            sector: 0,
+            index: 0,
+            pos: 0,
+        }
+    }
+}
+
+/// A mounted exFAT volume.
+pub struct ExFatFs<D: BlockDevice> {
+    dev: D,
+    /// First sector of the active FAT
+    fat_start: u64,
+    heap_start: u64,
+    sectors_per_cluster: u32,
+    cluster_count: u32,
+    root: Dir,
+    serial: u32,
+    label: [u16; LABEL_CHARS],
+    label_len: usize,
+    bitmap: Chain,
+    bitmap_size: u64,
+    upcase: [u16; UPCASE_LEN],
+    cache: [u8; SECTOR_SIZE],
+    cache_lba: Option<u64>,
+}
+
+impl<D: BlockDevice> ExFatFs<D> {
+    /// Check the boot region, falling back to the backup one, and read the
+    /// allocation bitmap, up-case table and label entries of the root
+    /// directory.
+    pub fn mount(mut dev: D) -> Result<Self, FsError> {
+        if dev.block_size() != SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut boot = [0u8; SECTOR_SIZE];
+        match read_boot_region(&mut dev, 0, &mut boot) {
+            Ok(()) => {}
+            Err(FsError::Corrupted) => {
+                warn!("exfat: main boot region damaged, using the backup");
+                read_boot_region(&mut dev, BACKUP_BOOT_REGION, &mut boot)?;
+            }
+            Err(err) => return Err(err),
+        }
+        if boot[108] != SECTOR_SHIFT {
+            return Err(FsError::Unsupported);
+        }
+        let cluster_shift = boot[109];
+        let fat_offset = u64::from(u32_le(&boot, 80));
+        let fat_length = u64::from(u32_le(&boot, 84));
+        let heap_start = u64::from(u32_le(&boot, 88));
+        let cluster_count = u32_le(&boot, 92);
+        let root = u32_le(&boot, 96);
+        let flags = u16_le(&boot, 106);
+        let num_fats = boot[110];
+        if cluster_shift > 25 - SECTOR_SHIFT || !(1..=2).contains(&num_fats) {
+            return Err(FsError::Corrupted);
+        }
+        let heap_end = heap_start + (u64::from(cluster_count) << cluster_shift);
+        if heap_end > dev.block_count() {
+            return Err(FsError::Corrupted);
+        }
+        if flags & VOLUME_DIRTY != 0 {
+            warn!("exfat: volume was not cleanly unmounted");
+        }
+        let active_fat = u8::from(num_fats == 2 && flags & ACTIVE_FAT != 0);
+        let root = Dir {
+            chain: Chain {
+                first: root,
+                contiguous: false,
+            },
+            size: None,
+        };
+        let mut upcase = [0u16; UPCASE_LEN];
+        for (c, unit) in upcase.iter_mut().enumerate() {
+            *unit = c as u16;
+        }
+        let fs = Self {
+            dev,
+            fat_start: fat_offset + u64::from(active_fat) * fat_length,
+            heap_start,
+            sectors_per_cluster: 1 << cluster_shift,
+            cluster_count,
+            root,
+            serial: u32_le(&boot, 100),
+            label: [0; LABEL_CHARS],
+            label_len: 0,
+            bitmap: root.chain,
+            bitmap_size: 0,
+            upcase,
+            cache: [0; SECTOR_SIZE],
+            cache_lba: None,
+        };
+        if !fs.is_cluster(fs.root.chain.first) {
+            return Err(FsError::Corrupted);
+        }
+        fs.read_root(active_fat)
+    }
+
+    /// Pick the critical entries out of the root directory.
+    fn read_root(mut self, active_fat: u8) -> Result<Self, FsError> {
+        let mut bitmap = None;
+        let mut upcase = None;
+        let mut cursor = Cursor::new(self.root);
+        loop {
+            let raw = self.entry(&cursor)?;
+            match raw[0] {
+                TYPE_END => break,
+                // with two FATs there is a bitmap for each
+                TYPE_BITMAP if raw[1] & 1 == active_fat => {
+                    bitmap = Some((u32_le(&raw, 20), u64_le(&raw, 24)));
+                }
+                TYPE_UPCASE => upcase = Some((u32_le(&raw, 20), u64_le(&raw, 24), u32_le(&raw, 4))),
+                TYPE_LABEL => {
+                    self.label_len = usize::from(raw[1]).min(LABEL_CHARS);
+                    for (i, unit) in self.label.iter_mut().enumerate() {
+                        *unit = u16_le(&raw, 2 + 2 * i);
+                    }
+                }
+                _ => {}
+            }
+            if !self.advance(&mut cursor)? {
+                break;
+            }
+        }
+        let (first, size) = bitmap.ok_or(FsError::Corrupted)?;
+        if !self.is_cluster(first) || size * 8 < u64::from(self.cluster_count) {
+            return Err(FsError::Corrupted);
+        }
+        self.bitmap = Chain {
+            first,
+            contiguous: false,
+        };
+        self.bitmap_size = size;
+        let (first, size, checksum) = upcase.ok_or(FsError::Corrupted)?;
+        self.read_upcase(first, size, checksum)?;
+        Ok(self)
+    }
+
+    /// Expand the compressed up-case table, keeping its first `UPCASE_LEN`
+    /// mappings.
+    fn read_upcase(&mut self, first: u32, size: u64, checksum: u32) -> Result<(), FsError> {
+        let chain = Chain {
+            first,
+            contiguous: false,
+        };
+        let mut file = File::new(chain, size, size);
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut sum = 0u32;
+        let mut index = 0usize;
+        // 0xFFFF starts a run of identity mappings, its length follows
+        let mut identity = false;
+        loop {
+            let len = self.read(&mut file, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            for &byte in &buf[..len] {
+                sum = sum.rotate_right(1).wrapping_add(u32::from(byte));
+            }
+            for unit in buf[..len].chunks_exact(2) {
+                let unit = u16::from_le_bytes([unit[0], unit[1]]);
+                if identity {
+                    index += usize::from(unit);
+                    identity = false;
+                } else if unit == 0xFFFF {
+                    identity = true;
+                } else {
+                    if let Some(upper) = self.upcase.get_mut(index) {
+                        *upper = unit;
+                    }
+                    index += 1;
+                }
+            }
+        }
+        if sum != checksum {
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+
+    /// Volume label, empty when there is none.
+    pub fn label(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.label[..self.label_len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Cluster size in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Clear bits of the allocation bitmap.
+    pub fn free_clusters(&mut self) -> Result<u32, FsError> {
+        let mut file = File::new(self.bitmap, self.bitmap_size, self.bitmap_size);
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut left = self.cluster_count;
+        let mut free = 0;
+        while left > 0 {
+            let len = self.read(&mut file, &mut buf)?;
+            if len == 0 {
+                return Err(FsError::Corrupted);
+            }
+            for &byte in &buf[..len] {
+                let bits = left.min(8);
+                free += (!byte & ((1u16 << bits) - 1) as u8).count_ones();
+                left -= bits;
+                if left == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(free)
+    }
+
+    pub fn root_dir(&self) -> Dir {
+        self.root
+    }
+
+    pub fn read_dir(&mut self, dir: Dir) -> ReadDir<'_, D> {
+        ReadDir {
+            fs: self,
+            cursor: Some(Cursor::new(dir)),
+        }
+    }
+
+    /// Directory at `path`.
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        match walk(self, path)? {
+            Some(entry) => self.entry_dir(&entry),
+            None => Ok(self.root),
+        }
+    }
+
+    /// Entry at `path`, `None` for the root directory.
+    pub fn metadata(&mut self, path: &str) -> Result<Option<DirEntry>, FsError> {
+        walk(self, path)
+    }
+
+    /// Open the file at `path`.
+    pub fn open(&mut self, path: &str) -> Result<File, FsError> {
+        match walk(self, path)? {
+            Some(entry) if !entry.is_dir() => {
+                Ok(File::new(entry.chain, entry.size, entry.valid_size))
+            }
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Read from the position of `file` into `buf`, returning the number of
+    /// bytes read, 0 at the end of the file.
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = (buf.len() as u64).min(file.size - file.pos) as usize;
+        let mut done = 0;
+        while done < len {
+            if file.pos >= file.valid_size {
+                // past the valid data length the content is undefined on
+                // disk and reads as zeros
+                buf[done..len].fill(0);
+                file.pos += (len - done) as u64;
+                break;
+            }
+            let want = (len - done).min((file.valid_size - file.pos) as usize);
+            let cluster = self.file_cluster(file)?;
+            let in_cluster = (file.pos % self.cluster_size() as u64) as usize;
+            let lba = self.cluster_lba(cluster) + (in_cluster / SECTOR_SIZE) as u64;
+            let offset = in_cluster % SECTOR_SIZE;
+            let n = if offset == 0 && want >= SECTOR_SIZE {
+                // whole sectors go straight into `buf`
+                let n = want.min(self.cluster_size() - in_cluster) / SECTOR_SIZE * SECTOR_SIZE;
+                self.dev.read_blocks(lba, &mut buf[done..done + n])?;
+                n
+            } else {
+                let n = want.min(SECTOR_SIZE - offset);
+                buf[done..done + n].copy_from_slice(&self.sector(lba)?[offset..offset + n]);
+                n
+            };
+            done += n;
+            file.pos += n as u64;
+        }
+        Ok(len)
+    }
+
+    /// Cluster holding the position of `file`.
+    fn file_cluster(&mut self, file: &mut File) -> Result<u32, FsError> {
+        let index = file.pos / self.cluster_size() as u64;
+        if file.chain.contiguous {
+            return u32::try_from(index)
+                .ok()
+                .and_then(|index| file.chain.first.checked_add(index))
+                .filter(|&cluster| self.is_cluster(cluster))
+                .ok_or(FsError::Corrupted);
+        }
+        if file.cluster == 0 || file.cluster_index > index {
+            file.cluster = file.chain.first;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < index {
+            file.cluster = self
+                .next_cluster(&file.chain, file.cluster)?
+                .ok_or(FsError::Corrupted)?;
+            file.cluster_index += 1;
+        }
+        if !self.is_cluster(file.cluster) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(file.cluster)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.heap_start + u64::from(cluster - 2) * u64::from(self.sectors_per_cluster)
+    }
+
+    /// Cluster after `cluster` in `chain`, `None` at the end of a FAT chain.
+    fn next_cluster(&mut self, chain: &Chain, cluster: u32) -> Result<Option<u32>, FsError> {
+        let next = if chain.contiguous {
+            cluster + 1
+        } else {
+            let offset = u64::from(cluster) * 4;
+            let lba = self.fat_start + offset / SECTOR_SIZE as u64;
+            let next = u32_le(self.sector(lba)?, (offset % SECTOR_SIZE as u64) as usize);
+            if next == END_OF_CHAIN {
+                return Ok(None);
+            }
+            next
+        };
+        if !self.is_cluster(next) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Some(next))
+    }
+
+    fn sector(&mut self, lba: u64) -> Result<&[u8; SECTOR_SIZE], FsError> {
+        if self.cache_lba != Some(lba) {
+            self.cache_lba = None;
+            self.dev.read_blocks(lba, &mut self.cache)?;
+            self.cache_lba = Some(lba);
+        }
+        Ok(&self.cache)
+    }
+
+    fn entry(&mut self, cursor: &Cursor) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let lba = self.cluster_lba(cursor.cluster) + u64::from(cursor.sector);
+        let offset = cursor.index * ENTRY_SIZE;
+        Ok(self.sector(lba)?[offset..offset + ENTRY_SIZE]
+            .try_into()
+            .unwrap())
+    }
+
+    /// Move to the next entry, false at the end of the directory.
+    fn advance(&mut self, cursor: &mut Cursor) -> Result<bool, FsError> {
+        cursor.pos += ENTRY_SIZE as u64;
+        if cursor.dir.size.is_some_and(|size| cursor.pos >= size) {
+            return Ok(false);
+        }
+        if cursor.pos / ENTRY_SIZE as u64 >= MAX_DIR_ENTRIES {
+            return Err(FsError::Corrupted);
+        }
+        if cursor.index + 1 < ENTRIES_PER_SECTOR {
+            cursor.index += 1;
+            return Ok(true);
+        }
+        cursor.index = 0;
+        if cursor.sector + 1 < self.sectors_per_cluster {
+            cursor.sector += 1;
+            return Ok(true);
+        }
+        cursor.sector = 0;
+        match self.next_cluster(&cursor.dir.chain, cursor.cluster)? {
+            Some(next) => {
+                cursor.cluster = next;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn upcase(&self, unit: u16) -> u16 {
+        self.upcase.get(usize::from(unit)).copied().unwrap_or(unit)
+    }
+
+    fn name_eq(&self, entry: &DirEntry, name: &str) -> bool {
+        entry.name[..entry.name_len]
+            .iter()
+            .map(|&unit| self.upcase(unit))
+            .eq(name.encode_utf16().map(|unit| self.upcase(unit)))
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FsError> {
+        let mut entries = self.read_dir(dir);
+        while let Some(entry) = entries.next().transpose()? {
+            if entries.fs.name_eq(&entry, name) {
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    fn entry_dir(&self, entry: &DirEntry) -> Result<Dir, FsError> {
+        if !entry.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if !self.is_cluster(entry.chain.first) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Dir {
+            chain: entry.chain,
+            size: Some(entry.size),
+        })
+    }
+}
+
+/// Iterator over the files and directories of a directory.
+pub struct ReadDir<'a, D: BlockDevice> {
+    fs: &'a mut ExFatFs<D>,
+    cursor: Option<Cursor>,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FsError> {
+        while let Some(raw) = self.next_raw()? {
+            if raw[0] == TYPE_END {
+                self.cursor = None;
+                return Ok(None);
+            }
+            if raw[0] == TYPE_FILE {
+                if let Some(entry) = self.read_set(&raw)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_raw(&mut self) -> Result<Option<[u8; ENTRY_SIZE]>, FsError> {
+        let Some(mut cursor) = self.cursor else {
+            return Ok(None);
+        };
+        let raw = self.fs.entry(&cursor)?;
+        self.cursor = self.fs.advance(&mut cursor)?.then_some(cursor);
+        Ok(Some(raw))
+    }
+
+    /// The entry set starting with the file entry `file`, `None` when it is
+    /// damaged.
+    fn read_set(&mut self, file: &[u8; ENTRY_SIZE]) -> Result<Option<DirEntry>, FsError> {
+        let count = usize::from(file[1]);
+        if !(MIN_SECONDARY..=MAX_SECONDARY).contains(&count) {
+            warn!("exfat: file entry with {count} secondary entries, skipping it");
+            return Ok(None);
+        }
+        let mut checksum = set_checksum(0, file, true);
+        let mut entry = DirEntry {
+            name: [0; MAX_NAME],
+            name_len: 0,
+            attributes: Attributes::from_bits_truncate(file[4]),
+            chain: Chain {
+                first: 0,
+                contiguous: false,
+            },
+            size: 0,
+            valid_size: 0,
+        };
+        let mut names = 0;
+        for i in 0..count {
+            let Some(raw) = self.next_raw()? else {
+                return Ok(None);
+            };
+            checksum = set_checksum(checksum, &raw, false);
+            match (i, raw[0]) {
+                (0, TYPE_STREAM) => {
+                    entry.name_len = usize::from(raw[3]);
+                    entry.chain = Chain {
+                        first: u32_le(&raw, 20),
+                        contiguous: raw[1] & NO_FAT_CHAIN != 0,
+                    };
+                    entry.valid_size = u64_le(&raw, 8);
+                    entry.size = u64_le(&raw, 24);
+                }
+                (0, _) => return Ok(None),
+                (_, TYPE_NAME) => {
+                    let start = names * NAME_CHARS;
+                    for (j, unit) in entry.name[start..(start + NAME_CHARS).min(MAX_NAME)]
+                        .iter_mut()
+                        .enumerate()
+                    {
+                        *unit = u16_le(&raw, 2 + 2 * j);
+                    }
+                    names += 1;
+                }
+                // vendor extensions
+                _ => {}
+            }
+        }
+        if checksum != u16_le(file, 2) {
+            warn!("exfat: entry set checksum mismatch, skipping it");
+            return Ok(None);
+        }
+        if names * NAME_CHARS < entry.name_len {
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.cursor = None;
+        }
+        entry.transpose()
+    }
+}
+
+impl<D: BlockDevice> Lookup for ExFatFs<D> {
+    type Dir = Dir;
+    type Entry = DirEntry;
+
+    fn root_dir(&self) -> Dir {
+        self.root
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FsError> {
+        ExFatFs::find(self, dir, name)
+    }
+
+    fn entry_dir(&self, entry: &DirEntry) -> Result<Dir, FsError> {
+        ExFatFs::entry_dir(self, entry)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for ExFatFs<D> {
+    type File = File;
+    type Dir = Dir;
+
+    fn open(&mut self, path: &str) -> Result<File, FsError> {
+        ExFatFs::open(self, path)
+    }
+
+    fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        ExFatFs::open_dir(self, path)
+    }
+
+    fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        ExFatFs::read(self, file, buf)
+    }
+}
+
+/// Check the boot region at `start` against its checksum sector and copy
+/// its boot sector to `boot`.
+fn read_boot_region<D: BlockDevice>(
+    dev: &mut D,
+    start: u64,
+    boot: &mut [u8; SECTOR_SIZE],
+) -> Result<(), FsError> {
+    let mut buf = [0u8; SECTOR_SIZE];
+    let mut checksum = 0u32;
+    for sector in 0..BOOT_REGION_SECTORS {
+        dev.read_blocks(start + sector, &mut buf)?;
+        if sector == 0 {
+            // the BPB area of FAT must be zero
+            if &buf[3..11] != FS_NAME
+                || buf[11..64].iter().any(|&byte| byte != 0)
+                || buf[510..] != BOOT_SIGNATURE
+            {
+                return Err(FsError::Corrupted);
+            }
+            boot.copy_from_slice(&buf);
+        }
+        for (i, &byte) in buf.iter().enumerate() {
+            // volume flags and percent in use change without a new checksum
+            if sector == 0 && matches!(i, 106 | 107 | 112) {
+                continue;
+            }
+            checksum = checksum.rotate_right(1).wrapping_add(u32::from(byte));
+        }
+    }
+    dev.read_blocks(start + BOOT_REGION_SECTORS, &mut buf)?;
+    if buf.chunks_exact(4).any(|word| u32_le(word, 0) != checksum) {
+        return Err(FsError::Corrupted);
+    }
+    Ok(())
+}
+
+/// Feed an entry into an entry set checksum, skipping the checksum field of
+/// the file entry.
+fn set_checksum(mut sum: u16, raw: &[u8], primary: bool) -> u16 {
+    for (i, &byte) in raw.iter().enumerate() {
+        if primary && (i == 2 || i == 3) {
+            continue;
+        }
+        sum = sum.rotate_right(1).wrapping_add(u16::from(byte));
+    }
+    sum
+}
+
+fn u16_le(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    const SECTORS: usize = 96;
+    const FAT_START: usize = 24;
+    const HEAP_START: usize = 32;
+    const CLUSTERS: u32 = 64;
+    const ROOT: u32 = 4;
+    const KERNEL: &str = "vmlinuz-6.6.20-starfive";
+    const KERNEL_PATH: &str = "/Boot/vmlinuz-6.6.20-starfive";
+
+    fn cluster(image: &mut [u8], cluster: u32) -> &mut [u8] {
+        &mut image[(HEAP_START + cluster as usize - 2) * SECTOR_SIZE..][..SECTOR_SIZE]
+    }
+
+    fn put32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Recompute the boot region checksum and copy the region to the backup.
+    fn seal(image: &mut [u8]) {
+        let mut checksum = 0u32;
+        for (i, &byte) in image[..BOOT_REGION_SECTORS as usize * SECTOR_SIZE]
+            .iter()
+            .enumerate()
+        {
+            if !matches!(i, 106 | 107 | 112) {
+                checksum = checksum.rotate_right(1).wrapping_add(u32::from(byte));
+            }
+        }
+        let sector = &mut image[BOOT_REGION_SECTORS as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        for word in sector.chunks_exact_mut(4) {
+            word.copy_from_slice(&checksum.to_le_bytes());
+        }
+        let backup = BACKUP_BOOT_REGION as usize * SECTOR_SIZE;
+        image.copy_within(..backup, backup);
+    }
+
+    /// File entry set for `name`, with its checksum.
+    fn file_set(
+        name: &str,
+        attributes: Attributes,
+        chain: Chain,
+        size: u64,
+        valid: u64,
+    ) -> Vec<[u8; ENTRY_SIZE]> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let mut set = Vec::new();
+        let mut file = [0u8; ENTRY_SIZE];
+        file[0] = TYPE_FILE;
+        file[1] = (1 + units.len().div_ceil(NAME_CHARS)) as u8;
+        file[4] = attributes.bits();
+        set.push(file);
+        let mut stream = [0u8; ENTRY_SIZE];
+        stream[0] = TYPE_STREAM;
+        stream[1] = 1 | if chain.contiguous { NO_FAT_CHAIN } else { 0 };
+        stream[3] = units.len() as u8;
+        put64(&mut stream, 8, valid);
+        put32(&mut stream, 20, chain.first);
+        put64(&mut stream, 24, size);
+        set.push(stream);
+        for chunk in units.chunks(NAME_CHARS) {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = TYPE_NAME;
+            for (i, unit) in chunk.iter().enumerate() {
+                entry[2 + 2 * i..4 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+            }
+            set.push(entry);
+        }
+        let checksum = set
+            .iter()
+            .enumerate()
+            .fold(0, |sum, (i, raw)| set_checksum(sum, raw, i == 0));
+        set[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        set
+    }
+
+    fn data(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i + seed) * 13 % 251) as u8).collect()
+    }
+
+    /// One sector clusters: bitmap in 2, up-case table in 3, the root
+    /// directory chained through 4 and 5, `/Boot` in 6 and the kernel in it
+    /// contiguous in 7-9, `/config.txt` chained through 10 and 12 with only
+    /// 600 of its 700 bytes valid.
+    fn image() -> Vec<u8> {
+        let mut image = std::vec![0u8; SECTORS * SECTOR_SIZE];
+        let boot = &mut image[..SECTOR_SIZE];
+        boot[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(FS_NAME);
+        put64(boot, 72, SECTORS as u64);
+        put32(boot, 80, FAT_START as u32);
+        put32(boot, 84, 1);
+        put32(boot, 88, HEAP_START as u32);
+        put32(boot, 92, CLUSTERS);
+        put32(boot, 96, ROOT);
+        put32(boot, 100, 0x1234_ABCD);
+        boot[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+        boot[108] = SECTOR_SHIFT;
+        boot[110] = 1;
+        boot[510..].copy_from_slice(&BOOT_SIGNATURE);
+        seal(&mut image);
+
+        let fat = &mut image[FAT_START * SECTOR_SIZE..][..SECTOR_SIZE];
+        for (cluster, next) in [
+            (0, 0xFFFF_FFF8),
+            (1, END_OF_CHAIN),
+            (2, END_OF_CHAIN),
+            (3, END_OF_CHAIN),
+            (4, 5),
+            (5, END_OF_CHAIN),
+            (10, 12),
+            (12, END_OF_CHAIN),
+        ] {
+            put32(fat, cluster * 4, next);
+        }
+        // clusters 2-10 and 12 in use
+        cluster(&mut image, 2)[..2].copy_from_slice(&[0xFF, 0x05]);
+
+        let mut upcase = [0u8; 256];
+        for c in 0..128u16 {
+            let upper = u16::from((c as u8).to_ascii_uppercase());
+            upcase[2 * c as usize..2 * c as usize + 2].copy_from_slice(&upper.to_le_bytes());
+        }
+        let upcase_sum = upcase.iter().fold(0u32, |sum, &byte| {
+            sum.rotate_right(1).wrapping_add(u32::from(byte))
+        });
+        cluster(&mut image, 3)[..256].copy_from_slice(&upcase);
+
+        let mut root = Vec::new();
+        let mut bitmap = [0u8; ENTRY_SIZE];
+        bitmap[0] = TYPE_BITMAP;
+        put32(&mut bitmap, 20, 2);
+        put64(&mut bitmap, 24, u64::from(CLUSTERS) / 8);
+        root.push(bitmap);
+        let mut upcase = [0u8; ENTRY_SIZE];
+        upcase[0] = TYPE_UPCASE;
+        put32(&mut upcase, 4, upcase_sum);
+        put32(&mut upcase, 20, 3);
+        put64(&mut upcase, 24, 256);
+        root.push(upcase);
+        let mut label = [0u8; ENTRY_SIZE];
+        label[0] = TYPE_LABEL;
+        label[1] = 4;
+        for (i, unit) in "SDXC".encode_utf16().enumerate() {
+            label[2 + 2 * i..4 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+        }
+        root.push(label);
+        let dir = Chain {
+            first: 6,
+            contiguous: true,
+        };
+        root.extend(file_set("Boot", Attributes::directory, dir, 512, 512));
+        // deleted entries so the next set crosses into the second cluster
+        while root.len() < ENTRIES_PER_SECTOR - 1 {
+            let mut deleted = [0u8; ENTRY_SIZE];
+            deleted[0] = TYPE_FILE & 0x7F;
+            root.push(deleted);
+        }
+        let config = Chain {
+            first: 10,
+            contiguous: false,
+        };
+        root.extend(file_set(
+            "config.txt",
+            Attributes::archive,
+            config,
+            700,
+            600,
+        ));
+        for (i, raw) in root.iter().enumerate() {
+            let dir = cluster(&mut image, ROOT + (i / ENTRIES_PER_SECTOR) as u32);
+            let at = i % ENTRIES_PER_SECTOR * ENTRY_SIZE;
+            dir[at..at + ENTRY_SIZE].copy_from_slice(raw);
+        }
+
+        let kernel = Chain {
+            first: 7,
+            contiguous: true,
+        };
+        let set = file_set(KERNEL, Attributes::archive, kernel, 1200, 1200);
+        for (i, raw) in set.iter().enumerate() {
+            cluster(&mut image, 6)[i * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(raw);
+        }
+        let at = (HEAP_START + 5) * SECTOR_SIZE;
+        image[at..at + 1200].copy_from_slice(&data(1200, 0));
+        let config = data(600, 1);
+        cluster(&mut image, 10).copy_from_slice(&config[..512]);
+        cluster(&mut image, 12)[..88].copy_from_slice(&config[512..]);
+        image
+    }
+
+    fn read_all<D: BlockDevice>(fs: &mut ExFatFs<D>, path: &str) -> Vec<u8> {
+        let mut buf = std::vec![0u8; 4096];
+        let len = fs.read_file(path, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn mount_reads_the_critical_entries() {
+        let mut image = image();
+        let mut fs = ExFatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert!(fs.label().eq("SDXC".chars()));
+        assert_eq!(fs.serial(), 0x1234_ABCD);
+        assert_eq!(fs.cluster_count(), CLUSTERS);
+        assert_eq!(fs.free_clusters().unwrap(), CLUSTERS - 10);
+    }
+
+    #[test]
+    fn lookup_and_read() {
+        let mut image = image();
+        let mut fs = ExFatFs::mount(RamDisk::new(&mut image)).unwrap();
+        let root = fs.root_dir();
+        let names: Vec<std::string::String> = fs
+            .read_dir(root)
+            .map(|entry| entry.unwrap().name().collect())
+            .collect();
+        assert_eq!(names, ["Boot", "config.txt"]);
+
+        // contiguous, with a name spanning two name entries, found through
+        // the up-case table
+        assert_eq!(
+            read_all(&mut fs, "/boot/VMLINUZ-6.6.20-STARFIVE"),
+            data(1200, 0)
+        );
+        let kernel = fs
+            .metadata("/Boot/../Boot/vmlinuz-6.6.20-starfive")
+            .unwrap()
+            .unwrap();
+        assert!(kernel.name().eq(KERNEL.chars()));
+        assert_eq!(kernel.size(), 1200);
+
+        // FAT chain, zeros past the valid data length
+        let config = read_all(&mut fs, "/CONFIG.TXT");
+        assert_eq!(config.len(), 700);
+        assert_eq!(config[..600], data(600, 1));
+        assert!(config[600..].iter().all(|&b| b == 0));
+
+        assert!(matches!(fs.open("/Boot"), Err(FsError::IsADirectory)));
+        assert!(matches!(
+            fs.open_dir("/config.txt"),
+            Err(FsError::NotADirectory)
+        ));
+        assert!(matches!(fs.open("/Boot/Image"), Err(FsError::NotFound)));
+    }
+
+    #[test]
+    fn damaged_entry_set_is_skipped() {
+        let mut image = image();
+        // a name unit of the kernel, past the file entry checksum
+        cluster(&mut image, 6)[2 * ENTRY_SIZE + 2] ^= 1;
+        let mut fs = ExFatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert!(matches!(fs.open(KERNEL_PATH), Err(FsError::NotFound)));
+        let boot = fs.open_dir("/Boot").unwrap();
+        assert_eq!(fs.read_dir(boot).count(), 0);
+    }
+
+    #[test]
+    fn damaged_boot_region_falls_back_to_the_backup() {
+        let mut image = image();
+        image[SECTOR_SIZE + 7] ^= 1;
+        let mut fs = ExFatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(read_all(&mut fs, KERNEL_PATH).len(), 1200);
+    }
+
+    #[test]
+    fn huge_cluster_shift_is_corrupted() {
+        for shift in [17, 255] {
+            let mut image = image();
+            image[109] = shift;
+            seal(&mut image);
+            let ret = ExFatFs::mount(RamDisk::new(&mut image));
+            assert!(matches!(ret, Err(FsError::Corrupted)));
+        }
+    }
+
+    #[test]
+    fn directory_outside_the_heap_is_corrupted() {
+        for first in [0, 1, CLUSTERS + 2] {
+            let mut image = image();
+            let dir = Chain {
+                first,
+                contiguous: true,
+            };
+            let set = file_set("Boot", Attributes::directory, dir, 512, 512);
+            for (i, raw) in set.iter().enumerate() {
+                cluster(&mut image, ROOT)[(3 + i) * ENTRY_SIZE..][..ENTRY_SIZE]
+                    .copy_from_slice(raw);
+            }
+            let mut fs = ExFatFs::mount(RamDisk::new(&mut image)).unwrap();
+            assert!(fs.metadata("/Boot").unwrap().unwrap().is_dir());
+            assert!(matches!(fs.open_dir("/Boot"), Err(FsError::Corrupted)));
+            assert!(matches!(fs.open(KERNEL_PATH), Err(FsError::Corrupted)));
+        }
+    }
+}
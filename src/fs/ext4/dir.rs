@@ -0,0 +1,542 @@
+//! Directory blocks, htree lookups and symbolic links.
+use log::warn;
+
+use crate::block::BlockDevice;
+use crate::fs::{walk, walk_from, FsError, Lookup};
+
+use super::inode::{Inode, INDEX_FL};
+use super::{
+    crc32c, hash, u16_le, u32_le, Ext4Fs, COMPAT_DIR_INDEX, INCOMPAT_FILETYPE, INCOMPAT_LARGEDIR,
+};
+
+/// Bytes in a name.
+pub const MAX_NAME: usize = 255;
+/// Longest symbolic link target followed in a path
+const MAX_LINK: usize = 512;
+/// Symbolic links followed while resolving one path
+const MAX_LINK_DEPTH: u8 = 8;
+
+const DIRENT_HEADER: u32 = 8;
+/// Fake entry at the end of a leaf block holding its checksum
+const TAIL_SIZE: u32 = 12;
+const TAIL_FILE_TYPE: u8 = 0xDE;
+/// rec_len of an entry spanning a whole 64 KiB block
+const MAX_REC_LEN: u32 = 65535;
+/// Offset of dx_root_info in the first block, after `.` and `..`
+const DX_ROOT_INFO: u64 = 0x18;
+/// Offset of the entry counts in the first block and in interior nodes
+const DX_ROOT_COUNT: u64 = 0x20;
+const DX_NODE_COUNT: u64 = 0x8;
+const DX_ENTRY_SIZE: u64 = 8;
+/// Bits of a dx entry that hold the block, the rest is reserved
+const DX_BLOCK_MASK: u32 = 0x0FFF_FFFF;
+
+/// Type of a directory entry, from the entry itself when the volume records
+/// it, else `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Unknown,
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+}
+
+impl From<u8> for FileType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Regular,
+            2 => Self::Directory,
+            3 => Self::CharDevice,
+            4 => Self::BlockDevice,
+            5 => Self::Fifo,
+            6 => Self::Socket,
+            7 => Self::Symlink,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// A directory handle.
+#[derive(Debug, Clone, Copy)]
+pub struct Dir {
+    inode: Inode,
+}
+
+impl Dir {
+    pub(super) const EMPTY: Self = Self {
+        inode: Inode::EMPTY,
+    };
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+}
+
+/// A name read from a directory. [`Ext4Fs::inode`] gives the rest of the
+/// metadata.
+#[derive(Clone)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub file_type: FileType,
+    name: [u8; MAX_NAME],
+    name_len: u8,
+}
+
+impl DirEntry {
+    /// The name as stored, ext4 does not impose an encoding.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..usize::from(self.name_len)]
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+/// Header of an on-disk entry.
+#[derive(Debug, Clone, Copy)]
+struct RawEntry {
+    inode: u32,
+    rec_len: u32,
+    name_len: u8,
+    file_type: u8,
+}
+
+/// Result of an htree search.
+enum Search {
+    Found(u32),
+    Missing,
+    /// The index cannot answer, search the blocks one by one
+    Unknown,
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    pub fn root_dir(&self) -> Dir {
+        self.root
+    }
+
+    pub fn read_dir(&mut self, dir: Dir) -> ReadDir<'_, D> {
+        ReadDir {
+            fs: self,
+            dir: dir.inode,
+            block: 0,
+            base: None,
+            offset: 0,
+        }
+    }
+
+    /// Directory at `path`.
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        let inode = self.metadata(path)?;
+        self.entry_dir(&inode)
+    }
+
+    /// Inode at `path`, following symbolic links.
+    pub fn metadata(&mut self, path: &str) -> Result<Inode, FsError> {
+        Ok(walk(self, path)?.unwrap_or(self.root.inode))
+    }
+
+    /// Copy the target of the symbolic link at `path` to `buf` and return
+    /// its length. Links before the last component are followed, `InvalidName`
+    /// when the last one is not a link.
+    pub fn read_link(&mut self, path: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = self.open_dir(parent)?;
+        let ino = self.find_raw(&dir.inode, name.as_bytes())?;
+        let inode = self.inode(ino)?;
+        if !inode.is_symlink() {
+            return Err(FsError::InvalidName);
+        }
+        self.link_target(&inode, buf)
+    }
+
+    /// Blocks of directory `dir`.
+    fn dir_blocks(&self, dir: &Inode) -> u64 {
+        dir.size().div_ceil(u64::from(self.block_size))
+    }
+
+    /// Byte offset of logical block `logical` of directory `dir`, `None` for
+    /// a hole. Leaf blocks carrying a checksum tail are checked.
+    fn dir_block(&mut self, dir: &Inode, logical: u64) -> Result<Option<u64>, FsError> {
+        if logical >= self.dir_blocks(dir) {
+            return Err(FsError::Corrupted);
+        }
+        let Some(block) = self.map(dir, logical)?.start else {
+            return Ok(None);
+        };
+        let base = self.block_offset(block);
+        if self.verify {
+            let at = base + u64::from(self.block_size - TAIL_SIZE);
+            let tail: [u8; TAIL_SIZE as usize] = self.read_array(at)?;
+            if u32_le(&tail, 0) == 0
+                && u16_le(&tail, 4) == TAIL_SIZE as u16
+                && tail[6] == 0
+                && tail[7] == TAIL_FILE_TYPE
+            {
+                let len = u64::from(self.block_size - TAIL_SIZE);
+                if self.crc32c_at(dir.csum_seed, base, len)? != u32_le(&tail, 8) {
+                    warn!(
+                        "ext4: directory {} block {logical} checksum mismatch",
+                        dir.ino()
+                    );
+                    return Err(FsError::Corrupted);
+                }
+            }
+        }
+        Ok(Some(base))
+    }
+
+    /// Entry header at `offset` of the directory block at `base`.
+    fn raw_entry(&mut self, base: u64, offset: u32) -> Result<RawEntry, FsError> {
+        let head: [u8; DIRENT_HEADER as usize] = self.read_array(base + u64::from(offset))?;
+        let raw_len = u32::from(u16_le(&head, 4));
+        let rec_len = if self.block_size >= 65536 && (raw_len == MAX_REC_LEN || raw_len == 0) {
+            self.block_size
+        } else {
+            (raw_len & 0xFFFC) | (raw_len & 3) << 16
+        };
+        let (name_len, file_type) = if self.incompat & INCOMPAT_FILETYPE != 0 {
+            (head[6], head[7])
+        } else {
+            // the high byte of the name length, always 0
+            (head[6], 0)
+        };
+        if rec_len < DIRENT_HEADER + u32::from(name_len)
+            || !rec_len.is_multiple_of(4)
+            || offset + rec_len > self.block_size
+        {
+            return Err(FsError::Corrupted);
+        }
+        Ok(RawEntry {
+            inode: u32_le(&head, 0),
+            rec_len,
+            name_len,
+            file_type,
+        })
+    }
+
+    /// Inode of the entry named `name` in the directory block at `base`.
+    fn find_in_block(&mut self, base: u64, name: &[u8]) -> Result<Option<u32>, FsError> {
+        let mut offset = 0;
+        let mut buf = [0u8; MAX_NAME];
+        while offset < self.block_size {
+            let raw = self.raw_entry(base, offset)?;
+            if raw.inode != 0 && usize::from(raw.name_len) == name.len() {
+                let at = base + u64::from(offset + DIRENT_HEADER);
+                self.read_bytes(at, &mut buf[..name.len()])?;
+                if buf[..name.len()] == *name {
+                    return Ok(Some(raw.inode));
+                }
+            }
+            offset += raw.rec_len;
+        }
+        Ok(None)
+    }
+
+    /// Inode named `name` in directory `dir`, without following links.
+    fn find_raw(&mut self, dir: &Inode, name: &[u8]) -> Result<u32, FsError> {
+        if name.is_empty() || name.len() > MAX_NAME {
+            return Err(FsError::InvalidName);
+        }
+        // `.` and `..` are only in the first block, ahead of the hash index
+        if name == b"." || name == b".." {
+            let base = self.dir_block(dir, 0)?.ok_or(FsError::Corrupted)?;
+            return self.find_in_block(base, name)?.ok_or(FsError::NotFound);
+        }
+        if dir.flags() & INDEX_FL != 0 && self.compat & COMPAT_DIR_INDEX != 0 {
+            match self.htree_find(dir, name)? {
+                Search::Found(ino) => return Ok(ino),
+                Search::Missing => return Err(FsError::NotFound),
+                Search::Unknown => {}
+            }
+        }
+        for logical in 0..self.dir_blocks(dir) {
+            if let Some(base) = self.dir_block(dir, logical)? {
+                if let Some(ino) = self.find_in_block(base, name)? {
+                    return Ok(ino);
+                }
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// Walk the hash index down to the leaf block that holds `name`.
+    fn htree_find(&mut self, dir: &Inode, name: &[u8]) -> Result<Search, FsError> {
+        let Some(root) = self.dir_block(dir, 0)? else {
+            return Ok(Search::Unknown);
+        };
+        let info: [u8; 8] = self.read_array(root + DX_ROOT_INFO)?;
+        let levels = info[6];
+        let max_levels = if self.incompat & INCOMPAT_LARGEDIR != 0 {
+            3
+        } else {
+            2
+        };
+        if u32_le(&info, 0) != 0 || info[5] != 8 || levels >= max_levels {
+            warn!("ext4: directory {} has a bad hash index", dir.ino());
+            return Ok(Search::Unknown);
+        }
+        let mut version = info[4];
+        if self.unsigned_hash && version <= hash::TEA {
+            version += hash::UNSIGNED;
+        }
+        let Some(hash) = hash::dirhash(version, name, &self.hash_seed) else {
+            return Ok(Search::Unknown);
+        };
+
+        let mut node = root;
+        let mut at = DX_ROOT_COUNT;
+        let mut count;
+        let mut index;
+        let mut level = 0;
+        loop {
+            count = self.dx_node(dir, node, at)?;
+            // entry 0 holds the counts and covers hashes below entry 1
+            let (mut lo, mut hi) = (1, count);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if self.read_u32(node + at + mid * DX_ENTRY_SIZE)? > hash {
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+            index = lo - 1;
+            if level == levels {
+                break;
+            }
+            let block = self.read_u32(node + at + index * DX_ENTRY_SIZE + 4)? & DX_BLOCK_MASK;
+            node = self
+                .dir_block(dir, u64::from(block))?
+                .ok_or(FsError::Corrupted)?;
+            at = DX_NODE_COUNT;
+            level += 1;
+        }
+
+        loop {
+            let block = self.read_u32(node + at + index * DX_ENTRY_SIZE + 4)? & DX_BLOCK_MASK;
+            let base = self
+                .dir_block(dir, u64::from(block))?
+                .ok_or(FsError::Corrupted)?;
+            if let Some(ino) = self.find_in_block(base, name)? {
+                return Ok(Search::Found(ino));
+            }
+            // names with the same hash spill into the next block, which
+            // then starts with the hash and bit 0 set
+            index += 1;
+            if index == count {
+                return Ok(if levels == 0 {
+                    Search::Missing
+                } else {
+                    Search::Unknown
+                });
+            }
+            if self.read_u32(node + at + index * DX_ENTRY_SIZE)? & !1 != hash {
+                return Ok(Search::Missing);
+            }
+        }
+    }
+
+    /// Check the index node at `node` with its counts at `at`, returning
+    /// the number of entries.
+    fn dx_node(&mut self, dir: &Inode, node: u64, at: u64) -> Result<u64, FsError> {
+        let counts: [u8; 4] = self.read_array(node + at)?;
+        let limit = u64::from(u16_le(&counts, 0));
+        let count = u64::from(u16_le(&counts, 2));
+        let end = at + limit * DX_ENTRY_SIZE;
+        if count == 0 || count > limit || end > u64::from(self.block_size) {
+            return Err(FsError::Corrupted);
+        }
+        if self.verify {
+            // the tail after the last possible entry holds a reserved word
+            // and the checksum of the used entries and that word
+            if end + 8 > u64::from(self.block_size) {
+                return Err(FsError::Corrupted);
+            }
+            let crc = self.crc32c_at(dir.csum_seed, node, at + count * DX_ENTRY_SIZE)?;
+            let crc = self.crc32c_at(crc, node + end, 4)?;
+            let crc = crc32c(crc, &[0; 4]);
+            if crc != self.read_u32(node + end + 4)? {
+                warn!("ext4: directory {} index checksum mismatch", dir.ino());
+                return Err(FsError::Corrupted);
+            }
+        }
+        Ok(count)
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<Inode, FsError> {
+        let ino = self.find_raw(&dir.inode, name.as_bytes())?;
+        let inode = self.inode(ino)?;
+        if !inode.is_symlink() {
+            return Ok(inode);
+        }
+        if self.link_depth >= MAX_LINK_DEPTH {
+            return Err(FsError::SymlinkLoop);
+        }
+        let mut buf = [0u8; MAX_LINK];
+        let len = match self.link_target(&inode, &mut buf) {
+            Err(FsError::NoSpace) => return Err(FsError::Unsupported),
+            len => len?,
+        };
+        let target = core::str::from_utf8(&buf[..len]).map_err(|_| FsError::InvalidName)?;
+        let start = if target.starts_with('/') {
+            self.root
+        } else {
+            dir
+        };
+        self.link_depth += 1;
+        let found = walk_from(self, start, target);
+        self.link_depth -= 1;
+        Ok(found?.unwrap_or(start.inode))
+    }
+
+    pub(super) fn entry_dir(&self, inode: &Inode) -> Result<Dir, FsError> {
+        if !inode.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(Dir { inode: *inode })
+    }
+}
+
+/// Iterator over the entries of a directory, `.` and `..` included.
+pub struct ReadDir<'a, D: BlockDevice> {
+    fs: &'a mut Ext4Fs<D>,
+    dir: Inode,
+    block: u64,
+    /// Byte offset of `block` once mapped
+    base: Option<u64>,
+    offset: u32,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FsError> {
+        loop {
+            if self.block >= self.fs.dir_blocks(&self.dir) {
+                return Ok(None);
+            }
+            if self.offset >= self.fs.block_size {
+                self.block += 1;
+                self.base = None;
+                self.offset = 0;
+                continue;
+            }
+            let base = match self.base {
+                Some(base) => base,
+                None => match self.fs.dir_block(&self.dir, self.block)? {
+                    Some(base) => *self.base.insert(base),
+                    None => {
+                        self.offset = self.fs.block_size;
+                        continue;
+                    }
+                },
+            };
+            let raw = self.fs.raw_entry(base, self.offset)?;
+            let at = base + u64::from(self.offset + DIRENT_HEADER);
+            self.offset += raw.rec_len;
+            // unused entries, index nodes and checksum tails have no inode
+            if raw.inode == 0 || raw.name_len == 0 {
+                continue;
+            }
+            let mut entry = DirEntry {
+                inode: raw.inode,
+                file_type: FileType::from(raw.file_type),
+                name: [0; MAX_NAME],
+                name_len: raw.name_len,
+            };
+            self.fs
+                .read_bytes(at, &mut entry.name[..usize::from(raw.name_len)])?;
+            return Ok(Some(entry));
+        }
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.block = u64::MAX;
+        }
+        entry.transpose()
+    }
+}
+
+impl<D: BlockDevice> Lookup for Ext4Fs<D> {
+    type Dir = Dir;
+    type Entry = Inode;
+
+    fn root_dir(&self) -> Dir {
+        self.root
+    }
+
+    /// Follows a symbolic link named `name`, so every component of a path
+    /// resolves through links.
+    fn find(&mut self, dir: Dir, name: &str) -> Result<Inode, FsError> {
+        Ext4Fs::find(self, dir, name)
+    }
+
+    fn entry_dir(&self, inode: &Inode) -> Result<Dir, FsError> {
+        Ext4Fs::entry_dir(self, inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::format;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+    use crate::fs::ext4::tests::{image, read_all};
+    use crate::fs::ext4::Ext4Config;
+
+    #[test]
+    fn htree_lookup() {
+        let mut image = image();
+        let config = Ext4Config {
+            verify_checksums: true,
+        };
+        let mut fs = Ext4Fs::mount_with(RamDisk::new(&mut image), config).unwrap();
+        let modules = fs.open_dir("/lib/modules").unwrap();
+        assert!(modules.inode.flags() & INDEX_FL != 0);
+        assert!(fs.dir_blocks(&modules.inode) > 2);
+
+        let mut found = 0;
+        let mut entries = fs.read_dir(modules);
+        while let Some(entry) = entries.next().transpose().unwrap() {
+            if !entry.name().starts_with(b"module-") {
+                continue;
+            }
+            // every name is answered by the index, not the linear scan
+            let search = entries.fs.htree_find(&modules.inode, entry.name()).unwrap();
+            assert!(matches!(search, Search::Found(ino) if ino == entry.inode));
+            found += 1;
+        }
+        assert_eq!(found, 80);
+        let search = fs.htree_find(&modules.inode, b"module-080-with-a-longer-name.ko");
+        assert!(matches!(search, Ok(Search::Missing)));
+        // `.` and `..` sit in the index root, outside every leaf
+        let lib = fs.metadata("/lib").unwrap().ino();
+        assert_eq!(fs.find_raw(&modules.inode, b"..").unwrap(), lib);
+        let ino = modules.inode.ino();
+        assert_eq!(fs.find_raw(&modules.inode, b".").unwrap(), ino);
+
+        for i in (0..80).step_by(20) {
+            let path = format!("/lib/modules/module-{i:03}-with-a-longer-name.ko");
+            assert_eq!(read_all(&mut fs, &path), format!("mod {i}\n").as_bytes());
+        }
+        let path = "/lib/modules/module-079-with-a-longer-name.ko";
+        assert_eq!(fs.metadata(path).unwrap().size(), 0);
+        assert!(matches!(
+            fs.metadata("/lib/modules/module-80"),
+            Err(FsError::NotFound)
+        ));
+    }
+}
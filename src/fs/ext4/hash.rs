@@ -0,0 +1,154 @@
+//! Name hashes of htree directories.
+
+pub(super) const LEGACY: u8 = 0;
+pub(super) const HALF_MD4: u8 = 1;
+pub(super) const TEA: u8 = 2;
+/// Added to a version when names are hashed as unsigned chars
+pub(super) const UNSIGNED: u8 = 3;
+
+const DEFAULT_SEED: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+/// Hash that marks the end of a directory in readdir cookies, never handed
+/// out for a name
+const EOF_HASH: u32 = 0x7FFF_FFFF << 1;
+
+/// Major hash of `name`, `None` for a version this reader does not know.
+/// Bit 0 is clear, in the index it marks a collision continuing from the
+/// previous block.
+pub(super) fn dirhash(version: u8, name: &[u8], seed: &[u32; 4]) -> Option<u32> {
+    if version > TEA + UNSIGNED {
+        return None;
+    }
+    let mut buf = if seed.iter().any(|&word| word != 0) {
+        *seed
+    } else {
+        DEFAULT_SEED
+    };
+    let signed = version < UNSIGNED;
+    let hash = match version % UNSIGNED {
+        LEGACY => legacy(name, signed),
+        HALF_MD4 => {
+            for (i, chunk) in name.chunks(32).enumerate() {
+                let mut words = [0u32; 8];
+                to_words(chunk, name.len() - 32 * i, signed, &mut words);
+                half_md4(&mut buf, &words);
+            }
+            buf[1]
+        }
+        _ => {
+            for (i, chunk) in name.chunks(16).enumerate() {
+                let mut words = [0u32; 4];
+                to_words(chunk, name.len() - 16 * i, signed, &mut words);
+                tea(&mut buf, &words);
+            }
+            buf[0]
+        }
+    };
+    let hash = hash & !1;
+    Some(if hash == EOF_HASH { EOF_HASH - 2 } else { hash })
+}
+
+fn char_value(byte: u8, signed: bool) -> u32 {
+    if signed {
+        byte as i8 as i32 as u32
+    } else {
+        u32::from(byte)
+    }
+}
+
+fn legacy(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3_FE2Du32, 0x37AB_E8F9u32);
+    for &byte in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(byte, signed).wrapping_mul(7_152_373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Pack the bytes of `chunk` big end first into `words`, padding with a
+/// pattern of `left`, the bytes left in the name from `chunk` on.
+fn to_words(chunk: &[u8], left: usize, signed: bool, words: &mut [u32]) {
+    let mut pad = left as u32 | (left as u32) << 8;
+    pad |= pad << 16;
+    words.fill(pad);
+    let mut value = pad;
+    for (i, &byte) in chunk.iter().enumerate() {
+        value = char_value(byte, signed).wrapping_add(value << 8);
+        if i % 4 == 3 {
+            words[i / 4] = value;
+            value = pad;
+        }
+    }
+    if !chunk.len().is_multiple_of(4) {
+        words[chunk.len() / 4] = value;
+    }
+}
+
+fn tea(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9E37_79B9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// The first three rounds of MD4 with ext4's constants.
+fn half_md4(buf: &mut [u32; 4], x: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let [mut a, mut b, mut c, mut d] = *buf;
+    macro_rules! round {
+        ($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+            $a = $a
+                .wrapping_add($f($b, $c, $d))
+                .wrapping_add($x)
+                .rotate_left($s)
+        };
+    }
+    round!(f, a, b, c, d, x[0], 3);
+    round!(f, d, a, b, c, x[1], 7);
+    round!(f, c, d, a, b, x[2], 11);
+    round!(f, b, c, d, a, x[3], 19);
+    round!(f, a, b, c, d, x[4], 3);
+    round!(f, d, a, b, c, x[5], 7);
+    round!(f, c, d, a, b, x[6], 11);
+    round!(f, b, c, d, a, x[7], 19);
+
+    round!(g, a, b, c, d, x[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, x[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, x[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, x[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, x[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, x[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, x[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, x[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, x[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, x[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, x[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, x[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, x[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, x[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, x[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, x[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
@@ -0,0 +1,451 @@
+//! Inodes and the mapping of their blocks, through extent trees or the
+//! direct and indirect block pointers of ext2 and ext3.
+use log::warn;
+
+use crate::block::BlockDevice;
+use crate::fs::FsError;
+
+use super::dir::FileType;
+use super::{crc32c, u16_le, u32_le, Ext4Fs, GOOD_OLD_INODE_SIZE, RO_COMPAT_HUGE_FILE};
+
+/// Bytes of an inode that are looked at
+const INODE_READ: usize = 0x84;
+const BLOCK_BYTES: usize = 60;
+/// Inode flags
+const HUGE_FILE_FL: u32 = 0x4_0000;
+const EXTENTS_FL: u32 = 0x8_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+pub(super) const INDEX_FL: u32 = 0x1000;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFSOCK: u16 = 0xC000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFREG: u16 = 0x8000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFIFO: u16 = 0x1000;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_SIZE: u64 = 12;
+/// Deepest extent tree the kernel builds
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Extents longer than this are preallocated and read as zeros
+const MAX_INIT_LEN: u16 = 32768;
+const DIRECT_BLOCKS: u64 = 12;
+
+/// An inode as read from the inode table.
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    ino: u32,
+    mode: u16,
+    flags: u32,
+    size: u64,
+    /// Blocks in use, in 512 byte units
+    blocks: u64,
+    file_acl: u64,
+    block: [u8; BLOCK_BYTES],
+    /// Seed of the checksums of blocks the inode owns
+    pub(super) csum_seed: u32,
+}
+
+impl Inode {
+    /// Stands in until the root directory is read.
+    pub(super) const EMPTY: Self = Self {
+        ino: 0,
+        mode: 0,
+        flags: 0,
+        size: 0,
+        blocks: 0,
+        file_acl: 0,
+        block: [0; BLOCK_BYTES],
+        csum_seed: 0,
+    };
+
+    /// Inode number.
+    pub fn ino(&self) -> u32 {
+        self.ino
+    }
+
+    /// Type and permission bits.
+    pub fn mode(&self) -> u16 {
+        self.mode
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::Fifo,
+            S_IFSOCK => FileType::Socket,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Unknown,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub(super) fn flags(&self) -> u32 {
+        self.flags
+    }
+}
+
+/// An open file. Handles do not borrow the filesystem, several can be open
+/// at once.
+#[derive(Debug, Clone)]
+pub struct File {
+    inode: Inode,
+    pos: u64,
+}
+
+impl File {
+    pub(super) fn new(inode: Inode) -> Self {
+        Self { inode, pos: 0 }
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    pub fn size(&self) -> u64 {
+        self.inode.size
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Move to byte `pos`, clamped to the end of the file.
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos.min(self.inode.size);
+    }
+}
+
+/// Blocks mapped from a logical block on.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Run {
+    /// First physical block, `None` for a hole that reads as zeros
+    pub start: Option<u64>,
+    /// Blocks in the run, at least 1
+    pub len: u64,
+}
+
+impl Run {
+    fn hole(len: u64) -> Self {
+        Self {
+            start: None,
+            len: len.max(1),
+        }
+    }
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Read inode `ino` from its group's inode table.
+    pub fn inode(&mut self, ino: u32) -> Result<Inode, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = (ino - 1) / self.inodes_per_group;
+        let index = (ino - 1) % self.inodes_per_group;
+        let table = self.inode_table(group)?;
+        let offset = self.block_offset(table) + u64::from(index) * u64::from(self.inode_size);
+        let mut raw = [0u8; INODE_READ];
+        let len = if self.inode_size > GOOD_OLD_INODE_SIZE {
+            INODE_READ
+        } else {
+            GOOD_OLD_INODE_SIZE as usize
+        };
+        self.read_bytes(offset, &mut raw[..len])?;
+        let generation = u32_le(&raw, 0x64);
+        let csum_seed = if self.verify {
+            let seed = crc32c(self.csum_seed, &ino.to_le_bytes());
+            crc32c(seed, &generation.to_le_bytes())
+        } else {
+            0
+        };
+        if self.verify {
+            self.check_inode(ino, offset, &raw, csum_seed)?;
+        }
+
+        let mut blocks = u64::from(u32_le(&raw, 0x1C)) | u64::from(u16_le(&raw, 0x74)) << 32;
+        let flags = u32_le(&raw, 0x20);
+        if self.ro_compat & RO_COMPAT_HUGE_FILE != 0 && flags & HUGE_FILE_FL != 0 {
+            blocks *= u64::from(self.block_size) / 512;
+        }
+        let inode = Inode {
+            ino,
+            mode: u16_le(&raw, 0x00),
+            flags,
+            size: u64::from(u32_le(&raw, 0x04)) | u64::from(u32_le(&raw, 0x6C)) << 32,
+            blocks,
+            file_acl: u64::from(u32_le(&raw, 0x68)) | u64::from(u16_le(&raw, 0x76)) << 32,
+            block: raw[0x28..0x28 + BLOCK_BYTES].try_into().unwrap(),
+            csum_seed,
+        };
+        if inode.flags & INLINE_DATA_FL != 0 {
+            return Err(FsError::Unsupported);
+        }
+        Ok(inode)
+    }
+
+    /// Compare the checksum of the inode at `offset`, split in a low half
+    /// at 0x7C and a high half at 0x82 when the extra fields reach it,
+    /// with the checksum fields taken as zero.
+    fn check_inode(&mut self, ino: u32, offset: u64, raw: &[u8], seed: u32) -> Result<(), FsError> {
+        let size = u64::from(self.inode_size);
+        let mut crc = crc32c(seed, &raw[..0x7C]);
+        crc = crc32c(crc, &[0; 2]);
+        crc = crc32c(crc, &raw[0x7E..0x80]);
+        let mut stored = u32::from(u16_le(raw, 0x7C));
+        let mut mask = 0xFFFF;
+        if size > u64::from(GOOD_OLD_INODE_SIZE) {
+            crc = crc32c(crc, &raw[0x80..0x82]);
+            let rest = if u16_le(raw, 0x80) >= 4 {
+                crc = crc32c(crc, &[0; 2]);
+                stored |= u32::from(u16_le(raw, 0x82)) << 16;
+                mask = !0;
+                0x84
+            } else {
+                0x82
+            };
+            crc = self.crc32c_at(crc, offset + rest, size - rest)?;
+        }
+        if crc & mask != stored {
+            warn!("ext4: inode {ino} checksum mismatch");
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+
+    /// Open the file at `path`, following symbolic links.
+    pub fn open(&mut self, path: &str) -> Result<File, FsError> {
+        let inode = self.metadata(path)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if !inode.is_file() {
+            return Err(FsError::Unsupported);
+        }
+        Ok(File::new(inode))
+    }
+
+    /// Read from the position of `file` into `buf`, returning the number of
+    /// bytes read, 0 at the end of the file.
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = (buf.len() as u64).min(file.inode.size - file.pos) as usize;
+        let block_size = u64::from(self.block_size);
+        let mut done = 0;
+        while done < len {
+            let offset = file.pos % block_size;
+            let run = self.map(&file.inode, file.pos / block_size)?;
+            let n = run
+                .len
+                .saturating_mul(block_size)
+                .saturating_sub(offset)
+                .min((len - done) as u64) as usize;
+            match run.start {
+                Some(block) => {
+                    let at = self.block_offset(block) + offset;
+                    self.read_bytes(at, &mut buf[done..done + n])?;
+                }
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+            file.pos += n as u64;
+        }
+        Ok(len)
+    }
+
+    /// Copy the target of the symbolic link `inode` to `buf` and return its
+    /// length, `NoSpace` when it does not fit.
+    pub(super) fn link_target(&mut self, inode: &Inode, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = inode.size as usize;
+        if inode.size > buf.len() as u64 {
+            return Err(FsError::NoSpace);
+        }
+        // fast links keep the target in the block pointers, an extended
+        // attribute block being the only block they own
+        let acl_blocks = if inode.file_acl != 0 {
+            u64::from(self.block_size) / 512
+        } else {
+            0
+        };
+        if inode.blocks == acl_blocks {
+            if len > BLOCK_BYTES {
+                return Err(FsError::Corrupted);
+            }
+            buf[..len].copy_from_slice(&inode.block[..len]);
+            return Ok(len);
+        }
+        let mut file = File::new(*inode);
+        self.read(&mut file, &mut buf[..len])
+    }
+
+    /// Physical blocks from logical block `logical` of `inode` on.
+    pub(super) fn map(&mut self, inode: &Inode, logical: u64) -> Result<Run, FsError> {
+        if inode.flags & EXTENTS_FL != 0 {
+            self.map_extents(inode, logical)
+        } else {
+            self.map_indirect(inode, logical)
+        }
+    }
+
+    fn extent_entry(
+        &mut self,
+        inode: &Inode,
+        node: Option<u64>,
+        index: u64,
+    ) -> Result<[u8; EXTENT_SIZE as usize], FsError> {
+        let offset = index * EXTENT_SIZE;
+        match node {
+            Some(block) => self.read_array(self.block_offset(block) + offset),
+            None => {
+                let offset = offset as usize;
+                inode
+                    .block
+                    .get(offset..offset + EXTENT_SIZE as usize)
+                    .map(|entry| entry.try_into().unwrap())
+                    .ok_or(FsError::Corrupted)
+            }
+        }
+    }
+
+    fn map_extents(&mut self, inode: &Inode, logical: u64) -> Result<Run, FsError> {
+        let Ok(target) = u32::try_from(logical) else {
+            return Ok(Run::hole(1));
+        };
+        // `None` is the root node in the inode
+        let mut node: Option<u64> = None;
+        let mut depth = None;
+        // logical blocks covered by the current node end before `end`
+        let mut end = 1u64 << 32;
+        loop {
+            let header = self.extent_entry(inode, node, 0)?;
+            let entries = u16_le(&header, 2);
+            let max = u16_le(&header, 4);
+            let node_depth = u16_le(&header, 6);
+            let capacity = match node {
+                Some(_) => (u64::from(self.block_size) - EXTENT_SIZE) / EXTENT_SIZE,
+                None => BLOCK_BYTES as u64 / EXTENT_SIZE - 1,
+            };
+            if u16_le(&header, 0) != EXTENT_MAGIC
+                || entries > max
+                || u64::from(max) > capacity
+                || node_depth > MAX_EXTENT_DEPTH
+                || depth.is_some_and(|want| node_depth != want)
+            {
+                return Err(FsError::Corrupted);
+            }
+            if let (Some(block), true) = (node, self.verify) {
+                // the checksum follows the last possible entry
+                let base = self.block_offset(block);
+                let len = (u64::from(max) + 1) * EXTENT_SIZE;
+                let crc = self.crc32c_at(inode.csum_seed, base, len)?;
+                if crc != self.read_u32(base + len)? {
+                    warn!("ext4: inode {} extent block checksum mismatch", inode.ino);
+                    return Err(FsError::Corrupted);
+                }
+            }
+
+            if node_depth == 0 {
+                for i in 1..=u64::from(entries) {
+                    let extent = self.extent_entry(inode, node, i)?;
+                    let first = u64::from(u32_le(&extent, 0));
+                    let raw_len = u16_le(&extent, 4);
+                    let start = u64::from(u32_le(&extent, 8)) | u64::from(u16_le(&extent, 6)) << 32;
+                    let (len, initialized) = if raw_len > MAX_INIT_LEN {
+                        (u64::from(raw_len - MAX_INIT_LEN), false)
+                    } else {
+                        (u64::from(raw_len), true)
+                    };
+                    if logical < first {
+                        return Ok(Run::hole(first - logical));
+                    }
+                    if logical < first + len {
+                        self.check_block(start + len - 1)?;
+                        let skip = logical - first;
+                        return Ok(Run {
+                            start: initialized.then_some(start + skip),
+                            len: len - skip,
+                        });
+                    }
+                }
+                return Ok(Run::hole(end - logical));
+            }
+
+            // the last index starting at or before the block
+            let mut child = None;
+            for i in 1..=u64::from(entries) {
+                let index = self.extent_entry(inode, node, i)?;
+                let first = u32_le(&index, 0);
+                if first > target {
+                    end = u64::from(first);
+                    break;
+                }
+                child = Some(u64::from(u32_le(&index, 4)) | u64::from(u16_le(&index, 8)) << 32);
+            }
+            let Some(child) = child else {
+                return Ok(Run::hole(end - logical));
+            };
+            self.check_block(child)?;
+            node = Some(child);
+            depth = Some(node_depth - 1);
+        }
+    }
+
+    /// Follow the direct, single, double and triple indirect pointers.
+    fn map_indirect(&mut self, inode: &Inode, logical: u64) -> Result<Run, FsError> {
+        let per_block = u64::from(self.block_size / 4);
+        let (slot, levels, mut index) = if logical < DIRECT_BLOCKS {
+            (logical, 0, 0)
+        } else {
+            let mut index = logical - DIRECT_BLOCKS;
+            let mut span = per_block;
+            let mut levels = 1;
+            while index >= span {
+                if levels == 3 {
+                    return Err(FsError::Corrupted);
+                }
+                index -= span;
+                span *= per_block;
+                levels += 1;
+            }
+            (DIRECT_BLOCKS - 1 + levels, levels, index)
+        };
+        let mut block = u64::from(u32_le(&inode.block, slot as usize * 4));
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(Run::hole(1));
+            }
+            self.check_block(block)?;
+            let span = per_block.pow(level as u32);
+            let at = self.block_offset(block) + index / span * 4;
+            index %= span;
+            block = u64::from(self.read_u32(at)?);
+        }
+        if block == 0 {
+            return Ok(Run::hole(1));
+        }
+        self.check_block(block)?;
+        Ok(Run {
+            start: Some(block),
+            len: 1,
+        })
+    }
+}
@@ -0,0 +1,559 @@
+//! Read-only ext2, ext3 and ext4, for loading kernels and modules straight
+//! from a root filesystem.
+//!
+//! Files are mapped through extent trees or the classic indirect blocks,
+//! directories are searched through their htree index when they have one.
+//! Symbolic links are followed anywhere in a path. The journal is not
+//! replayed, a volume that needs recovery is read as it stands.
+//!
+//! With [`Ext4Config::verify_checksums`] set, volumes with `metadata_csum`
+//! have their superblock, group descriptors, inodes, extent blocks and
+//! directory blocks checked, a mismatch failing with `Corrupted`.
+//!
+//! ```no_run
+//! use vf2_driver::fs::ext4::{Ext4Config, Ext4Fs};
+//! use vf2_driver::fs::FileSystem;
+//! use vf2_driver::part::gpt;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let mut card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let root = gpt::find_partition(&mut card, "root").unwrap();
+//! let config = Ext4Config {
+//!     verify_checksums: true,
+//! };
+//! let mut fs = Ext4Fs::mount_with(root, config).unwrap();
+//! let mut image = fs.open("/boot/Image").unwrap();
+//! let mut buf = [0u8; 4096];
+//! while fs.read(&mut image, &mut buf).unwrap() != 0 {
+//!     // copy out
+//! }
+//! ```
+use log::warn;
+
+use crate::block::BlockDevice;
+
+use super::{FileSystem, FsError};
+
+mod dir;
+mod hash;
+mod inode;
+
+pub use dir::{Dir, DirEntry, FileType, ReadDir};
+pub use inode::{File, Inode};
+
+const SECTOR_SIZE: usize = 512;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Largest block size, 64 KiB
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const ROOT_INO: u32 = 2;
+const MIN_DESC_SIZE: u32 = 32;
+const MAX_DESC_SIZE: u32 = 1024;
+/// Bytes of a group descriptor that are looked at
+const DESC_READ: usize = 64;
+/// The crc32c checksum type, the only one defined
+const CSUM_CRC32C: u8 = 1;
+
+/// Compatible features
+const COMPAT_DIR_INDEX: u32 = 0x20;
+/// Incompatible features
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+/// Names are still stored as given, exact lookups find them
+const INCOMPAT_CASEFOLD: u32 = 0x20000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_META_BG
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_CASEFOLD;
+/// Read-only compatible features
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+/// Superblock flags: directory hashes treat names as unsigned chars
+const FLAG_UNSIGNED_HASH: u32 = 0x2;
+
+/// Mount options.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ext4Config {
+    /// Check metadata checksums on volumes with `metadata_csum`
+    pub verify_checksums: bool,
+}
+
+/// A mounted ext2, ext3 or ext4 volume.
+pub struct Ext4Fs<D: BlockDevice> {
+    dev: D,
+    block_size: u32,
+    blocks_count: u64,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inodes_count: u32,
+    inode_size: u32,
+    desc_size: u32,
+    group_count: u32,
+    /// First meta block group when `meta_bg` is on
+    first_meta_bg: Option<u32>,
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+    /// Checksums are checked
+    verify: bool,
+    csum_seed: u32,
+    hash_seed: [u32; 4],
+    /// Directory hashes treat names as unsigned
+    unsigned_hash: bool,
+    uuid: [u8; 16],
+    label: [u8; 16],
+    root: Dir,
+    /// Symbolic links being followed
+    link_depth: u8,
+    cache: [u8; SECTOR_SIZE],
+    cache_lba: Option<u64>,
+}
+
+impl<D: BlockDevice> Ext4Fs<D> {
+    /// Mount without checksum verification.
+    pub fn mount(dev: D) -> Result<Self, FsError> {
+        Self::mount_with(dev, Ext4Config::default())
+    }
+
+    /// Read and check the superblock, refusing volumes with incompatible
+    /// features this reader does not know.
+    pub fn mount_with(dev: D, config: Ext4Config) -> Result<Self, FsError> {
+        if dev.block_size() != SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut fs = Self {
+            dev,
+            block_size: 1024,
+            blocks_count: 0,
+            first_data_block: 0,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+            inodes_count: 0,
+            inode_size: GOOD_OLD_INODE_SIZE,
+            desc_size: MIN_DESC_SIZE,
+            group_count: 0,
+            first_meta_bg: None,
+            compat: 0,
+            incompat: 0,
+            ro_compat: 0,
+            verify: false,
+            csum_seed: 0,
+            hash_seed: [0; 4],
+            unsigned_hash: false,
+            uuid: [0; 16],
+            label: [0; 16],
+            root: Dir::EMPTY,
+            link_depth: 0,
+            cache: [0; SECTOR_SIZE],
+            cache_lba: None,
+        };
+        let mut sb = [0u8; SUPERBLOCK_SIZE];
+        fs.read_bytes(SUPERBLOCK_OFFSET, &mut sb)?;
+        if u16_le(&sb, 0x38) != MAGIC {
+            return Err(FsError::Corrupted);
+        }
+        fs.compat = u32_le(&sb, 0x5C);
+        fs.incompat = u32_le(&sb, 0x60);
+        fs.ro_compat = u32_le(&sb, 0x64);
+        if fs.incompat & !INCOMPAT_SUPPORTED != 0 {
+            warn!(
+                "ext4: unsupported incompatible features {:#x}",
+                fs.incompat & !INCOMPAT_SUPPORTED
+            );
+            return Err(FsError::Unsupported);
+        }
+        if fs.incompat & INCOMPAT_RECOVER != 0 {
+            warn!("ext4: journal needs recovery, reading without it");
+        }
+        let log_block_size = u32_le(&sb, 0x18);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(FsError::Corrupted);
+        }
+        fs.block_size = 1024 << log_block_size;
+        fs.first_data_block = u32_le(&sb, 0x14);
+        fs.blocks_per_group = u32_le(&sb, 0x20);
+        fs.inodes_per_group = u32_le(&sb, 0x28);
+        fs.inodes_count = u32_le(&sb, 0x00);
+        fs.blocks_count = u64::from(u32_le(&sb, 0x04));
+        if fs.is_64bit() {
+            fs.blocks_count |= u64::from(u32_le(&sb, 0x150)) << 32;
+            fs.desc_size = u32::from(u16_le(&sb, 0xFE));
+            if !(MIN_DESC_SIZE..=MAX_DESC_SIZE).contains(&fs.desc_size)
+                || !fs.desc_size.is_power_of_two()
+            {
+                return Err(FsError::Corrupted);
+            }
+        }
+        let first_ino = if u32_le(&sb, 0x4C) == 0 {
+            GOOD_OLD_FIRST_INO
+        } else {
+            fs.inode_size = u32::from(u16_le(&sb, 0x58));
+            u32_le(&sb, 0x54)
+        };
+        if fs.blocks_per_group == 0
+            || fs.inodes_per_group == 0
+            || u64::from(fs.first_data_block) >= fs.blocks_count
+            || first_ino <= ROOT_INO
+            || !fs.inode_size.is_power_of_two()
+            || !(GOOD_OLD_INODE_SIZE..=fs.block_size).contains(&fs.inode_size)
+        {
+            return Err(FsError::Corrupted);
+        }
+        if fs.blocks_count * u64::from(fs.block_size / SECTOR_SIZE as u32) > fs.dev.block_count() {
+            return Err(FsError::Corrupted);
+        }
+        let groups = (fs.blocks_count - u64::from(fs.first_data_block))
+            .div_ceil(u64::from(fs.blocks_per_group));
+        fs.group_count = u32::try_from(groups).map_err(|_| FsError::Corrupted)?;
+        if u64::from(fs.inodes_count) > groups * u64::from(fs.inodes_per_group) {
+            return Err(FsError::Corrupted);
+        }
+        if fs.incompat & INCOMPAT_META_BG != 0 {
+            fs.first_meta_bg = Some(u32_le(&sb, 0x104));
+        }
+        fs.hash_seed = core::array::from_fn(|i| u32_le(&sb, 0xEC + 4 * i));
+        fs.unsigned_hash = u32_le(&sb, 0x160) & FLAG_UNSIGNED_HASH != 0;
+        fs.uuid.copy_from_slice(&sb[0x68..0x78]);
+        fs.label.copy_from_slice(&sb[0x78..0x88]);
+
+        if config.verify_checksums && fs.ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            if sb[0x175] != CSUM_CRC32C
+                || crc32c(!0, &sb[..SUPERBLOCK_SIZE - 4]) != u32_le(&sb, SUPERBLOCK_SIZE - 4)
+            {
+                return Err(FsError::Corrupted);
+            }
+            fs.verify = true;
+            fs.csum_seed = if fs.incompat & INCOMPAT_CSUM_SEED != 0 {
+                u32_le(&sb, 0x270)
+            } else {
+                crc32c(!0, &fs.uuid)
+            };
+        }
+        let root = fs.inode(ROOT_INO)?;
+        fs.root = fs.entry_dir(&root)?;
+        Ok(fs)
+    }
+
+    /// Volume name, empty when there is none.
+    pub fn label(&self) -> &[u8] {
+        let len = self.label.iter().position(|&c| c == 0).unwrap_or(16);
+        &self.label[..len]
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    /// Block size in bytes.
+    pub fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.blocks_count
+    }
+
+    /// Give the device back.
+    pub fn unmount(self) -> D {
+        self.dev
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.incompat & INCOMPAT_64BIT != 0
+    }
+
+    /// Block holding the descriptor of `group`.
+    fn desc_block(&self, group: u32) -> u64 {
+        let per_block = self.block_size / self.desc_size;
+        let desc_block = group / per_block;
+        match self.first_meta_bg {
+            // each meta group keeps its descriptors in its first group,
+            // after the superblock backup when there is one
+            Some(first) if desc_block >= first => {
+                let first_group = desc_block * per_block;
+                let block = u64::from(self.first_data_block)
+                    + u64::from(first_group) * u64::from(self.blocks_per_group);
+                block + u64::from(self.has_super(first_group))
+            }
+            _ => u64::from(self.first_data_block) + 1 + u64::from(desc_block),
+        }
+    }
+
+    /// A superblock backup starts `group`: every group without
+    /// `sparse_super`, else 0, 1 and the powers of 3, 5 and 7.
+    fn has_super(&self, group: u32) -> bool {
+        if self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 || group <= 1 {
+            return true;
+        }
+        [3, 5, 7].iter().any(|&base| {
+            let mut n = group;
+            while n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        })
+    }
+
+    /// First block of the inode table of `group`.
+    fn inode_table(&mut self, group: u32) -> Result<u64, FsError> {
+        if group >= self.group_count {
+            return Err(FsError::Corrupted);
+        }
+        let per_block = self.block_size / self.desc_size;
+        let offset = self.block_offset(self.desc_block(group))
+            + u64::from(group % per_block * self.desc_size);
+        let mut desc = [0u8; DESC_READ];
+        let len = (self.desc_size as usize).min(DESC_READ);
+        self.read_bytes(offset, &mut desc[..len])?;
+        if self.verify {
+            // crc32c of the group number and the descriptor with its
+            // checksum field zeroed, cut to 16 bits
+            let crc = crc32c(self.csum_seed, &group.to_le_bytes());
+            let crc = crc32c(crc, &desc[..0x1E]);
+            let crc = crc32c(crc, &[0; 2]);
+            let crc = self.crc32c_at(crc, offset + 0x20, u64::from(self.desc_size) - 0x20)?;
+            if crc as u16 != u16_le(&desc, 0x1E) {
+                warn!("ext4: group {group} descriptor checksum mismatch");
+                return Err(FsError::Corrupted);
+            }
+        }
+        let mut table = u64::from(u32_le(&desc, 0x08));
+        if self.is_64bit() && self.desc_size >= 64 {
+            table |= u64::from(u32_le(&desc, 0x28)) << 32;
+        }
+        self.check_block(table)?;
+        Ok(table)
+    }
+
+    /// `Corrupted` for a block number past the end of the volume.
+    fn check_block(&self, block: u64) -> Result<(), FsError> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+
+    fn block_offset(&self, block: u64) -> u64 {
+        block * u64::from(self.block_size)
+    }
+
+    fn sector(&mut self, lba: u64) -> Result<&[u8; SECTOR_SIZE], FsError> {
+        if self.cache_lba != Some(lba) {
+            self.cache_lba = None;
+            self.dev.read_blocks(lba, &mut self.cache)?;
+            self.cache_lba = Some(lba);
+        }
+        Ok(&self.cache)
+    }
+
+    /// Fill `buf` from byte `offset` of the volume. Whole sectors go
+    /// straight into `buf`, the ends through the sector cache.
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let lba = pos / SECTOR_SIZE as u64;
+            let in_sector = (pos % SECTOR_SIZE as u64) as usize;
+            let left = buf.len() - done;
+            let n = if in_sector == 0 && left >= SECTOR_SIZE {
+                let n = left / SECTOR_SIZE * SECTOR_SIZE;
+                self.dev.read_blocks(lba, &mut buf[done..done + n])?;
+                n
+            } else {
+                let n = left.min(SECTOR_SIZE - in_sector);
+                buf[done..done + n].copy_from_slice(&self.sector(lba)?[in_sector..in_sector + n]);
+                n
+            };
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self, offset: u64) -> Result<[u8; N], FsError> {
+        let mut buf = [0u8; N];
+        self.read_bytes(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self, offset: u64) -> Result<u32, FsError> {
+        Ok(u32::from_le_bytes(self.read_array(offset)?))
+    }
+
+    /// Feed `len` bytes of the volume from `offset` into `crc`.
+    fn crc32c_at(&mut self, mut crc: u32, offset: u64, len: u64) -> Result<u32, FsError> {
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let in_sector = (pos % SECTOR_SIZE as u64) as usize;
+            let n = ((end - pos) as usize).min(SECTOR_SIZE - in_sector);
+            crc = crc32c(
+                crc,
+                &self.sector(pos / SECTOR_SIZE as u64)?[in_sector..in_sector + n],
+            );
+            pos += n as u64;
+        }
+        Ok(crc)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Ext4Fs<D> {
+    type File = File;
+    type Dir = Dir;
+
+    fn open(&mut self, path: &str) -> Result<File, FsError> {
+        Ext4Fs::open(self, path)
+    }
+
+    fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        Ext4Fs::open_dir(self, path)
+    }
+
+    fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ext4Fs::read(self, file, buf)
+    }
+}
+
+/// CRC-32C (Castagnoli) as ext4 uses it: reflected, without the final
+/// inversion, `crc` being the seed or the result of an earlier call.
+pub(crate) fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn u16_le(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    const VERIFY: Ext4Config = Ext4Config {
+        verify_checksums: true,
+    };
+
+    /// The volume made by `testdata/mkext4.sh`.
+    pub(super) fn image() -> Vec<u8> {
+        let mut image = include_bytes!("../../../testdata/ext4.img").to_vec();
+        image.resize(256 * 1024, 0);
+        image
+    }
+
+    pub(super) fn read_all<D: BlockDevice>(fs: &mut Ext4Fs<D>, path: &str) -> Vec<u8> {
+        let mut buf = std::vec![0u8; 16 * 1024];
+        let len = fs.read_file(path, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    fn kernel() -> Vec<u8> {
+        (0..12000).map(|i| ((i * 31 + 7) % 251) as u8).collect()
+    }
+
+    #[test]
+    fn mount_checks_the_superblock() {
+        let mut image = image();
+        let fs = Ext4Fs::mount_with(RamDisk::new(&mut image), VERIFY).unwrap();
+        assert_eq!(fs.label(), b"vf2test");
+        assert_eq!(fs.block_size(), 1024);
+        assert_eq!(fs.block_count(), 256);
+        assert!(fs.verify);
+
+        // the label is covered by the superblock checksum
+        image[1024 + 0x78] ^= 1;
+        let ret = Ext4Fs::mount_with(RamDisk::new(&mut image), VERIFY);
+        assert!(matches!(ret, Err(FsError::Corrupted)));
+        assert!(Ext4Fs::mount(RamDisk::new(&mut image)).is_ok());
+    }
+
+    #[test]
+    fn extents() {
+        let mut image = image();
+        let mut fs = Ext4Fs::mount_with(RamDisk::new(&mut image), VERIFY).unwrap();
+        assert_eq!(read_all(&mut fs, "/boot/Image"), kernel());
+
+        // six extents, one level below the inode, and holes between them
+        let sparse = read_all(&mut fs, "/boot/sparse.bin");
+        assert_eq!(sparse.len(), 12 * 1024);
+        for (i, block) in sparse.chunks(1024).enumerate() {
+            let fill = if i % 2 == 0 { i as u8 + 1 } else { 0 };
+            assert!(block.iter().all(|&b| b == fill), "block {i}");
+        }
+
+        let mut file = fs.open("/boot/sparse.bin").unwrap();
+        file.seek(4 * 1024 + 1000);
+        let mut buf = [0u8; 48];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 48);
+        assert_eq!(buf[..24], [5; 24]);
+        assert_eq!(buf[24..], [0; 24]);
+    }
+
+    #[test]
+    fn symlinks() {
+        let mut image = image();
+        let mut fs = Ext4Fs::mount_with(RamDisk::new(&mut image), VERIFY).unwrap();
+        let mut buf = [0u8; 128];
+        let len = fs.read_link("/vmlinuz", &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"boot/Image");
+        assert_eq!(read_all(&mut fs, "/vmlinuz"), kernel());
+        assert_eq!(read_all(&mut fs, "/lib/modules/kernel"), kernel());
+
+        // too long for the inode, kept in a block
+        let target =
+            b"../../../boot/../boot/./../boot/Image-with-a-very-long-target-name-over-sixty";
+        let len = fs.read_link("/lib/modules/long", &mut buf).unwrap();
+        assert_eq!(&buf[..len], target);
+        assert!(matches!(
+            fs.open("/lib/modules/long"),
+            Err(FsError::NotFound)
+        ));
+        assert!(matches!(
+            fs.read_link("/boot/Image", &mut buf),
+            Err(FsError::InvalidName)
+        ));
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(!crc32c(!0, b"123456789"), 0xE306_9283);
+    }
+}
@@ -0,0 +1,752 @@
+//! Directory entries, long file names and path lookup.
+use bitflags::bitflags;
+
+use crate::block::BlockDevice;
+use crate::fs::{walk, FsError, Lookup};
+
+use super::{u16_le, u32_le, FatFs, FatKind, SECTOR_SIZE};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / ENTRY_SIZE;
+/// Most entries a directory can have
+const MAX_DIR_ENTRIES: usize = 65536;
+/// First name byte of a deleted entry
+const FREE: u8 = 0xE5;
+/// First name byte of the entry after the last one
+const END: u8 = 0x00;
+/// Stored instead of 0xE5 as the first byte of a name
+const ESCAPED_E5: u8 = 0x05;
+const ATTR_LFN: u8 = 0x0F;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Offsets of the UTF-16 units in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_LFN_ENTRIES: usize = MAX_NAME.div_ceil(LFN_CHARS);
+/// NT reserved byte flags: base name and extension are shown lowercase
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+/// Largest `~N` tail tried for generated short names
+const MAX_TAIL: u32 = 999_999;
+/// 1980-01-01, there is no clock to stamp entries with
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+const DOT: &[u8; 11] = b".          ";
+const DOT_DOT: &[u8; 11] = b"..         ";
+
+/// UTF-16 units in a long name.
+pub const MAX_NAME: usize = 255;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Attributes: u8 {
+        const read_only = 0x01;
+        const hidden = 0x02;
+        const system = 0x04;
+        const volume_id = 0x08;
+        const directory = 0x10;
+        const archive = 0x20;
+    }
+}
+
+/// A directory handle, stale once the directory is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dir {
+    /// First cluster, 0 for the fixed FAT12/16 root directory
+    cluster: u32,
+}
+
+/// Position of an entry slot.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Slot {
+    pub(super) lba: u64,
+    pub(super) offset: usize,
+}
+
+/// Walks the slots of a directory.
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    cluster: u32,
+    sector: u32,
+    index: usize,
+}
+
+impl Cursor {
+    fn new(dir: Dir) -> Self {
+        Self {
+            cluster: dir.cluster,
+            sector: 0,
+            index: 0,
+        }
+    }
+}
+
+/// An entry read from a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u16; MAX_NAME],
+    name_len: usize,
+    short_name: [u8; 11],
+    pub attributes: Attributes,
+    pub(super) cluster: u32,
+    pub(super) size: u32,
+    /// The short entry
+    pub(super) slot: Slot,
+    /// First slot of the entry, long name entries included
+    first: Cursor,
+    lfn_count: usize,
+}
+
+impl DirEntry {
+    /// Long name, or the short name when there is none.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        utf16_chars(&self.name[..self.name_len])
+    }
+
+    /// Whether `name` is the long or the short name, ignoring ASCII case.
+    pub fn name_eq(&self, name: &str) -> bool {
+        let mut short = [0u16; 12];
+        let len = short_display(&self.short_name, 0, &mut short);
+        eq_ignore_case(&self.name[..self.name_len], name) || eq_ignore_case(&short[..len], name)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::directory)
+    }
+
+    pub fn size(&self) -> u64 {
+        u64::from(self.size)
+    }
+
+    /// The `.` and `..` entries of a subdirectory.
+    fn is_dot(&self) -> bool {
+        self.short_name == *DOT || self.short_name == *DOT_DOT
+    }
+}
+
+/// Long name entries seen before a short entry.
+struct Lfn {
+    name: [u16; MAX_LFN_ENTRIES * LFN_CHARS],
+    count: usize,
+    /// Sequence number expected next, 0 once complete
+    next: u8,
+    checksum: u8,
+    first: Option<Cursor>,
+}
+
+impl Lfn {
+    fn new() -> Self {
+        Self {
+            name: [0; MAX_LFN_ENTRIES * LFN_CHARS],
+            count: 0,
+            next: 0,
+            checksum: 0,
+            first: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.first = None;
+    }
+
+    fn push(&mut self, raw: &[u8], here: Cursor) {
+        let seq = raw[0] & 0x1F;
+        if raw[0] & LFN_LAST != 0 {
+            if seq == 0 || usize::from(seq) > MAX_LFN_ENTRIES {
+                self.reset();
+                return;
+            }
+            self.count = usize::from(seq);
+            self.checksum = raw[13];
+            self.first = Some(here);
+        } else if self.first.is_none() || seq == 0 || seq != self.next || raw[13] != self.checksum {
+            self.reset();
+            return;
+        }
+        let start = (usize::from(seq) - 1) * LFN_CHARS;
+        for (unit, &offset) in self.name[start..start + LFN_CHARS]
+            .iter_mut()
+            .zip(&LFN_OFFSETS)
+        {
+            *unit = u16_le(raw, offset);
+        }
+        self.next = seq - 1;
+    }
+
+    /// The long name, if complete and belonging to the short name with
+    /// `checksum`.
+    fn take(&mut self, checksum: u8) -> Option<(&[u16], Cursor, usize)> {
+        let first = self.first.take()?;
+        if self.next != 0 || self.checksum != checksum {
+            return None;
+        }
+        let units = &self.name[..self.count * LFN_CHARS];
+        let len = units
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(units.len())
+            .min(MAX_NAME);
+        Some((&units[..len], first, self.count))
+    }
+}
+
+/// Iterator over the entries of a directory, `.` and `..` included.
+pub struct ReadDir<'a, D: BlockDevice> {
+    fs: &'a mut FatFs<D>,
+    cursor: Option<Cursor>,
+    slots: usize,
+    lfn: Lfn,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FsError> {
+        while let Some(mut cursor) = self.cursor {
+            self.slots += 1;
+            if self.slots > MAX_DIR_ENTRIES {
+                return Err(FsError::Corrupted);
+            }
+            let slot = self.fs.slot(&cursor);
+            let raw: [u8; ENTRY_SIZE] = self.fs.sector(slot.lba)?
+                [slot.offset..slot.offset + ENTRY_SIZE]
+                .try_into()
+                .unwrap();
+            if raw[0] == END {
+                self.cursor = None;
+                return Ok(None);
+            }
+            let here = cursor;
+            self.cursor = self.fs.advance(&mut cursor, false)?.then_some(cursor);
+            if raw[0] == FREE {
+                self.lfn.reset();
+            } else if raw[11] & 0x3F == ATTR_LFN {
+                self.lfn.push(&raw, here);
+            } else if raw[11] & (Attributes::volume_id | Attributes::directory).bits()
+                == Attributes::volume_id.bits()
+            {
+                self.lfn.reset();
+            } else {
+                let fat32 = self.fs.kind == FatKind::Fat32;
+                return Ok(Some(DirEntry::parse(
+                    &raw,
+                    slot,
+                    here,
+                    &mut self.lfn,
+                    fat32,
+                )));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.cursor = None;
+        }
+        entry.transpose()
+    }
+}
+
+impl DirEntry {
+    fn parse(raw: &[u8], slot: Slot, here: Cursor, lfn: &mut Lfn, fat32: bool) -> Self {
+        let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+        let mut name = [0u16; MAX_NAME];
+        let (name_len, first, lfn_count) = match lfn.take(checksum(&short_name)) {
+            Some((units, first, count)) => {
+                name[..units.len()].copy_from_slice(units);
+                (units.len(), first, count)
+            }
+            None => (short_display(&short_name, raw[12], &mut name), here, 0),
+        };
+        let high = if fat32 { u16_le(raw, 20) } else { 0 };
+        Self {
+            name,
+            name_len,
+            short_name,
+            attributes: Attributes::from_bits_truncate(raw[11]),
+            cluster: (u32::from(high) << 16) | u32::from(u16_le(raw, 26)),
+            size: u32_le(raw, 28),
+            slot,
+            first,
+            lfn_count,
+        }
+    }
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    pub fn root_dir(&self) -> Dir {
+        Dir {
+            cluster: self.root_cluster,
+        }
+    }
+
+    pub fn read_dir(&mut self, dir: Dir) -> ReadDir<'_, D> {
+        ReadDir {
+            fs: self,
+            cursor: Some(Cursor::new(dir)),
+            slots: 0,
+            lfn: Lfn::new(),
+        }
+    }
+
+    /// Directory at `path`.
+    pub fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        match self.walk(path)? {
+            Some(entry) => self.entry_dir(&entry),
+            None => Ok(self.root_dir()),
+        }
+    }
+
+    /// Entry at `path`, `None` for the root directory.
+    pub fn metadata(&mut self, path: &str) -> Result<Option<DirEntry>, FsError> {
+        self.walk(path)
+    }
+
+    /// Create an empty directory at `path`.
+    pub fn create_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        let (parent, name) = self.parent(path)?;
+        self.check_writable()?;
+        let cluster = self.alloc_cluster(None)?;
+        self.zero_cluster(cluster)?;
+        if let Err(err) = self.create_entry(parent, name, Attributes::directory, cluster) {
+            self.free_chain(cluster)?;
+            return Err(err);
+        }
+        // `..` of a directory in the root is 0, on FAT32 too
+        let parent = if parent == self.root_dir() {
+            0
+        } else {
+            parent.cluster
+        };
+        let buf = self.sector_mut(self.cluster_lba(cluster))?;
+        write_short_entry(&mut buf[..ENTRY_SIZE], DOT, Attributes::directory, cluster);
+        write_short_entry(
+            &mut buf[ENTRY_SIZE..2 * ENTRY_SIZE],
+            DOT_DOT,
+            Attributes::directory,
+            parent,
+        );
+        Ok(Dir { cluster })
+    }
+
+    /// Remove the file or empty directory at `path`. Open handles to it go
+    /// stale.
+    pub fn remove(&mut self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let entry = self.walk(path)?.ok_or(FsError::InvalidName)?;
+        if entry.is_dir() {
+            let dir = self.entry_dir(&entry)?;
+            for child in self.read_dir(dir) {
+                if !child?.is_dot() {
+                    return Err(FsError::DirectoryNotEmpty);
+                }
+            }
+        }
+        let mut cursor = entry.first;
+        for i in 0..=entry.lfn_count {
+            let slot = self.slot(&cursor);
+            self.sector_mut(slot.lba)?[slot.offset] = FREE;
+            if i < entry.lfn_count && !self.advance(&mut cursor, false)? {
+                return Err(FsError::Corrupted);
+            }
+        }
+        if entry.cluster != 0 {
+            self.free_chain(entry.cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Follow `path` from the root directory.
+    pub(super) fn walk(&mut self, path: &str) -> Result<Option<DirEntry>, FsError> {
+        walk(self, path)
+    }
+
+    /// Directory holding the last component of `path`, and that component.
+    pub(super) fn parent<'p>(&mut self, path: &'p str) -> Result<(Dir, &'p str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = match self.walk(parent)? {
+            Some(entry) => self.entry_dir(&entry)?,
+            None => self.root_dir(),
+        };
+        Ok((dir, name))
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FsError> {
+        for entry in self.read_dir(dir) {
+            let entry = entry?;
+            if entry.name_eq(name) {
+                return Ok(entry);
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    fn entry_dir(&self, entry: &DirEntry) -> Result<Dir, FsError> {
+        if !entry.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        // `..` of a directory in the root
+        if entry.cluster == 0 {
+            return Ok(self.root_dir());
+        }
+        Ok(Dir {
+            cluster: entry.cluster,
+        })
+    }
+
+    fn slot(&self, cursor: &Cursor) -> Slot {
+        let start = if cursor.cluster == 0 {
+            self.root_start
+        } else {
+            self.cluster_lba(cursor.cluster)
+        };
+        Slot {
+            lba: start + u64::from(cursor.sector),
+            offset: cursor.index * ENTRY_SIZE,
+        }
+    }
+
+    /// Move to the next slot. At the end of the directory this returns
+    /// false, or appends a zeroed cluster when `extend` is set.
+    fn advance(&mut self, cursor: &mut Cursor, extend: bool) -> Result<bool, FsError> {
+        if cursor.index + 1 < ENTRIES_PER_SECTOR {
+            cursor.index += 1;
+            return Ok(true);
+        }
+        let sectors = if cursor.cluster == 0 {
+            self.root_sectors as u32
+        } else {
+            self.sectors_per_cluster
+        };
+        if cursor.sector + 1 < sectors {
+            cursor.sector += 1;
+            cursor.index = 0;
+            return Ok(true);
+        }
+        if cursor.cluster == 0 {
+            return Ok(false);
+        }
+        let next = match self.next_cluster(cursor.cluster)? {
+            Some(next) => next,
+            None if extend => {
+                let next = self.alloc_cluster(Some(cursor.cluster))?;
+                self.zero_cluster(next)?;
+                next
+            }
+            None => return Ok(false),
+        };
+        *cursor = Cursor {
+            cluster: next,
+            sector: 0,
+            index: 0,
+        };
+        Ok(true)
+    }
+
+    /// Add an entry named `name` to `dir`, with long name entries when it is
+    /// not a valid uppercase 8.3 name.
+    pub(super) fn create_entry(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        attributes: Attributes,
+        cluster: u32,
+    ) -> Result<DirEntry, FsError> {
+        self.check_writable()?;
+        let mut long = [0u16; MAX_NAME];
+        let len = encode_name(name, &mut long)?;
+        match self.find(dir, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let (short_name, lfn_count) = match exact_short_name(name) {
+            Some(short) => (short, 0),
+            None => (self.unique_short_name(dir, name)?, len.div_ceil(LFN_CHARS)),
+        };
+        let first = self.free_slots(dir, lfn_count + 1)?;
+        let checksum = checksum(&short_name);
+        let mut cursor = first;
+        for i in 0..lfn_count {
+            let seq = lfn_count - i;
+            let slot = self.slot(&cursor);
+            let raw = &mut self.sector_mut(slot.lba)?[slot.offset..slot.offset + ENTRY_SIZE];
+            raw.fill(0);
+            raw[0] = seq as u8 | if i == 0 { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LFN;
+            raw[13] = checksum;
+            for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+                // the name is terminated by 0 and padded with 0xFFFF
+                let k = (seq - 1) * LFN_CHARS + j;
+                let unit = match k.cmp(&len) {
+                    core::cmp::Ordering::Less => long[k],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.advance(&mut cursor, false)?;
+        }
+        let slot = self.slot(&cursor);
+        let raw = &mut self.sector_mut(slot.lba)?[slot.offset..slot.offset + ENTRY_SIZE];
+        write_short_entry(raw, &short_name, attributes, cluster);
+        Ok(DirEntry {
+            name: long,
+            name_len: len,
+            short_name,
+            attributes,
+            cluster,
+            size: 0,
+            slot,
+            first,
+            lfn_count,
+        })
+    }
+
+    /// First of `count` consecutive free slots in `dir`, growing it if
+    /// needed.
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<Cursor, FsError> {
+        let mut cursor = Cursor::new(dir);
+        let mut run: Option<(Cursor, usize)> = None;
+        for _ in 0..MAX_DIR_ENTRIES {
+            let slot = self.slot(&cursor);
+            let first = self.sector(slot.lba)?[slot.offset];
+            if first == FREE || first == END {
+                let (start, len) = run.get_or_insert((cursor, 0));
+                *len += 1;
+                if *len == count {
+                    return Ok(*start);
+                }
+            } else {
+                run = None;
+            }
+            if !self.advance(&mut cursor, true)? {
+                break;
+            }
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Short name derived from `name` with a `~N` tail not used in `dir`.
+    fn unique_short_name(&mut self, dir: Dir, name: &str) -> Result<[u8; 11], FsError> {
+        let (basis, base_len) = basis_name(name);
+        'tail: for n in 1..=MAX_TAIL {
+            let short = with_tail(&basis, base_len, n);
+            for entry in self.read_dir(dir) {
+                if entry?.short_name == short {
+                    continue 'tail;
+                }
+            }
+            return Ok(short);
+        }
+        Err(FsError::AlreadyExists)
+    }
+
+    /// First cluster and size of a file as its entry has them, `NotFound`
+    /// once the entry was removed.
+    pub(super) fn read_entry(&mut self, slot: Slot) -> Result<(u32, u32), FsError> {
+        let fat32 = self.kind == FatKind::Fat32;
+        let raw = &self.sector(slot.lba)?[slot.offset..slot.offset + ENTRY_SIZE];
+        if raw[0] == FREE || raw[0] == END {
+            return Err(FsError::NotFound);
+        }
+        let high = if fat32 { u16_le(raw, 20) } else { 0 };
+        Ok((
+            (u32::from(high) << 16) | u32::from(u16_le(raw, 26)),
+            u32_le(raw, 28),
+        ))
+    }
+
+    /// Record the first cluster and size of a file in its entry.
+    pub(super) fn update_entry(
+        &mut self,
+        slot: Slot,
+        cluster: u32,
+        size: u32,
+    ) -> Result<(), FsError> {
+        let raw = &mut self.sector_mut(slot.lba)?[slot.offset..slot.offset + ENTRY_SIZE];
+        raw[11] |= Attributes::archive.bits();
+        raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Lookup for FatFs<D> {
+    type Dir = Dir;
+    type Entry = DirEntry;
+
+    fn root_dir(&self) -> Dir {
+        FatFs::root_dir(self)
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<DirEntry, FsError> {
+        FatFs::find(self, dir, name)
+    }
+
+    fn entry_dir(&self, entry: &DirEntry) -> Result<Dir, FsError> {
+        FatFs::entry_dir(self, entry)
+    }
+}
+
+fn write_short_entry(raw: &mut [u8], short_name: &[u8; 11], attributes: Attributes, cluster: u32) {
+    raw.fill(0);
+    raw[..11].copy_from_slice(short_name);
+    raw[11] = attributes.bits();
+    // creation, access and modification dates
+    for offset in [16, 18, 24] {
+        raw[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// "NAME.EXT" of an 8.3 name in `out`, lowercased as the NT flags say.
+fn short_display(short_name: &[u8; 11], nt: u8, out: &mut [u16]) -> usize {
+    let trimmed = |part: &[u8]| part.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let base_len = trimmed(&short_name[..8]);
+    let ext_len = trimmed(&short_name[8..]);
+    let mut len = 0;
+    for (i, &byte) in short_name[..base_len].iter().enumerate() {
+        let byte = if i == 0 && byte == ESCAPED_E5 {
+            FREE
+        } else {
+            byte
+        };
+        out[len] = u16::from(if nt & LOWER_BASE != 0 {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        });
+        len += 1;
+    }
+    if ext_len > 0 {
+        out[len] = u16::from(b'.');
+        len += 1;
+        for &byte in &short_name[8..8 + ext_len] {
+            out[len] = u16::from(if nt & LOWER_EXT != 0 {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            });
+            len += 1;
+        }
+    }
+    len
+}
+
+fn utf16_chars(units: &[u16]) -> impl Iterator<Item = char> + '_ {
+    char::decode_utf16(units.iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+}
+
+fn eq_ignore_case(units: &[u16], name: &str) -> bool {
+    utf16_chars(units)
+        .map(|c| c.to_ascii_uppercase())
+        .eq(name.chars().map(|c| c.to_ascii_uppercase()))
+}
+
+/// Check `name` and encode it as UTF-16 into `out`.
+fn encode_name(name: &str, out: &mut [u16; MAX_NAME]) -> Result<usize, FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.ends_with(['.', ' ']) {
+        return Err(FsError::InvalidName);
+    }
+    let mut len = 0;
+    for c in name.chars() {
+        if c < ' ' || "\"*/:<>?\\|".contains(c) {
+            return Err(FsError::InvalidName);
+        }
+        let mut units = [0u16; 2];
+        for &unit in c.encode_utf16(&mut units).iter() {
+            *out.get_mut(len).ok_or(FsError::InvalidName)? = unit;
+            len += 1;
+        }
+    }
+    Ok(len)
+}
+
+fn is_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// `name` itself when it is a valid uppercase 8.3 name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.bytes().chain(ext.bytes()).all(is_short_char)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    if short[0] == FREE {
+        short[0] = ESCAPED_E5;
+    }
+    Some(short)
+}
+
+/// Uppercased 8.3 basis of a long name and the length of its base part.
+fn basis_name(name: &str) -> ([u8; 11], usize) {
+    let short_char = |c: char| {
+        let c = c.to_ascii_uppercase();
+        if c.is_ascii() && is_short_char(c as u8) {
+            c as u8
+        } else {
+            b'_'
+        }
+    };
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    let mut short = [b' '; 11];
+    let mut base_len = 0;
+    for (byte, c) in short[..8]
+        .iter_mut()
+        .zip(base.chars().filter(|&c| c != ' ' && c != '.'))
+    {
+        *byte = short_char(c);
+        base_len += 1;
+    }
+    for (byte, c) in short[8..].iter_mut().zip(ext.chars().filter(|&c| c != ' ')) {
+        *byte = short_char(c);
+    }
+    if base_len == 0 {
+        short[0] = b'_';
+        base_len = 1;
+    }
+    (short, base_len)
+}
+
+/// `basis` with its base cut to fit a `~n` tail.
+fn with_tail(basis: &[u8; 11], base_len: usize, n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 7];
+    let mut len = 0;
+    let mut n = n;
+    while n > 0 || len == 0 {
+        digits[len] = b'0' + (n % 10) as u8;
+        n /= 10;
+        len += 1;
+    }
+    let keep = base_len.min(8 - len - 1);
+    let mut short = *basis;
+    short[keep] = b'~';
+    for i in 0..len {
+        short[keep + 1 + i] = digits[len - 1 - i];
+    }
+    short[keep + 1 + len..8].fill(b' ');
+    short
+}
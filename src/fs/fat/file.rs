@@ -0,0 +1,207 @@
+//! File contents along the cluster chain.
+use crate::block::BlockDevice;
+use crate::fs::FsError;
+
+use super::dir::{Attributes, DirEntry, Slot};
+use super::{FatFs, SECTOR_SIZE};
+
+/// An open file. Handles do not borrow the filesystem, several can be open
+/// at once: every read, write and truncate picks up the first cluster and
+/// size from the directory entry, so handles to one file see each other's
+/// changes. They go stale when the file is removed.
+#[derive(Debug, Clone)]
+pub struct File {
+    entry: Slot,
+    first_cluster: u32,
+    size: u32,
+    pos: u32,
+    /// Cluster number `cluster_index` of the chain, 0 when not looked up
+    cluster: u32,
+    cluster_index: u32,
+}
+
+impl File {
+    fn new(entry: &DirEntry) -> Self {
+        Self {
+            entry: entry.slot,
+            first_cluster: entry.cluster,
+            size: entry.size,
+            pos: 0,
+            cluster: 0,
+            cluster_index: 0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        u64::from(self.size)
+    }
+
+    pub fn position(&self) -> u64 {
+        u64::from(self.pos)
+    }
+
+    /// Move to byte `pos`, clamped to the end of the file.
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos.min(u64::from(self.size)) as u32;
+    }
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Open the file at `path`.
+    pub fn open(&mut self, path: &str) -> Result<File, FsError> {
+        match self.walk(path)? {
+            Some(entry) if !entry.is_dir() => Ok(File::new(&entry)),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Create an empty file at `path`, failing with `AlreadyExists` when
+    /// there is one.
+    pub fn create(&mut self, path: &str) -> Result<File, FsError> {
+        let (dir, name) = self.parent(path)?;
+        let entry = self.create_entry(dir, name, Attributes::archive, 0)?;
+        Ok(File::new(&entry))
+    }
+
+    /// Read from the position of `file` into `buf`, returning the number of
+    /// bytes read, 0 at the end of the file.
+    pub fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.refresh(file)?;
+        let len = buf.len().min((file.size - file.pos) as usize);
+        let mut done = 0;
+        while done < len {
+            let cluster = self.file_cluster(file, false)?.ok_or(FsError::Corrupted)?;
+            let (lba, offset, left) = self.file_position(cluster, file.pos);
+            let n = if offset == 0 && len - done >= SECTOR_SIZE {
+                // whole sectors go straight into `buf`
+                let n = (len - done).min(left) / SECTOR_SIZE * SECTOR_SIZE;
+                self.read_sectors(lba, &mut buf[done..done + n])?;
+                n
+            } else {
+                let n = (len - done).min(SECTOR_SIZE - offset);
+                buf[done..done + n].copy_from_slice(&self.sector(lba)?[offset..offset + n]);
+                n
+            };
+            done += n;
+            file.pos += n as u32;
+        }
+        Ok(done)
+    }
+
+    /// Write all of `buf` at the position of `file`, growing it as needed.
+    /// FAT files end at 4 GiB.
+    pub fn write(&mut self, file: &mut File, buf: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        if u64::from(file.pos) + buf.len() as u64 > u64::from(u32::MAX) {
+            return Err(FsError::NoSpace);
+        }
+        self.refresh(file)?;
+        let written = self.write_data(file, buf);
+        // record what made it to the disk, even when the volume filled up
+        file.size = file.size.max(file.pos);
+        self.update_entry(file.entry, file.first_cluster, file.size)?;
+        written.map(|()| buf.len())
+    }
+
+    fn write_data(&mut self, file: &mut File, buf: &[u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cluster = self.file_cluster(file, true)?.ok_or(FsError::Corrupted)?;
+            let (lba, offset, left) = self.file_position(cluster, file.pos);
+            let n = if offset == 0 && buf.len() - done >= SECTOR_SIZE {
+                let n = (buf.len() - done).min(left) / SECTOR_SIZE * SECTOR_SIZE;
+                self.write_sectors(lba, &buf[done..done + n])?;
+                n
+            } else {
+                let n = (buf.len() - done).min(SECTOR_SIZE - offset);
+                self.sector_mut(lba)?[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+                n
+            };
+            done += n;
+            file.pos += n as u32;
+        }
+        Ok(())
+    }
+
+    /// Shrink `file` to `len` bytes and free the clusters past it. A `len`
+    /// at or past the end leaves the file as it is.
+    pub fn truncate(&mut self, file: &mut File, len: u64) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.refresh(file)?;
+        if len >= u64::from(file.size) {
+            return Ok(());
+        }
+        let len = len as u32;
+        let keep = len.div_ceil(self.cluster_size() as u32);
+        if keep == 0 {
+            if file.first_cluster != 0 {
+                self.free_chain(file.first_cluster)?;
+            }
+            file.first_cluster = 0;
+        } else {
+            let mut last = file.first_cluster;
+            for _ in 1..keep {
+                last = self.next_cluster(last)?.ok_or(FsError::Corrupted)?;
+            }
+            if let Some(rest) = self.next_cluster(last)? {
+                self.fat_set(last, self.eoc())?;
+                self.free_chain(rest)?;
+            }
+        }
+        file.size = len;
+        file.pos = file.pos.min(len);
+        file.cluster = 0;
+        file.cluster_index = 0;
+        self.update_entry(file.entry, file.first_cluster, file.size)
+    }
+
+    /// Pick up changes made to the file through other handles. The cached
+    /// cluster is dropped when the chain may have changed under it.
+    fn refresh(&mut self, file: &mut File) -> Result<(), FsError> {
+        let (first_cluster, size) = self.read_entry(file.entry)?;
+        if first_cluster != file.first_cluster || size < file.size {
+            file.cluster = 0;
+            file.cluster_index = 0;
+        }
+        file.first_cluster = first_cluster;
+        file.size = size;
+        file.pos = file.pos.min(size);
+        Ok(())
+    }
+
+    /// Sector, offset in it and bytes left in the cluster of byte `pos` of a
+    /// file, `pos` being in `cluster`.
+    fn file_position(&self, cluster: u32, pos: u32) -> (u64, usize, usize) {
+        let in_cluster = pos as usize % self.cluster_size();
+        (
+            self.cluster_lba(cluster) + (in_cluster / SECTOR_SIZE) as u64,
+            in_cluster % SECTOR_SIZE,
+            self.cluster_size() - in_cluster,
+        )
+    }
+
+    /// Cluster holding the position of `file`. When the chain ends before
+    /// it, a cluster is appended if `allocate` is set.
+    fn file_cluster(&mut self, file: &mut File, allocate: bool) -> Result<Option<u32>, FsError> {
+        let index = file.pos / self.cluster_size() as u32;
+        if file.cluster == 0 || file.cluster_index > index {
+            if file.first_cluster == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+                file.first_cluster = self.alloc_cluster(None)?;
+            }
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < index {
+            file.cluster = match self.next_cluster(file.cluster)? {
+                Some(next) => next,
+                None if allocate => self.alloc_cluster(Some(file.cluster))?,
+                None => return Ok(None),
+            };
+            file.cluster_index += 1;
+        }
+        Ok(Some(file.cluster))
+    }
+}
@@ -0,0 +1,763 @@
+//! FAT12/16/32 with long file names.
+//!
+//! Only 512 byte sectors are handled. Metadata goes through a one sector
+//! cache that is written back when another sector is needed; FAT sectors are
+//! written to every FAT copy. [`FatFs::flush`] writes the cache and the
+//! FSInfo free cluster hints, call it or [`FatFs::unmount`] before the card
+//! goes away.
+//!
+//! ```no_run
+//! use vf2_driver::fs::fat::FatFs;
+//! use vf2_driver::part::mbr::Mbr;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let mut card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let boot = Mbr::read(&mut card).unwrap().get(1).unwrap().open(&mut card).unwrap();
+//! let mut fs = FatFs::mount(boot).unwrap();
+//!
+//! let mut config = fs.open("/config.txt").unwrap();
+//! let mut buf = [0u8; 4096];
+//! let len = fs.read(&mut config, &mut buf).unwrap();
+//!
+//! let mut log = fs.create("/boot.log").unwrap();
+//! fs.write(&mut log, &buf[..len]).unwrap();
+//! fs.unmount().unwrap();
+//! ```
+use log::warn;
+
+use crate::block::BlockDevice;
+
+use super::{FileSystem, FsError};
+
+mod dir;
+mod file;
+
+pub use dir::{Attributes, Dir, DirEntry, ReadDir};
+pub use file::File;
+
+const SECTOR_SIZE: usize = 512;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xAA55_0000;
+/// FSInfo value of a field that is not known
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+/// FAT32 extended flags: only the active FAT is in use
+const NO_MIRRORING: u16 = 1 << 7;
+/// Cluster counts from which FAT16 and FAT32 are used
+const FAT16_MIN_CLUSTERS: u64 = 4085;
+const FAT32_MIN_CLUSTERS: u64 = 65525;
+const FAT32_MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatKind {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A mounted FAT volume.
+pub struct FatFs<D: BlockDevice> {
+    dev: D,
+    kind: FatKind,
+    sectors_per_cluster: u32,
+    /// First sector of the first FAT
+    fats_start: u64,
+    /// Sectors per FAT
+    fat_size: u64,
+    num_fats: u8,
+    /// FAT that is read, the first one unless mirroring is disabled
+    active_fat: u8,
+    mirroring: bool,
+    /// Fixed root directory of FAT12/16
+    root_start: u64,
+    root_sectors: u64,
+    /// Root directory cluster of FAT32, 0 on FAT12/16
+    root_cluster: u32,
+    data_start: u64,
+    cluster_count: u32,
+    label: [u8; 11],
+    /// FSInfo sector of FAT32
+    fsinfo: Option<u64>,
+    free_count: Option<u32>,
+    next_free: u32,
+    fsinfo_dirty: bool,
+    cache: [u8; SECTOR_SIZE],
+    cache_lba: Option<u64>,
+    cache_dirty: bool,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Parse the BPB in the first sector of `dev`, and the FSInfo sector on
+    /// FAT32.
+    pub fn mount(mut dev: D) -> Result<Self, FsError> {
+        if dev.block_size() != SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let mut buf = [0u8; SECTOR_SIZE];
+        dev.read_blocks(0, &mut buf)?;
+        if buf[510..] != BOOT_SIGNATURE {
+            return Err(FsError::Corrupted);
+        }
+        if usize::from(u16_le(&buf, 11)) != SECTOR_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let sectors_per_cluster = buf[13];
+        let reserved = u16_le(&buf, 14);
+        let num_fats = buf[16];
+        let root_entries = u16_le(&buf, 17);
+        let total = match u16_le(&buf, 19) {
+            0 => u32_le(&buf, 32),
+            total => u32::from(total),
+        };
+        let fat_size = match u16_le(&buf, 22) {
+            0 => u32_le(&buf, 36),
+            size => u32::from(size),
+        };
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0 || fat_size == 0
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut total = u64::from(total);
+        if total > dev.block_count() {
+            warn!("fat: volume is larger than the device, ignoring the rest");
+            total = dev.block_count();
+        }
+        let fats_start = u64::from(reserved);
+        let fat_size = u64::from(fat_size);
+        let root_start = fats_start + u64::from(num_fats) * fat_size;
+        let root_sectors = (u64::from(root_entries) * 32).div_ceil(SECTOR_SIZE as u64);
+        let data_start = root_start + root_sectors;
+        let cluster_count = total.checked_sub(data_start).ok_or(FsError::Corrupted)?
+            / u64::from(sectors_per_cluster);
+        let (kind, fat_bytes) = if cluster_count < FAT16_MIN_CLUSTERS {
+            (FatKind::Fat12, (cluster_count + 2) * 3 / 2 + 1)
+        } else if cluster_count < FAT32_MIN_CLUSTERS {
+            (FatKind::Fat16, (cluster_count + 2) * 2)
+        } else {
+            (FatKind::Fat32, (cluster_count + 2) * 4)
+        };
+        if cluster_count > FAT32_MAX_CLUSTERS || fat_bytes > fat_size * SECTOR_SIZE as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut fs = Self {
+            dev,
+            kind,
+            sectors_per_cluster: u32::from(sectors_per_cluster),
+            fats_start,
+            fat_size,
+            num_fats,
+            active_fat: 0,
+            mirroring: true,
+            root_start,
+            root_sectors,
+            root_cluster: 0,
+            data_start,
+            cluster_count: cluster_count as u32,
+            label: [b' '; 11],
+            fsinfo: None,
+            free_count: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+            cache: [0; SECTOR_SIZE],
+            cache_lba: None,
+            cache_dirty: false,
+        };
+        if kind == FatKind::Fat32 {
+            let ext_flags = u16_le(&buf, 40);
+            if ext_flags & NO_MIRRORING != 0 {
+                fs.mirroring = false;
+                fs.active_fat = (ext_flags & 0xF) as u8;
+            }
+            fs.root_cluster = u32_le(&buf, 44);
+            if fs.active_fat >= num_fats || !fs.is_cluster(fs.root_cluster) {
+                return Err(FsError::Corrupted);
+            }
+            fs.label.copy_from_slice(&buf[71..82]);
+            let fsinfo = u16_le(&buf, 48);
+            if fsinfo != 0 && fsinfo < reserved {
+                fs.read_fsinfo(u64::from(fsinfo))?;
+            }
+        } else {
+            if root_entries == 0 {
+                return Err(FsError::Corrupted);
+            }
+            fs.label.copy_from_slice(&buf[43..54]);
+        }
+        Ok(fs)
+    }
+
+    fn read_fsinfo(&mut self, lba: u64) -> Result<(), FsError> {
+        let buf = self.sector(lba)?;
+        if u32_le(buf, 0) != FSINFO_LEAD_SIG
+            || u32_le(buf, 484) != FSINFO_STRUC_SIG
+            || u32_le(buf, 508) != FSINFO_TRAIL_SIG
+        {
+            warn!("fat: invalid FSInfo sector, ignoring it");
+            return Ok(());
+        }
+        let free = u32_le(buf, 488);
+        let next = u32_le(buf, 492);
+        self.fsinfo = Some(lba);
+        self.free_count = (free <= self.cluster_count).then_some(free);
+        if self.is_cluster(next) {
+            self.next_free = next;
+        }
+        Ok(())
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.kind
+    }
+
+    /// Volume label from the boot sector, without the space padding.
+    pub fn label(&self) -> &str {
+        core::str::from_utf8(&self.label).unwrap_or("").trim_end()
+    }
+
+    /// Cluster size in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// Number of free clusters, from FSInfo or by scanning the FAT once.
+    pub fn free_clusters(&mut self) -> Result<u32, FsError> {
+        if let Some(free) = self.free_count {
+            return Ok(free);
+        }
+        let mut free = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_get(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        self.free_count = Some(free);
+        // a read-only volume keeps the unknown count on disk
+        self.fsinfo_dirty = !self.dev.is_read_only();
+        Ok(free)
+    }
+
+    /// Write back the cached sector and FSInfo, then flush the device.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.writeback()?;
+        if self.fsinfo_dirty {
+            if let Some(lba) = self.fsinfo {
+                let free = self.free_count.unwrap_or(FSINFO_UNKNOWN);
+                let next = self.next_free;
+                let buf = self.sector_mut(lba)?;
+                buf[488..492].copy_from_slice(&free.to_le_bytes());
+                buf[492..496].copy_from_slice(&next.to_le_bytes());
+                self.writeback()?;
+            }
+            self.fsinfo_dirty = false;
+        }
+        self.dev.flush()?;
+        Ok(())
+    }
+
+    /// Flush and give the device back.
+    pub fn unmount(mut self) -> Result<D, FsError> {
+        self.flush()?;
+        Ok(self.dev)
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.dev.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Sector `lba` through the cache.
+    fn sector(&mut self, lba: u64) -> Result<&[u8; SECTOR_SIZE], FsError> {
+        self.load(lba)?;
+        Ok(&self.cache)
+    }
+
+    /// Sector `lba` through the cache, written back later.
+    fn sector_mut(&mut self, lba: u64) -> Result<&mut [u8; SECTOR_SIZE], FsError> {
+        self.load(lba)?;
+        self.cache_dirty = true;
+        Ok(&mut self.cache)
+    }
+
+    fn load(&mut self, lba: u64) -> Result<(), FsError> {
+        if self.cache_lba == Some(lba) {
+            return Ok(());
+        }
+        self.writeback()?;
+        self.cache_lba = None;
+        self.dev.read_blocks(lba, &mut self.cache)?;
+        self.cache_lba = Some(lba);
+        Ok(())
+    }
+
+    fn writeback(&mut self) -> Result<(), FsError> {
+        let Some(lba) = self.cache_lba else {
+            return Ok(());
+        };
+        if !self.cache_dirty {
+            return Ok(());
+        }
+        let active = self.fat_start();
+        if self.mirroring && (active..active + self.fat_size).contains(&lba) {
+            for fat in 0..u64::from(self.num_fats) {
+                let copy = self.fats_start + fat * self.fat_size + (lba - active);
+                self.dev.write_blocks(copy, &self.cache)?;
+            }
+        } else {
+            self.dev.write_blocks(lba, &self.cache)?;
+        }
+        self.cache_dirty = false;
+        Ok(())
+    }
+
+    /// Read whole sectors past the cache, writing it back first when it
+    /// holds one of them.
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let end = lba + (buf.len() / SECTOR_SIZE) as u64;
+        if self
+            .cache_lba
+            .is_some_and(|cached| (lba..end).contains(&cached))
+        {
+            self.writeback()?;
+        }
+        self.dev.read_blocks(lba, buf)?;
+        Ok(())
+    }
+
+    /// Write whole sectors past the cache, dropping it when it holds one of
+    /// them.
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), FsError> {
+        let end = lba + (buf.len() / SECTOR_SIZE) as u64;
+        if self
+            .cache_lba
+            .is_some_and(|cached| (lba..end).contains(&cached))
+        {
+            self.cache_lba = None;
+            self.cache_dirty = false;
+        }
+        self.dev.write_blocks(lba, buf)?;
+        Ok(())
+    }
+
+    fn fat_start(&self) -> u64 {
+        self.fats_start + u64::from(self.active_fat) * self.fat_size
+    }
+
+    /// Sector and offset of byte `offset` of the active FAT.
+    fn fat_pos(&self, offset: u64) -> (u64, usize) {
+        (
+            self.fat_start() + offset / SECTOR_SIZE as u64,
+            (offset % SECTOR_SIZE as u64) as usize,
+        )
+    }
+
+    fn fat_byte(&mut self, offset: u64) -> Result<u8, FsError> {
+        let (lba, offset) = self.fat_pos(offset);
+        Ok(self.sector(lba)?[offset])
+    }
+
+    fn set_fat_byte(&mut self, offset: u64, value: u8) -> Result<(), FsError> {
+        let (lba, offset) = self.fat_pos(offset);
+        self.sector_mut(lba)?[offset] = value;
+        Ok(())
+    }
+
+    fn fat_get(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let cluster = u64::from(cluster);
+        match self.kind {
+            // 12 bit entries are packed in pairs and may cross a sector
+            FatKind::Fat12 => {
+                let offset = cluster * 3 / 2;
+                let value =
+                    u16::from_le_bytes([self.fat_byte(offset)?, self.fat_byte(offset + 1)?]);
+                Ok(u32::from(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xFFF
+                }))
+            }
+            FatKind::Fat16 => {
+                let (lba, offset) = self.fat_pos(cluster * 2);
+                Ok(u32::from(u16_le(self.sector(lba)?, offset)))
+            }
+            FatKind::Fat32 => {
+                let (lba, offset) = self.fat_pos(cluster * 4);
+                Ok(u32_le(self.sector(lba)?, offset) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    fn fat_set(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let odd = cluster & 1 == 1;
+        let cluster = u64::from(cluster);
+        match self.kind {
+            FatKind::Fat12 => {
+                let offset = cluster * 3 / 2;
+                let (lo, hi) = (self.fat_byte(offset)?, self.fat_byte(offset + 1)?);
+                let (lo, hi) = if odd {
+                    ((lo & 0x0F) | (value << 4) as u8, (value >> 4) as u8)
+                } else {
+                    (value as u8, (hi & 0xF0) | ((value >> 8) as u8 & 0x0F))
+                };
+                self.set_fat_byte(offset, lo)?;
+                self.set_fat_byte(offset + 1, hi)
+            }
+            FatKind::Fat16 => {
+                let (lba, offset) = self.fat_pos(cluster * 2);
+                self.sector_mut(lba)?[offset..offset + 2]
+                    .copy_from_slice(&(value as u16).to_le_bytes());
+                Ok(())
+            }
+            FatKind::Fat32 => {
+                // the top 4 bits are reserved and kept
+                let (lba, offset) = self.fat_pos(cluster * 4);
+                let buf = self.sector_mut(lba)?;
+                let value = (u32_le(buf, offset) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                Ok(())
+            }
+        }
+    }
+
+    /// End of chain marker.
+    fn eoc(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat12 => 0xFFF,
+            FatKind::Fat16 => 0xFFFF,
+            FatKind::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * u64::from(self.sectors_per_cluster)
+    }
+
+    /// Cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FsError> {
+        if !self.is_cluster(cluster) {
+            return Err(FsError::Corrupted);
+        }
+        let next = self.fat_get(cluster)?;
+        // 0x..FF8 and up all mark the end of a chain
+        if next >= self.eoc() - 7 {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(FsError::Corrupted)
+        }
+    }
+
+    /// Allocate a free cluster as the end of a chain, linked after `prev`.
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, FsError> {
+        if self.free_count == Some(0) {
+            return Err(FsError::NoSpace);
+        }
+        let mut cluster = if self.is_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        for _ in 0..self.cluster_count {
+            if self.fat_get(cluster)? == 0 {
+                self.fat_set(cluster, self.eoc())?;
+                if let Some(prev) = prev {
+                    self.fat_set(prev, cluster)?;
+                }
+                self.free_count = self.free_count.map(|free| free.saturating_sub(1));
+                self.next_free = cluster + 1;
+                self.fsinfo_dirty = true;
+                return Ok(cluster);
+            }
+            cluster = if self.is_cluster(cluster + 1) {
+                cluster + 1
+            } else {
+                2
+            };
+        }
+        self.free_count = Some(0);
+        Err(FsError::NoSpace)
+    }
+
+    /// Free the chain starting at `start`.
+    fn free_chain(&mut self, start: u32) -> Result<(), FsError> {
+        let mut cluster = Some(start);
+        // a chain longer than the FAT is a loop
+        for _ in 0..=self.cluster_count {
+            let Some(current) = cluster else {
+                return Ok(());
+            };
+            cluster = self.next_cluster(current)?;
+            self.fat_set(current, 0)?;
+            self.free_count = self.free_count.map(|free| free + 1);
+            self.fsinfo_dirty = true;
+        }
+        Err(FsError::Corrupted)
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), FsError> {
+        let lba = self.cluster_lba(cluster);
+        let zero = [0u8; SECTOR_SIZE];
+        for sector in 0..u64::from(self.sectors_per_cluster) {
+            self.write_sectors(lba + sector, &zero)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> FileSystem for FatFs<D> {
+    type File = File;
+    type Dir = Dir;
+
+    fn open(&mut self, path: &str) -> Result<File, FsError> {
+        FatFs::open(self, path)
+    }
+
+    fn open_dir(&mut self, path: &str) -> Result<Dir, FsError> {
+        FatFs::open_dir(self, path)
+    }
+
+    fn read(&mut self, file: &mut File, buf: &mut [u8]) -> Result<usize, FsError> {
+        FatFs::read(self, file, buf)
+    }
+}
+
+fn u16_le(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::block::ram::RamDisk;
+    use crate::part::Partition;
+
+    const FSINFO_LBA: usize = 1;
+
+    fn put16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// An empty volume of `kind` with one sector clusters and two FATs.
+    fn format(kind: FatKind) -> Vec<u8> {
+        // total sectors, reserved sectors, root entries, sectors per FAT
+        let (total, reserved, root_entries, fat_size) = match kind {
+            FatKind::Fat12 => (2048, 1, 64, 6),
+            FatKind::Fat16 => (8192, 1, 512, 32),
+            FatKind::Fat32 => (67072, 32, 0, 520),
+        };
+        let mut image = vec![0u8; total as usize * SECTOR_SIZE];
+        put16(&mut image, 11, SECTOR_SIZE as u16);
+        image[13] = 1;
+        put16(&mut image, 14, reserved);
+        image[16] = 2;
+        put16(&mut image, 17, root_entries);
+        put32(&mut image, 32, total);
+        image[21] = 0xF8;
+        image[510..512].copy_from_slice(&BOOT_SIGNATURE);
+        let mut fat = [0u8; 12];
+        let fat = match kind {
+            FatKind::Fat32 => {
+                put32(&mut image, 36, fat_size);
+                put32(&mut image, 44, 2);
+                put16(&mut image, 48, FSINFO_LBA as u16);
+                image[71..82].copy_from_slice(b"TEST32     ");
+                let clusters = total - u32::from(reserved) - 2 * fat_size;
+                let fsinfo = &mut image[FSINFO_LBA * SECTOR_SIZE..][..SECTOR_SIZE];
+                put32(fsinfo, 0, FSINFO_LEAD_SIG);
+                put32(fsinfo, 484, FSINFO_STRUC_SIG);
+                put32(fsinfo, 488, clusters - 1);
+                put32(fsinfo, 492, 3);
+                put32(fsinfo, 508, FSINFO_TRAIL_SIG);
+                // media, reserved and the root directory cluster
+                put32(&mut fat, 0, 0x0FFF_FFF8);
+                put32(&mut fat, 4, 0x0FFF_FFFF);
+                put32(&mut fat, 8, 0x0FFF_FFFF);
+                &fat[..12]
+            }
+            _ => {
+                put16(&mut image, 22, fat_size as u16);
+                image[43..54].copy_from_slice(b"TEST       ");
+                fat[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+                &fat[..if kind == FatKind::Fat12 { 3 } else { 4 }]
+            }
+        };
+        for copy in 0..2 {
+            let at = (usize::from(reserved) + copy * fat_size as usize) * SECTOR_SIZE;
+            image[at..at + fat.len()].copy_from_slice(fat);
+        }
+        image
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn read_all<D: BlockDevice>(fs: &mut FatFs<D>, path: &str) -> Vec<u8> {
+        let mut file = fs.open(path).unwrap();
+        let mut buf = vec![0u8; 8192];
+        let len = fs.read(&mut file, &mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    fn round_trip(kind: FatKind) {
+        const PATH: &str = "/boot/A long file name.txt";
+        let mut image = format(kind);
+        let content = data(3000);
+
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(fs.kind(), kind);
+        let free = fs.free_clusters().unwrap();
+        fs.create_dir("/boot").unwrap();
+        let mut file = fs.create(PATH).unwrap();
+        assert!(matches!(fs.create(PATH), Err(FsError::AlreadyExists)));
+        // an unaligned write, then one crossing sectors and clusters
+        fs.write(&mut file, &content[..100]).unwrap();
+        fs.write(&mut file, &content[100..]).unwrap();
+        assert_eq!(file.size(), 3000);
+        assert_eq!(fs.free_clusters().unwrap(), free - 1 - 6);
+        fs.unmount().unwrap();
+
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(read_all(&mut fs, PATH), content);
+        assert_eq!(read_all(&mut fs, "/BOOT/a LONG file NAME.TXT"), content);
+        let mut file = fs.open(PATH).unwrap();
+        fs.truncate(&mut file, 700).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 1 - 2);
+        assert_eq!(read_all(&mut fs, PATH), content[..700]);
+        assert!(matches!(
+            fs.remove("/boot"),
+            Err(FsError::DirectoryNotEmpty)
+        ));
+        fs.remove(PATH).unwrap();
+        assert!(matches!(fs.open(PATH), Err(FsError::NotFound)));
+        fs.remove("/boot").unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free);
+        fs.unmount().unwrap();
+
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert!(matches!(fs.metadata("/boot"), Err(FsError::NotFound)));
+        assert_eq!(fs.read_dir(fs.root_dir()).count(), 0);
+        assert_eq!(fs.free_clusters().unwrap(), free);
+
+        // both FATs carry the same chains
+        let (reserved, fat_size) = match kind {
+            FatKind::Fat12 => (1, 6),
+            FatKind::Fat16 => (1, 32),
+            FatKind::Fat32 => (32, 520),
+        };
+        let fats = &image[reserved * SECTOR_SIZE..(reserved + 2 * fat_size) * SECTOR_SIZE];
+        let (first, second) = fats.split_at(fat_size * SECTOR_SIZE);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn fat12_round_trip() {
+        round_trip(FatKind::Fat12);
+    }
+
+    #[test]
+    fn fat16_round_trip() {
+        round_trip(FatKind::Fat16);
+    }
+
+    #[test]
+    fn fat32_round_trip() {
+        round_trip(FatKind::Fat32);
+    }
+
+    #[test]
+    fn fsinfo_tracks_allocations() {
+        let mut image = format(FatKind::Fat32);
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(fs.label(), "TEST32");
+        let free = fs.free_clusters().unwrap();
+        assert_eq!(free, fs.cluster_count() - 1);
+        let mut file = fs.create("/initrd").unwrap();
+        fs.write(&mut file, &data(5 * 512)).unwrap();
+        fs.unmount().unwrap();
+
+        let fsinfo = &image[FSINFO_LBA * SECTOR_SIZE..][..SECTOR_SIZE];
+        assert_eq!(u32_le(fsinfo, 488), free - 5);
+        assert_eq!(u32_le(fsinfo, 492), 8);
+
+        // the hint agrees with a scan of the FAT
+        put32(&mut image[FSINFO_LBA * SECTOR_SIZE..], 488, FSINFO_UNKNOWN);
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 5);
+    }
+
+    #[test]
+    fn read_only_volume_unmounts_after_a_scan() {
+        let mut image = format(FatKind::Fat32);
+        put32(&mut image[FSINFO_LBA * SECTOR_SIZE..], 488, FSINFO_UNKNOWN);
+        let before = image.clone();
+        let blocks = (image.len() / SECTOR_SIZE) as u64;
+        let dev = Partition::new(RamDisk::new(&mut image), 0, blocks).unwrap();
+        let mut fs = FatFs::mount(dev.read_only()).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), fs.cluster_count() - 1);
+        assert!(matches!(fs.create("/x"), Err(FsError::ReadOnly)));
+        fs.unmount().unwrap();
+        assert!(image == before);
+    }
+
+    #[test]
+    fn handles_to_one_file_share_its_chain() {
+        let mut image = format(FatKind::Fat16);
+        let mut fs = FatFs::mount(RamDisk::new(&mut image)).unwrap();
+        let free = fs.free_clusters().unwrap();
+        let mut first = fs.create("/x").unwrap();
+        let mut second = fs.open("/x").unwrap();
+        let content = data(600);
+        fs.write(&mut first, &content).unwrap();
+        fs.write(&mut second, &[0xAA; 100]).unwrap();
+        assert_eq!(second.size(), 600);
+        assert_eq!(fs.free_clusters().unwrap(), free - 2);
+        let mut expected = content.clone();
+        expected[..100].fill(0xAA);
+        assert_eq!(read_all(&mut fs, "/x"), expected);
+
+        // a truncate through one handle shows in the other
+        fs.truncate(&mut second, 10).unwrap();
+        let mut buf = [0u8; 16];
+        first.seek(0);
+        assert_eq!(fs.read(&mut first, &mut buf).unwrap(), 10);
+        assert_eq!(first.size(), 10);
+        fs.write(&mut first, &content[..100]).unwrap();
+        assert_eq!(fs.free_clusters().unwrap(), free - 1);
+
+        fs.remove("/x").unwrap();
+        assert!(matches!(
+            fs.write(&mut first, b"stale"),
+            Err(FsError::NotFound)
+        ));
+        assert_eq!(fs.free_clusters().unwrap(), free);
+    }
+}
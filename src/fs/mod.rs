@@ -0,0 +1,183 @@
+//! Filesystems on top of [`BlockDevice`](crate::block::BlockDevice).
+//!
+//! Nothing is allocated: the filesystem value owns the device and a sector
+//! buffer, files and directories are small handles passed back to it.
+//! [`FileSystem`] is the part every filesystem shares, for code that should
+//! not care which one the card holds.
+use crate::block::BlockError;
+
+pub mod exfat;
+pub mod ext4;
+pub mod fat;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FsError {
+    /// No file or directory with that path
+    NotFound,
+    /// A path component before the last one is a file
+    NotADirectory,
+    /// Opened a directory as a file
+    IsADirectory,
+    AlreadyExists,
+    /// Removing a directory that still has entries
+    DirectoryNotEmpty,
+    /// No free cluster, or no free entry in a fixed size directory
+    NoSpace,
+    /// Empty name, reserved character or name too long
+    InvalidName,
+    /// Write to a read-only filesystem or device
+    ReadOnly,
+    /// On-disk structures are inconsistent
+    Corrupted,
+    /// A feature of the volume this implementation does not handle
+    Unsupported,
+    /// Too many symbolic links followed while resolving a path
+    SymlinkLoop,
+    /// Error from the block device
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(value: BlockError) -> Self {
+        match value {
+            BlockError::ReadOnly => Self::ReadOnly,
+            BlockError::Corrupted => Self::Corrupted,
+            BlockError::NotFound => Self::NotFound,
+            BlockError::Unsupported => Self::Unsupported,
+            BlockError::NoSpace => Self::NoSpace,
+            err => Self::Block(err),
+        }
+    }
+}
+
+/// Path based reading every filesystem here provides, for loaders that take
+/// any of them. Paths are `/` separated from the root directory, `..` is
+/// resolved lexically.
+pub trait FileSystem {
+    type File;
+    type Dir;
+
+    fn open(&mut self, path: &str) -> Result<Self::File, FsError>;
+
+    fn open_dir(&mut self, path: &str) -> Result<Self::Dir, FsError>;
+
+    /// Read from the position of `file` into `buf`, returning the number of
+    /// bytes read, 0 at the end of the file.
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Read the whole file at `path` into `buf` and return its length, or
+    /// fail with `NoSpace` when it does not fit.
+    fn read_file(&mut self, path: &str, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut file = self.open(path)?;
+        let mut len = 0;
+        while len < buf.len() {
+            match self.read(&mut file, &mut buf[len..])? {
+                0 => return Ok(len),
+                n => len += n,
+            }
+        }
+        if self.read(&mut file, &mut [0u8; 1])? != 0 {
+            return Err(FsError::NoSpace);
+        }
+        Ok(len)
+    }
+}
+
+/// Directory search a filesystem provides to [`walk`].
+pub(crate) trait Lookup {
+    type Dir: Copy;
+    type Entry;
+
+    fn root_dir(&self) -> Self::Dir;
+
+    /// Entry named `name` in `dir`, `NotFound` when there is none.
+    fn find(&mut self, dir: Self::Dir, name: &str) -> Result<Self::Entry, FsError>;
+
+    /// Directory described by `entry`, `NotADirectory` for a file.
+    fn entry_dir(&self, entry: &Self::Entry) -> Result<Self::Dir, FsError>;
+}
+
+/// Follow `path` from the root directory of `fs`, `None` being the root
+/// itself.
+pub(crate) fn walk<L: Lookup>(fs: &mut L, path: &str) -> Result<Option<L::Entry>, FsError> {
+    let root = fs.root_dir();
+    // `..` at the root stays there
+    let (_, names) = components(path);
+    follow(fs, root, None, names)
+}
+
+/// Follow the relative `path` from `dir`, `None` being `dir` itself. `..`
+/// left over after the lexical pass is looked up in the directory, so only
+/// filesystems with `..` entries should see it.
+pub(crate) fn walk_from<L: Lookup>(
+    fs: &mut L,
+    mut dir: L::Dir,
+    path: &str,
+) -> Result<Option<L::Entry>, FsError> {
+    let (ups, names) = components(path);
+    let mut entry: Option<L::Entry> = None;
+    for _ in 0..ups {
+        if let Some(parent) = entry.take() {
+            dir = fs.entry_dir(&parent)?;
+        }
+        entry = Some(fs.find(dir, "..")?);
+    }
+    follow(fs, dir, entry, names)
+}
+
+fn follow<'a, L: Lookup>(
+    fs: &mut L,
+    mut dir: L::Dir,
+    mut entry: Option<L::Entry>,
+    names: impl Iterator<Item = &'a str>,
+) -> Result<Option<L::Entry>, FsError> {
+    for name in names {
+        if let Some(parent) = entry.take() {
+            dir = fs.entry_dir(&parent)?;
+        }
+        entry = Some(fs.find(dir, name)?);
+    }
+    Ok(entry)
+}
+
+/// Components of a `/` separated path, with empty and `.` components
+/// dropped and `..` applied to the component before it, along with the
+/// number of `..` that climb above the start.
+pub(crate) fn components(path: &str) -> (usize, impl Iterator<Item = &str>) {
+    let parts = move || {
+        path.split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+    };
+    let mut depth = 0usize;
+    let mut ups = 0;
+    for name in parts() {
+        if name != ".." {
+            depth += 1;
+        } else if depth == 0 {
+            ups += 1;
+        } else {
+            depth -= 1;
+        }
+    }
+    let names = parts()
+        .enumerate()
+        .filter(move |&(i, name)| {
+            if name == ".." {
+                return false;
+            }
+            // dropped when a later `..` climbs back above it
+            let mut depth = 0usize;
+            for later in parts().skip(i + 1) {
+                if later != ".." {
+                    depth += 1;
+                } else if depth == 0 {
+                    return false;
+                } else {
+                    depth -= 1;
+                }
+            }
+            true
+        })
+        .map(|(_, name)| name);
+    (ups, names)
+}
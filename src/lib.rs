@@ -1,5 +1,9 @@
 #![no_std]
-pub mod timer;
+pub mod block;
+mod crc;
+pub mod fs;
+pub mod part;
 pub mod sd;
 pub mod serial;
-pub extern crate log;
\ No newline at end of file
+pub mod timer;
+pub extern crate log;
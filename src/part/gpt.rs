@@ -0,0 +1,549 @@
+//! GUID partition table.
+//!
+//! The primary header at LBA 1 and its entry array are checked against
+//! their CRC32s; when either is damaged the backup header at the last LBA is
+//! used instead. Entries are read from the device on each lookup, nothing is
+//! buffered beyond one sector.
+use core::fmt::{Debug, Display};
+
+use log::warn;
+
+use crate::block::{BlockDevice, BlockError};
+use crate::crc::{crc32, crc32_update};
+
+use super::mbr::Mbr;
+use super::{read_sector, Partition, SECTOR_SIZE};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const HEADER_MIN_SIZE: usize = 92;
+const ENTRY_MIN_SIZE: usize = 128;
+/// More entries than this is not a sane table
+const MAX_ENTRIES: u32 = 1024;
+/// UTF-16 code units in an entry name
+const NAME_LEN: usize = 36;
+
+/// A GUID, kept in its on-disk mixed-endian byte order.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    pub const ZERO: Self = Self([0; 16]);
+
+    /// GUID `d1-d2-d3-d4[0..2]-d4[2..8]`, as it is written in text.
+    pub const fn new(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> Self {
+        let a = d1.to_le_bytes();
+        let b = d2.to_le_bytes();
+        let c = d3.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4],
+            d4[5], d4[6], d4[7],
+        ])
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+impl Debug for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Partition type GUIDs
+pub const TYPE_EFI_SYSTEM: Guid = Guid::new(
+    0xC12A7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+pub const TYPE_LINUX_FS: Guid = Guid::new(
+    0x0FC63DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+/// Second stage boot loader of the VisionFive 2 images
+pub const TYPE_STARFIVE_SPL: Guid = Guid::new(
+    0x2E54B353,
+    0x1271,
+    0x4842,
+    [0x80, 0x6F, 0xE4, 0x36, 0xD6, 0xAF, 0x69, 0x85],
+);
+/// U-Boot FIT image of the VisionFive 2 images
+pub const TYPE_STARFIVE_UBOOT: Guid = Guid::new(
+    0x5B193300,
+    0xFC78,
+    0x40CD,
+    [0x80, 0x02, 0xE8, 0x6C, 0x45, 0x58, 0x0B, 0x47],
+);
+
+/// One partition entry.
+#[derive(Clone, Copy)]
+pub struct GptEntry {
+    /// Slot in the entry array
+    pub index: u32,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    /// Last block, inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    name: [u16; NAME_LEN],
+}
+
+impl GptEntry {
+    fn parse(index: u32, raw: &[u8]) -> Self {
+        let guid = |offset: usize| Guid(raw[offset..offset + 16].try_into().unwrap());
+        let mut name = [0u16; NAME_LEN];
+        for (i, unit) in name.iter_mut().enumerate() {
+            *unit = u16::from_le_bytes([raw[56 + 2 * i], raw[57 + 2 * i]]);
+        }
+        Self {
+            index,
+            type_guid: guid(0),
+            unique_guid: guid(16),
+            first_lba: u64_le(raw, 32),
+            last_lba: u64_le(raw, 40),
+            attributes: u64_le(raw, 48),
+            name,
+        }
+    }
+
+    /// Number of blocks, 0 when the range is empty or runs past the
+    /// last LBA.
+    pub fn count(&self) -> u64 {
+        self.last_lba
+            .checked_add(1)
+            .map_or(0, |end| end.saturating_sub(self.first_lba))
+    }
+
+    /// Partition name, decoded from UTF-16.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let len = self
+            .name
+            .iter()
+            .position(|&unit| unit == 0)
+            .unwrap_or(NAME_LEN);
+        char::decode_utf16(self.name[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    pub fn name_eq(&self, name: &str) -> bool {
+        self.name().eq(name.chars())
+    }
+
+    /// View of this partition on `dev`.
+    pub fn open<D: BlockDevice>(&self, dev: D) -> Result<Partition<D>, BlockError> {
+        Partition::new(dev, self.first_lba, self.count())
+    }
+}
+
+impl Debug for GptEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        struct Name<'a>(&'a GptEntry);
+        impl Debug for Name<'_> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                self.0.name().try_for_each(|c| write!(f, "{c}"))
+            }
+        }
+        f.debug_struct("GptEntry")
+            .field("index", &self.index)
+            .field("name", &Name(self))
+            .field("type_guid", &self.type_guid)
+            .field("unique_guid", &self.unique_guid)
+            .field("first_lba", &self.first_lba)
+            .field("last_lba", &self.last_lba)
+            .field("attributes", &format_args!("{:#x}", self.attributes))
+            .finish()
+    }
+}
+
+/// A validated GPT header.
+#[derive(Debug, Clone, Copy)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    /// LBA of the header in use
+    pub header_lba: u64,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: usize,
+}
+
+impl Gpt {
+    /// Check the protective MBR, then read the primary header, or the backup
+    /// header when the primary one or its entries are damaged.
+    ///
+    /// Fails with `NotFound` when the disk has no protective MBR.
+    pub fn read<D: BlockDevice>(dev: &mut D) -> Result<Self, BlockError> {
+        match Mbr::read(dev) {
+            Ok(mbr) if mbr.is_protective() => {}
+            Ok(_) | Err(BlockError::Corrupted) => return Err(BlockError::NotFound),
+            Err(err) => return Err(err),
+        }
+        let primary = Self::read_header(dev, 1);
+        match primary {
+            Ok(gpt) => return Ok(gpt),
+            Err(BlockError::Corrupted) => warn!("gpt: primary header damaged, trying the backup"),
+            Err(err) => return Err(err),
+        }
+        let last = dev
+            .block_count()
+            .checked_sub(1)
+            .ok_or(BlockError::Corrupted)?;
+        Self::read_header(dev, last)
+    }
+
+    fn read_header<D: BlockDevice>(dev: &mut D, lba: u64) -> Result<Self, BlockError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        read_sector(dev, lba, &mut buf)?;
+        if &buf[..8] != SIGNATURE {
+            return Err(BlockError::Corrupted);
+        }
+        let header_size = u32_le(&buf, 12) as usize;
+        if !(HEADER_MIN_SIZE..=SECTOR_SIZE).contains(&header_size) {
+            return Err(BlockError::Corrupted);
+        }
+        let crc = u32_le(&buf, 16);
+        buf[16..20].fill(0);
+        if crc32(&buf[..header_size]) != crc || u64_le(&buf, 24) != lba {
+            return Err(BlockError::Corrupted);
+        }
+        let gpt = Self {
+            disk_guid: Guid(buf[56..72].try_into().unwrap()),
+            first_usable_lba: u64_le(&buf, 40),
+            last_usable_lba: u64_le(&buf, 48),
+            header_lba: lba,
+            entries_lba: u64_le(&buf, 72),
+            num_entries: u32_le(&buf, 80),
+            entry_size: u32_le(&buf, 84) as usize,
+        };
+        if gpt.entry_size < ENTRY_MIN_SIZE
+            || !gpt.entry_size.is_power_of_two()
+            || gpt.num_entries > MAX_ENTRIES
+        {
+            return Err(BlockError::Corrupted);
+        }
+        let entries_crc = u32_le(&buf, 88);
+        if gpt.entries_crc(dev)? != entries_crc {
+            return Err(BlockError::Corrupted);
+        }
+        Ok(gpt)
+    }
+
+    /// CRC32 of the whole entry array.
+    fn entries_crc<D: BlockDevice>(&self, dev: &mut D) -> Result<u32, BlockError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut left = self.num_entries as usize * self.entry_size;
+        let mut lba = self.entries_lba;
+        let mut crc = !0;
+        while left > 0 {
+            read_sector(dev, lba, &mut buf).map_err(|err| match err {
+                BlockError::OutOfRange => BlockError::Corrupted,
+                err => err,
+            })?;
+            let n = left.min(SECTOR_SIZE);
+            crc = crc32_update(crc, &buf[..n]);
+            left -= n;
+            lba += 1;
+        }
+        Ok(!crc)
+    }
+
+    /// Whether the backup header is in use because the primary one is damaged.
+    pub fn is_backup(&self) -> bool {
+        self.header_lba != 1
+    }
+
+    /// Non-empty entries, read from `dev`.
+    pub fn entries<'a, D: BlockDevice>(&self, dev: &'a mut D) -> GptEntries<'a, D> {
+        GptEntries {
+            gpt: *self,
+            dev,
+            index: 0,
+            buf: [0; SECTOR_SIZE],
+            buf_lba: None,
+        }
+    }
+
+    /// First entry matching `pred`.
+    pub fn find<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        mut pred: impl FnMut(&GptEntry) -> bool,
+    ) -> Result<Option<GptEntry>, BlockError> {
+        for entry in self.entries(dev) {
+            let entry = entry?;
+            if pred(&entry) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn find_by_type<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        guid: &Guid,
+    ) -> Result<Option<GptEntry>, BlockError> {
+        self.find(dev, |entry| entry.type_guid == *guid)
+    }
+
+    pub fn find_by_guid<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        guid: &Guid,
+    ) -> Result<Option<GptEntry>, BlockError> {
+        self.find(dev, |entry| entry.unique_guid == *guid)
+    }
+
+    pub fn find_by_name<D: BlockDevice>(
+        &self,
+        dev: &mut D,
+        name: &str,
+    ) -> Result<Option<GptEntry>, BlockError> {
+        self.find(dev, |entry| entry.name_eq(name))
+    }
+}
+
+/// Iterator over the non-empty entries of a [`Gpt`], entries without a
+/// valid block range are skipped.
+pub struct GptEntries<'a, D: BlockDevice> {
+    gpt: Gpt,
+    dev: &'a mut D,
+    index: u32,
+    buf: [u8; SECTOR_SIZE],
+    buf_lba: Option<u64>,
+}
+
+impl<D: BlockDevice> Iterator for GptEntries<'_, D> {
+    type Item = Result<GptEntry, BlockError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.gpt.num_entries {
+            let index = self.index;
+            self.index += 1;
+            let offset = index as usize * self.gpt.entry_size;
+            // entries are at least 128 bytes and a power of two, the part we
+            // parse never crosses a sector
+            let lba = self.gpt.entries_lba + (offset / SECTOR_SIZE) as u64;
+            if self.buf_lba != Some(lba) {
+                if let Err(err) = read_sector(self.dev, lba, &mut self.buf) {
+                    self.index = self.gpt.num_entries;
+                    return Some(Err(err));
+                }
+                self.buf_lba = Some(lba);
+            }
+            let offset = offset % SECTOR_SIZE;
+            let entry = GptEntry::parse(index, &self.buf[offset..offset + ENTRY_MIN_SIZE]);
+            if entry.type_guid == Guid::ZERO {
+                continue;
+            }
+            if entry.count() == 0 {
+                warn!("gpt: entry {index} has no valid block range, ignoring it");
+                continue;
+            }
+            return Some(Ok(entry));
+        }
+        None
+    }
+}
+
+/// Read-only view of the GPT partition named `name` on `dev`, e.g. "rootfs".
+pub fn find_partition<D: BlockDevice>(mut dev: D, name: &str) -> Result<Partition<D>, BlockError> {
+    let gpt = Gpt::read(&mut dev)?;
+    let entry = gpt
+        .find_by_name(&mut dev, name)?
+        .ok_or(BlockError::NotFound)?;
+    Ok(entry.open(dev)?.read_only())
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    const BLOCKS: usize = 64;
+    const ROOTFS: Guid = Guid::new(0x1234_5678, 0x9ABC, 0xDEF0, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+    fn entry(
+        raw: &mut [u8],
+        type_guid: Guid,
+        unique_guid: Guid,
+        first: u64,
+        last: u64,
+        name: &str,
+    ) {
+        raw[..16].copy_from_slice(type_guid.as_bytes());
+        raw[16..32].copy_from_slice(unique_guid.as_bytes());
+        raw[32..40].copy_from_slice(&first.to_le_bytes());
+        raw[40..48].copy_from_slice(&last.to_le_bytes());
+        for (i, unit) in name.encode_utf16().enumerate() {
+            raw[56 + 2 * i..58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+
+    fn header(image: &mut [u8], lba: u64, backup: u64, entries_lba: u64) {
+        let entries_crc = {
+            let at = entries_lba as usize * SECTOR_SIZE;
+            crc32(&image[at..at + 4 * ENTRY_MIN_SIZE])
+        };
+        let buf = &mut image[lba as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        buf[..8].copy_from_slice(SIGNATURE);
+        buf[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        buf[12..16].copy_from_slice(&(HEADER_MIN_SIZE as u32).to_le_bytes());
+        buf[24..32].copy_from_slice(&lba.to_le_bytes());
+        buf[32..40].copy_from_slice(&backup.to_le_bytes());
+        buf[40..48].copy_from_slice(&4u64.to_le_bytes());
+        buf[48..56].copy_from_slice(&(BLOCKS as u64 - 4).to_le_bytes());
+        buf[56..72].copy_from_slice(&[0x42; 16]);
+        buf[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        buf[80..84].copy_from_slice(&4u32.to_le_bytes());
+        buf[84..88].copy_from_slice(&(ENTRY_MIN_SIZE as u32).to_le_bytes());
+        buf[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&buf[..HEADER_MIN_SIZE]);
+        buf[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Protective MBR, spl and rootfs partitions and an entry whose last
+    /// LBA overflows, with the backup table at the end of the disk.
+    fn image() -> [u8; BLOCKS * SECTOR_SIZE] {
+        let mut image = [0u8; BLOCKS * SECTOR_SIZE];
+        image[446 + 4] = 0xEE;
+        image[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        image[446 + 12..446 + 16].copy_from_slice(&(BLOCKS as u32 - 1).to_le_bytes());
+        image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        let entries = &mut image[2 * SECTOR_SIZE..3 * SECTOR_SIZE];
+        entry(
+            entries,
+            TYPE_STARFIVE_SPL,
+            Guid::from_bytes([7; 16]),
+            4,
+            7,
+            "spl",
+        );
+        entry(
+            &mut entries[ENTRY_MIN_SIZE..],
+            TYPE_LINUX_FS,
+            ROOTFS,
+            8,
+            59,
+            "rootfs",
+        );
+        entry(
+            &mut entries[2 * ENTRY_MIN_SIZE..],
+            TYPE_LINUX_FS,
+            Guid::ZERO,
+            10,
+            u64::MAX,
+            "bad",
+        );
+        image.copy_within(2 * SECTOR_SIZE..3 * SECTOR_SIZE, (BLOCKS - 2) * SECTOR_SIZE);
+        header(&mut image, 1, BLOCKS as u64 - 1, 2);
+        header(&mut image, BLOCKS as u64 - 1, 1, BLOCKS as u64 - 2);
+        image
+    }
+
+    fn check_lookups(disk: &mut RamDisk) {
+        let gpt = Gpt::read(disk).unwrap();
+        assert_eq!(gpt.disk_guid, Guid::from_bytes([0x42; 16]));
+        assert_eq!(gpt.entries(disk).count(), 2);
+        let spl = gpt.find_by_type(disk, &TYPE_STARFIVE_SPL).unwrap().unwrap();
+        assert!(spl.name_eq("spl"));
+        assert_eq!((spl.index, spl.count()), (0, 4));
+        let root = gpt.find_by_guid(disk, &ROOTFS).unwrap().unwrap();
+        assert!(root.name_eq("rootfs"));
+        assert!(gpt.find_by_name(disk, "bad").unwrap().is_none());
+        let root = find_partition(disk, "rootfs").unwrap();
+        assert_eq!((root.start(), root.block_count()), (8, 52));
+        assert!(root.is_read_only());
+    }
+
+    #[test]
+    fn primary_table() {
+        let mut image = image();
+        let mut disk = RamDisk::new(&mut image);
+        assert!(!Gpt::read(&mut disk).unwrap().is_backup());
+        check_lookups(&mut disk);
+    }
+
+    #[test]
+    fn corrupt_primary_header_falls_back_to_the_backup() {
+        let mut image = image();
+        image[SECTOR_SIZE + 60] ^= 1;
+        let mut disk = RamDisk::new(&mut image);
+        let gpt = Gpt::read(&mut disk).unwrap();
+        assert!(gpt.is_backup());
+        assert_eq!(gpt.header_lba, BLOCKS as u64 - 1);
+        check_lookups(&mut disk);
+    }
+
+    #[test]
+    fn corrupt_primary_entries_fall_back_to_the_backup() {
+        let mut image = image();
+        image[2 * SECTOR_SIZE + 56] ^= 1;
+        let mut disk = RamDisk::new(&mut image);
+        assert!(Gpt::read(&mut disk).unwrap().is_backup());
+        check_lookups(&mut disk);
+    }
+
+    #[test]
+    fn both_headers_corrupt() {
+        let mut image = image();
+        image[SECTOR_SIZE + 60] ^= 1;
+        image[(BLOCKS - 1) * SECTOR_SIZE + 60] ^= 1;
+        let mut disk = RamDisk::new(&mut image);
+        assert!(matches!(Gpt::read(&mut disk), Err(BlockError::Corrupted)));
+    }
+
+    #[test]
+    fn no_protective_mbr() {
+        let mut image = image();
+        image[446 + 4] = crate::part::mbr::TYPE_LINUX;
+        let mut disk = RamDisk::new(&mut image);
+        assert!(matches!(Gpt::read(&mut disk), Err(BlockError::NotFound)));
+    }
+
+    #[test]
+    fn overflowing_range_has_no_blocks() {
+        let mut image = image();
+        let mut disk = RamDisk::new(&mut image);
+        let gpt = Gpt::read(&mut disk).unwrap();
+        let mut entries = gpt.entries(&mut disk);
+        entries.index = 2;
+        assert!(entries.next().is_none());
+        let raw = &image[2 * SECTOR_SIZE + 2 * ENTRY_MIN_SIZE..][..ENTRY_MIN_SIZE];
+        assert_eq!(GptEntry::parse(2, raw).count(), 0);
+    }
+}
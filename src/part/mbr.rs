@@ -0,0 +1,330 @@
+//! MBR (DOS) partition table, with extended/logical partition chains.
+use log::warn;
+
+use crate::block::{BlockDevice, BlockError};
+
+use super::{read_sector, Partition, SECTOR_SIZE};
+
+/// Primary, logical and extended container entries kept, extra logical
+/// partitions are ignored.
+pub const MBR_MAX_PARTITIONS: usize = 32;
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const BOOTABLE: u8 = 0x80;
+
+/// Partition type IDs
+pub const TYPE_EMPTY: u8 = 0x00;
+pub const TYPE_FAT12: u8 = 0x01;
+pub const TYPE_FAT16_SMALL: u8 = 0x04;
+pub const TYPE_EXTENDED_CHS: u8 = 0x05;
+pub const TYPE_FAT16: u8 = 0x06;
+pub const TYPE_NTFS_EXFAT: u8 = 0x07;
+pub const TYPE_FAT32_CHS: u8 = 0x0B;
+pub const TYPE_FAT32: u8 = 0x0C;
+pub const TYPE_FAT16_LBA: u8 = 0x0E;
+pub const TYPE_EXTENDED: u8 = 0x0F;
+pub const TYPE_LINUX_SWAP: u8 = 0x82;
+pub const TYPE_LINUX: u8 = 0x83;
+pub const TYPE_LINUX_EXTENDED: u8 = 0x85;
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+pub const TYPE_EFI_SYSTEM: u8 = 0xEF;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MbrEntry {
+    /// Partition number as Linux counts them: 1-4 primary, 5.. logical
+    pub number: u8,
+    pub bootable: bool,
+    /// Partition type ID
+    pub kind: u8,
+    /// First block, relative to the start of the disk
+    pub start: u64,
+    /// Number of blocks
+    pub count: u64,
+}
+
+impl MbrEntry {
+    fn parse(raw: &[u8]) -> Self {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                raw[offset],
+                raw[offset + 1],
+                raw[offset + 2],
+                raw[offset + 3],
+            ])
+        };
+        Self {
+            number: 0,
+            bootable: raw[0] & BOOTABLE != 0,
+            kind: raw[4],
+            start: u64::from(u32_at(8)),
+            count: u64::from(u32_at(12)),
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self.kind,
+            TYPE_EXTENDED_CHS | TYPE_EXTENDED | TYPE_LINUX_EXTENDED
+        )
+    }
+
+    pub fn is_fat(&self) -> bool {
+        matches!(
+            self.kind,
+            TYPE_FAT12
+                | TYPE_FAT16_SMALL
+                | TYPE_FAT16
+                | TYPE_FAT32_CHS
+                | TYPE_FAT32
+                | TYPE_FAT16_LBA
+                | TYPE_EFI_SYSTEM
+        )
+    }
+
+    /// View of this partition on `dev`.
+    pub fn open<D: BlockDevice>(&self, dev: D) -> Result<Partition<D>, BlockError> {
+        Partition::new(dev, self.start, self.count)
+    }
+}
+
+/// A parsed MBR.
+#[derive(Debug, Clone, Copy)]
+pub struct Mbr {
+    pub disk_signature: u32,
+    entries: [MbrEntry; MBR_MAX_PARTITIONS],
+    len: usize,
+}
+
+impl Mbr {
+    /// Read the MBR from LBA 0 of `dev` and follow the extended partition chain.
+    pub fn read<D: BlockDevice>(dev: &mut D) -> Result<Self, BlockError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        read_sector(dev, 0, &mut buf)?;
+        if buf[510..] != BOOT_SIGNATURE {
+            return Err(BlockError::Corrupted);
+        }
+        let mut mbr = Self {
+            disk_signature: u32::from_le_bytes([buf[440], buf[441], buf[442], buf[443]]),
+            entries: [MbrEntry::default(); MBR_MAX_PARTITIONS],
+            len: 0,
+        };
+        let mut extended = None;
+        for i in 0..4 {
+            let offset = TABLE_OFFSET + i * ENTRY_SIZE;
+            let mut entry = MbrEntry::parse(&buf[offset..offset + ENTRY_SIZE]);
+            if entry.kind == TYPE_EMPTY || entry.count == 0 {
+                continue;
+            }
+            entry.number = i as u8 + 1;
+            if entry.is_extended() && extended.is_none() {
+                extended = Some(entry);
+            }
+            mbr.push(entry);
+        }
+        if let Some(extended) = extended {
+            mbr.read_logical(dev, &extended)?;
+        }
+        Ok(mbr)
+    }
+
+    /// Walk the EBR chain of `extended`. Each EBR describes one logical
+    /// partition relative to itself and links to the next EBR relative to the
+    /// start of the extended partition.
+    fn read_logical<D: BlockDevice>(
+        &mut self,
+        dev: &mut D,
+        extended: &MbrEntry,
+    ) -> Result<(), BlockError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut ebr = extended.start;
+        let mut number = 5;
+        // a chain longer than the table can hold is either corrupted or a loop
+        for _ in 0..MBR_MAX_PARTITIONS {
+            read_sector(dev, ebr, &mut buf)?;
+            if buf[510..] != BOOT_SIGNATURE {
+                return Err(BlockError::Corrupted);
+            }
+            let mut logical = MbrEntry::parse(&buf[TABLE_OFFSET..TABLE_OFFSET + ENTRY_SIZE]);
+            let next =
+                MbrEntry::parse(&buf[TABLE_OFFSET + ENTRY_SIZE..TABLE_OFFSET + 2 * ENTRY_SIZE]);
+            if logical.kind != TYPE_EMPTY && logical.count != 0 {
+                logical.number = number;
+                logical.start += ebr;
+                if logical.start + logical.count > extended.start + extended.count {
+                    return Err(BlockError::Corrupted);
+                }
+                number += 1;
+                if !self.push(logical) {
+                    warn!("mbr: more than {MBR_MAX_PARTITIONS} partitions, ignoring the rest");
+                    return Ok(());
+                }
+            }
+            if next.kind == TYPE_EMPTY || next.start == 0 {
+                return Ok(());
+            }
+            let next = extended.start + next.start;
+            if next <= ebr {
+                return Err(BlockError::Corrupted);
+            }
+            ebr = next;
+        }
+        Err(BlockError::Corrupted)
+    }
+
+    fn push(&mut self, entry: MbrEntry) -> bool {
+        if self.len == MBR_MAX_PARTITIONS {
+            return false;
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        true
+    }
+
+    /// Every entry, including extended containers, in partition number order.
+    pub fn entries(&self) -> &[MbrEntry] {
+        &self.entries[..self.len]
+    }
+
+    /// Primary and logical partitions holding data.
+    pub fn partitions(&self) -> impl Iterator<Item = &MbrEntry> {
+        self.entries().iter().filter(|entry| !entry.is_extended())
+    }
+
+    /// Partition with Linux number `number` (1-4 primary, 5.. logical).
+    pub fn get(&self, number: u8) -> Option<&MbrEntry> {
+        self.entries().iter().find(|entry| entry.number == number)
+    }
+
+    pub fn bootable(&self) -> Option<&MbrEntry> {
+        self.partitions().find(|entry| entry.bootable)
+    }
+
+    /// Whether this is the protective MBR of a GPT disk.
+    pub fn is_protective(&self) -> bool {
+        self.entries()
+            .iter()
+            .any(|entry| entry.kind == TYPE_GPT_PROTECTIVE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    fn entry(table: &mut [u8], slot: usize, boot: bool, kind: u8, start: u32, count: u32) {
+        let raw = &mut table[TABLE_OFFSET + slot * ENTRY_SIZE..][..ENTRY_SIZE];
+        raw[0] = if boot { BOOTABLE } else { 0 };
+        raw[4] = kind;
+        raw[8..12].copy_from_slice(&start.to_le_bytes());
+        raw[12..16].copy_from_slice(&count.to_le_bytes());
+    }
+
+    fn sector(image: &mut [u8], lba: usize) -> &mut [u8] {
+        let sector = &mut image[lba * SECTOR_SIZE..][..SECTOR_SIZE];
+        sector[510..].copy_from_slice(&BOOT_SIGNATURE);
+        sector
+    }
+
+    /// FAT16 boot partition 1, extended partition 2 with logical
+    /// partitions 5 and 6, Linux partition 3.
+    fn image() -> [u8; 64 * SECTOR_SIZE] {
+        let mut image = [0u8; 64 * SECTOR_SIZE];
+        let mbr = sector(&mut image, 0);
+        mbr[440..444].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        entry(mbr, 0, true, TYPE_FAT16, 1, 7);
+        entry(mbr, 1, false, TYPE_EXTENDED, 8, 40);
+        entry(mbr, 2, false, TYPE_LINUX, 48, 16);
+        // logical partitions start relative to their EBR, the next EBR
+        // relative to the extended partition
+        let ebr = sector(&mut image, 8);
+        entry(ebr, 0, false, TYPE_LINUX, 1, 9);
+        entry(ebr, 1, false, TYPE_EXTENDED, 12, 20);
+        let ebr = sector(&mut image, 20);
+        entry(ebr, 0, false, TYPE_FAT32, 2, 10);
+        image
+    }
+
+    #[test]
+    fn primary_and_logical_partitions() {
+        let mut image = image();
+        let mut disk = RamDisk::new(&mut image);
+        let mbr = Mbr::read(&mut disk).unwrap();
+        assert_eq!(mbr.disk_signature, 0x1234_5678);
+        assert!(!mbr.is_protective());
+        let numbers: [u8; 5] = core::array::from_fn(|i| mbr.entries()[i].number);
+        assert_eq!(numbers, [1, 2, 3, 5, 6]);
+        assert_eq!(mbr.partitions().count(), 4);
+        assert!(mbr.get(2).unwrap().is_extended());
+        let boot = mbr.bootable().unwrap();
+        assert_eq!((boot.number, boot.start, boot.count), (1, 1, 7));
+        assert!(boot.is_fat());
+        let five = mbr.get(5).unwrap();
+        assert_eq!((five.kind, five.start, five.count), (TYPE_LINUX, 9, 9));
+        let six = mbr.get(6).unwrap();
+        assert_eq!((six.kind, six.start, six.count), (TYPE_FAT32, 22, 10));
+        assert!(mbr.get(7).is_none());
+    }
+
+    #[test]
+    fn partition_views_stay_inside_their_blocks() {
+        let mut image = image();
+        let mut disk = RamDisk::new(&mut image);
+        let mbr = Mbr::read(&mut disk).unwrap();
+        let mut six = mbr.get(6).unwrap().open(&mut disk).unwrap();
+        assert_eq!(six.block_count(), 10);
+        six.write_blocks(9, &[0xA5; SECTOR_SIZE]).unwrap();
+        assert!(matches!(
+            six.write_blocks(10, &[0; SECTOR_SIZE]),
+            Err(BlockError::OutOfRange)
+        ));
+        let mut two = [0u8; 2 * SECTOR_SIZE];
+        assert!(matches!(
+            six.read_blocks(9, &mut two),
+            Err(BlockError::OutOfRange)
+        ));
+        let mut one = six.read_only();
+        assert!(matches!(
+            one.write_blocks(0, &[0; SECTOR_SIZE]),
+            Err(BlockError::ReadOnly)
+        ));
+        let image = disk.into_inner();
+        assert!(image[31 * SECTOR_SIZE..32 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xA5));
+        assert!(image[32 * SECTOR_SIZE..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn looping_chain_is_corrupted() {
+        let mut image = image();
+        // second EBR links to itself
+        entry(
+            &mut image[20 * SECTOR_SIZE..],
+            1,
+            false,
+            TYPE_EXTENDED,
+            12,
+            8,
+        );
+        let mut disk = RamDisk::new(&mut image);
+        assert!(matches!(Mbr::read(&mut disk), Err(BlockError::Corrupted)));
+    }
+
+    #[test]
+    fn logical_partition_past_the_container_is_corrupted() {
+        let mut image = image();
+        entry(&mut image[20 * SECTOR_SIZE..], 0, false, TYPE_LINUX, 2, 40);
+        let mut disk = RamDisk::new(&mut image);
+        assert!(matches!(Mbr::read(&mut disk), Err(BlockError::Corrupted)));
+    }
+
+    #[test]
+    fn missing_signature_is_corrupted() {
+        let mut image = [0u8; 4 * SECTOR_SIZE];
+        let mut disk = RamDisk::new(&mut image);
+        assert!(matches!(Mbr::read(&mut disk), Err(BlockError::Corrupted)));
+    }
+}
@@ -0,0 +1,136 @@
+//! Partition tables.
+//!
+//! Parsing a table gives entries that open [`Partition`] views: block devices
+//! restricted to the blocks of one partition, so a FAT boot partition and an
+//! ext4 rootfs on the same card can be handed to different consumers.
+//! GPT disks are looked up by name with [`gpt::find_partition`].
+//!
+//! ```no_run
+//! use core::cell::RefCell;
+//! use vf2_driver::block::BlockDevice;
+//! use vf2_driver::part::mbr::Mbr;
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let card = unsafe { SdHost::new(SDIO1_BASE, SdConfig::default()) }.init().unwrap();
+//! let card = RefCell::new(card);
+//! let mbr = Mbr::read(&mut &card).unwrap();
+//! let mut boot = mbr.get(1).unwrap().open(&card).unwrap();
+//! let mut root = mbr.get(2).unwrap().open(&card).unwrap();
+//! let mut buf = [0u8; 512];
+//! boot.read_blocks(0, &mut buf).unwrap();
+//! root.read_blocks(0, &mut buf).unwrap();
+//! ```
+use crate::block::{BlockDevice, BlockError};
+
+pub mod gpt;
+pub mod mbr;
+
+/// Sector size the partition tables are read with.
+pub(crate) const SECTOR_SIZE: usize = 512;
+
+/// Read one 512 byte sector, partition tables are not defined for other
+/// block sizes here.
+pub(crate) fn read_sector<D: BlockDevice>(
+    dev: &mut D,
+    lba: u64,
+    buf: &mut [u8; SECTOR_SIZE],
+) -> Result<(), BlockError> {
+    if dev.block_size() != SECTOR_SIZE {
+        return Err(BlockError::Unsupported);
+    }
+    dev.read_blocks(lba, buf)
+}
+
+/// A block device limited to `count` blocks starting at `start` of `dev`.
+pub struct Partition<D: BlockDevice> {
+    dev: D,
+    start: u64,
+    count: u64,
+    read_only: bool,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// Fails with `OutOfRange` when the partition does not fit on `dev`.
+    pub fn new(dev: D, start: u64, count: u64) -> Result<Self, BlockError> {
+        match start.checked_add(count) {
+            Some(end) if end <= dev.block_count() => Ok(Self {
+                dev,
+                start,
+                count,
+                read_only: false,
+            }),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    /// First block of the partition on the underlying device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Reject writes and erases with `BlockError::ReadOnly`.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    /// Translate `count` blocks at `lba` into the underlying device.
+    fn translate(&self, lba: u64, count: u64) -> Result<u64, BlockError> {
+        match lba.checked_add(count) {
+            Some(end) if end <= self.count => Ok(self.start + lba),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+
+    fn blocks(&self, len: usize) -> Result<u64, BlockError> {
+        let size = self.dev.block_size();
+        if len == 0 || !len.is_multiple_of(size) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        Ok((len / size) as u64)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let lba = self.translate(lba, self.blocks(buf.len())?)?;
+        self.dev.read_blocks(lba, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let lba = self.translate(lba, self.blocks(buf.len())?)?;
+        self.dev.write_blocks(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let lba = self.translate(lba, count)?;
+        self.dev.erase(lba, count)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only || self.dev.is_read_only()
+    }
+}
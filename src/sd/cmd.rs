@@ -1,26 +1,54 @@
-use crate::sd::reg::CmdMask;
+use crate::sd::reg::{CmdMask, BLKSIZ_DEFAULT};
 use core::fmt::Debug;
 
+use super::err::CardError;
 use super::sd_reg::{CardStatus, Cic, Cid, Csd, Ocr, Rca};
 
+const GO_IDLE_STATE: u32 = 0;
 const ALL_SEND_CID: u32 = 2;
 const SEND_RCA: u32 = 3;
 const SWITCH_FUNCTION: u32 = 6;
 const SELECT_CARD: u32 = 7;
 const SEND_IF_COND: u32 = 8;
 const SEND_CSD: u32 = 9;
+const VOLTAGE_SWITCH: u32 = 11;
 const STOP_TRANSMISSION: u32 = 12;
+const SEND_STATUS: u32 = 13;
+const GO_INACTIVE_STATE: u32 = 15;
+const SET_BLOCKLEN: u32 = 16;
 const READ_SINGLE_BLOCK: u32 = 17;
+const READ_MULTIPLE_BLOCK: u32 = 18;
+const ADDRESS_EXTENSION: u32 = 22;
+const SET_BLOCK_COUNT: u32 = 23;
 const WRITE_SINGLE_BLOCK: u32 = 24;
+const WRITE_MULTIPLE_BLOCK: u32 = 25;
+const ERASE_WR_BLK_START: u32 = 32;
+const ERASE_WR_BLK_END: u32 = 33;
+const ERASE: u32 = 38;
 const APP_CMD: u32 = 55;
 const ACMD_SD_SEND_OP_COND: u32 = 41;
 const ACMD_SET_BUS: u32 = 6;
-#[derive(Clone, Copy, Default)]
+const ACMD_SET_WR_BLK_ERASE_COUNT: u32 = 23;
+const ACMD_SEND_SCR: u32 = 51;
+#[derive(Clone, Copy)]
 pub struct Command {
     reg_flags: u32,
     index: u32,
     arg: u32,
     resp_ty: ResponseType,
+    byte_count: u32,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self {
+            reg_flags: 0,
+            index: 0,
+            arg: 0,
+            resp_ty: ResponseType::Non,
+            byte_count: BLKSIZ_DEFAULT,
+        }
+    }
 }
 
 impl Debug for Command {
@@ -30,16 +58,19 @@ impl Debug for Command {
             .field("\n\targ", &self.arg)
             .field("\n\tflags", &self.reg_flags)
             .field("\n\tresponse type", &self.resp_ty)
+            .field("\n\tbyte count", &self.byte_count)
             .finish()
     }
 }
 
 impl Command {
     fn transfer_cmd(index: u32, resp_ty: ResponseType, addr: u32, write_mode: bool) -> Self {
-        let mut cmd = Command::default();
-        cmd.index = index;
-        cmd.arg = addr;
-        cmd.resp_ty = resp_ty;
+        let mut cmd = Command {
+            index,
+            arg: addr,
+            resp_ty,
+            ..Default::default()
+        };
         cmd.reg_flags |= CmdMask::start_cmd.bits()
             | CmdMask::use_hold_reg.bits()
             | CmdMask::data_expected.bits()
@@ -53,10 +84,12 @@ impl Command {
     }
 
     fn no_data_cmd_r48(index: u32, resp_ty: ResponseType, arg: u32) -> Self {
-        let mut cmd = Command::default();
-        cmd.index = index;
-        cmd.resp_ty = resp_ty;
-        cmd.arg = arg;
+        let mut cmd = Command {
+            index,
+            resp_ty,
+            arg,
+            ..Default::default()
+        };
         cmd.reg_flags |= CmdMask::start_cmd.bits()
             | CmdMask::use_hold_reg.bits()
             | CmdMask::wait_prvdata_complete.bits()
@@ -65,13 +98,55 @@ impl Command {
         cmd
     }
 
-    pub fn to_cmd(&self) -> u32 {
+    pub(crate) fn from_spec(spec: &CommandSpec) -> Self {
+        let mut cmd = Command {
+            index: u32::from(spec.index),
+            arg: spec.arg,
+            resp_ty: spec.resp_ty,
+            ..Default::default()
+        };
+        cmd.reg_flags |= CmdMask::start_cmd.bits()
+            | CmdMask::use_hold_reg.bits()
+            | CmdMask::wait_prvdata_complete.bits();
+        if spec.resp_ty != ResponseType::Non {
+            cmd.reg_flags |= CmdMask::response_expect.bits();
+        }
+        if spec.resp_ty == ResponseType::R2 {
+            cmd.reg_flags |= CmdMask::response_length.bits();
+        }
+        // R3 (OCR) carries no valid CRC
+        if spec.resp_ty != ResponseType::R3 && spec.resp_ty != ResponseType::Non {
+            cmd.reg_flags |= CmdMask::check_response_crc.bits();
+        }
+        match &spec.data {
+            Data::None => {}
+            Data::Read(buf) => {
+                cmd.reg_flags |= CmdMask::data_expected.bits();
+                cmd.byte_count = buf.len() as u32;
+            }
+            Data::Write(buf) => {
+                cmd.reg_flags |= CmdMask::data_expected.bits() | CmdMask::write.bits();
+                cmd.byte_count = buf.len() as u32;
+            }
+        }
+        cmd
+    }
+
+    pub fn to_cmd(self) -> u32 {
         self.reg_flags | self.index
     }
 
     pub fn arg(&self) -> u32 {
         self.arg
     }
+
+    pub fn byte_count(&self) -> u32 {
+        self.byte_count
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.byte_count.min(BLKSIZ_DEFAULT)
+    }
     pub fn data_exp(&self) -> bool {
         self.reg_flags & CmdMask::data_expected.bits() != 0
     }
@@ -85,8 +160,9 @@ impl Command {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
 pub enum ResponseType {
+    #[default]
     Non = 0,
     R1 = 1,
     R1b = 10,
@@ -96,11 +172,6 @@ pub enum ResponseType {
     R7 = 7,
 }
 
-impl Default for ResponseType {
-    fn default() -> Self {
-        Self::Non
-    }
-}
 impl Debug for ResponseType {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
@@ -115,6 +186,7 @@ impl Debug for ResponseType {
     }
 }
 
+#[derive(Debug)]
 pub enum Response {
     Rz,
     R48(u32),
@@ -122,40 +194,40 @@ pub enum Response {
 }
 
 impl Response {
-    pub(crate) fn card_status(self) -> CardStatus {
+    pub fn card_status(self) -> CardStatus {
         match self {
             Response::R48(r) => CardStatus::from(r),
             _ => CardStatus::default(),
         }
     }
-    pub(crate) fn csd(self) -> Csd {
+    pub fn csd(self) -> Csd {
         match self {
             Self::R136(r) => Csd::from(r),
             _ => Csd::default(),
         }
     }
-    pub(crate) fn cid(self) -> Cid {
+    pub fn cid(self) -> Cid {
         match self {
             Self::R136(r) => Cid::from(r),
             _ => Cid::default(),
         }
     }
 
-    pub(crate) fn ocr(self) -> Ocr {
+    pub fn ocr(self) -> Ocr {
         match self {
             Response::R48(r) => Ocr::from(r),
             _ => Ocr::default(),
         }
     }
 
-    pub(crate) fn cic(self) -> Cic {
+    pub fn cic(self) -> Cic {
         match self {
             Response::R48(r) => Cic::from(r),
             _ => Cic::default(),
         }
     }
 
-    pub(crate) fn rca(self) -> Rca {
+    pub fn rca(self) -> Rca {
         match self {
             Response::R48(r) => Rca::from(r),
             _ => Rca::default(),
@@ -163,6 +235,105 @@ impl Response {
     }
 }
 
+/// Data phase of a [`CommandSpec`].
+pub enum Data<'a> {
+    None,
+    /// Read up to one block from the card into the buffer
+    Read(&'a mut [u8]),
+    /// Write up to one block from the buffer to the card
+    Write(&'a [u8]),
+}
+
+/// Description of a raw command for [`SdHost::execute`](super::SdHost::execute).
+///
+/// Commands that change state the driver tracks itself are rejected: the
+/// card identification and selection state (CMD0, CMD2, CMD3, CMD7, CMD11,
+/// CMD12, CMD15, ACMD41), the block length, bus width and bus speed (CMD16,
+/// ACMD6, CMD6), the arguments of the next data command (CMD22, CMD23), and
+/// CMD55, which would turn the next driver command into an ACMD. Set `app`
+/// to send an ACMD.
+pub struct CommandSpec<'a> {
+    pub index: u8,
+    pub arg: u32,
+    pub resp_ty: ResponseType,
+    pub data: Data<'a>,
+    /// Application specific command, sent after a CMD55 with the card RCA
+    pub app: bool,
+    /// Wait for the card to release the busy signal (R1b, programming)
+    pub busy_wait: bool,
+}
+
+impl<'a> CommandSpec<'a> {
+    pub fn new(index: u8, arg: u32, resp_ty: ResponseType) -> Self {
+        Self {
+            index,
+            arg,
+            resp_ty,
+            data: Data::None,
+            app: false,
+            busy_wait: resp_ty == ResponseType::R1b,
+        }
+    }
+
+    pub fn app(mut self) -> Self {
+        self.app = true;
+        self
+    }
+
+    pub fn read(mut self, buf: &'a mut [u8]) -> Self {
+        self.data = Data::Read(buf);
+        self
+    }
+
+    pub fn write(mut self, buf: &'a [u8]) -> Self {
+        self.data = Data::Write(buf);
+        self
+    }
+
+    pub fn busy_wait(mut self) -> Self {
+        self.busy_wait = true;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), CardError> {
+        let index = u32::from(self.index);
+        if index > 0x3F {
+            return Err(CardError::InvalidArgument);
+        }
+        let reserved = if self.app {
+            index == ACMD_SET_BUS || index == ACMD_SD_SEND_OP_COND
+        } else {
+            matches!(
+                index,
+                GO_IDLE_STATE
+                    | ALL_SEND_CID
+                    | SEND_RCA
+                    | SWITCH_FUNCTION
+                    | SELECT_CARD
+                    | VOLTAGE_SWITCH
+                    | STOP_TRANSMISSION
+                    | GO_INACTIVE_STATE
+                    | SET_BLOCKLEN
+                    | ADDRESS_EXTENSION
+                    | SET_BLOCK_COUNT
+                    | APP_CMD
+            )
+        };
+        if reserved {
+            return Err(CardError::UnsupportedCommand);
+        }
+        let len = match &self.data {
+            Data::None => return Ok(()),
+            Data::Read(buf) => buf.len(),
+            Data::Write(buf) => buf.len(),
+        };
+        if len == 0 || len > BLKSIZ_DEFAULT as usize {
+            return Err(CardError::InvalidArgument);
+        }
+        Ok(())
+    }
+}
+
 pub fn idle() -> Command {
     let mut cmd = Command::default();
     cmd.reg_flags |= CmdMask::send_initialization.bits()
@@ -213,6 +384,16 @@ pub fn select_card(rca: u16) -> Command {
     Command::no_data_cmd_r48(SELECT_CARD, ResponseType::R1b, arg)
 }
 
+/// CMD7 with RCA 0: deselect the card, it moves back to standby and does not respond
+pub fn deselect_card() -> Command {
+    let mut cmd = Command::default();
+    cmd.reg_flags |= CmdMask::start_cmd.bits()
+        | CmdMask::use_hold_reg.bits()
+        | CmdMask::wait_prvdata_complete.bits();
+    cmd.index = SELECT_CARD;
+    cmd
+}
+
 /// CMD9: Send CSD
 pub fn send_csd(rca: u16) -> Command {
     let arg = u32::from(rca) << 16;
@@ -221,16 +402,61 @@ pub fn send_csd(rca: u16) -> Command {
     cmd
 }
 
+/// CMD13: Send card status
+pub fn send_status(rca: u16) -> Command {
+    let arg = u32::from(rca) << 16;
+    Command::no_data_cmd_r48(SEND_STATUS, ResponseType::R1, arg)
+}
+
 /// CMD17: Read a single block from the card
 pub fn read_single_block(addr: u32) -> Command {
     Command::transfer_cmd(READ_SINGLE_BLOCK, ResponseType::R1, addr, false)
 }
 
+/// CMD22: Address extension, upper bits [37:32] of the next data address (SDUC)
+pub fn address_extension(ext: u8) -> Command {
+    Command::no_data_cmd_r48(ADDRESS_EXTENSION, ResponseType::R1, u32::from(ext) & 0x3F)
+}
+
+/// CMD18: Read blocks until CMD12, or the count given by CMD23
+pub fn read_multiple_block(addr: u32, blocks: u32) -> Command {
+    let mut cmd = Command::transfer_cmd(READ_MULTIPLE_BLOCK, ResponseType::R1, addr, false);
+    cmd.byte_count = blocks * BLKSIZ_DEFAULT;
+    cmd
+}
+
+/// CMD23: Set the block count of the next multiple block command
+pub fn set_block_count(blocks: u32) -> Command {
+    Command::no_data_cmd_r48(SET_BLOCK_COUNT, ResponseType::R1, blocks)
+}
+
 /// CMD24: Write block
 pub fn write_single_block(addr: u32) -> Command {
     Command::transfer_cmd(WRITE_SINGLE_BLOCK, ResponseType::R1, addr, true)
 }
 
+/// CMD25: Write blocks until CMD12, or the count given by CMD23
+pub fn write_multiple_block(addr: u32, blocks: u32) -> Command {
+    let mut cmd = Command::transfer_cmd(WRITE_MULTIPLE_BLOCK, ResponseType::R1, addr, true);
+    cmd.byte_count = blocks * BLKSIZ_DEFAULT;
+    cmd
+}
+
+/// CMD32: Set the address of the first block to erase
+pub fn erase_wr_blk_start(addr: u32) -> Command {
+    Command::no_data_cmd_r48(ERASE_WR_BLK_START, ResponseType::R1, addr)
+}
+
+/// CMD33: Set the address of the last block to erase
+pub fn erase_wr_blk_end(addr: u32) -> Command {
+    Command::no_data_cmd_r48(ERASE_WR_BLK_END, ResponseType::R1, addr)
+}
+
+/// CMD38: Erase the selected blocks
+pub fn erase() -> Command {
+    Command::no_data_cmd_r48(ERASE, ResponseType::R1b, 0)
+}
+
 /// CMD55: App Command. Indicates that next command will be a app command
 pub fn app_cmd(rca: u16) -> Command {
     Command::no_data_cmd_r48(APP_CMD, ResponseType::R1, u32::from(rca) << 16)
@@ -251,10 +477,29 @@ pub fn set_bus_width(arg: u32) -> Command {
     Command::no_data_cmd_r48(ACMD_SET_BUS, ResponseType::R1, arg)
 }
 
+/// ACMD23: Number of blocks to pre-erase before the next multiple block write
+pub fn set_wr_blk_erase_count(blocks: u32) -> Command {
+    Command::no_data_cmd_r48(
+        ACMD_SET_WR_BLK_ERASE_COUNT,
+        ResponseType::R1,
+        blocks & 0x7F_FFFF,
+    )
+}
+
+/// ACMD51: Read the SD Configuration Register
+pub fn send_scr() -> Command {
+    let mut cmd = Command::transfer_cmd(ACMD_SEND_SCR, ResponseType::R1, 0, false);
+    cmd.byte_count = 8;
+    cmd
+}
+
 /// ACMD41: App Op Command
-pub fn sd_send_op_cond(host_high_capacity_support: bool, sr18: bool) -> Command {
+pub fn sd_send_op_cond(host_high_capacity_support: bool, ho2t: bool, sr18: bool) -> Command {
     let mut cmd = Command::default();
-    let arg = u32::from(host_high_capacity_support) << 30 | u32::from(sr18) << 24 | 1 << 20;
+    let arg = u32::from(host_high_capacity_support) << 30
+        | u32::from(ho2t) << 27
+        | u32::from(sr18) << 24
+        | 1 << 20;
     cmd.arg = arg;
     cmd.index = ACMD_SD_SEND_OP_COND;
     cmd.resp_ty = ResponseType::R3;
@@ -264,3 +509,88 @@ pub fn sd_send_op_cond(host_high_capacity_support: bool, sr18: bool) -> Command
         | CmdMask::response_expect.bits();
     cmd
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(index: u8, app: bool) -> Result<(), CardError> {
+        let spec = CommandSpec::new(index, 0, ResponseType::R1);
+        if app { spec.app() } else { spec }.validate()
+    }
+
+    fn unsupported(ret: Result<(), CardError>) -> bool {
+        matches!(ret, Err(CardError::UnsupportedCommand))
+    }
+
+    fn invalid(ret: Result<(), CardError>) -> bool {
+        matches!(ret, Err(CardError::InvalidArgument))
+    }
+
+    #[test]
+    fn driver_state_commands_are_reserved() {
+        for index in [0, 2, 3, 6, 7, 11, 12, 15, 16, 22, 23, 55] {
+            assert!(unsupported(validate(index, false)), "CMD{index}");
+        }
+        for index in [6, 41] {
+            assert!(unsupported(validate(index, true)), "ACMD{index}");
+        }
+        // status, CID/CSD reads, GEN_CMD, vendor commands
+        for index in [9, 10, 13, 56, 60, 63] {
+            assert!(validate(index, false).is_ok(), "CMD{index}");
+        }
+        assert!(invalid(validate(64, false)));
+    }
+
+    #[test]
+    fn acmd_and_cmd_of_one_index_differ() {
+        // ACMD23 only sets the pre-erase count of the next write
+        assert!(validate(23, true).is_ok());
+        assert!(unsupported(validate(23, false)));
+        // CMD41 is reserved, ACMD41 restarts the initialization
+        assert!(validate(41, false).is_ok());
+        assert!(unsupported(validate(41, true)));
+        // ACMD13 SD_STATUS next to CMD13 SEND_STATUS
+        assert!(validate(13, true).is_ok());
+    }
+
+    #[test]
+    fn data_phase_is_one_block_at_most() {
+        let mut buf = [0u8; 513];
+        let read = |buf: &mut [u8]| {
+            CommandSpec::new(56, 1, ResponseType::R1)
+                .read(buf)
+                .validate()
+        };
+        assert!(invalid(read(&mut buf[..0])));
+        assert!(read(&mut buf[..1]).is_ok());
+        assert!(read(&mut buf[..512]).is_ok());
+        assert!(invalid(read(&mut buf)));
+        let write = |buf: &[u8]| {
+            CommandSpec::new(56, 0, ResponseType::R1)
+                .write(buf)
+                .validate()
+        };
+        assert!(invalid(write(&[])));
+        assert!(write(&buf[..64]).is_ok());
+        assert!(invalid(write(&buf)));
+    }
+
+    #[test]
+    fn spec_flags() {
+        let mut buf = [0u8; 64];
+        let cmd = Command::from_spec(&CommandSpec::new(56, 1, ResponseType::R1).read(&mut buf));
+        assert!(cmd.data_exp() && cmd.resp_exp() && !cmd.resp_lang());
+        assert_eq!(cmd.byte_count(), 64);
+        assert_eq!(cmd.to_cmd() & 0x3F, 56);
+        assert_eq!(cmd.to_cmd() & CmdMask::write.bits(), 0);
+        let cmd = Command::from_spec(&CommandSpec::new(56, 0, ResponseType::R1).write(&buf));
+        assert_ne!(cmd.to_cmd() & CmdMask::write.bits(), 0);
+        let cmd = Command::from_spec(&CommandSpec::new(9, 0, ResponseType::R2));
+        assert!(!cmd.data_exp() && cmd.resp_lang());
+        // R3 has no CRC to check, R1b waits for busy
+        let cmd = Command::from_spec(&CommandSpec::new(58, 0, ResponseType::R3));
+        assert_eq!(cmd.to_cmd() & CmdMask::check_response_crc.bits(), 0);
+        assert!(CommandSpec::new(28, 0, ResponseType::R1b).busy_wait);
+    }
+}
@@ -1,4 +1,5 @@
 use super::reg::InterruptMask;
+use super::sd_reg::CurrentState;
 use core::fmt::Debug;
 
 #[derive(Debug, Clone, Copy)]
@@ -8,6 +9,34 @@ pub enum CardError {
     TimeoutErr(Timeout),
     VoltagePattern,
     DataTransferTimeout,
+    AddressOutOfRange,
+    InvalidArgument,
+    UnsupportedCommand,
+    /// The card reported a state other than the one the driver expects
+    UnexpectedState(CurrentState),
+    /// The transfer was stopped through the flag set with `SdHost::set_cancel`
+    Cancelled,
+}
+
+/// A failed card state transition, with the host so the caller can retry,
+/// reinitialize or shut down the card.
+pub struct TransitionError<H> {
+    pub host: H,
+    pub error: CardError,
+}
+
+impl<H> Debug for TransitionError<H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TransitionError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> From<TransitionError<H>> for CardError {
+    fn from(value: TransitionError<H>) -> Self {
+        value.error
+    }
 }
 
 impl From<Timeout> for CardError {
@@ -28,6 +57,7 @@ pub enum Timeout {
     WaitCmdLine,
     WaitCmdDone,
     WaitDataLine,
+    WaitDataIdle,
     FifoStatus,
 }
 
@@ -0,0 +1,259 @@
+use super::{
+    cmd::{CommandSpec, ResponseType},
+    err::CardError,
+    sd_reg::CommandClasses,
+    SdCard, Transfer,
+};
+
+/// CMD56: GEN_CMD, bit 0 of the argument selects read (1) or write (0)
+const GEN_CMD: u8 = 56;
+
+/// Health data reported by the card, normalised over the vendor formats.
+///
+/// Fields a vendor does not report are `None`, `raw` keeps the block
+/// returned by the card for vendor specific analysis.
+#[derive(Debug, Clone, Copy)]
+pub struct CardHealth {
+    pub manufacturer_id: u8,
+    /// Remaining life in percent, 100 for a new card
+    pub life_remaining: Option<u8>,
+    pub spare_blocks: Option<u32>,
+    pub bad_blocks: Option<u32>,
+    pub power_cycles: Option<u32>,
+    pub abnormal_power_offs: Option<u32>,
+    pub avg_erase_count: Option<u32>,
+    pub max_erase_count: Option<u32>,
+    pub raw: [u8; 512],
+}
+
+impl CardHealth {
+    fn new(manufacturer_id: u8, raw: [u8; 512]) -> Self {
+        Self {
+            manufacturer_id,
+            life_remaining: None,
+            spare_blocks: None,
+            bad_blocks: None,
+            power_cycles: None,
+            abnormal_power_offs: None,
+            avg_erase_count: None,
+            max_erase_count: None,
+            raw,
+        }
+    }
+}
+
+enum Vendor {
+    /// SanDisk industrial: single read with argument 1, "DS" signature
+    SanDisk,
+    /// Phison based controllers (ADATA, Transcend, Kingston): a write with
+    /// argument 0x10 selects the SMART page, read with 0x21
+    Smart,
+}
+
+/// Vendors with a known CMD56 format.
+///
+/// A CMD56 write in the wrong format may be taken as some other vendor
+/// command, so other cards are reported as unsupported. Swissbit (0x5D) uses
+/// its own page layout, which is not implemented.
+fn vendor(manufacturer_id: u8) -> Option<Vendor> {
+    match manufacturer_id {
+        0x03 => Some(Vendor::SanDisk),
+        0x1D | 0x27 | 0x41 | 0x74 => Some(Vendor::Smart),
+        _ => None,
+    }
+}
+
+fn u16_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from(u16::from_le_bytes([buf[offset], buf[offset + 1]]))
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn gen_cmd_read(
+    host: &mut SdCard<Transfer>,
+    arg: u32,
+    buf: &mut [u8; 512],
+) -> Result<(), CardError> {
+    host.execute(CommandSpec::new(GEN_CMD, arg | 1, ResponseType::R1).read(buf))?;
+    Ok(())
+}
+
+fn gen_cmd_write(host: &mut SdCard<Transfer>, arg: u32, buf: &[u8; 512]) -> Result<(), CardError> {
+    host.execute(
+        CommandSpec::new(GEN_CMD, arg & !1, ResponseType::R1)
+            .write(buf)
+            .busy_wait(),
+    )?;
+    Ok(())
+}
+
+fn sandisk(host: &mut SdCard<Transfer>, manufacturer_id: u8) -> Result<CardHealth, CardError> {
+    let mut buf = [0u8; 512];
+    gen_cmd_read(host, 0x0000_0001, &mut buf)?;
+    parse_sandisk(manufacturer_id, buf)
+}
+
+fn parse_sandisk(manufacturer_id: u8, buf: [u8; 512]) -> Result<CardHealth, CardError> {
+    if buf[0] != b'D' || !(buf[1] == b'S' || buf[1] == b'W') {
+        return Err(CardError::UnsupportedCommand);
+    }
+    let mut health = CardHealth::new(manufacturer_id, buf);
+    // byte 8 is the percentage of life used
+    health.life_remaining = Some(100u8.saturating_sub(buf[8]));
+    health.power_cycles = Some(u32_le(&buf, 24));
+    Ok(health)
+}
+
+fn smart(host: &mut SdCard<Transfer>, manufacturer_id: u8) -> Result<CardHealth, CardError> {
+    let mut buf = [0u8; 512];
+    buf[0] = 0x10;
+    gen_cmd_write(host, 0x0000_0010, &buf)?;
+    gen_cmd_read(host, 0x0000_0021, &mut buf)?;
+    Ok(parse_smart(manufacturer_id, buf))
+}
+
+fn parse_smart(manufacturer_id: u8, buf: [u8; 512]) -> CardHealth {
+    let mut health = CardHealth::new(manufacturer_id, buf);
+    health.spare_blocks = Some(u16_le(&buf, 16));
+    health.bad_blocks = Some(u16_le(&buf, 18) + u16_le(&buf, 66));
+    health.avg_erase_count = Some(u32_le(&buf, 36));
+    health.max_erase_count = Some(u32_le(&buf, 44));
+    health.power_cycles = Some(u32_le(&buf, 48));
+    health.abnormal_power_offs = Some(u16_le(&buf, 52));
+    // remaining life in hundredths of a percent
+    health.life_remaining = Some((u16_le(&buf, 70) / 100).min(100) as u8);
+    health
+}
+
+impl SdCard<Transfer> {
+    /// Read the vendor health data (remaining life, spare blocks, ...) via CMD56.
+    ///
+    /// The format is chosen from the CID manufacturer ID. Cards without a
+    /// known CMD56 format, or whose CSD does not list the application
+    /// specific command class, return `CardError::UnsupportedCommand`.
+    pub fn health(&mut self) -> Result<CardHealth, CardError> {
+        let info = self.info.ok_or(CardError::CardInitErr)?;
+        let classes = info.csd.command_classes();
+        if !classes.contains(CommandClasses::application_specific) {
+            return Err(CardError::UnsupportedCommand);
+        }
+        let mid = info.cid.manufacturer_id();
+        match vendor(mid) {
+            Some(Vendor::SanDisk) => sandisk(self, mid),
+            Some(Vendor::Smart) => smart(self, mid),
+            None => Err(CardError::UnsupportedCommand),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd::info::CardInfo;
+    use crate::sd::sd_reg::{Cid, Csd};
+    use crate::sd::{SdConfig, SdHost};
+
+    fn put16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn sandisk_page() {
+        let mut page = [0u8; 512];
+        page[..2].copy_from_slice(b"DS");
+        // 7 % of the life used
+        page[8] = 7;
+        put32(&mut page, 24, 1234);
+        let health = parse_sandisk(0x03, page).unwrap();
+        assert_eq!(health.manufacturer_id, 0x03);
+        assert_eq!(health.life_remaining, Some(93));
+        assert_eq!(health.power_cycles, Some(1234));
+        assert_eq!(health.spare_blocks, None);
+        assert_eq!(health.raw, page);
+
+        page[1] = b'W';
+        page[8] = 130;
+        assert_eq!(parse_sandisk(0x03, page).unwrap().life_remaining, Some(0));
+        // not a health page, e.g. a card ignoring the argument
+        page[..2].copy_from_slice(b"XX");
+        assert!(matches!(
+            parse_sandisk(0x03, page),
+            Err(CardError::UnsupportedCommand)
+        ));
+    }
+
+    #[test]
+    fn phison_smart_page() {
+        let mut page = [0u8; 512];
+        put16(&mut page, 16, 120);
+        put16(&mut page, 18, 3);
+        put16(&mut page, 66, 2);
+        put32(&mut page, 36, 410);
+        put32(&mut page, 44, 977);
+        put32(&mut page, 48, 5021);
+        put16(&mut page, 52, 17);
+        // 87.65 %
+        put16(&mut page, 70, 8765);
+        let health = parse_smart(0x41, page);
+        assert_eq!(health.manufacturer_id, 0x41);
+        assert_eq!(health.spare_blocks, Some(120));
+        assert_eq!(health.bad_blocks, Some(5));
+        assert_eq!(health.avg_erase_count, Some(410));
+        assert_eq!(health.max_erase_count, Some(977));
+        assert_eq!(health.power_cycles, Some(5021));
+        assert_eq!(health.abnormal_power_offs, Some(17));
+        assert_eq!(health.life_remaining, Some(87));
+
+        put16(&mut page, 70, u16::MAX);
+        assert_eq!(parse_smart(0x41, page).life_remaining, Some(100));
+    }
+
+    #[test]
+    fn vendors_with_a_known_format() {
+        assert!(matches!(vendor(0x03), Some(Vendor::SanDisk)));
+        for mid in [0x1D, 0x27, 0x41, 0x74] {
+            assert!(matches!(vendor(mid), Some(Vendor::Smart)));
+        }
+        // Swissbit and Samsung
+        assert!(vendor(0x5D).is_none());
+        assert!(vendor(0x1B).is_none());
+    }
+
+    /// A card from manufacturer `mid` with the command classes `ccc`, and no
+    /// registers behind it.
+    fn card(mid: u8, ccc: u16) -> SdCard<Transfer> {
+        // SAFETY: the tests only take paths that never touch the registers
+        let mut host = unsafe { SdHost::new(0, SdConfig::default()) };
+        host.info = Some(CardInfo {
+            cid: Cid::from(u128::from(mid) << 120),
+            csd: Csd::from(1 << 126 | u128::from(ccc) << 84),
+            ..Default::default()
+        });
+        host.into_state()
+    }
+
+    #[test]
+    fn cmd56_needs_the_application_specific_class() {
+        let without = CommandClasses::all() - CommandClasses::application_specific;
+        assert!(matches!(
+            card(0x03, without.bits()).health(),
+            Err(CardError::UnsupportedCommand)
+        ));
+        // class 8 alone does not make a vendor format known
+        assert!(matches!(
+            card(0x1B, CommandClasses::all().bits()).health(),
+            Err(CardError::UnsupportedCommand)
+        ));
+    }
+}
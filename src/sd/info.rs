@@ -0,0 +1,68 @@
+use core::fmt::Display;
+
+use super::sd_reg::{Cid, Csd, Ocr, Scr};
+
+/// Identification of the card collected during enumeration.
+///
+/// The `Display` impl prints a report in the spirit of Linux's
+/// `/sys/block/mmcblk0/device` attributes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CardInfo {
+    pub cid: Cid,
+    pub csd: Csd,
+    pub ocr: Ocr,
+    pub scr: Scr,
+    pub rca: u16,
+}
+
+impl Display for CardInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (cid, csd) = (&self.cid, &self.csd);
+        let (month, year) = cid.manufacturing_date();
+        writeln!(f, "cid: {:032x}", cid.raw())?;
+        writeln!(f, "csd: {:032x}", csd.raw())?;
+        writeln!(f, "ocr: {:#010x}", self.ocr.raw())?;
+        writeln!(f, "scr: {:016x}", self.scr.raw())?;
+        writeln!(f, "rca: {:#06x}", self.rca)?;
+        writeln!(
+            f,
+            "manfid: {:#08x} ({})",
+            cid.manufacturer_id(),
+            cid.manufacturer()
+        )?;
+        writeln!(f, "oemid: {:#06x} ({})", cid.oem_id_raw(), cid.oem_id())?;
+        writeln!(f, "name: {}", cid.product_name())?;
+        writeln!(f, "hwrev: {:#x}", cid.hardware_revision())?;
+        writeln!(f, "fwrev: {:#x}", cid.firmware_revision())?;
+        writeln!(f, "serial: {:#010x}", cid.serial())?;
+        writeln!(f, "date: {month:02}/{year:04}")?;
+        writeln!(
+            f,
+            "type: {}",
+            if csd.is_sduc() {
+                "SDUC"
+            } else if self.ocr.high_capacity() {
+                "SDHC/SDXC"
+            } else {
+                "SDSC"
+            }
+        )?;
+        writeln!(f, "csd_structure: {}", csd.version())?;
+        writeln!(f, "size: {} bytes", csd.card_size())?;
+        writeln!(f, "tran_speed: {} Hz", csd.transfer_rate_hz())?;
+        writeln!(f, "taac: {} ns", csd.taac_ns())?;
+        writeln!(f, "nsac: {} clks", csd.nsac_clocks())?;
+        writeln!(f, "r2w_factor: x{}", csd.r2w_factor())?;
+        writeln!(
+            f,
+            "ccc: {:#05x} {:?}",
+            csd.command_classes().bits(),
+            csd.command_classes()
+        )?;
+        writeln!(f, "erase_size: {} blocks", csd.erase_size_blocks())?;
+        writeln!(f, "file_format: {:?}", csd.file_format())?;
+        writeln!(f, "copy: {}", u8::from(csd.copy()))?;
+        writeln!(f, "perm_wp: {}", u8::from(csd.perm_write_protect()))?;
+        write!(f, "tmp_wp: {}", u8::from(csd.tmp_write_protect()))
+    }
+}
@@ -1,23 +1,204 @@
-use self::{
-    err::CardError,
-    ops::{read_block, write_block},
-};
+pub use self::cmd::{CommandSpec, Data, Response, ResponseType};
+use self::err::CardError;
+use self::info::CardInfo;
+use self::quirks::{Quirk, Quirks};
+pub use self::state::{CardState, SdCard, Standby, Transfer, Uninit};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
 mod cmd;
 pub mod err;
+pub mod health;
+pub mod info;
 mod ops;
+pub mod quirks;
 mod reg;
-mod sd_reg;
+mod rmw;
+pub mod sd_reg;
+pub mod spi;
+mod state;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "embedded-storage")]
+mod storage;
+#[cfg(feature = "trace")]
+pub mod trace;
 mod utils;
-pub struct SdHost;
-impl SdHost {
-    pub fn init(&self) -> Result<(), CardError> {
-        ops::init_card()
+
+/// Base address of the SDIO0 controller (eMMC on the VisionFive 2)
+pub const SDIO0_BASE: usize = 0x1601_0000;
+/// Base address of the SDIO1 controller (TF card slot on the VisionFive 2)
+pub const SDIO1_BASE: usize = 0x1602_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SdConfig {
+    /// Clock fed to the controller (cclk_in) in Hz, the card clock is
+    /// `clk_in / (2 * clkdiv)`
+    pub clk_in: u32,
+    /// Clock divider used during card identification (400 kHz at most)
+    pub init_clkdiv: u32,
+    /// Clock divider used once the card is in transfer state
+    pub clkdiv: u32,
+    /// Switch the card to the 4 bit data bus, the controller stays on 1 bit
+    /// unless the card accepted ACMD6
+    pub wide_bus: bool,
+    /// Delay after each identification step, a quirk can override it for
+    /// the steps from CMD2 (ALL_SEND_CID) on, once the card is known
+    pub init_delay: Duration,
+    /// Board specific quirks, looked at before the built-in table
+    pub quirks: &'static [Quirk],
+}
+
+impl Default for SdConfig {
+    fn default() -> Self {
+        Self {
+            clk_in: 50_000_000,
+            init_clkdiv: 62,
+            clkdiv: 1,
+            wide_bus: true,
+            init_delay: Duration::from_millis(10),
+            quirks: &[],
+        }
+    }
+}
+
+/// A DesignWare mobile storage host controller and the card behind it.
+///
+/// All state lives in the instance, so both JH7110 controllers can be driven
+/// independently, e.g. from different harts. `S` tracks the card state, see
+/// [`SdCard`] for the methods available once the card is initialized.
+pub struct SdHost<S: CardState = Uninit> {
+    base: usize,
+    config: SdConfig,
+    rca: u16,
+    info: Option<CardInfo>,
+    quirks: Quirks,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    cancel: Option<&'static AtomicBool>,
+    #[cfg(feature = "stats")]
+    stats: stats::IoStats,
+    #[cfg(feature = "trace")]
+    trace: trace::Trace,
+    _state: PhantomData<S>,
+}
+
+impl SdHost<Uninit> {
+    /// A host for the controller whose registers are at `base`.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the register base of a DesignWare mobile storage host
+    /// controller mapped as device memory, such as [`SDIO0_BASE`] or
+    /// [`SDIO1_BASE`]. The host reads and writes those registers for as long
+    /// as it lives, so nothing else may use the controller meanwhile, another
+    /// `SdHost` on the same `base` included.
+    pub unsafe fn new(base: usize, config: SdConfig) -> Self {
+        Self {
+            base,
+            config,
+            rca: 0,
+            info: None,
+            quirks: Quirks::new(),
+            // spec maximums until the CSD is known
+            read_timeout: Duration::from_millis(100),
+            write_timeout: Duration::from_millis(500),
+            cancel: None,
+            #[cfg(feature = "stats")]
+            stats: stats::IoStats::default(),
+            #[cfg(feature = "trace")]
+            trace: trace::Trace::new(),
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<S: CardState> SdHost<S> {
+    pub fn base(&self) -> usize {
+        self.base
+    }
+    pub fn config(&self) -> &SdConfig {
+        &self.config
+    }
+    /// CID, CSD, OCR and SCR of the card, available once `init` succeeded.
+    pub fn card_info(&self) -> Option<CardInfo> {
+        self.info
     }
-    pub fn read_block(&self, addr: u32, buf: &mut [u8; 512]) -> Result<(), CardError> {
-        read_block(buf, addr)
+    /// Quirks applied to the card, from its CID.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
     }
-    pub fn write_block(&self, addr: u32, buf: &[u8; 512]) -> Result<(), CardError> {
-        write_block(buf, addr)
+    /// Flag the data transfers check while they wait on the card.
+    ///
+    /// Once it is set, e.g. by a timer interrupt enforcing a deadline or by
+    /// another hart shutting down, the transfer in progress is stopped with
+    /// CMD12 and returns `CardError::Cancelled`. Clear it before the next
+    /// request.
+    pub fn set_cancel(&mut self, flag: Option<&'static AtomicBool>) {
+        self.cancel = flag;
+    }
+    /// `Err(Cancelled)` once the flag set with [`set_cancel`](Self::set_cancel) is raised.
+    pub(crate) fn check_cancel(&self) -> Result<(), CardError> {
+        match self.cancel {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(CardError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_host_keeps_its_own_state() {
+        let slow = SdConfig {
+            clkdiv: 4,
+            wide_bus: false,
+            ..Default::default()
+        };
+        // SAFETY: the registers are never touched
+        let (mut emmc, sd) = unsafe {
+            (
+                SdHost::new(SDIO0_BASE, SdConfig::default()),
+                SdHost::new(SDIO1_BASE, slow),
+            )
+        };
+        emmc.rca = 0x1234;
+        emmc.info = Some(CardInfo::default());
+        emmc.read_timeout = Duration::from_millis(20);
+
+        assert_eq!(emmc.base(), SDIO0_BASE);
+        assert_eq!(sd.base(), SDIO1_BASE);
+        assert_eq!(emmc.config().clkdiv, 1);
+        assert_eq!(sd.config().clkdiv, 4);
+        assert!(!sd.config().wide_bus);
+        assert_eq!(sd.rca, 0);
+        assert!(sd.card_info().is_none());
+        assert_eq!(sd.read_timeout, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn cancel_flag_stops_only_its_host() {
+        static CANCEL: AtomicBool = AtomicBool::new(false);
+        // SAFETY: the registers are never touched
+        let (mut flagged, other) = unsafe {
+            (
+                SdHost::new(SDIO0_BASE, SdConfig::default()),
+                SdHost::new(SDIO1_BASE, SdConfig::default()),
+            )
+        };
+        assert!(flagged.check_cancel().is_ok());
+        flagged.set_cancel(Some(&CANCEL));
+        assert!(flagged.check_cancel().is_ok());
+        CANCEL.store(true, Ordering::Relaxed);
+        assert!(matches!(flagged.check_cancel(), Err(CardError::Cancelled)));
+        assert!(other.check_cancel().is_ok());
+        CANCEL.store(false, Ordering::Relaxed);
+        assert!(flagged.check_cancel().is_ok());
+        flagged.set_cancel(None);
+        CANCEL.store(true, Ordering::Relaxed);
+        assert!(flagged.check_cancel().is_ok());
     }
 }
@@ -1,312 +1,934 @@
-use core::sync::atomic::AtomicU16;
-use core::sync::atomic::Ordering;
 use core::time::Duration;
 
+use crate::block::{BlockDevice, BlockError};
 use crate::sd::cmd::*;
+use crate::sd::info::CardInfo;
 use crate::sd::reg::*;
 use crate::sd::sd_reg::*;
-use crate::sd::utils::*;
 use crate::timer::delay;
 use crate::timer::Timer;
 use log::{debug, error, info};
 
 use super::err::*;
-static RCA: AtomicU16 = AtomicU16::new(0);
-
-fn send_cmd(cmd: Command) -> Result<Response, CardError> {
-    loop {
-        wait_for_data_line()?;
-        wait_for_cmd_line()?;
-        write_reg(REG_RINTSTS, InterruptMask::all().bits());
-        write_reg(REG_CMDARG, cmd.arg());
-        write_reg(REG_CMD, cmd.to_cmd());
-        if read_reg(REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
-            debug!("Send CMD {:?}", CmdMask::from_bits(cmd.to_cmd()).unwrap());
-            break;
-        }
-    }
-    debug!(
-        "{:?}",
-        InterruptMask::from_bits(read_reg(REG_RINTSTS)).unwrap()
-    );
-    debug!("{:?}", StatusMask::from_bits(read_reg(REG_STATUS)).unwrap());
-    wait_for_cmd_done()?;
-    let resp = if cmd.resp_exp() {
-        let mask: u32 = read_reg(REG_RINTSTS);
-        if mask & InterruptMask::rto.bits() != 0 {
-            write_reg(REG_RINTSTS, mask);
-            error!(
-                "Response Timeout, mask: {:?}",
-                InterruptMask::from_bits(mask).unwrap()
-            );
-            return Err(Interrupt::ResponseTimeout.into());
-        } else if mask & InterruptMask::re.bits() != 0 {
-            write_reg(REG_RINTSTS, mask);
-            error!(
-                "Response Error, mask : {:?}",
-                InterruptMask::from_bits(mask).unwrap()
-            );
-            return Err(Interrupt::ResponseErr.into());
-        }
-        if cmd.resp_lang() {
-            let resp0 = read_reg(REG_RESP0);
-            let resp1 = read_reg(REG_RESP1);
-            let resp2 = read_reg(REG_RESP2);
-            let resp3 = read_reg(REG_RESP3);
-            Response::R136((resp0, resp1, resp2, resp3))
-        } else {
-            Response::R48(read_reg(REG_RESP0))
+use super::quirks::{self, QuirkFlags};
+use super::rmw::{self, block_count};
+#[cfg(feature = "stats")]
+use super::stats::IoStats;
+use super::{CardState, SdCard, SdHost, Transfer, Uninit};
+
+/// Erase timeout per block when the card does not report one (SD spec 4.14)
+const ERASE_TIMEOUT_PER_BLOCK: Duration = Duration::from_millis(250);
+
+impl<S: CardState> SdHost<S> {
+    pub(crate) fn send_cmd(&mut self, cmd: Command) -> Result<Response, CardError> {
+        #[cfg(feature = "trace")]
+        self.trace.begin(&cmd);
+        let ret = self.issue_cmd(cmd);
+        #[cfg(feature = "trace")]
+        self.trace.end(&ret);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.command();
+            if let Err(err) = &ret {
+                self.stats.error(err);
+            }
         }
-    } else {
-        Response::Rz
-    };
-    if cmd.data_exp() {
-        wait_reset(ControlMask::fifo_reset.bits())?;
-        write_reg(REG_BLKSIZ, BLKSIZ_DEFAULT);
-        write_reg(REG_BYTCNT, BLKSIZ_DEFAULT);
+        ret
     }
 
-    Ok(resp)
-}
+    fn issue_cmd(&mut self, cmd: Command) -> Result<Response, CardError> {
+        if cmd.data_exp() {
+            self.write_reg(REG_BLKSIZ, cmd.block_size());
+            self.write_reg(REG_BYTCNT, cmd.byte_count());
+        }
+        loop {
+            self.wait_for_data_line(self.write_timeout)?;
+            self.wait_for_cmd_line()?;
+            self.write_reg(REG_RINTSTS, InterruptMask::all().bits());
+            self.write_reg(REG_CMDARG, cmd.arg());
+            self.write_reg(REG_CMD, cmd.to_cmd());
+            if self.read_reg(REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
+                debug!("Send CMD {:?}", CmdMask::from_bits(cmd.to_cmd()).unwrap());
+                break;
+            }
+            #[cfg(feature = "stats")]
+            self.stats.retry();
+        }
+        debug!(
+            "{:?}",
+            InterruptMask::from_bits(self.read_reg(REG_RINTSTS)).unwrap()
+        );
+        debug!(
+            "{:?}",
+            StatusMask::from_bits(self.read_reg(REG_STATUS)).unwrap()
+        );
+        self.wait_for_cmd_done()?;
+        #[cfg(feature = "trace")]
+        self.trace
+            .snapshot(self.read_reg(REG_RINTSTS), self.read_reg(REG_STATUS));
+        let resp = if cmd.resp_exp() {
+            let mask: u32 = self.read_reg(REG_RINTSTS);
+            if mask & InterruptMask::rto.bits() != 0 {
+                self.write_reg(REG_RINTSTS, mask);
+                error!(
+                    "Response Timeout, mask: {:?}",
+                    InterruptMask::from_bits(mask).unwrap()
+                );
+                return Err(Interrupt::ResponseTimeout.into());
+            } else if mask & InterruptMask::re.bits() != 0 {
+                self.write_reg(REG_RINTSTS, mask);
+                error!(
+                    "Response Error, mask : {:?}",
+                    InterruptMask::from_bits(mask).unwrap()
+                );
+                return Err(Interrupt::ResponseErr.into());
+            }
+            if cmd.resp_lang() {
+                let resp0 = self.read_reg(REG_RESP0);
+                let resp1 = self.read_reg(REG_RESP1);
+                let resp2 = self.read_reg(REG_RESP2);
+                let resp3 = self.read_reg(REG_RESP3);
+                Response::R136((resp0, resp1, resp2, resp3))
+            } else {
+                Response::R48(self.read_reg(REG_RESP0))
+            }
+        } else {
+            Response::Rz
+        };
+        if cmd.data_exp() {
+            self.wait_reset(ControlMask::fifo_reset.bits())?;
+        }
 
-fn read_data(buf: &mut [u8; BLKSIZ_DEFAULT as usize]) -> Result<(), CardError> {
-    let mut offset = 0;
-    let timer = Timer::start(Duration::from_micros(DATA_TMOUT_DEFUALT as u64));
-    loop {
-        let mask = read_reg(REG_RINTSTS);
-        if offset == BLKSIZ_DEFAULT as usize && InterruptMask::dto.bits() & mask != 0 {
-            break;
-        }
-        Interrupt::check(mask)?;
-        delay(Duration::from_micros(10));
-        if timer.timeout() {
-            return Err(CardError::DataTransferTimeout);
-        }
-        if mask & InterruptMask::rxdr.bits() != 0 || mask & InterruptMask::dto.bits() != 0 {
-            while fifo_cnt() > 0 {
-                buf[offset] = read_fifo(offset);
-                offset += 1;
+        Ok(resp)
+    }
+
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<(), CardError> {
+        let mut offset = 0;
+        let blocks = buf.len().div_ceil(BLKSIZ_DEFAULT as usize) as u32;
+        let timer = Timer::start(self.read_timeout * blocks);
+        loop {
+            let mask = self.read_reg(REG_RINTSTS);
+            if offset == buf.len() && InterruptMask::dto.bits() & mask != 0 {
+                break;
+            }
+            Interrupt::check(mask)?;
+            self.check_cancel()?;
+            delay(Duration::from_micros(10));
+            if timer.timeout() {
+                return Err(CardError::DataTransferTimeout);
+            }
+            if mask & InterruptMask::rxdr.bits() != 0 || mask & InterruptMask::dto.bits() != 0 {
+                while self.fifo_cnt() > 0 && offset < buf.len() {
+                    buf[offset] = self.read_fifo(offset % BLKSIZ_DEFAULT as usize);
+                    offset += 1;
+                }
+                self.write_reg(REG_RINTSTS, InterruptMask::rxdr.bits());
             }
-            write_reg(REG_RINTSTS, InterruptMask::rxdr.bits());
         }
+        self.write_reg(REG_RINTSTS, self.read_reg(REG_RINTSTS));
+        Ok(())
     }
-    write_reg(REG_RINTSTS, read_reg(REG_RINTSTS));
-    Ok(())
-}
 
-fn write_data(buf: &[u8; BLKSIZ_DEFAULT as usize]) -> Result<(), CardError> {
-    let timer = Timer::start(Duration::from_micros(DATA_TMOUT_DEFUALT as u64));
-    loop {
-        let mask = read_reg(REG_RINTSTS);
-        if InterruptMask::dto.bits() & mask != 0 {
-            break;
-        }
-        Interrupt::check(mask)?;
-        delay(Duration::from_micros(10));
-        if timer.timeout() {
-            return Err(CardError::DataTransferTimeout);
-        }
-        if mask & InterruptMask::txdr.bits() != 0 {
-            for offset in 0..BLKSIZ_DEFAULT as usize {
-                write_fifo(offset, buf[offset])
+    fn write_data(&mut self, buf: &[u8]) -> Result<(), CardError> {
+        let mut offset = 0;
+        let blocks = buf.len().div_ceil(BLKSIZ_DEFAULT as usize) as u32;
+        let timer = Timer::start(self.write_timeout * blocks);
+        loop {
+            let mask = self.read_reg(REG_RINTSTS);
+            if InterruptMask::dto.bits() & mask != 0 {
+                break;
+            }
+            Interrupt::check(mask)?;
+            self.check_cancel()?;
+            delay(Duration::from_micros(10));
+            if timer.timeout() {
+                return Err(CardError::DataTransferTimeout);
+            }
+            if mask & InterruptMask::txdr.bits() != 0 && offset < buf.len() {
+                // one block per request, the FIFO holds a full block
+                let end = (offset + BLKSIZ_DEFAULT as usize).min(buf.len());
+                for (i, byte) in buf[offset..end].iter().enumerate() {
+                    self.write_fifo(i, *byte)
+                }
+                offset = end;
+                self.write_reg(REG_RINTSTS, InterruptMask::txdr.bits());
             }
-            write_reg(REG_RINTSTS, InterruptMask::txdr.bits());
         }
+        self.write_reg(REG_RINTSTS, self.read_reg(REG_RINTSTS));
+        Ok(())
     }
-    write_reg(REG_RINTSTS, read_reg(REG_RINTSTS));
-    Ok(())
-}
 
-fn reset_clock(ena: u32, div: u32) -> Result<(), Timeout> {
-    wait_for_cmd_line()?;
-    write_reg(REG_CLKENA, 0);
-    write_reg(REG_CLKDIV, div);
-    let cmd = up_clk();
-    write_reg(REG_CMDARG, cmd.arg());
-    write_reg(REG_CMD, cmd.to_cmd());
-    if ena == 0 {
-        return Ok(());
-    }
-    wait_for_cmd_line()?;
-    write_reg(REG_CMD, cmd.to_cmd());
-    wait_for_cmd_line()?;
-    write_reg(REG_CLKENA, ena);
-    write_reg(REG_CMDARG, 0);
-    write_reg(REG_CMD, cmd.to_cmd());
-    debug!("reset clock");
-    Ok(())
-}
+    fn reset_clock(&mut self, ena: u32, div: u32) -> Result<(), Timeout> {
+        self.wait_for_cmd_line()?;
+        self.write_reg(REG_CLKENA, 0);
+        self.write_reg(REG_CLKDIV, div);
+        let cmd = up_clk();
+        self.write_reg(REG_CMDARG, cmd.arg());
+        self.write_reg(REG_CMD, cmd.to_cmd());
+        if ena == 0 {
+            return Ok(());
+        }
+        self.wait_for_cmd_line()?;
+        self.write_reg(REG_CMD, cmd.to_cmd());
+        self.wait_for_cmd_line()?;
+        self.write_reg(REG_CLKENA, ena);
+        self.write_reg(REG_CMDARG, 0);
+        self.write_reg(REG_CMD, cmd.to_cmd());
+        debug!("reset clock");
+        Ok(())
+    }
+
+    /// Stop the card clock and cut the card power.
+    pub(crate) fn power_off(&mut self) -> Result<(), Timeout> {
+        self.reset_clock(0, self.config.init_clkdiv)?;
+        self.write_reg(REG_PWREN, 0);
+        debug!("sdio power off");
+        Ok(())
+    }
+
+    fn card_clock_hz(&self, div: u32) -> u32 {
+        if div == 0 {
+            self.config.clk_in
+        } else {
+            self.config.clk_in / (2 * div)
+        }
+    }
 
-pub(crate) fn init_card() -> Result<(), CardError> {
-    info!("init sdio...");
-    let hconf = HardConf::from(read_reg(REG_HCON));
-    debug!("{hconf:?}");
-    // Reset Control Register
-    let reset_mask = ControlMask::controller_reset.bits()
-        | ControlMask::fifo_reset.bits()
-        | ControlMask::dma_reset.bits();
-    write_reg(REG_CTRL, reset_mask);
-    wait_reset(reset_mask)?;
-    // enable power
-    write_reg(REG_PWREN, 1);
-    reset_clock(1, 62)?;
-    write_reg(REG_TMOUT, 0xFFFFFFFF);
-    // setup interrupt mask
-    write_reg(REG_RINTSTS, InterruptMask::all().bits());
-    write_reg(REG_INTMASK, 0);
-    write_reg(REG_CTYPE, 1);
-    write_reg(REG_BMOD, 1);
-    // // enumerate card stack
-    send_cmd(idle())?;
-    delay(Duration::from_millis(10));
-    check_version()?;
-    check_v18_sdhc()?;
-    check_cid()?;
-    let rca = check_rca()?;
-    RCA.store(rca.address(), Ordering::Relaxed);
-    check_csd(rca)?;
-    sel_card(rca)?;
-    function_switch(16777201)?;
-    set_bus(rca)?;
-    reset_clock(1, 1)?;
-    info!("sdio init success!");
-    Ok(())
+    /// Derive the data timeouts from the CSD and program the hardware read timeout.
+    fn set_data_timeouts(&mut self, csd: &Csd, clock_hz: u32) {
+        let read = csd.read_timeout(clock_hz);
+        let write = csd.write_timeout(clock_hz);
+        self.read_timeout = read;
+        self.write_timeout = write;
+        let cycles = (read.as_micros() as u64 * clock_hz as u64 / 1_000_000)
+            .min(DATA_TMOUT_MAX as u64) as u32;
+        self.write_reg(REG_TMOUT, cycles << 8 | RESP_TMOUT_DEFAULT);
+        debug!("data timeout: read {read:?}, write {write:?}, {cycles} cycles");
+    }
 }
 
-fn check_version() -> Result<(), CardError> {
-    let cmd = send_if_cond(1, 0xAA);
-    let cic = send_cmd(cmd)?.cic();
-    if cic.voltage_accepted() == 1 && cic.pattern() == 0xAA {
-        debug!("sd vision 2.0");
-        delay(Duration::from_millis(10));
+impl SdHost<Uninit> {
+    /// Reset the controller, enumerate the card and bring it to the transfer state.
+    ///
+    /// On failure the host comes back with the error, e.g. to `reinit` it.
+    #[allow(clippy::result_large_err)]
+    pub fn init(mut self) -> Result<SdCard<Transfer>, TransitionError<Self>> {
+        let ret = self.enumerate();
+        self.transition(ret)
+    }
+
+    fn enumerate(&mut self) -> Result<(), CardError> {
+        info!("init sdio {:#x}...", self.base);
+        let hconf = HardConf::from(self.read_reg(REG_HCON));
+        debug!("{hconf:?}");
+        // Reset Control Register
+        let reset_mask = ControlMask::controller_reset.bits()
+            | ControlMask::fifo_reset.bits()
+            | ControlMask::dma_reset.bits();
+        self.write_reg(REG_CTRL, reset_mask);
+        self.wait_reset(reset_mask)?;
+        // enable power
+        self.write_reg(REG_PWREN, 1);
+        self.reset_clock(1, self.config.init_clkdiv)?;
+        self.write_reg(REG_TMOUT, DATA_TMOUT_MAX << 8 | RESP_TMOUT_DEFAULT);
+        // setup interrupt mask
+        self.write_reg(REG_RINTSTS, InterruptMask::all().bits());
+        self.write_reg(REG_INTMASK, 0);
+        // 1 bit data bus until the card accepted ACMD6
+        self.write_reg(REG_CTYPE, 0);
+        self.write_reg(REG_BMOD, 1);
+        // // enumerate card stack
+        self.send_cmd(idle())?;
+        self.init_delay();
+        self.check_version()?;
+        let ocr = self.check_v18_sdhc()?;
+        let cid = self.check_cid()?;
+        self.quirks = quirks::lookup(&cid, self.config.quirks);
+        debug!("{:?}", self.quirks);
+        let rca = self.check_rca()?;
+        self.rca = rca.address();
+        let csd = self.check_csd(rca)?;
+        self.sel_card(rca)?;
+        let scr = self.check_scr(rca)?;
+        self.info = Some(CardInfo {
+            cid,
+            csd,
+            ocr,
+            scr,
+            rca: rca.address(),
+        });
+        if !self.quirks.flags.contains(QuirkFlags::no_high_speed) {
+            self.function_switch(16777201)?;
+        }
+        if self.config.wide_bus && !self.quirks.narrow_bus {
+            self.set_bus(rca)?;
+        }
+        let clkdiv = self.clkdiv();
+        self.reset_clock(1, clkdiv)?;
+        self.set_data_timeouts(&csd, self.card_clock_hz(clkdiv));
+        self.expect_state(CurrentState::Transfer)?;
+        info!("sdio init success!");
+        Ok(())
+    }
+
+    fn init_delay(&self) {
+        delay(self.quirks.init_delay.unwrap_or(self.config.init_delay));
+    }
+
+    /// Transfer clock divider, lowered when a quirk caps the card clock.
+    fn clkdiv(&self) -> u32 {
+        match self.quirks.max_clock_hz {
+            Some(max) if max != 0 => self.config.clkdiv.max(self.config.clk_in.div_ceil(2 * max)),
+            _ => self.config.clkdiv,
+        }
+    }
+
+    fn check_version(&mut self) -> Result<(), CardError> {
+        let cmd = send_if_cond(1, 0xAA);
+        let cic = self.send_cmd(cmd)?.cic();
+        if cic.voltage_accepted() == 1 && cic.pattern() == 0xAA {
+            debug!("sd vision 2.0");
+            self.init_delay();
+            Ok(())
+        } else {
+            Err(CardError::VoltagePattern)
+        }
+    }
+
+    fn check_v18_sdhc(&mut self) -> Result<Ocr, CardError> {
+        let ocr = loop {
+            let cmd = app_cmd(0);
+            let status = self.send_cmd(cmd)?.card_status();
+            debug!("{status:?}");
+            let cmd = sd_send_op_cond(true, true, true);
+            let ocr = self.send_cmd(cmd)?.ocr();
+            if !ocr.is_busy() {
+                if ocr.high_capacity() {
+                    debug!("card is high capacity!");
+                }
+                if ocr.over_2tb() {
+                    debug!("card is over 2TB!");
+                }
+                if ocr.v18_allowed() {
+                    debug!("card can switch to 1.8 voltage!");
+                }
+                break ocr;
+            }
+            self.init_delay();
+        };
+        self.init_delay();
+        Ok(ocr)
+    }
+
+    fn check_rca(&mut self) -> Result<Rca, CardError> {
+        let cmd = send_relative_address();
+        let rca = self.send_cmd(cmd)?.rca();
+        debug!("{:?}", rca);
+        self.init_delay();
+        Ok(rca)
+    }
+
+    fn check_cid(&mut self) -> Result<Cid, CardError> {
+        let cmd = all_send_cid();
+        let cid = self.send_cmd(cmd)?.cid();
+        debug!("{:?}", cid);
+        self.init_delay();
+        Ok(cid)
+    }
+
+    fn check_csd(&mut self, rca: Rca) -> Result<Csd, CardError> {
+        let cmd = send_csd(rca.address());
+        let csd = self.send_cmd(cmd)?.csd();
+        debug!("{:?}", csd);
+        self.init_delay();
+        Ok(csd)
+    }
+
+    fn check_scr(&mut self, rca: Rca) -> Result<Scr, CardError> {
+        self.send_cmd(app_cmd(rca.address()))?;
+        self.send_cmd(send_scr())?;
+        let mut buf = [0u8; 8];
+        self.read_data(&mut buf)?;
+        let scr = Scr::from(buf);
+        debug!("{:?}", scr);
+        self.init_delay();
+        Ok(scr)
+    }
+
+    fn sel_card(&mut self, rca: Rca) -> Result<(), CardError> {
+        let cmd = select_card(rca.address());
+        let status = self.send_cmd(cmd)?.card_status();
+        debug!("{:?}", status);
+        self.init_delay();
+        Ok(())
+    }
+
+    fn function_switch(&mut self, arg: u32) -> Result<(), CardError> {
+        let cmd = switch_function(arg);
+        let status = self.send_cmd(cmd)?.card_status();
+        debug!("{:?}", status);
+        self.init_delay();
+        Ok(())
+    }
+
+    fn set_bus(&mut self, rca: Rca) -> Result<(), CardError> {
+        self.send_cmd(app_cmd(rca.address()))?;
+        let status = self.send_cmd(set_bus_width(2))?.card_status();
+        debug!("{:?}", status);
+        self.write_reg(REG_CTYPE, 1);
+        self.init_delay();
         Ok(())
-    } else {
-        Err(CardError::VoltagePattern)
     }
 }
 
-fn check_v18_sdhc() -> Result<(), CardError> {
-    loop {
-        let cmd = app_cmd(0);
-        let status = send_cmd(cmd)?.card_status();
-        debug!("{status:?}");
-        let cmd = sd_send_op_cond(true, true);
-        let ocr = send_cmd(cmd)?.ocr();
-        if !ocr.is_busy() {
-            if ocr.high_capacity() {
-                debug!("card is high capacity!");
+impl<S: CardState> SdHost<S> {
+    fn stop_transmission_ops(&mut self) -> Result<(), CardError> {
+        let cmd = stop_transmission();
+        #[cfg(feature = "trace")]
+        self.trace.begin(&cmd);
+        let ret = self.issue_stop(cmd);
+        #[cfg(feature = "trace")]
+        {
+            let resp = ret.map(|()| Response::R48(self.read_reg(REG_RESP0)));
+            self.trace.end(&resp);
+        }
+        #[cfg(feature = "stats")]
+        {
+            self.stats.command();
+            if let Err(err) = &ret {
+                self.stats.error(err);
             }
-            if ocr.v18_allowed() {
-                debug!("card can switch to 1.8 voltage!");
+        }
+        ret
+    }
+
+    /// Close a transfer that failed with `err`. The card is often not in a
+    /// data state then, e.g. after a command error, so a failing CMD12 is
+    /// logged and `err` stays the error reported to the caller.
+    fn stop_after_error(&mut self, err: &CardError) {
+        if let Err(stop) = self.stop_transmission_ops() {
+            debug!("CMD12 after {err:?} failed: {stop:?}");
+        }
+    }
+
+    fn issue_stop(&mut self, cmd: Command) -> Result<(), CardError> {
+        loop {
+            self.wait_for_cmd_line()?;
+            self.write_reg(REG_RINTSTS, InterruptMask::all().bits());
+            self.write_reg(REG_CMDARG, cmd.arg());
+            self.write_reg(REG_CMD, cmd.to_cmd());
+            if self.read_reg(REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
+                debug!("send {:?}", CmdMask::from_bits(cmd.to_cmd()).unwrap());
+                break;
             }
-            break;
         }
-        delay(Duration::from_millis(10));
+        let status = Response::R48(self.read_reg(REG_RESP0)).card_status();
+        debug!("{status:?}");
+        let ret = self.wait_for_cmd_done();
+        #[cfg(feature = "trace")]
+        self.trace
+            .snapshot(self.read_reg(REG_RINTSTS), self.read_reg(REG_STATUS));
+        ret?;
+        Ok(())
     }
-    delay(Duration::from_millis(10));
-    Ok(())
 }
 
-fn check_rca() -> Result<Rca, CardError> {
-    let cmd = send_relative_address();
-    let rca = send_cmd(cmd)?.rca();
-    debug!("{:?}", rca);
-    delay(Duration::from_millis(10));
-    Ok(rca)
-}
+impl SdCard<Transfer> {
+    /// Argument of a data command on `blocks` blocks from `addr`, see
+    /// [`data_address`].
+    fn block_arg(&self, addr: u64, blocks: u64) -> Result<u32, CardError> {
+        data_address(&self.info.unwrap_or_default(), addr, blocks)
+    }
 
-fn check_cid() -> Result<(), CardError> {
-    let cmd = all_send_cid();
-    let cid = send_cmd(cmd)?.cid();
-    debug!("{:?}", cid);
-    delay(Duration::from_millis(10));
-    Ok(())
-}
+    /// Give an SDUC card the address bits above 32 with CMD22, right before
+    /// the command using the address and after CMD23.
+    fn extend_address(&mut self, addr: u64) -> Result<(), CardError> {
+        if let Some(ext) = address_extension_bits(&self.info.unwrap_or_default(), addr) {
+            self.send_cmd(address_extension(ext))?;
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&mut self, addr: u64, buf: &mut [u8; 512]) -> Result<(), CardError> {
+        #[cfg(feature = "stats")]
+        let start = IoStats::start();
+        let arg = self.block_arg(addr, 1)?;
+        self.extend_address(addr)?;
+        let cmd = read_single_block(arg);
+        match self.send_cmd(cmd) {
+            Ok(resp) => {
+                let status = resp.card_status();
+                debug!("{status:?}");
+                match self.read_data(buf) {
+                    Ok(()) => {
+                        #[cfg(feature = "stats")]
+                        self.stats.read_done(start, 1, buf.len());
+                        Ok(())
+                    }
+                    Err(err) => {
+                        #[cfg(feature = "stats")]
+                        self.stats.error(&err);
+                        self.stop_after_error(&err);
+                        Err(err)
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("{err:?}");
+                self.stop_after_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn write_block(&mut self, addr: u64, buf: &[u8; 512]) -> Result<(), CardError> {
+        #[cfg(feature = "stats")]
+        let start = IoStats::start();
+        let arg = self.block_arg(addr, 1)?;
+        self.extend_address(addr)?;
+        let cmd = write_single_block(arg);
+        match self.send_cmd(cmd) {
+            Ok(resp) => {
+                let status = resp.card_status();
+                debug!("{status:?}");
+                match self.write_data(buf) {
+                    Ok(()) => {
+                        #[cfg(feature = "stats")]
+                        self.stats.write_done(start, 1, buf.len());
+                        Ok(())
+                    }
+                    Err(err) => {
+                        #[cfg(feature = "stats")]
+                        self.stats.error(&err);
+                        self.stop_after_error(&err);
+                        Err(err)
+                    }
+                }
+            }
+            Err(err) => {
+                debug!("{err:?}");
+                self.stop_after_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Read `buf.len() / 512` consecutive blocks starting at `addr` with CMD18.
+    ///
+    /// The transfer is closed-ended with CMD23 when the card supports it,
+    /// otherwise it is terminated with CMD12.
+    pub fn read_blocks(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), CardError> {
+        let blocks = block_count(buf.len())?;
+        if blocks == 1 {
+            return self.read_block(
+                addr,
+                (&mut buf[..BLKSIZ_DEFAULT as usize]).try_into().unwrap(),
+            );
+        }
+        let arg = self.block_arg(addr, blocks.into())?;
+        #[cfg(feature = "stats")]
+        let start = IoStats::start();
+        let closed = closed_ended(self.info.as_ref(), self.quirks.flags);
+        if closed {
+            self.send_cmd(set_block_count(blocks))?;
+        }
+        self.extend_address(addr)?;
+        let cmd = read_multiple_block(arg, blocks);
+        let ret = self.send_cmd(cmd).and_then(|resp| {
+            debug!("{:?}", resp.card_status());
+            let ret = self.read_data(buf);
+            // command errors are counted by send_cmd
+            #[cfg(feature = "stats")]
+            if let Err(err) = &ret {
+                self.stats.error(err);
+            }
+            ret
+        });
+        match ret {
+            Ok(()) => {
+                if !closed {
+                    self.stop_transmission_ops()?;
+                }
+                #[cfg(feature = "stats")]
+                self.stats.read_done(start, blocks as usize, buf.len());
+                Ok(())
+            }
+            Err(err) => {
+                debug!("{err:?}");
+                self.stop_after_error(&err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Write `buf.len() / 512` consecutive blocks starting at `addr` with CMD25.
+    ///
+    /// ACMD23 tells the card how many blocks to pre-erase, then the transfer is
+    /// closed-ended with CMD23 when the card supports it, otherwise it is
+    /// terminated with CMD12.
+    pub fn write_blocks(&mut self, addr: u64, buf: &[u8]) -> Result<(), CardError> {
+        let blocks = block_count(buf.len())?;
+        if blocks == 1 {
+            return self.write_block(addr, buf[..BLKSIZ_DEFAULT as usize].try_into().unwrap());
+        }
+        let arg = self.block_arg(addr, blocks.into())?;
+        #[cfg(feature = "stats")]
+        let start = IoStats::start();
+        if pre_erase(self.quirks.flags) {
+            self.send_cmd(app_cmd(self.rca))?;
+            self.send_cmd(set_wr_blk_erase_count(blocks))?;
+        }
+        let closed = closed_ended(self.info.as_ref(), self.quirks.flags);
+        if closed {
+            self.send_cmd(set_block_count(blocks))?;
+        }
+        self.extend_address(addr)?;
+        let cmd = write_multiple_block(arg, blocks);
+        let ret = self.send_cmd(cmd).and_then(|resp| {
+            debug!("{:?}", resp.card_status());
+            let ret = self.write_data(buf);
+            // command errors are counted by send_cmd
+            #[cfg(feature = "stats")]
+            if let Err(err) = &ret {
+                self.stats.error(err);
+            }
+            ret
+        });
+        match ret {
+            Ok(()) => {
+                if !closed {
+                    self.stop_transmission_ops()?;
+                }
+                #[cfg(feature = "stats")]
+                self.stats.write_done(start, blocks as usize, buf.len());
+                Ok(())
+            }
+            Err(err) => {
+                debug!("{err:?}");
+                self.stop_after_error(&err);
+                Err(err)
+            }
+        }
+    }
 
-fn check_csd(rca: Rca) -> Result<(), CardError> {
-    let cmd = send_csd(rca.address());
-    let csd = send_cmd(cmd)?.csd();
-    debug!("{:?}", csd);
-    delay(Duration::from_millis(10));
-    Ok(())
+    /// Read `buf.len()` bytes starting at byte `offset` of the card.
+    ///
+    /// Unaligned head and tail go through a block bounce buffer, the aligned
+    /// middle is read directly into `buf` with a multiple block read.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        rmw::read_at(self, offset, buf)
+    }
+
+    /// Write `buf` starting at byte `offset` of the card.
+    ///
+    /// Unaligned head and tail blocks are read, patched and written back, the
+    /// aligned middle is written directly from `buf` with a multiple block write.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+        rmw::write_at(self, offset, buf)
+    }
+
+    /// Erase `count` blocks starting at `addr` with CMD32, CMD33 and CMD38.
+    pub fn erase(&mut self, addr: u64, count: u64) -> Result<(), CardError> {
+        if count == 0 {
+            return Ok(());
+        }
+        let info = self.info.unwrap_or_default();
+        if !info.csd.command_classes().contains(CommandClasses::erase) {
+            return Err(CardError::UnsupportedCommand);
+        }
+        let end = addr
+            .checked_add(count - 1)
+            .ok_or(CardError::AddressOutOfRange)?;
+        let start = self.block_arg(addr, count)?;
+        let last = self.block_arg(end, 1)?;
+        self.extend_address(addr)?;
+        self.send_cmd(erase_wr_blk_start(start))?;
+        self.extend_address(end)?;
+        self.send_cmd(erase_wr_blk_end(last))?;
+        self.send_cmd(erase())?;
+        let timeout = ERASE_TIMEOUT_PER_BLOCK * u32::try_from(count).unwrap_or(u32::MAX);
+        self.wait_for_data_line(timeout)?;
+        Ok(())
+    }
+
+    /// Whether the CSD write protect bits are set.
+    pub fn is_write_protected(&self) -> bool {
+        self.info
+            .is_some_and(|info| info.csd.perm_write_protect() || info.csd.tmp_write_protect())
+    }
+
+    /// Send a raw command, e.g. CMD56 GEN_CMD or a vendor specific command,
+    /// including its optional data phase.
+    ///
+    /// On a failed data phase the transfer is stopped with CMD12, and the data
+    /// line is waited on when `busy_wait` is set, so the next command starts
+    /// from a clean state.
+    pub fn execute(&mut self, spec: CommandSpec) -> Result<Response, CardError> {
+        spec.validate()?;
+        let cmd = Command::from_spec(&spec);
+        if spec.app {
+            self.send_cmd(app_cmd(self.rca))?;
+        }
+        let ret = match (self.send_cmd(cmd), spec.data) {
+            (Ok(resp), Data::Read(buf)) => self.read_data(buf).map(|_| resp),
+            (Ok(resp), Data::Write(buf)) => self.write_data(buf).map(|_| resp),
+            (ret, _) => ret,
+        };
+        if let (Err(err), true) = (&ret, cmd.data_exp()) {
+            self.stop_after_error(err);
+        }
+        if spec.busy_wait {
+            self.wait_for_data_line(self.write_timeout)?;
+        }
+        ret
+    }
+
+    /// Abort the data transfer in progress, if any, and leave the controller
+    /// and the card in the transfer state.
+    ///
+    /// A busy data path is stopped with CMD12 (`stop_abort_cmd`), the pending
+    /// read data is dropped with `abort_read_data`, then the FIFO is reset once
+    /// the data state machine is idle.
+    ///
+    /// This needs the card, so it runs between requests. To stop a request
+    /// still running, e.g. when a deadline expires, set the flag given to
+    /// [`set_cancel`](Self::set_cancel), then call this once the request
+    /// returned `CardError::Cancelled`.
+    pub fn abort(&mut self) -> Result<(), CardError> {
+        let busy = StatusMask::data_state_mc_busy.bits() | StatusMask::data_busy.bits();
+        if self.read_reg(REG_STATUS) & busy != 0 {
+            self.stop_transmission_ops()?;
+        }
+        let ctrl = self.read_reg(REG_CTRL);
+        self.write_reg(REG_CTRL, ctrl | ControlMask::abort_read_data.bits());
+        self.wait_reset(ControlMask::abort_read_data.bits())?;
+        self.wait_for_data_idle(self.read_timeout)?;
+        self.write_reg(REG_CTRL, ctrl | ControlMask::fifo_reset.bits());
+        self.wait_reset(ControlMask::fifo_reset.bits())?;
+        self.write_reg(REG_RINTSTS, InterruptMask::all().bits());
+        // the controller may have finished while the card is still sending
+        if let CurrentState::Sending | CurrentState::Receiving = self.card_state()? {
+            self.stop_transmission_ops()?;
+        }
+        // wait for the card to leave the programming state after a write
+        self.wait_for_data_line(self.write_timeout)?;
+        self.expect_state(CurrentState::Transfer)?;
+        debug!("sdio transfer aborted");
+        Ok(())
+    }
 }
 
-fn sel_card(rca: Rca) -> Result<(), CardError> {
-    let cmd = select_card(rca.address());
-    let status = send_cmd(cmd)?.card_status();
-    debug!("{:?}", status);
-    delay(Duration::from_millis(10));
-    Ok(())
+/// Translate block `addr` into the argument of a data command on `blocks`
+/// blocks of the card described by `info`, checking the whole range against
+/// the card size.
+///
+/// SDSC cards are byte addressed, SDHC/SDXC cards take the block number as is,
+/// and SDUC cards take its low 32 bits, see [`address_extension_bits`].
+/// Nothing is sent to the card, so a rejected request leaves no state behind.
+fn data_address(info: &CardInfo, addr: u64, blocks: u64) -> Result<u32, CardError> {
+    let count = info.csd.sector_count();
+    let end = addr
+        .checked_add(blocks)
+        .ok_or(CardError::AddressOutOfRange)?;
+    if count != 0 && end > count {
+        return Err(CardError::AddressOutOfRange);
+    }
+    if !info.ocr.high_capacity() {
+        return addr
+            .checked_mul(BLKSIZ_DEFAULT as u64)
+            .and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(CardError::AddressOutOfRange);
+    }
+    if info.csd.is_sduc() {
+        if addr >> 38 != 0 {
+            return Err(CardError::AddressOutOfRange);
+        }
+        return Ok(addr as u32);
+    }
+    u32::try_from(addr).map_err(|_| CardError::AddressOutOfRange)
 }
 
-fn function_switch(arg: u32) -> Result<(), CardError> {
-    let cmd = switch_function(arg);
-    let status = send_cmd(cmd)?.card_status();
-    debug!("{:?}", status);
-    delay(Duration::from_millis(10));
-    Ok(())
+/// Whether multiple block transfers are closed-ended with CMD23: the SCR
+/// advertises CMD23 and no quirk says the card mishandles it.
+fn closed_ended(info: Option<&CardInfo>, flags: QuirkFlags) -> bool {
+    info.is_some_and(|info| info.scr.cmd23_support()) && !flags.contains(QuirkFlags::no_cmd23)
 }
 
-fn set_bus(rca: Rca) -> Result<(), CardError> {
-    send_cmd(app_cmd(rca.address()))?;
-    let status = send_cmd(set_bus_width(2))?.card_status();
-    debug!("{:?}", status);
-    delay(Duration::from_millis(10));
-    Ok(())
+/// Whether ACMD23 tells the card how many blocks a multiple block write is
+/// going to program, so it can pre-erase them.
+fn pre_erase(flags: QuirkFlags) -> bool {
+    !flags.contains(QuirkFlags::no_pre_erase)
 }
 
-fn stop_transmission_ops() -> Result<(), CardError> {
-    let cmd = stop_transmission();
-    loop {
-        wait_for_cmd_line()?;
-        write_reg(REG_RINTSTS, InterruptMask::all().bits());
-        write_reg(REG_CMDARG, cmd.arg());
-        write_reg(REG_CMD, cmd.to_cmd());
-        if read_reg(REG_RINTSTS) & InterruptMask::hle.bits() == 0 {
-            debug!("send {:?}", CmdMask::from_bits(cmd.to_cmd()).unwrap());
-            break;
-        }
-    }
-    let status = Response::R48(read_reg(REG_RESP0)).card_status();
-    debug!("{status:?}");
-    wait_for_cmd_done()?;
-    Ok(())
+/// Bits 37:32 of block `addr`, sent with CMD22 before a data command to an
+/// SDUC card, `None` for the other cards.
+fn address_extension_bits(info: &CardInfo, addr: u64) -> Option<u8> {
+    (info.ocr.high_capacity() && info.csd.is_sduc()).then_some((addr >> 32) as u8)
 }
 
-pub(crate) fn read_block(buf: &mut [u8; 512], addr: u32) -> Result<(), CardError> {
-    let cmd = read_single_block(addr);
-    match send_cmd(cmd) {
-        Ok(resp) => {
-            let status = resp.card_status();
-            debug!("{status:?}");
-            if read_data(buf).is_err() {
-                stop_transmission_ops()
-            } else {
-                Ok(())
-            }
+impl BlockDevice for SdCard<Transfer> {
+    fn block_size(&self) -> usize {
+        BLKSIZ_DEFAULT as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.map_or(0, |info| info.csd.sector_count())
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        Ok(SdCard::read_blocks(self, lba, buf)?)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.is_write_protected() {
+            return Err(BlockError::ReadOnly);
         }
-        Err(err) => {
-            debug!("{err:?}");
-            stop_transmission_ops()
+        Ok(SdCard::write_blocks(self, lba, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(SdCard::flush(self)?)
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        if self.is_write_protected() {
+            return Err(BlockError::ReadOnly);
         }
+        Ok(SdCard::erase(self, lba, count)?)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.is_write_protected()
     }
 }
 
-pub(crate) fn write_block(buf: &[u8; BLKSIZ_DEFAULT as usize], addr: u32) -> Result<(), CardError> {
-    let cmd = write_single_block(addr);
-    match send_cmd(cmd) {
-        Ok(resp) => {
-            let status = resp.card_status();
-            debug!("{status:?}");
-            if write_data(buf).is_err() {
-                stop_transmission_ops()
-            } else {
-                Ok(())
-            }
-        }
-        Err(err) => {
-            debug!("{err:?}");
-            stop_transmission_ops()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd::quirks::Quirks;
+    use crate::sd::SdConfig;
+
+    /// OCR of a powered up high capacity card
+    const OCR_CCS: u32 = 0xC0FF_8000;
+
+    fn card(csd: u128, ocr: u32) -> CardInfo {
+        CardInfo {
+            csd: Csd::from(csd),
+            ocr: Ocr::from(ocr),
+            ..Default::default()
         }
     }
+
+    /// A 2 GB SDSC card, 3950592 blocks
+    fn sdsc() -> CardInfo {
+        card(0x0026_0032_5f5a_83c4_6db7_ff9f_9640_0001, 0x80FF_8000)
+    }
+
+    /// A 32 GB SDHC card, 62333952 blocks
+    fn sdhc() -> CardInfo {
+        card(0x400e_0032_5b59_0000_edc8_7f80_0a40_4001, OCR_CCS)
+    }
+
+    /// A 4 TiB SDUC card, 2^33 blocks
+    fn sduc() -> CardInfo {
+        card(
+            0x800e_0032_5b59_007f_ffff_7f80_0a40_4001,
+            OCR_CCS | 0x0800_0000,
+        )
+    }
+
+    fn out_of_range(ret: Result<u32, CardError>) -> bool {
+        matches!(ret, Err(CardError::AddressOutOfRange))
+    }
+
+    /// `info` with the SCR CMD_SUPPORT bits `cmd_support`
+    fn with_scr(mut info: CardInfo, cmd_support: u8) -> CardInfo {
+        // SD spec 3.0, 1 and 4 bit bus
+        let scr = 0x0235_8000_0000_0000 | u64::from(cmd_support) << 32;
+        info.scr = Scr::from(scr.to_be_bytes());
+        info
+    }
+
+    #[test]
+    fn cmd23_closes_transfers_when_the_scr_lists_it() {
+        let flags = QuirkFlags::empty();
+        assert!(!closed_ended(None, flags));
+        assert!(!closed_ended(Some(&sdhc()), flags));
+        // CMD20 only
+        assert!(!closed_ended(Some(&with_scr(sdhc(), 0b01)), flags));
+        assert!(closed_ended(Some(&with_scr(sdhc(), 0b10)), flags));
+        assert!(closed_ended(Some(&with_scr(sdhc(), 0b11)), flags));
+        // ACMD23 is sent whatever the SCR says
+        assert!(pre_erase(flags));
+    }
+
+    #[test]
+    fn quirks_override_the_scr() {
+        let info = with_scr(sdhc(), 0b10);
+        assert!(!closed_ended(Some(&info), QuirkFlags::no_cmd23));
+        assert!(closed_ended(Some(&info), QuirkFlags::no_pre_erase));
+        assert!(!pre_erase(QuirkFlags::no_pre_erase));
+        assert!(pre_erase(QuirkFlags::no_cmd23));
+    }
+
+    #[test]
+    fn clock_quirk_raises_the_divider() {
+        // SAFETY: the registers are never touched
+        let mut host = unsafe { SdHost::new(0, SdConfig::default()) };
+        // 50 MHz in, 25 MHz card clock
+        assert_eq!(host.clkdiv(), 1);
+        host.quirks = Quirks::new().max_clock_hz(12_500_000);
+        assert_eq!(host.clkdiv(), 2);
+        // rounded down to the next divider, 8.3 MHz
+        host.quirks = Quirks::new().max_clock_hz(10_000_000);
+        assert_eq!(host.clkdiv(), 3);
+        host.quirks = Quirks::new().max_clock_hz(0);
+        assert_eq!(host.clkdiv(), 1);
+        // never faster than the configured divider
+        host.config.clkdiv = 4;
+        host.quirks = Quirks::new().max_clock_hz(12_500_000);
+        assert_eq!(host.clkdiv(), 4);
+    }
+
+    #[test]
+    fn sdsc_is_byte_addressed() {
+        let info = sdsc();
+        assert_eq!(data_address(&info, 0, 1).unwrap(), 0);
+        assert_eq!(data_address(&info, 3, 8).unwrap(), 3 * 512);
+        assert_eq!(data_address(&info, 3_950_591, 1).unwrap(), 3_950_591 * 512);
+        assert!(out_of_range(data_address(&info, 3_950_591, 2)));
+        assert_eq!(address_extension_bits(&info, 5), None);
+        // an unknown size still has to fit the 32 bit byte address
+        let unknown = card(0, 0x80FF_8000);
+        assert_eq!(
+            data_address(&unknown, (1 << 23) - 1, 1).unwrap(),
+            u32::MAX - 511
+        );
+        assert!(out_of_range(data_address(&unknown, 1 << 23, 1)));
+    }
+
+    #[test]
+    fn sdhc_is_block_addressed() {
+        let info = sdhc();
+        assert_eq!(data_address(&info, 0x1234, 16).unwrap(), 0x1234);
+        assert_eq!(data_address(&info, 62_333_951, 1).unwrap(), 62_333_951);
+        assert!(out_of_range(data_address(&info, 62_333_951, 2)));
+        assert!(out_of_range(data_address(&info, 62_333_952, 1)));
+        assert!(out_of_range(data_address(&info, u64::MAX, 1)));
+        assert_eq!(address_extension_bits(&info, 0x1234), None);
+    }
+
+    #[test]
+    fn sduc_splits_the_address_for_cmd22() {
+        let info = sduc();
+        assert_eq!(info.csd.sector_count(), 1 << 33);
+        let addr = 0x1_2345_6789;
+        assert_eq!(data_address(&info, addr, 8).unwrap(), 0x2345_6789);
+        assert_eq!(address_extension_bits(&info, addr), Some(1));
+        assert_eq!(address_extension_bits(&info, 0x1234), Some(0));
+        let last = (1 << 33) - 1;
+        assert_eq!(data_address(&info, last, 1).unwrap(), u32::MAX);
+        assert!(out_of_range(data_address(&info, last, 2)));
+        assert!(out_of_range(data_address(&info, 1 << 38, 1)));
+    }
 }
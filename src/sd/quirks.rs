@@ -0,0 +1,216 @@
+//! Per-card workarounds.
+//!
+//! Cards are matched on their CID (manufacturer ID, OEM ID, product name and
+//! revision). The first matching entry wins, entries from
+//! [`SdConfig::quirks`](super::SdConfig::quirks) are looked at before the
+//! built-in [`QUIRKS`] table, so boards can add or override entries:
+//!
+//! ```no_run
+//! use vf2_driver::sd::quirks::{Quirk, Quirks};
+//! use vf2_driver::sd::{SdConfig, SdHost, SDIO1_BASE};
+//!
+//! static MY_QUIRKS: [Quirk; 1] = [Quirk::new(Quirks::new().max_clock_hz(12_500_000))
+//!     .manufacturer(0x9F)
+//!     .product("SD16G")];
+//! let config = SdConfig {
+//!     quirks: &MY_QUIRKS,
+//!     ..Default::default()
+//! };
+//! // SAFETY: SDIO1 is mapped and driven by nothing else
+//! let card = unsafe { SdHost::new(SDIO1_BASE, config) }.init();
+//! ```
+use core::time::Duration;
+
+use bitflags::bitflags;
+
+use super::sd_reg::Cid;
+
+bitflags! {
+    /// Command support assumptions to override.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct QuirkFlags: u32 {
+        /// The SCR claims CMD23 support but the card mishandles it, use
+        /// open-ended transfers terminated by CMD12
+        const no_cmd23 = 0b1;
+        /// Do not send ACMD23 before multiple block writes
+        const no_pre_erase = 0b1 << 1;
+        /// Skip the CMD6 high speed function switch
+        const no_high_speed = 0b1 << 2;
+    }
+}
+
+/// What to change for a matching card.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quirks {
+    /// Delay after each identification step from CMD2 (ALL_SEND_CID) on,
+    /// instead of `SdConfig::init_delay`. The steps before CMD2 run before
+    /// the CID is known and keep `SdConfig::init_delay`.
+    pub init_delay: Option<Duration>,
+    /// Upper bound of the card clock in transfer state
+    pub max_clock_hz: Option<u32>,
+    /// Stay on the 1 bit data bus, ACMD6 is not sent and the controller stays
+    /// on 1 bit too
+    pub narrow_bus: bool,
+    pub flags: QuirkFlags,
+}
+
+impl Quirks {
+    pub const fn new() -> Self {
+        Self {
+            init_delay: None,
+            max_clock_hz: None,
+            narrow_bus: false,
+            flags: QuirkFlags::empty(),
+        }
+    }
+
+    pub const fn init_delay(mut self, delay: Duration) -> Self {
+        self.init_delay = Some(delay);
+        self
+    }
+
+    pub const fn max_clock_hz(mut self, hz: u32) -> Self {
+        self.max_clock_hz = Some(hz);
+        self
+    }
+
+    pub const fn narrow_bus(mut self) -> Self {
+        self.narrow_bus = true;
+        self
+    }
+
+    pub const fn flags(mut self, flags: QuirkFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+}
+
+/// A quirks entry, fields left to `None` match any card.
+#[derive(Debug, Clone, Copy)]
+pub struct Quirk {
+    pub manufacturer_id: Option<u8>,
+    pub oem_id: Option<u16>,
+    pub product_name: Option<&'static str>,
+    /// Product revision, hardware revision in the high nibble
+    pub revision: Option<u8>,
+    pub quirks: Quirks,
+}
+
+impl Quirk {
+    pub const fn new(quirks: Quirks) -> Self {
+        Self {
+            manufacturer_id: None,
+            oem_id: None,
+            product_name: None,
+            revision: None,
+            quirks,
+        }
+    }
+
+    pub const fn manufacturer(mut self, id: u8) -> Self {
+        self.manufacturer_id = Some(id);
+        self
+    }
+
+    pub const fn oem(mut self, id: u16) -> Self {
+        self.oem_id = Some(id);
+        self
+    }
+
+    pub const fn product(mut self, name: &'static str) -> Self {
+        self.product_name = Some(name);
+        self
+    }
+
+    pub const fn revision(mut self, rev: u8) -> Self {
+        self.revision = Some(rev);
+        self
+    }
+
+    pub fn matches(&self, cid: &Cid) -> bool {
+        self.manufacturer_id
+            .is_none_or(|id| id == cid.manufacturer_id())
+            && self.oem_id.is_none_or(|id| id == cid.oem_id_raw())
+            && self
+                .product_name
+                .is_none_or(|name| name == cid.product_name())
+            && self
+                .revision
+                .is_none_or(|rev| rev == cid.product_revision())
+    }
+}
+
+/// Built-in entries, empty until a card needing one shows up on our boards.
+pub const QUIRKS: &[Quirk] = &[];
+
+/// Quirks of the card with `cid`, looking at `extra` before the built-in table.
+pub fn lookup(cid: &Cid, extra: &[Quirk]) -> Quirks {
+    extra
+        .iter()
+        .chain(QUIRKS)
+        .find(|quirk| quirk.matches(cid))
+        .map(|quirk| quirk.quirks)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cid(mid: u8, oem: &[u8; 2], name: &[u8; 5], rev: u8) -> Cid {
+        let mut bytes = [0u8; 16];
+        bytes[0] = mid;
+        bytes[1..3].copy_from_slice(oem);
+        bytes[3..8].copy_from_slice(name);
+        bytes[8] = rev;
+        Cid::from(u128::from_be_bytes(bytes))
+    }
+
+    const SLOW: Quirks = Quirks::new().max_clock_hz(12_500_000);
+    const NARROW: Quirks = Quirks::new().narrow_bus();
+
+    #[test]
+    fn unset_fields_match_any_card() {
+        let any = Quirk::new(SLOW);
+        assert!(any.matches(&cid(0x03, b"SD", b"SC16G", 0x80)));
+        assert!(any.matches(&cid(0x9F, b"TI", b"     ", 0)));
+    }
+
+    #[test]
+    fn every_set_field_must_match() {
+        let quirk = Quirk::new(SLOW)
+            .manufacturer(0x9F)
+            .oem(u16::from_be_bytes(*b"TI"))
+            .product("SD16G")
+            .revision(0x10);
+        assert!(quirk.matches(&cid(0x9F, b"TI", b"SD16G", 0x10)));
+        assert!(!quirk.matches(&cid(0x03, b"TI", b"SD16G", 0x10)));
+        assert!(!quirk.matches(&cid(0x9F, b"SD", b"SD16G", 0x10)));
+        assert!(!quirk.matches(&cid(0x9F, b"TI", b"SD32G", 0x10)));
+        assert!(!quirk.matches(&cid(0x9F, b"TI", b"SD16G", 0x20)));
+    }
+
+    #[test]
+    fn first_matching_entry_wins() {
+        let extra = [
+            Quirk::new(NARROW).manufacturer(0x9F).product("SD16G"),
+            Quirk::new(SLOW).manufacturer(0x9F),
+        ];
+        let quirks = lookup(&cid(0x9F, b"TI", b"SD16G", 0x10), &extra);
+        assert!(quirks.narrow_bus);
+        assert_eq!(quirks.max_clock_hz, None);
+        let quirks = lookup(&cid(0x9F, b"TI", b"SD32G", 0x10), &extra);
+        assert!(!quirks.narrow_bus);
+        assert_eq!(quirks.max_clock_hz, Some(12_500_000));
+    }
+
+    #[test]
+    fn unknown_card_gets_no_quirks() {
+        let extra = [Quirk::new(SLOW).manufacturer(0x9F)];
+        let quirks = lookup(&cid(0x03, b"SD", b"SC16G", 0x80), &extra);
+        assert!(!quirks.narrow_bus);
+        assert_eq!(quirks.max_clock_hz, None);
+        assert_eq!(quirks.init_delay, None);
+        assert!(quirks.flags.is_empty());
+    }
+}
@@ -28,7 +28,8 @@ pub(crate) const REG_CLKDIV: u32 = 0x008;
 pub(crate) const REG_CLKENA: u32 = 0x010;
 
 pub(crate) const REG_TMOUT: u32 = 0x014;
-pub(crate) const DATA_TMOUT_DEFUALT: u32 = 0xFFFFFF << 8;
+pub(crate) const DATA_TMOUT_MAX: u32 = 0xFFFFFF;
+pub(crate) const RESP_TMOUT_DEFAULT: u32 = 0xFF;
 pub(crate) const REG_CTYPE: u32 = 0x018;
 pub(crate) const REG_BLKSIZ: u32 = 0x01C;
 pub(crate) const BLKSIZ_DEFAULT: u32 = 0x200;
@@ -251,4 +252,3 @@ impl Debug for HardConf {
 }
 
 pub(crate) const REG_BMOD: u32 = 0x080;
-
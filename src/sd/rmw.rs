@@ -0,0 +1,175 @@
+//! Byte granular access on top of the block transfers of a card.
+use crate::block::{BlockDevice, BlockError};
+
+use super::err::CardError;
+use super::reg::BLKSIZ_DEFAULT;
+
+/// Number of 512 byte blocks in `len`, which must be a non zero multiple of 512.
+pub(crate) fn block_count(len: usize) -> Result<u32, CardError> {
+    if len == 0 || !len.is_multiple_of(BLKSIZ_DEFAULT as usize) {
+        return Err(CardError::InvalidArgument);
+    }
+    u32::try_from(len / BLKSIZ_DEFAULT as usize).map_err(|_| CardError::InvalidArgument)
+}
+
+/// Read `buf.len()` bytes starting at byte `offset` of the card.
+///
+/// Unaligned head and tail go through a block bounce buffer, the aligned
+/// middle is read directly into `buf` with a multiple block read.
+pub(crate) fn read_at<D: BlockDevice>(
+    dev: &mut D,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), BlockError> {
+    let bs = BLKSIZ_DEFAULT as usize;
+    let mut block = [0u8; BLKSIZ_DEFAULT as usize];
+    let mut pos = offset;
+    let mut done = 0;
+    let head = (pos % bs as u64) as usize;
+    if head != 0 && !buf.is_empty() {
+        let n = (bs - head).min(buf.len());
+        dev.read_blocks(pos / bs as u64, &mut block)?;
+        buf[..n].copy_from_slice(&block[head..head + n]);
+        done += n;
+        pos += n as u64;
+    }
+    let middle = (buf.len() - done) / bs * bs;
+    if middle != 0 {
+        dev.read_blocks(pos / bs as u64, &mut buf[done..done + middle])?;
+        done += middle;
+        pos += middle as u64;
+    }
+    if done < buf.len() {
+        let n = buf.len() - done;
+        dev.read_blocks(pos / bs as u64, &mut block)?;
+        buf[done..].copy_from_slice(&block[..n]);
+    }
+    Ok(())
+}
+
+/// Write `buf` starting at byte `offset` of the card.
+///
+/// Unaligned head and tail blocks are read, patched and written back, the
+/// aligned middle is written directly from `buf` with a multiple block write.
+pub(crate) fn write_at<D: BlockDevice>(
+    dev: &mut D,
+    offset: u64,
+    buf: &[u8],
+) -> Result<(), BlockError> {
+    let bs = BLKSIZ_DEFAULT as usize;
+    let mut block = [0u8; BLKSIZ_DEFAULT as usize];
+    let mut pos = offset;
+    let mut done = 0;
+    let head = (pos % bs as u64) as usize;
+    if head != 0 && !buf.is_empty() {
+        let n = (bs - head).min(buf.len());
+        dev.read_blocks(pos / bs as u64, &mut block)?;
+        block[head..head + n].copy_from_slice(&buf[..n]);
+        dev.write_blocks(pos / bs as u64, &block)?;
+        done += n;
+        pos += n as u64;
+    }
+    let middle = (buf.len() - done) / bs * bs;
+    if middle != 0 {
+        dev.write_blocks(pos / bs as u64, &buf[done..done + middle])?;
+        done += middle;
+        pos += middle as u64;
+    }
+    if done < buf.len() {
+        let n = buf.len() - done;
+        dev.read_blocks(pos / bs as u64, &mut block)?;
+        block[..n].copy_from_slice(&buf[done..]);
+        dev.write_blocks(pos / bs as u64, &block)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ram::RamDisk;
+
+    /// Reads fail, everything else goes to the RAM disk.
+    struct ReadFails<'a>(RamDisk<'a>);
+
+    impl BlockDevice for ReadFails<'_> {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+
+        fn block_count(&self) -> u64 {
+            self.0.block_count()
+        }
+
+        fn read_blocks(&mut self, _lba: u64, _buf: &mut [u8]) -> Result<(), BlockError> {
+            Err(BlockError::Card(CardError::DataTransferTimeout))
+        }
+
+        fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+            self.0.write_blocks(lba, buf)
+        }
+
+        fn flush(&mut self) -> Result<(), BlockError> {
+            self.0.flush()
+        }
+
+        fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+            self.0.erase(lba, count)
+        }
+    }
+
+    fn pattern(buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (i % 251) as u8 + 1;
+        }
+    }
+
+    #[test]
+    fn unaligned_round_trip() {
+        let mut image = [0u8; 8 * 512];
+        let mut disk = RamDisk::new(&mut image);
+        let mut data = [0u8; 1500];
+        pattern(&mut data);
+        write_at(&mut disk, 300, &data).unwrap();
+        let mut back = [0u8; 1500];
+        read_at(&mut disk, 300, &mut back).unwrap();
+        assert_eq!(back, data);
+        let mut small = [0u8; 10];
+        read_at(&mut disk, 295, &mut small).unwrap();
+        assert_eq!(small[..5], [0; 5]);
+        assert_eq!(small[5..], data[..5]);
+        let image = disk.into_inner();
+        assert!(image[..300].iter().all(|&b| b == 0));
+        assert!(image[1800..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn failed_head_read_writes_nothing() {
+        let mut image = [0xAAu8; 4 * 512];
+        let mut dev = ReadFails(RamDisk::new(&mut image));
+        let ret = write_at(&mut dev, 100, &[1; 50]);
+        assert!(matches!(ret, Err(BlockError::Card(_))));
+        assert_eq!(dev.0.block_writes(), 0);
+        assert!(dev.0.into_inner().iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn failed_tail_read_keeps_the_tail_block() {
+        let mut image = [0xAAu8; 4 * 512];
+        let mut dev = ReadFails(RamDisk::new(&mut image));
+        let ret = write_at(&mut dev, 0, &[1; 700]);
+        assert!(matches!(ret, Err(BlockError::Card(_))));
+        // the aligned block went out before the tail read failed
+        let image = dev.0.into_inner();
+        assert!(image[..512].iter().all(|&b| b == 1));
+        assert!(image[512..].iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn failed_read_is_reported() {
+        let mut image = [0u8; 4 * 512];
+        let mut dev = ReadFails(RamDisk::new(&mut image));
+        let mut buf = [0u8; 10];
+        assert!(read_at(&mut dev, 3, &mut buf).is_err());
+    }
+}
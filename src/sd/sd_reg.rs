@@ -1,4 +1,6 @@
-use core::{fmt::Debug, str};
+use core::{fmt::Debug, str, time::Duration};
+
+use bitflags::bitflags;
 
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -152,6 +154,10 @@ impl From<u32> for Ocr {
     }
 }
 impl Ocr {
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
     pub fn is_busy(&self) -> bool {
         self.0 & 0x8000_0000 == 0
     }
@@ -242,27 +248,82 @@ impl From<u128> for Cid {
     }
 }
 
+/// Known SD card manufacturer IDs, as reported in the CID MID field.
+const MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "Panasonic"),
+    (0x02, "Toshiba"),
+    (0x03, "SanDisk"),
+    (0x06, "Ritek"),
+    (0x09, "ATP"),
+    (0x13, "Kingmax"),
+    (0x19, "Dynacard"),
+    (0x1A, "Power Quotient"),
+    (0x1B, "Samsung"),
+    (0x1D, "ADATA"),
+    (0x27, "Phison"),
+    (0x28, "Lexar"),
+    (0x31, "Silicon Power"),
+    (0x41, "Kingston"),
+    (0x51, "STEC"),
+    (0x5D, "Swissbit"),
+    (0x61, "Netlist"),
+    (0x63, "Cactus"),
+    (0x73, "Bongiovi"),
+    (0x74, "Transcend"),
+    (0x76, "Patriot"),
+    (0x82, "Sony"),
+    (0x9C, "Angelbird"),
+];
+
+/// Name of the manufacturer with the given CID MID, if known.
+pub fn manufacturer_name(id: u8) -> Option<&'static str> {
+    MANUFACTURERS
+        .iter()
+        .find(|(mid, _)| *mid == id)
+        .map(|(_, name)| *name)
+}
+
 impl Cid {
+    pub fn raw(&self) -> u128 {
+        self.inner
+    }
+
     pub fn manufacturer_id(&self) -> u8 {
         self.bytes[0]
     }
+
+    pub fn manufacturer(&self) -> &'static str {
+        manufacturer_name(self.manufacturer_id()).unwrap_or("Unknown")
+    }
+
+    pub fn oem_id_raw(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[1], self.bytes[2]])
+    }
     #[allow(unused)]
     pub fn crc7(&self) -> u8 {
         (self.bytes[15] >> 1) & 0x7F
     }
 
     pub fn oem_id(&self) -> &str {
-        str::from_utf8(&self.bytes[1..3]).unwrap_or(&"<ERR>")
+        str::from_utf8(&self.bytes[1..3]).unwrap_or("<ERR>")
     }
 
     pub fn product_name(&self) -> &str {
-        str::from_utf8(&self.bytes[3..8]).unwrap_or(&"<ERR>")
+        str::from_utf8(&self.bytes[3..8]).unwrap_or("<ERR>")
     }
 
     pub fn product_revision(&self) -> u8 {
         self.bytes[8]
     }
 
+    pub fn hardware_revision(&self) -> u8 {
+        self.product_revision() >> 4
+    }
+
+    pub fn firmware_revision(&self) -> u8 {
+        self.product_revision() & 0xF
+    }
+
     pub fn serial(&self) -> u32 {
         (self.inner >> 24) as u32
     }
@@ -279,6 +340,7 @@ impl Debug for Cid {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CID: Card Identification")
             .field("Manufacturer ID", &self.manufacturer_id())
+            .field("Manufacturer", &self.manufacturer())
             .field("OEM ID", &self.oem_id())
             .field("Product Name", &self.product_name())
             .field("Product Revision", &self.product_revision())
@@ -287,6 +349,49 @@ impl Debug for Cid {
             .finish()
     }
 }
+bitflags! {
+    /// Card Command Classes supported by the card (CSD CCC field).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CommandClasses: u16 {
+        const basic = 0b1;
+        const block_read = 0b1 << 2;
+        const block_write = 0b1 << 4;
+        const erase = 0b1 << 5;
+        const write_protection = 0b1 << 6;
+        const lock_card = 0b1 << 7;
+        const application_specific = 0b1 << 8;
+        const io_mode = 0b1 << 9;
+        const switch = 0b1 << 10;
+        const extension = 0b1 << 11;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FileFormat {
+    HardDisk,
+    Floppy,
+    Universal,
+    Other,
+    Reserved,
+}
+
+/// CSD_STRUCTURE field.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CsdStructure {
+    /// CSD Version 1.0, Standard Capacity
+    V1,
+    /// CSD Version 2.0, High Capacity and Extended Capacity
+    V2,
+    /// CSD Version 3.0, Ultra Capacity (SDUC)
+    V3,
+    Reserved,
+}
+
+/// Mantissa of TAAC and TRAN_SPEED, multiplied by 10.
+const TIME_VALUE: [u32; 16] = [
+    0, 10, 12, 13, 15, 20, 25, 30, 35, 40, 45, 50, 55, 60, 70, 80,
+];
+
 #[derive(Copy, Clone, Default)]
 pub struct Csd(u128);
 impl From<(u32, u32, u32, u32)> for Csd {
@@ -299,15 +404,103 @@ impl From<(u32, u32, u32, u32)> for Csd {
     }
 }
 
+impl From<u128> for Csd {
+    fn from(value: u128) -> Self {
+        Self(value)
+    }
+}
+
 impl Csd {
     pub fn version(&self) -> u8 {
         (self.0 >> 126) as u8 & 3
     }
 
+    pub fn structure(&self) -> CsdStructure {
+        match self.version() {
+            0 => CsdStructure::V1,
+            1 => CsdStructure::V2,
+            2 => CsdStructure::V3,
+            _ => CsdStructure::Reserved,
+        }
+    }
+
+    pub fn raw(&self) -> u128 {
+        self.0
+    }
+
     pub fn transfer_rate(&self) -> u8 {
         (self.0 >> 96) as u8
     }
 
+    /// Maximum data transfer rate per data line, in Hz.
+    pub fn transfer_rate_hz(&self) -> u32 {
+        let rate = self.transfer_rate();
+        let unit = match rate & 0x7 {
+            0 => 100_000,
+            1 => 1_000_000,
+            2 => 10_000_000,
+            3 => 100_000_000,
+            _ => 0,
+        };
+        unit / 10 * TIME_VALUE[(rate >> 3) as usize & 0xF]
+    }
+
+    /// Raw TAAC field, the asynchronous part of the data access time.
+    pub fn taac(&self) -> u8 {
+        (self.0 >> 112) as u8
+    }
+
+    /// Asynchronous part of the data access time, in nanoseconds.
+    pub fn taac_ns(&self) -> u32 {
+        let taac = self.taac();
+        let unit = 10u32.pow(u32::from(taac & 0x7));
+        unit * TIME_VALUE[(taac >> 3) as usize & 0xF] / 10
+    }
+
+    /// Raw NSAC field, the clock dependent part of the data access time.
+    pub fn nsac(&self) -> u8 {
+        (self.0 >> 104) as u8
+    }
+
+    /// Clock dependent part of the data access time, in card clock cycles.
+    pub fn nsac_clocks(&self) -> u32 {
+        u32::from(self.nsac()) * 100
+    }
+
+    /// Typical block program time as a multiple of the read access time.
+    pub fn r2w_factor(&self) -> u8 {
+        1 << ((self.0 >> 26) as u8 & 0x7)
+    }
+
+    pub fn command_classes(&self) -> CommandClasses {
+        CommandClasses::from_bits_truncate((self.0 >> 84) as u16 & 0xFFF)
+    }
+
+    pub fn file_format(&self) -> FileFormat {
+        if (self.0 >> 15) & 1 != 0 {
+            return FileFormat::Reserved;
+        }
+        match (self.0 >> 10) & 0x3 {
+            0 => FileFormat::HardDisk,
+            1 => FileFormat::Floppy,
+            2 => FileFormat::Universal,
+            _ => FileFormat::Other,
+        }
+    }
+
+    /// The content has been copied, the card is not the original.
+    pub fn copy(&self) -> bool {
+        (self.0 >> 14) & 1 != 0
+    }
+
+    pub fn perm_write_protect(&self) -> bool {
+        (self.0 >> 13) & 1 != 0
+    }
+
+    pub fn tmp_write_protect(&self) -> bool {
+        (self.0 >> 12) & 1 != 0
+    }
+
     pub fn block_length(&self) -> BlockSize {
         // Read block length
         match (self.0 >> 80) & 0xF {
@@ -346,31 +539,74 @@ impl Csd {
         CurrentConsumption::from_maximum_reg((self.0 >> 50) & 0x7)
     }
 
+    /// Number of blocks of `block_length()` bytes.
     pub fn block_count(&self) -> u64 {
-        match self.version() {
-            0 => {
-                // SDSC
+        match self.structure() {
+            CsdStructure::V1 => {
+                // SDSC: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of READ_BL_LEN
                 let c_size: u16 = ((self.0 >> 62) as u16) & 0xFFF;
                 let c_size_mult: u8 = ((self.0 >> 47) as u8) & 7;
 
                 ((c_size + 1) as u64) * ((1 << (c_size_mult + 2)) as u64)
             }
-            1 => {
-                // SDHC/SDXC
+            CsdStructure::V2 => {
+                // SDHC/SDXC: (C_SIZE + 1) * 512 KiB
                 (((self.0 >> 48) as u64 & 0x3F_FFFF) + 1) * 1024
             }
-            2 => {
-                // SDUC
+            CsdStructure::V3 => {
+                // SDUC: (C_SIZE + 1) * 512 KiB
                 (((self.0 >> 48) as u64 & 0xFFF_FFFF) + 1) * 1024
             }
-            _ => 0,
+            CsdStructure::Reserved => 0,
         }
     }
 
     pub fn card_size(&self) -> u64 {
-        let block_size_bytes = 1 << self.block_length() as u64;
+        match self.structure() {
+            // READ_BL_LEN is fixed to 512 bytes from version 2.0 on
+            CsdStructure::V1 => self.block_count() << (self.block_length() as u64),
+            _ => self.block_count() * 512,
+        }
+    }
 
-        self.block_count() * block_size_bytes
+    /// Capacity in 512 byte sectors, the unit used to address the card.
+    pub fn sector_count(&self) -> u64 {
+        self.card_size() / 512
+    }
+
+    /// Read data timeout at the given card clock (SD spec 4.6.2.1).
+    ///
+    /// SDSC cards use 100 times the access time from TAAC/NSAC, capped at
+    /// 100 ms. High capacity cards have a fixed 100 ms timeout.
+    pub fn read_timeout(&self, clock_hz: u32) -> Duration {
+        let max = Duration::from_millis(100);
+        match self.structure() {
+            CsdStructure::V1 if clock_hz != 0 => {
+                let nsac_ns = u64::from(self.nsac_clocks()) * 1_000_000_000 / u64::from(clock_hz);
+                let access_ns = u64::from(self.taac_ns()) + nsac_ns;
+                Duration::from_nanos(access_ns * 100).min(max)
+            }
+            _ => max,
+        }
+    }
+
+    /// Write data (busy) timeout at the given card clock (SD spec 4.6.2.2).
+    ///
+    /// SDSC cards use the read timeout times R2W_FACTOR, capped at 250 ms.
+    /// SDHC cards have a fixed 250 ms timeout, SDXC/SDUC cards 500 ms.
+    pub fn write_timeout(&self, clock_hz: u32) -> Duration {
+        match self.structure() {
+            CsdStructure::V1 => (self.read_timeout(clock_hz) * u32::from(self.r2w_factor()))
+                .min(Duration::from_millis(250)),
+            // SDXC starts above 32 GB
+            _ if self.card_size() > 32 << 30 => Duration::from_millis(500),
+            _ => Duration::from_millis(250),
+        }
+    }
+
+    /// The card is larger than 2 TB and needs CMD22 for the upper address bits.
+    pub fn is_sduc(&self) -> bool {
+        self.structure() == CsdStructure::V3
     }
 
     pub fn erase_size_blocks(&self) -> u32 {
@@ -389,7 +625,12 @@ impl Csd {
 impl Debug for Csd {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("CSD: Card Specific Data")
-            .field("Transfer Rate", &self.transfer_rate())
+            .field("Transfer Rate (Hz)", &self.transfer_rate_hz())
+            .field("TAAC (ns)", &self.taac_ns())
+            .field("NSAC (clocks)", &self.nsac_clocks())
+            .field("R2W Factor", &self.r2w_factor())
+            .field("Command Classes", &self.command_classes())
+            .field("Structure", &self.structure())
             .field("Block Count", &self.block_count())
             .field("Card Size (bytes)", &self.card_size())
             .field("Read I (@min VDD)", &self.read_current_minimum_vdd())
@@ -397,6 +638,10 @@ impl Debug for Csd {
             .field("Read I (@max VDD)", &self.read_current_maximum_vdd())
             .field("Write I (@max VDD)", &self.write_current_maximum_vdd())
             .field("Erase Size (Blocks)", &self.erase_size_blocks())
+            .field("File Format", &self.file_format())
+            .field("Copy", &self.copy())
+            .field("Permanent Write Protect", &self.perm_write_protect())
+            .field("Temporary Write Protect", &self.tmp_write_protect())
             .finish()
     }
 }
@@ -563,6 +808,13 @@ impl From<(u32, u32)> for Scr {
     }
 }
 
+impl From<[u8; 8]> for Scr {
+    fn from(value: [u8; 8]) -> Self {
+        // The SCR is sent MSB first on the data line
+        Self(u64::from_be_bytes(value))
+    }
+}
+
 impl Scr {
     pub fn version(&self) -> SDSpecVersion {
         let spec = (self.0 >> 56) & 0xF;
@@ -597,6 +849,20 @@ impl Scr {
     pub fn bus_width_four(&self) -> bool {
         (self.0 >> 50) & 1 != 0
     }
+
+    /// CMD20 (speed class control) is supported.
+    pub fn cmd20_support(&self) -> bool {
+        (self.0 >> 32) & 1 != 0
+    }
+
+    /// CMD23 (set block count) is supported.
+    pub fn cmd23_support(&self) -> bool {
+        (self.0 >> 33) & 1 != 0
+    }
+
+    pub fn raw(&self) -> u64 {
+        self.0
+    }
 }
 
 impl Debug for Scr {
@@ -605,6 +871,8 @@ impl Debug for Scr {
             .field("Version", &self.version())
             .field("1-bit width", &self.bus_width_one())
             .field("4-bit width", &self.bus_width_four())
+            .field("CMD20 support", &self.cmd20_support())
+            .field("CMD23 support", &self.cmd23_support())
             .finish()
     }
 }
@@ -628,6 +896,7 @@ impl Cic {
 }
 
 #[derive(Clone, Copy, Default)]
+#[allow(unused)]
 pub struct SdStatus {
     inner: [u32; 16],
 }
@@ -638,6 +907,7 @@ impl From<[u32; 16]> for SdStatus {
     }
 }
 
+#[allow(unused)]
 impl SdStatus {
     pub fn bus_width(&self) -> BusWidth {
         match (self.inner[15] >> 30) & 3 {
@@ -709,3 +979,162 @@ impl Debug for SdStatus {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CSD of a 2 GB SDSC card, with a 1024 byte READ_BL_LEN
+    const CSD_SDSC_2GB: u128 = 0x0026_0032_5f5a_83c4_6db7_ff9f_9640_0001;
+    /// CSD of a 32 GB SDHC card
+    const CSD_SDHC_32GB: u128 = 0x400e_0032_5b59_0000_edc8_7f80_0a40_4001;
+    /// CSD of a 4 TiB SDUC card
+    const CSD_SDUC_4TB: u128 = 0x800e_0032_5b59_007f_ffff_7f80_0a40_4001;
+    /// CID of a SanDisk "SD32G", made in October 2020
+    const CID_SANDISK: u128 = 0x0353_4453_4433_3247_8012_3456_7801_4a01;
+
+    #[test]
+    fn csd_from_response_words() {
+        let words = (0x0a40_4001, 0xedc8_7f80, 0x5b59_0000, 0x400e_0032);
+        assert_eq!(Csd::from(words).raw(), CSD_SDHC_32GB);
+    }
+
+    #[test]
+    fn csd_v1_sdsc() {
+        let csd = Csd::from(CSD_SDSC_2GB);
+        assert_eq!(csd.version(), 0);
+        assert_eq!(csd.taac_ns(), 1_500_000);
+        assert_eq!(csd.nsac_clocks(), 0);
+        assert_eq!(csd.transfer_rate_hz(), 25_000_000);
+        assert_eq!(csd.r2w_factor(), 32);
+        assert_eq!(csd.command_classes().bits(), 0x5F5);
+        assert_eq!(csd.block_length(), BlockSize::B1024);
+        // (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of 1024 bytes
+        assert_eq!(csd.block_count(), 3858 << 9);
+        assert_eq!(csd.card_size(), 2_022_703_104);
+        assert_eq!(csd.erase_size_blocks(), 1);
+        assert_eq!(csd.file_format(), FileFormat::HardDisk);
+        assert!(!csd.copy());
+        assert!(!csd.perm_write_protect() && !csd.tmp_write_protect());
+    }
+
+    #[test]
+    fn csd_v2_sdhc() {
+        let csd = Csd::from(CSD_SDHC_32GB);
+        assert_eq!(csd.version(), 1);
+        assert_eq!(csd.taac_ns(), 1_000_000);
+        assert_eq!(csd.nsac_clocks(), 0);
+        assert_eq!(csd.transfer_rate_hz(), 25_000_000);
+        assert_eq!(csd.r2w_factor(), 4);
+        let classes = csd.command_classes();
+        assert_eq!(classes.bits(), 0x5B5);
+        assert!(classes.contains(CommandClasses::application_specific | CommandClasses::switch));
+        assert!(!classes.contains(CommandClasses::write_protection));
+        // (C_SIZE + 1) * 512 KiB
+        assert_eq!(csd.block_count(), 60873 * 1024);
+        assert_eq!(csd.card_size(), 31_914_983_424);
+        assert!(csd.copy());
+    }
+
+    #[test]
+    fn csd_structures_and_sector_counts() {
+        let sdsc = Csd::from(CSD_SDSC_2GB);
+        assert_eq!(sdsc.structure(), CsdStructure::V1);
+        // 1024 byte blocks counted in 512 byte sectors
+        assert_eq!(sdsc.sector_count(), 3_950_592);
+        assert!(!sdsc.is_sduc());
+
+        let sdhc = Csd::from(CSD_SDHC_32GB);
+        assert_eq!(sdhc.structure(), CsdStructure::V2);
+        assert_eq!(sdhc.sector_count(), 62_333_952);
+        assert!(!sdhc.is_sduc());
+
+        // 28 bit C_SIZE, past the 22 bits of CSD 2.0
+        let sduc = Csd::from(CSD_SDUC_4TB);
+        assert_eq!(sduc.structure(), CsdStructure::V3);
+        assert_eq!(sduc.block_count(), 0x80_0000 * 1024);
+        assert_eq!(sduc.card_size(), 4 << 40);
+        assert_eq!(sduc.sector_count(), 1 << 33);
+        assert!(sduc.is_sduc());
+        let largest = Csd::from(CSD_SDUC_4TB | 0xFFF_FFFF << 48);
+        assert_eq!(largest.sector_count(), 1 << 38);
+
+        let reserved = Csd::from(CSD_SDHC_32GB | 3 << 126);
+        assert_eq!(reserved.structure(), CsdStructure::Reserved);
+        assert_eq!(reserved.sector_count(), 0);
+    }
+
+    #[test]
+    fn csd_access_time_fields() {
+        let with = |taac: u8, nsac: u8, r2w: u8| {
+            let raw = CSD_SDSC_2GB & !(0xFFFF << 104) & !(0x7 << 26);
+            Csd::from(
+                raw | u128::from(taac) << 112 | u128::from(nsac) << 104 | u128::from(r2w) << 26,
+            )
+        };
+        // 1.0 ns
+        assert_eq!(with(0x08, 0, 0).taac_ns(), 1);
+        // 2.5 us
+        assert_eq!(with(0x33, 0, 0).taac_ns(), 2_500);
+        // 8.0 * 10 ms, the largest TAAC
+        assert_eq!(with(0x7F, 0, 0).taac_ns(), 80_000_000);
+        assert_eq!(with(0, 0xFF, 0).nsac_clocks(), 25_500);
+        assert_eq!(with(0, 0, 0).r2w_factor(), 1);
+        assert_eq!(with(0, 0, 5).r2w_factor(), 32);
+    }
+
+    #[test]
+    fn sdsc_timeouts_follow_taac_nsac_and_r2w_factor() {
+        // TAAC 200 us, NSAC 100 clocks, R2W_FACTOR 4
+        let raw = CSD_SDSC_2GB & !(0xFFFF << 104) & !(0x7 << 26);
+        let csd = Csd::from(raw | 0x2D01 << 104 | 2 << 26);
+        // 100 * (200 us + 100 clocks at 25 MHz)
+        assert_eq!(csd.read_timeout(25_000_000), Duration::from_micros(20_400));
+        assert_eq!(csd.write_timeout(25_000_000), Duration::from_micros(81_600));
+        // the clock dependent part grows at the identification clock
+        assert_eq!(csd.read_timeout(400_000), Duration::from_millis(45));
+        assert_eq!(csd.write_timeout(400_000), Duration::from_millis(180));
+        // no clock known yet, the spec maximums
+        assert_eq!(csd.read_timeout(0), Duration::from_millis(100));
+        assert_eq!(csd.write_timeout(0), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn timeouts_are_capped_at_the_spec_maximums() {
+        // 100 * 1.5 ms read access time, R2W_FACTOR 32
+        let sdsc = Csd::from(CSD_SDSC_2GB);
+        assert_eq!(sdsc.read_timeout(25_000_000), Duration::from_millis(100));
+        assert_eq!(sdsc.write_timeout(25_000_000), Duration::from_millis(250));
+
+        let sdhc = Csd::from(CSD_SDHC_32GB);
+        assert_eq!(sdhc.read_timeout(50_000_000), Duration::from_millis(100));
+        assert_eq!(sdhc.write_timeout(50_000_000), Duration::from_millis(250));
+        // a slower TAAC does not matter past SDSC
+        let slow = Csd::from(CSD_SDHC_32GB | 0x7F << 112);
+        assert_eq!(slow.read_timeout(50_000_000), Duration::from_millis(100));
+
+        // 64 GB SDXC and SDUC have a 500 ms write timeout
+        let sdxc = Csd::from(CSD_SDHC_32GB & !(0x3F_FFFF << 48) | 121_811 << 48);
+        assert_eq!(sdxc.sector_count(), 124_735_488);
+        assert_eq!(sdxc.read_timeout(50_000_000), Duration::from_millis(100));
+        assert_eq!(sdxc.write_timeout(50_000_000), Duration::from_millis(500));
+        let sduc = Csd::from(CSD_SDUC_4TB);
+        assert_eq!(sduc.write_timeout(50_000_000), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn cid_fields() {
+        let cid = Cid::from(CID_SANDISK);
+        assert_eq!(cid.manufacturer_id(), 0x03);
+        assert_eq!(cid.manufacturer(), "SanDisk");
+        assert_eq!(cid.oem_id(), "SD");
+        assert_eq!(cid.product_name(), "SD32G");
+        assert_eq!(cid.hardware_revision(), 8);
+        assert_eq!(cid.firmware_revision(), 0);
+        assert_eq!(cid.serial(), 0x1234_5678);
+        assert_eq!(cid.manufacturing_date(), (10, 2020));
+        assert_eq!(manufacturer_name(0x1B), Some("Samsung"));
+        assert_eq!(manufacturer_name(0xFF), None);
+        assert_eq!(Cid::from(0).manufacturer(), "Unknown");
+    }
+}
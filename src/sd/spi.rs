@@ -0,0 +1,1037 @@
+//! SD card access over SPI.
+//!
+//! Some carrier boards route a microSD slot to a SPI controller instead of an
+//! SDIO host. [`SpiHost`] speaks the SPI mode of the SD protocol over any
+//! [`SpiBus`]; once initialized it gives a [`SpiCard`] with the same block API
+//! as [`SdCard<Transfer>`](super::SdCard), and the same `sd_reg` registers.
+use core::time::Duration;
+
+use log::{debug, info};
+
+use crate::block::{BlockDevice, BlockError};
+use crate::timer::{delay, Timer};
+
+use super::err::{CardError, Interrupt, Timeout, TransitionError};
+use super::info::CardInfo;
+use super::quirks::{self, Quirk, QuirkFlags, Quirks};
+use super::reg::BLKSIZ_DEFAULT;
+use super::rmw::{self, block_count};
+use super::sd_reg::{CardStatus, Cic, Cid, CommandClasses, Csd, CurrentState, Ocr, Scr};
+
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SEND_CID: u8 = 10;
+const STOP_TRANSMISSION: u8 = 12;
+const SEND_STATUS: u8 = 13;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const ERASE_WR_BLK_START: u8 = 32;
+const ERASE_WR_BLK_END: u8 = 33;
+const ERASE: u8 = 38;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const CRC_ON_OFF: u8 = 59;
+const ACMD_SET_WR_BLK_ERASE_COUNT: u8 = 23;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+const ACMD_SEND_SCR: u8 = 51;
+
+/// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ERASE_RESET: u8 = 0x02;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_COM_CRC: u8 = 0x08;
+const R1_ERASE_SEQ: u8 = 0x10;
+const R1_ADDRESS: u8 = 0x20;
+const R1_PARAMETER: u8 = 0x40;
+
+/// Data tokens
+const TOKEN_START_BLOCK: u8 = 0xFE;
+const TOKEN_START_MULTI_WRITE: u8 = 0xFC;
+const TOKEN_STOP_TRAN: u8 = 0xFD;
+
+/// Data error token, `000xxxxx`: card locked, out of range, card ECC
+/// failed, CC error, error
+const DATA_ERROR_MAX: u8 = 0x1F;
+const DATA_ERROR_OUT_OF_RANGE: u8 = 0x08;
+
+/// Data response token, `xxx0sss1`
+const DATA_RESP_MASK: u8 = 0x1F;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_CRC_ERR: u8 = 0x0B;
+
+/// Bytes to wait for a response after the command (N_CR)
+const NCR_MAX: usize = 8;
+const CMD_TIMEOUT: Duration = Duration::from_millis(500);
+const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Erase timeout per block when the card does not report one (SD spec 4.14)
+const ERASE_TIMEOUT_PER_BLOCK: Duration = Duration::from_millis(250);
+
+/// A full duplex SPI bus with the card as the only device on its chip select.
+pub trait SpiBus {
+    /// Shift `byte` out on MOSI and return the byte clocked in on MISO.
+    fn transfer(&mut self, byte: u8) -> u8;
+    /// Drive the chip select of the card, `true` pulls CS low.
+    fn select(&mut self, selected: bool);
+    /// Set the SCK frequency in Hz.
+    fn set_clock(&mut self, hz: u32);
+
+    fn write(&mut self, buf: &[u8]) {
+        for byte in buf {
+            self.transfer(*byte);
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.transfer(0xFF);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpiConfig {
+    /// SCK during identification, 400 kHz at most
+    pub init_clock_hz: u32,
+    /// Upper bound of SCK once the card is initialized, TRAN_SPEED of the
+    /// card limits it too
+    pub max_clock_hz: u32,
+    /// Check CRCs of commands and data (CMD59)
+    pub crc: bool,
+    /// Board specific quirks, looked at before the built-in table
+    pub quirks: &'static [Quirk],
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            init_clock_hz: 400_000,
+            max_clock_hz: 25_000_000,
+            crc: true,
+            quirks: &[],
+        }
+    }
+}
+
+/// A card on a SPI bus that has not been initialized yet.
+pub struct SpiHost<B: SpiBus> {
+    bus: B,
+    config: SpiConfig,
+    quirks: Quirks,
+    read_timeout: Duration,
+    write_timeout: Duration,
+}
+
+/// An initialized card on a SPI bus.
+pub struct SpiCard<B: SpiBus> {
+    host: SpiHost<B>,
+    info: CardInfo,
+}
+
+impl<B: SpiBus> SpiHost<B> {
+    pub fn new(bus: B, config: SpiConfig) -> Self {
+        Self {
+            bus,
+            config,
+            quirks: Quirks::new(),
+            // spec maximums until the CSD is known
+            read_timeout: Duration::from_millis(100),
+            write_timeout: Duration::from_millis(500),
+        }
+    }
+
+    /// Give the bus back.
+    pub fn free(self) -> B {
+        self.bus
+    }
+
+    /// Switch the card to SPI mode and initialize it.
+    ///
+    /// On failure the host comes back with the error, so the bus can be
+    /// freed or the init retried.
+    pub fn init(mut self) -> Result<SpiCard<B>, TransitionError<Self>> {
+        match self.enumerate() {
+            Ok(info) => Ok(SpiCard { host: self, info }),
+            Err(error) => Err(TransitionError { host: self, error }),
+        }
+    }
+
+    fn enumerate(&mut self) -> Result<CardInfo, CardError> {
+        info!("init sd over spi...");
+        self.bus.set_clock(self.config.init_clock_hz);
+        // at least 74 clocks with CS high before the first command
+        self.bus.select(false);
+        for _ in 0..10 {
+            self.bus.transfer(0xFF);
+        }
+        self.go_idle()?;
+        let v2 = self.check_version()?;
+        if self.config.crc {
+            self.transaction(|host| host.command(CRC_ON_OFF, 1).and_then(check_r1))?;
+        }
+        self.check_op_cond(v2)?;
+        let ocr = self.transaction(|host| {
+            check_r1(host.command(READ_OCR, 0)?)?;
+            Ok(Ocr::from(host.read_u32()))
+        })?;
+        debug!("card is high capacity: {}", ocr.high_capacity());
+        let mut buf = [0u8; 16];
+        self.read_register(SEND_CID, &mut buf)?;
+        let cid = Cid::from(u128::from_be_bytes(buf));
+        debug!("{cid:?}");
+        self.quirks = quirks::lookup(&cid, self.config.quirks);
+        self.read_register(SEND_CSD, &mut buf)?;
+        let csd = Csd::from(u128::from_be_bytes(buf));
+        debug!("{csd:?}");
+        if !ocr.high_capacity() {
+            self.transaction(|host| {
+                host.command(SET_BLOCKLEN, BLKSIZ_DEFAULT)
+                    .and_then(check_r1)
+            })?;
+        }
+        let mut buf = [0u8; 8];
+        self.transaction(|host| {
+            check_r1(host.app_command(ACMD_SEND_SCR, 0)?)?;
+            host.read_data(&mut buf)
+        })?;
+        let scr = Scr::from(buf);
+        debug!("{scr:?}");
+        let mut clock_hz = csd.transfer_rate_hz().min(self.config.max_clock_hz);
+        if let Some(max) = self.quirks.max_clock_hz {
+            clock_hz = clock_hz.min(max);
+        }
+        self.bus.set_clock(clock_hz);
+        self.read_timeout = csd.read_timeout(clock_hz);
+        self.write_timeout = csd.write_timeout(clock_hz);
+        info!("sd over spi init success, {clock_hz} Hz");
+        Ok(CardInfo {
+            cid,
+            csd,
+            ocr,
+            scr,
+            rca: 0,
+        })
+    }
+
+    /// CMD0 with CS low puts the card in SPI mode.
+    fn go_idle(&mut self) -> Result<(), CardError> {
+        for _ in 0..10 {
+            match self.transaction(|host| host.command(GO_IDLE_STATE, 0)) {
+                Ok(R1_IDLE) => return Ok(()),
+                ret => debug!("CMD0: {ret:?}"),
+            }
+            delay(Duration::from_millis(10));
+        }
+        Err(CardError::CardInitErr)
+    }
+
+    /// CMD8, returns false for version 1.x cards which do not know it.
+    fn check_version(&mut self) -> Result<bool, CardError> {
+        let (r1, cic) = self.transaction(|host| {
+            let r1 = host.command(SEND_IF_COND, 0x1AA)?;
+            Ok((r1, Cic::from(host.read_u32())))
+        })?;
+        if r1 & R1_ILLEGAL_COMMAND != 0 {
+            debug!("sd version 1.x");
+            return Ok(false);
+        }
+        check_r1(r1)?;
+        if cic.voltage_accepted() == 1 && cic.pattern() == 0xAA {
+            debug!("sd version 2.0");
+            Ok(true)
+        } else {
+            Err(CardError::VoltagePattern)
+        }
+    }
+
+    /// ACMD41 until the card leaves the idle state.
+    fn check_op_cond(&mut self, hcs: bool) -> Result<(), CardError> {
+        let arg = if hcs { 1 << 30 } else { 0 };
+        let timer = Timer::start(INIT_TIMEOUT);
+        loop {
+            let r1 =
+                self.transaction(|host| check_r1(host.app_command(ACMD_SD_SEND_OP_COND, arg)?))?;
+            if r1 & R1_IDLE == 0 {
+                return Ok(());
+            }
+            if timer.timeout() {
+                return Err(CardError::CardInitErr);
+            }
+            delay(Duration::from_millis(10));
+        }
+    }
+
+    /// CMD9 / CMD10, the CSD and CID are sent as a 16 bytes data block.
+    fn read_register(&mut self, index: u8, buf: &mut [u8; 16]) -> Result<(), CardError> {
+        self.transaction(|host| {
+            check_r1(host.command(index, 0)?)?;
+            host.read_data(buf)
+        })
+    }
+
+    /// Run `f` with the card selected, then release MISO with one more byte.
+    fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, CardError>,
+    ) -> Result<T, CardError> {
+        self.bus.select(true);
+        let ret = f(self);
+        self.bus.select(false);
+        self.bus.transfer(0xFF);
+        ret
+    }
+
+    /// Wait for the card to release MISO, it holds it low while busy.
+    fn wait_ready(&mut self, dur: Duration) -> Result<(), CardError> {
+        let timer = Timer::start(dur);
+        while self.bus.transfer(0xFF) != 0xFF {
+            if timer.timeout() {
+                return Err(Timeout::WaitDataLine.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a command frame and return its R1 response.
+    fn command(&mut self, index: u8, arg: u32) -> Result<u8, CardError> {
+        // the card is not in SPI mode before CMD0, and CMD12 interrupts a read
+        if index != GO_IDLE_STATE && index != STOP_TRANSMISSION {
+            self.wait_ready(CMD_TIMEOUT)?;
+        }
+        let mut frame = [0u8; 6];
+        frame[0] = 0x40 | index;
+        frame[1..5].copy_from_slice(&arg.to_be_bytes());
+        frame[5] = crc7(&frame[..5]) << 1 | 1;
+        self.bus.write(&frame);
+        if index == STOP_TRANSMISSION {
+            // stuff byte
+            self.bus.transfer(0xFF);
+        }
+        for _ in 0..NCR_MAX {
+            let r1 = self.bus.transfer(0xFF);
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(Interrupt::ResponseTimeout.into())
+    }
+
+    fn app_command(&mut self, index: u8, arg: u32) -> Result<u8, CardError> {
+        check_r1(self.command(APP_CMD, 0)?)?;
+        self.command(index, arg)
+    }
+
+    /// Trailing 32 bits of an R3 / R7 response.
+    fn read_u32(&mut self) -> u32 {
+        let mut buf = [0u8; 4];
+        self.bus.read(&mut buf);
+        u32::from_be_bytes(buf)
+    }
+
+    /// Receive one data block: start token, data, CRC16.
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<(), CardError> {
+        let timer = Timer::start(self.read_timeout);
+        loop {
+            match self.bus.transfer(0xFF) {
+                TOKEN_START_BLOCK => break,
+                token @ 0x01..=DATA_ERROR_MAX => return Err(data_error(token)),
+                _ => {}
+            }
+            if timer.timeout() {
+                return Err(CardError::DataTransferTimeout);
+            }
+        }
+        self.bus.read(buf);
+        let mut crc = [0u8; 2];
+        self.bus.read(&mut crc);
+        if self.config.crc && u16::from_be_bytes(crc) != crc16(buf) {
+            return Err(Interrupt::DataCrc.into());
+        }
+        Ok(())
+    }
+
+    /// Send one data block and wait until the card has programmed it.
+    fn write_data(&mut self, token: u8, buf: &[u8]) -> Result<(), CardError> {
+        self.bus.transfer(token);
+        self.bus.write(buf);
+        self.bus.write(&crc16(buf).to_be_bytes());
+        match self.bus.transfer(0xFF) & DATA_RESP_MASK {
+            DATA_ACCEPTED => self.wait_ready(self.write_timeout),
+            DATA_CRC_ERR => Err(Interrupt::DataCrc.into()),
+            _ => Err(Interrupt::ResponseErr.into()),
+        }
+    }
+}
+
+impl<B: SpiBus> SpiCard<B> {
+    /// CID, CSD, OCR and SCR of the card.
+    pub fn card_info(&self) -> CardInfo {
+        self.info
+    }
+
+    /// Give the bus back.
+    pub fn free(self) -> B {
+        self.host.bus
+    }
+
+    /// Quirks applied to the card, from its CID.
+    pub fn quirks(&self) -> Quirks {
+        self.host.quirks
+    }
+
+    /// Card status (CMD13), the R2 response is mapped onto the SD mode bits.
+    pub fn status(&mut self) -> Result<CardStatus, CardError> {
+        self.host.transaction(|host| {
+            let r1 = host.command(SEND_STATUS, 0)?;
+            let r2 = host.bus.transfer(0xFF);
+            Ok(card_status(r1, r2))
+        })
+    }
+
+    /// Argument of a data command on `blocks` blocks from `addr`, checking
+    /// the whole range against the card size. SDSC cards are byte addressed,
+    /// SDHC/SDXC cards take the block number.
+    fn block_arg(&self, addr: u64, blocks: u64) -> Result<u32, CardError> {
+        let count = self.info.csd.sector_count();
+        let end = addr
+            .checked_add(blocks)
+            .ok_or(CardError::AddressOutOfRange)?;
+        if count != 0 && end > count {
+            return Err(CardError::AddressOutOfRange);
+        }
+        let addr = if self.info.ocr.high_capacity() {
+            Some(addr)
+        } else {
+            addr.checked_mul(BLKSIZ_DEFAULT as u64)
+        };
+        addr.and_then(|addr| u32::try_from(addr).ok())
+            .ok_or(CardError::AddressOutOfRange)
+    }
+
+    pub fn read_block(&mut self, addr: u64, buf: &mut [u8; 512]) -> Result<(), CardError> {
+        let arg = self.block_arg(addr, 1)?;
+        self.host.transaction(|host| {
+            check_r1(host.command(READ_SINGLE_BLOCK, arg)?)?;
+            host.read_data(buf)
+        })
+    }
+
+    pub fn write_block(&mut self, addr: u64, buf: &[u8; 512]) -> Result<(), CardError> {
+        let arg = self.block_arg(addr, 1)?;
+        self.host.transaction(|host| {
+            check_r1(host.command(WRITE_BLOCK, arg)?)?;
+            host.write_data(TOKEN_START_BLOCK, buf)
+        })
+    }
+
+    /// Read `buf.len() / 512` consecutive blocks starting at `addr` with CMD18,
+    /// terminated with CMD12.
+    pub fn read_blocks(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), CardError> {
+        let blocks = block_count(buf.len())?;
+        if blocks == 1 {
+            return self.read_block(
+                addr,
+                (&mut buf[..BLKSIZ_DEFAULT as usize]).try_into().unwrap(),
+            );
+        }
+        let arg = self.block_arg(addr, blocks.into())?;
+        self.host.transaction(|host| {
+            check_r1(host.command(READ_MULTIPLE_BLOCK, arg)?)?;
+            let ret = buf
+                .chunks_exact_mut(BLKSIZ_DEFAULT as usize)
+                .try_for_each(|block| host.read_data(block));
+            let stop = host.command(STOP_TRANSMISSION, 0).and_then(check_r1);
+            ret?;
+            stop?;
+            host.wait_ready(host.write_timeout)
+        })
+    }
+
+    /// Write `buf.len() / 512` consecutive blocks starting at `addr` with CMD25,
+    /// after telling the card how many blocks to pre-erase with ACMD23.
+    pub fn write_blocks(&mut self, addr: u64, buf: &[u8]) -> Result<(), CardError> {
+        let blocks = block_count(buf.len())?;
+        if blocks == 1 {
+            return self.write_block(addr, buf[..BLKSIZ_DEFAULT as usize].try_into().unwrap());
+        }
+        let arg = self.block_arg(addr, blocks.into())?;
+        self.host.transaction(|host| {
+            if !host.quirks.flags.contains(QuirkFlags::no_pre_erase) {
+                check_r1(host.app_command(ACMD_SET_WR_BLK_ERASE_COUNT, blocks)?)?;
+            }
+            check_r1(host.command(WRITE_MULTIPLE_BLOCK, arg)?)?;
+            let ret = buf
+                .chunks_exact(BLKSIZ_DEFAULT as usize)
+                .try_for_each(|block| host.write_data(TOKEN_START_MULTI_WRITE, block));
+            host.bus.transfer(TOKEN_STOP_TRAN);
+            // one byte before the card signals busy
+            host.bus.transfer(0xFF);
+            let busy = host.wait_ready(host.write_timeout);
+            ret.and(busy)
+        })
+    }
+
+    /// Wait until the card has programmed every block written so far.
+    pub fn flush(&mut self) -> Result<(), CardError> {
+        self.host
+            .transaction(|host| host.wait_ready(host.write_timeout))
+    }
+
+    /// Erase `count` blocks starting at `addr` with CMD32, CMD33 and CMD38.
+    pub fn erase(&mut self, addr: u64, count: u64) -> Result<(), CardError> {
+        if count == 0 {
+            return Ok(());
+        }
+        if !self
+            .info
+            .csd
+            .command_classes()
+            .contains(CommandClasses::erase)
+        {
+            return Err(CardError::UnsupportedCommand);
+        }
+        let end = addr
+            .checked_add(count - 1)
+            .ok_or(CardError::AddressOutOfRange)?;
+        let (start, end) = (self.block_arg(addr, count)?, self.block_arg(end, 1)?);
+        let timeout = ERASE_TIMEOUT_PER_BLOCK * u32::try_from(count).unwrap_or(u32::MAX);
+        self.host.transaction(|host| {
+            check_r1(host.command(ERASE_WR_BLK_START, start)?)?;
+            check_r1(host.command(ERASE_WR_BLK_END, end)?)?;
+            check_r1(host.command(ERASE, 0)?)?;
+            host.wait_ready(timeout)
+        })
+    }
+
+    /// Whether the CSD write protect bits are set.
+    pub fn is_write_protected(&self) -> bool {
+        self.info.csd.perm_write_protect() || self.info.csd.tmp_write_protect()
+    }
+
+    /// Read `buf.len()` bytes starting at byte `offset` of the card.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        rmw::read_at(self, offset, buf)
+    }
+
+    /// Write `buf` starting at byte `offset` of the card, partial blocks are
+    /// read, patched and written back.
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), BlockError> {
+        rmw::write_at(self, offset, buf)
+    }
+}
+
+impl<B: SpiBus> BlockDevice for SpiCard<B> {
+    fn block_size(&self) -> usize {
+        BLKSIZ_DEFAULT as usize
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.csd.sector_count()
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        Ok(SpiCard::read_blocks(self, lba, buf)?)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.is_write_protected() {
+            return Err(BlockError::ReadOnly);
+        }
+        Ok(SpiCard::write_blocks(self, lba, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(SpiCard::flush(self)?)
+    }
+
+    fn erase(&mut self, lba: u64, count: u64) -> Result<(), BlockError> {
+        if self.is_write_protected() {
+            return Err(BlockError::ReadOnly);
+        }
+        Ok(SpiCard::erase(self, lba, count)?)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.is_write_protected()
+    }
+}
+
+fn check_r1(r1: u8) -> Result<u8, CardError> {
+    if r1 & R1_ILLEGAL_COMMAND != 0 {
+        Err(CardError::UnsupportedCommand)
+    } else if r1 & R1_ADDRESS != 0 {
+        Err(CardError::AddressOutOfRange)
+    } else if r1 & R1_PARAMETER != 0 {
+        Err(CardError::InvalidArgument)
+    } else if r1 & (R1_COM_CRC | R1_ERASE_SEQ | R1_ERASE_RESET) != 0 {
+        Err(Interrupt::ResponseErr.into())
+    } else {
+        Ok(r1)
+    }
+}
+
+/// Data error token, sent instead of the start block token. A locked card
+/// and the card's own errors are response errors.
+fn data_error(token: u8) -> CardError {
+    if token & DATA_ERROR_OUT_OF_RANGE != 0 {
+        CardError::AddressOutOfRange
+    } else {
+        Interrupt::ResponseErr.into()
+    }
+}
+
+/// Map the SPI mode R1 + R2 status onto the SD mode card status.
+///
+/// SPI mode has no card states, an initialized card reports transfer.
+fn card_status(r1: u8, r2: u8) -> CardStatus {
+    const R1_BITS: [(u8, u32); 6] = [
+        (R1_ERASE_RESET, 1 << 13),
+        (R1_ILLEGAL_COMMAND, 1 << 22),
+        (R1_COM_CRC, 1 << 23),
+        (R1_ERASE_SEQ, 1 << 28),
+        (R1_ADDRESS, 1 << 30),
+        (R1_PARAMETER, 1 << 31),
+    ];
+    const R2_BITS: [u32; 8] = [
+        1 << 25,           // card is locked
+        1 << 24 | 1 << 15, // lock/unlock failed, wp erase skip
+        1 << 19,           // error
+        1 << 20,           // CC error
+        1 << 21,           // card ECC failed
+        1 << 26,           // wp violation
+        1 << 27,           // erase param
+        1 << 31 | 1 << 16, // out of range, csd overwrite
+    ];
+    let mut status = R1_BITS
+        .iter()
+        .filter(|(bit, _)| r1 & bit != 0)
+        .fold(0, |status, (_, flag)| status | flag);
+    status |= R2_BITS
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| r2 & (1 << i) != 0)
+        .fold(0, |status, (_, flag)| status | flag);
+    let state = if r1 & R1_IDLE != 0 {
+        CurrentState::Ready
+    } else {
+        status |= 0x100; // ready for data
+        CurrentState::Transfer
+    };
+    CardStatus::from(status | (state as u32) << 9)
+}
+
+/// CRC7 of a command frame, polynomial x^7 + x^3 + 1.
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        for i in (0..8).rev() {
+            let feedback = ((byte >> i) ^ (crc >> 6)) & 1;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC16-CCITT of a data block, polynomial x^16 + x^12 + x^5 + 1.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// CSD of a 2 GB SDSC card
+    const CSD_SDSC_2GB: u128 = 0x0026_0032_5f5a_83c4_6db7_ff9f_9640_0001;
+    /// CSD of a 32 GB SDHC card
+    const CSD_SDHC_32GB: u128 = 0x400e_0032_5b59_0000_edc8_7f80_0a40_4001;
+    const CID_SANDISK: u128 = 0x0353_4453_4433_3247_8012_3456_7801_4a01;
+    /// SD 3.0 SCR, 4 bit bus
+    const SCR: u64 = 0x0235_8000_0000_0000;
+    const OCR_SDSC: u32 = 0x80FF_8000;
+    const OCR_SDHC: u32 = 0xC0FF_8000;
+    const BLOCK: usize = BLKSIZ_DEFAULT as usize;
+    /// Size of the mock card, in blocks
+    const BLOCKS: usize = 8;
+
+    /// What a read command gets instead of its data block.
+    #[derive(Clone, Copy)]
+    enum ReadFault {
+        Token(u8),
+        Silence,
+    }
+
+    /// A card in SPI mode, answering the command frames it is sent.
+    struct MockCard {
+        v2: bool,
+        ocr: u32,
+        csd: u128,
+        selected: bool,
+        clocks: Vec<u32>,
+        /// Commands received, an ACMD follows its CMD55
+        commands: Vec<(u8, u32)>,
+        frame: Vec<u8>,
+        miso: VecDeque<u8>,
+        idle: bool,
+        app: bool,
+        /// ACMD41 answers still reporting idle
+        busy_polls: u32,
+        /// Next block streamed by CMD18
+        reading: Option<usize>,
+        /// Block written next and whether it is a CMD25
+        writing: Option<(usize, bool)>,
+        received: Option<Vec<u8>>,
+        fault: Option<ReadFault>,
+        data: Vec<u8>,
+    }
+
+    impl MockCard {
+        fn new(v2: bool, ocr: u32, csd: u128) -> Self {
+            Self {
+                v2,
+                ocr,
+                csd,
+                selected: false,
+                clocks: Vec::new(),
+                commands: Vec::new(),
+                frame: Vec::new(),
+                miso: VecDeque::new(),
+                idle: true,
+                app: false,
+                busy_polls: 1,
+                reading: None,
+                writing: None,
+                received: None,
+                fault: None,
+                data: (0..BLOCKS * BLOCK).map(|i| (i % 251) as u8).collect(),
+            }
+        }
+
+        fn sdsc() -> Self {
+            Self::new(false, OCR_SDSC, CSD_SDSC_2GB)
+        }
+
+        fn sdhc() -> Self {
+            Self::new(true, OCR_SDHC, CSD_SDHC_32GB)
+        }
+
+        fn lba(&self, arg: u32) -> usize {
+            if self.ocr == OCR_SDHC {
+                arg as usize
+            } else {
+                assert_eq!(arg as usize % BLOCK, 0, "unaligned SDSC address");
+                arg as usize / BLOCK
+            }
+        }
+
+        fn block(&self, lba: usize) -> &[u8] {
+            &self.data[lba * BLOCK..(lba + 1) * BLOCK]
+        }
+
+        fn execute(&mut self, index: u8, arg: u32) {
+            self.commands.push((index, arg));
+            let app = core::mem::take(&mut self.app);
+            let mut resp = vec![0xFF, if self.idle { R1_IDLE } else { 0 }];
+            match (app, index) {
+                (_, GO_IDLE_STATE) => {
+                    assert!(self.selected, "CMD0 with CS high");
+                    self.idle = true;
+                    resp[1] = R1_IDLE;
+                }
+                (_, SEND_IF_COND) if !self.v2 => resp[1] |= R1_ILLEGAL_COMMAND,
+                (_, SEND_IF_COND) => resp.extend(arg.to_be_bytes()),
+                (_, CRC_ON_OFF | SET_BLOCKLEN) => {}
+                (_, APP_CMD) => self.app = true,
+                (true, ACMD_SD_SEND_OP_COND) => match self.busy_polls {
+                    0 => {
+                        self.idle = false;
+                        resp[1] = 0;
+                    }
+                    _ => self.busy_polls -= 1,
+                },
+                (_, READ_OCR) => resp.extend(self.ocr.to_be_bytes()),
+                (_, SEND_CID) => resp.extend(data_block(&CID_SANDISK.to_be_bytes())),
+                (_, SEND_CSD) => resp.extend(data_block(&self.csd.to_be_bytes())),
+                (true, ACMD_SEND_SCR) => resp.extend(data_block(&SCR.to_be_bytes())),
+                (true, ACMD_SET_WR_BLK_ERASE_COUNT) => {}
+                (_, SEND_STATUS) => resp.push(0),
+                (_, READ_SINGLE_BLOCK) => match self.fault {
+                    Some(ReadFault::Token(token)) => resp.extend([0xFF, token]),
+                    Some(ReadFault::Silence) => {}
+                    None => resp.extend(data_block(self.block(self.lba(arg)))),
+                },
+                (_, READ_MULTIPLE_BLOCK) => self.reading = Some(self.lba(arg)),
+                (_, STOP_TRANSMISSION) => {
+                    self.reading = None;
+                    // stuff byte, R1, then busy
+                    resp = vec![0x3F, 0xFF, 0, 0, 0];
+                }
+                (_, WRITE_BLOCK) => self.writing = Some((self.lba(arg), false)),
+                (_, WRITE_MULTIPLE_BLOCK) => self.writing = Some((self.lba(arg), true)),
+                _ => resp[1] |= R1_ILLEGAL_COMMAND,
+            }
+            self.miso.extend(resp);
+        }
+
+        /// Take one byte of a data block written by the host.
+        fn receive(&mut self, byte: u8) {
+            let Some((lba, multi)) = self.writing else {
+                return;
+            };
+            let Some(block) = &mut self.received else {
+                match byte {
+                    TOKEN_START_BLOCK if !multi => self.received = Some(Vec::new()),
+                    TOKEN_START_MULTI_WRITE if multi => self.received = Some(Vec::new()),
+                    TOKEN_STOP_TRAN if multi => {
+                        self.writing = None;
+                        self.miso.extend([0xFF, 0, 0]);
+                    }
+                    0xFF => {}
+                    _ => panic!("unexpected data token {byte:#x}"),
+                }
+                return;
+            };
+            block.push(byte);
+            if block.len() < BLOCK + 2 {
+                return;
+            }
+            let block = self.received.take().unwrap();
+            if u16::from_be_bytes([block[BLOCK], block[BLOCK + 1]]) != crc16(&block[..BLOCK]) {
+                self.miso.push_back(0xE0 | DATA_CRC_ERR);
+                return;
+            }
+            self.data[lba * BLOCK..(lba + 1) * BLOCK].copy_from_slice(&block[..BLOCK]);
+            self.writing = multi.then_some((lba + 1, true));
+            // accepted, then busy while programming
+            self.miso.extend([0xE0 | DATA_ACCEPTED, 0, 0]);
+        }
+    }
+
+    /// Start token, `data` and its CRC16, one byte after the response.
+    fn data_block(data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, TOKEN_START_BLOCK];
+        bytes.extend_from_slice(data);
+        bytes.extend(crc16(data).to_be_bytes());
+        bytes
+    }
+
+    impl SpiBus for MockCard {
+        fn transfer(&mut self, byte: u8) -> u8 {
+            if !self.selected {
+                return 0xFF;
+            }
+            if self.miso.is_empty() && self.frame.is_empty() && byte == 0xFF {
+                if let Some(lba) = self.reading {
+                    self.reading = Some(lba + 1);
+                    let block = data_block(self.block(lba));
+                    self.miso.extend(block);
+                }
+            }
+            let out = self.miso.pop_front().unwrap_or(0xFF);
+            if self.writing.is_some() {
+                self.receive(byte);
+            } else if !self.frame.is_empty() || byte & 0xC0 == 0x40 {
+                self.frame.push(byte);
+                if self.frame.len() == 6 {
+                    let frame = core::mem::take(&mut self.frame);
+                    assert_eq!(frame[5], crc7(&frame[..5]) << 1 | 1, "command CRC");
+                    let arg = u32::from_be_bytes(frame[1..5].try_into().unwrap());
+                    self.execute(frame[0] & 0x3F, arg);
+                }
+            }
+            out
+        }
+
+        fn select(&mut self, selected: bool) {
+            self.selected = selected;
+            if !selected {
+                self.miso.clear();
+            }
+        }
+
+        fn set_clock(&mut self, hz: u32) {
+            self.clocks.push(hz);
+        }
+    }
+
+    fn init(card: MockCard, config: SpiConfig) -> SpiCard<MockCard> {
+        SpiHost::new(card, config).init().unwrap()
+    }
+
+    #[test]
+    fn crc7_of_command_frames() {
+        assert_eq!(crc7(&[0x40, 0, 0, 0, 0]) << 1 | 1, 0x95);
+        assert_eq!(crc7(&[0x48, 0, 0, 0x01, 0xAA]) << 1 | 1, 0x87);
+    }
+
+    #[test]
+    fn crc16_of_an_erased_block() {
+        assert_eq!(crc16(&[0xFF; 512]), 0x7FA1);
+    }
+
+    #[test]
+    fn sdhc_init_sequence() {
+        let card = init(MockCard::sdhc(), SpiConfig::default());
+        assert!(card.card_info().ocr.high_capacity());
+        assert_eq!(card.card_info().csd.sector_count(), 62_333_952);
+        let hcs = 1 << 30;
+        assert_eq!(
+            card.host.bus.commands,
+            [
+                (GO_IDLE_STATE, 0),
+                (SEND_IF_COND, 0x1AA),
+                (CRC_ON_OFF, 1),
+                (APP_CMD, 0),
+                (ACMD_SD_SEND_OP_COND, hcs),
+                (APP_CMD, 0),
+                (ACMD_SD_SEND_OP_COND, hcs),
+                (READ_OCR, 0),
+                (SEND_CID, 0),
+                (SEND_CSD, 0),
+                (APP_CMD, 0),
+                (ACMD_SEND_SCR, 0),
+            ]
+        );
+        assert_eq!(card.host.bus.clocks, [400_000, 25_000_000]);
+    }
+
+    #[test]
+    fn sdsc_init_sequence() {
+        let config = SpiConfig {
+            max_clock_hz: 20_000_000,
+            ..Default::default()
+        };
+        let card = init(MockCard::sdsc(), config);
+        assert!(!card.card_info().ocr.high_capacity());
+        // version 1.x: no HCS in ACMD41, 512 byte blocks set with CMD16
+        assert_eq!(
+            card.host.bus.commands,
+            [
+                (GO_IDLE_STATE, 0),
+                (SEND_IF_COND, 0x1AA),
+                (CRC_ON_OFF, 1),
+                (APP_CMD, 0),
+                (ACMD_SD_SEND_OP_COND, 0),
+                (APP_CMD, 0),
+                (ACMD_SD_SEND_OP_COND, 0),
+                (READ_OCR, 0),
+                (SEND_CID, 0),
+                (SEND_CSD, 0),
+                (SET_BLOCKLEN, 512),
+                (APP_CMD, 0),
+                (ACMD_SEND_SCR, 0),
+            ]
+        );
+        assert_eq!(card.host.bus.clocks, [400_000, 20_000_000]);
+    }
+
+    #[test]
+    fn single_block_read_and_write() {
+        for (mock, arg) in [(MockCard::sdhc(), 3), (MockCard::sdsc(), 3 * 512)] {
+            let mut card = init(mock, SpiConfig::default());
+            card.host.bus.commands.clear();
+            let mut buf = [0u8; 512];
+            card.read_block(3, &mut buf).unwrap();
+            assert_eq!(buf, card.host.bus.block(3));
+
+            buf.fill(0xA5);
+            card.write_block(3, &buf).unwrap();
+            assert_eq!(card.host.bus.block(3), [0xA5; 512]);
+            assert_eq!(
+                card.host.bus.commands,
+                [(READ_SINGLE_BLOCK, arg), (WRITE_BLOCK, arg)]
+            );
+        }
+    }
+
+    #[test]
+    fn multiple_block_read_and_write() {
+        let mut card = init(MockCard::sdhc(), SpiConfig::default());
+        card.host.bus.commands.clear();
+        let mut buf = [0u8; 3 * 512];
+        card.read_blocks(1, &mut buf).unwrap();
+        assert_eq!(buf[..], card.host.bus.data[512..4 * 512]);
+
+        let written: Vec<u8> = (0..3 * 512).map(|i| (i / 512 + 0x10) as u8).collect();
+        card.write_blocks(4, &written).unwrap();
+        assert_eq!(card.host.bus.data[4 * 512..7 * 512], written[..]);
+        card.read_blocks(4, &mut buf).unwrap();
+        assert_eq!(buf[..], written[..]);
+        assert_eq!(
+            card.host.bus.commands,
+            [
+                (READ_MULTIPLE_BLOCK, 1),
+                (STOP_TRANSMISSION, 0),
+                (APP_CMD, 0),
+                (ACMD_SET_WR_BLK_ERASE_COUNT, 3),
+                (WRITE_MULTIPLE_BLOCK, 4),
+                (READ_MULTIPLE_BLOCK, 4),
+                (STOP_TRANSMISSION, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn quirks_of_the_card_apply() {
+        static QUIRKS: [Quirk; 1] = [Quirk::new(
+            Quirks::new()
+                .max_clock_hz(12_500_000)
+                .flags(QuirkFlags::no_pre_erase),
+        )
+        .manufacturer(0x03)
+        .product("SD32G")];
+        let config = SpiConfig {
+            quirks: &QUIRKS,
+            ..Default::default()
+        };
+        let mut card = init(MockCard::sdhc(), config);
+        assert_eq!(card.host.bus.clocks, [400_000, 12_500_000]);
+        card.host.bus.commands.clear();
+        card.write_blocks(0, &[0x5A; 2 * 512]).unwrap();
+        assert_eq!(card.host.bus.commands, [(WRITE_MULTIPLE_BLOCK, 0)]);
+    }
+
+    #[test]
+    fn data_error_tokens() {
+        let mut card = init(MockCard::sdhc(), SpiConfig::default());
+        let mut buf = [0u8; 512];
+        card.host.bus.fault = Some(ReadFault::Token(0x10));
+        assert!(matches!(
+            card.read_block(0, &mut buf),
+            Err(CardError::InterruptErr(Interrupt::ResponseErr))
+        ));
+        card.host.bus.fault = Some(ReadFault::Token(0x08));
+        assert!(matches!(
+            card.read_block(0, &mut buf),
+            Err(CardError::AddressOutOfRange)
+        ));
+        card.host.bus.fault = Some(ReadFault::Silence);
+        assert!(matches!(
+            card.read_block(0, &mut buf),
+            Err(CardError::DataTransferTimeout)
+        ));
+    }
+}
@@ -0,0 +1,260 @@
+//! Card lifecycle typestates.
+//!
+//! The state of the card is part of the host type, so block I/O on a card
+//! that was never initialized or that is deselected does not compile:
+//!
+//! ```text
+//! SdHost<Uninit> --init()--> SdCard<Transfer> --deselect()--> SdCard<Standby>
+//!                                    ^----------------select()--------'
+//! ```
+//!
+//! `shutdown()` powers the card down and gives the `SdHost<Uninit>` back,
+//! `reinit()` power cycles and enumerates the card again from any state.
+//!
+//! Each transition still asks the card for its `CurrentState` with CMD13, so
+//! the driver notices when the card does not follow.
+use core::marker::PhantomData;
+
+use log::{debug, error};
+
+use crate::timer::{delay, Timer};
+
+use super::cmd::{deselect_card, select_card, send_status};
+use super::err::{CardError, TransitionError};
+use super::sd_reg::CurrentState;
+use super::SdHost;
+
+/// An initialized card, in state `S`.
+pub type SdCard<S> = SdHost<S>;
+
+/// Controller not initialized, no card enumerated yet.
+pub struct Uninit;
+/// Card enumerated but not selected, only accepts addressed commands.
+pub struct Standby;
+/// Card selected, ready for data transfers.
+pub struct Transfer;
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for super::Uninit {}
+    impl Sealed for super::Standby {}
+    impl Sealed for super::Transfer {}
+}
+
+/// Marker for the typestates of [`SdHost`].
+pub trait CardState: private::Sealed {}
+impl CardState for Uninit {}
+impl CardState for Standby {}
+impl CardState for Transfer {}
+
+impl<S: CardState> SdHost<S> {
+    pub(crate) fn into_state<T: CardState>(self) -> SdHost<T> {
+        SdHost {
+            base: self.base,
+            config: self.config,
+            rca: self.rca,
+            info: self.info,
+            quirks: self.quirks,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            cancel: self.cancel,
+            #[cfg(feature = "stats")]
+            stats: self.stats,
+            #[cfg(feature = "trace")]
+            trace: self.trace,
+            _state: PhantomData,
+        }
+    }
+
+    /// A fresh host on the same controller and configuration, the cancel
+    /// flag, the statistics and the trace are kept.
+    fn reset(self) -> SdHost<Uninit> {
+        SdHost {
+            cancel: self.cancel,
+            #[cfg(feature = "stats")]
+            stats: self.stats,
+            #[cfg(feature = "trace")]
+            trace: self.trace,
+            // SAFETY: the controller of `self`, which is consumed
+            ..unsafe { SdHost::new(self.base, self.config) }
+        }
+    }
+
+    /// Move to state `T` when `ret` is `Ok`, give the host back with the
+    /// error otherwise.
+    // the error carries the host, which is as large as the card itself
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn transition<T: CardState>(
+        self,
+        ret: Result<(), CardError>,
+    ) -> Result<SdHost<T>, TransitionError<Self>> {
+        match ret {
+            Ok(()) => Ok(self.into_state()),
+            Err(error) => Err(TransitionError { host: self, error }),
+        }
+    }
+
+    /// Power cycle the card, then reset the controller and enumerate the card
+    /// again, e.g. after a fatal error. The configuration is kept, and so is
+    /// the host when this fails too.
+    #[allow(clippy::result_large_err)]
+    pub fn reinit(mut self) -> Result<SdCard<Transfer>, TransitionError<SdHost<Uninit>>> {
+        if let Err(err) = self.power_off() {
+            debug!("power off before reinit: {err:?}");
+        }
+        delay(self.config.init_delay);
+        self.reset().init()
+    }
+
+    /// Ask the card for its state with CMD13.
+    pub(crate) fn card_state(&mut self) -> Result<CurrentState, CardError> {
+        let status = self.send_cmd(send_status(self.rca))?.card_status();
+        debug!("{status:?}");
+        Ok(status.state())
+    }
+
+    /// Check that the card reports `expected`.
+    pub(crate) fn expect_state(&mut self, expected: CurrentState) -> Result<(), CardError> {
+        match self.card_state()? {
+            state if state == expected => Ok(()),
+            state => Err(CardError::UnexpectedState(state)),
+        }
+    }
+}
+
+impl SdCard<Standby> {
+    /// State reported by the card (CMD13).
+    pub fn current_state(&mut self) -> Result<CurrentState, CardError> {
+        self.card_state()
+    }
+
+    /// Stop the clock and power the card down, the returned host can be
+    /// initialized again.
+    pub fn shutdown(mut self) -> SdHost<Uninit> {
+        if let Err(err) = self.power_off() {
+            error!("sdio power off failed: {err:?}");
+        }
+        self.reset()
+    }
+
+    /// Select the card with CMD7 so it accepts data transfers again.
+    #[allow(clippy::result_large_err)]
+    pub fn select(mut self) -> Result<SdCard<Transfer>, TransitionError<Self>> {
+        let ret = self
+            .send_cmd(select_card(self.rca))
+            .and_then(|_| self.expect_state(CurrentState::Transfer));
+        self.transition(ret)
+    }
+}
+
+impl SdCard<Transfer> {
+    /// State reported by the card (CMD13).
+    pub fn current_state(&mut self) -> Result<CurrentState, CardError> {
+        self.card_state()
+    }
+
+    /// Wait until the card has programmed every block written so far.
+    ///
+    /// The driver does not buffer writes, but the card keeps programming
+    /// after the last block was transferred.
+    pub fn flush(&mut self) -> Result<(), CardError> {
+        self.wait_for_data_line(self.write_timeout)?;
+        let timer = Timer::start(self.write_timeout);
+        while self.card_state()? == CurrentState::Programming {
+            if timer.timeout() {
+                return Err(CardError::DataTransferTimeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush outstanding writes, deselect the card (CMD7 with RCA 0), stop the
+    /// clock and power it down, e.g. before a reboot or kexec.
+    ///
+    /// Errors are logged, the card is powered down regardless.
+    pub fn shutdown(mut self) -> SdHost<Uninit> {
+        if let Err(err) = self.flush() {
+            error!("sd flush failed: {err:?}");
+        }
+        if let Err(err) = self.send_cmd(deselect_card()) {
+            error!("sd deselect failed: {err:?}");
+        }
+        self.into_state::<Standby>().shutdown()
+    }
+
+    /// Deselect the card with CMD7 and RCA 0, e.g. to share the bus or to
+    /// read its registers again.
+    #[allow(clippy::result_large_err)]
+    pub fn deselect(mut self) -> Result<SdCard<Standby>, TransitionError<Self>> {
+        let ret = self
+            .send_cmd(deselect_card())
+            .and_then(|_| self.expect_state(CurrentState::Standby));
+        self.transition(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+    use core::time::Duration;
+
+    use super::*;
+    use crate::sd::info::CardInfo;
+    use crate::sd::quirks::Quirks;
+    use crate::sd::SdConfig;
+
+    fn host() -> SdHost<Uninit> {
+        // SAFETY: the registers are never touched
+        let mut host = unsafe { SdHost::new(0x1000, SdConfig::default()) };
+        host.rca = 0xAAAA;
+        host
+    }
+
+    #[test]
+    fn transition_moves_the_host_on_success() {
+        let Ok(card) = host().transition::<Transfer>(Ok(())) else {
+            panic!("transition failed");
+        };
+        assert_eq!((card.base(), card.rca), (0x1000, 0xAAAA));
+    }
+
+    #[test]
+    fn failed_transition_gives_the_host_back() {
+        let error = CardError::UnexpectedState(CurrentState::Standby);
+        let Err(err) = host().transition::<Transfer>(Err(error)) else {
+            panic!("transition succeeded");
+        };
+        assert!(matches!(
+            err.error,
+            CardError::UnexpectedState(CurrentState::Standby)
+        ));
+        assert_eq!((err.host.base(), err.host.rca), (0x1000, 0xAAAA));
+        assert!(matches!(
+            CardError::from(err),
+            CardError::UnexpectedState(CurrentState::Standby)
+        ));
+    }
+
+    #[test]
+    fn reset_keeps_the_controller_and_forgets_the_card() {
+        static CANCEL: AtomicBool = AtomicBool::new(true);
+        let mut host = host();
+        host.config.clkdiv = 4;
+        host.info = Some(CardInfo::default());
+        host.quirks = Quirks::new().narrow_bus();
+        host.read_timeout = Duration::from_millis(3);
+        host.set_cancel(Some(&CANCEL));
+        #[cfg(feature = "stats")]
+        host.stats.command();
+
+        let host = host.into_state::<Standby>().reset();
+        assert_eq!((host.base(), host.config().clkdiv), (0x1000, 4));
+        assert_eq!(host.rca, 0);
+        assert!(host.card_info().is_none());
+        assert!(!host.quirks().narrow_bus);
+        assert_eq!(host.read_timeout, Duration::from_millis(100));
+        assert!(matches!(host.check_cancel(), Err(CardError::Cancelled)));
+        #[cfg(feature = "stats")]
+        assert_eq!(host.stats().commands, 1);
+    }
+}
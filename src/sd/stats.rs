@@ -0,0 +1,310 @@
+//! I/O performance counters and latency histograms.
+//!
+//! Every [`SdHost`] keeps its own counters. Only compiled with the `stats`
+//! feature, so the hot path is untouched otherwise. Latencies are measured
+//! with the `timer` tick counter.
+use core::time::Duration;
+
+use crate::timer::{read_tick, to_duration};
+
+use super::err::{CardError, Interrupt};
+use super::{CardState, SdHost};
+
+/// Number of histogram buckets. Bucket `i` counts requests that took less
+/// than `2^i` microseconds (and at least `2^(i-1)`), the last bucket
+/// collects everything slower.
+pub const LATENCY_BUCKETS: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    /// Fastest request, in ticks
+    pub min: usize,
+    /// Slowest request, in ticks
+    pub max: usize,
+    /// Sum of all requests, in ticks
+    pub total: u64,
+    pub buckets: [u32; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            min: 0,
+            max: 0,
+            total: 0,
+            buckets: [0; LATENCY_BUCKETS],
+        }
+    }
+
+    fn record(&mut self, ticks: usize) {
+        if self.count == 0 || ticks < self.min {
+            self.min = ticks;
+        }
+        if ticks > self.max {
+            self.max = ticks;
+        }
+        self.count += 1;
+        self.total += ticks as u64;
+        let us = to_duration(ticks).as_micros() as u64;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    pub fn min(&self) -> Duration {
+        to_duration(self.min)
+    }
+
+    pub fn max(&self) -> Duration {
+        to_duration(self.max)
+    }
+
+    pub fn avg(&self) -> Duration {
+        self.total
+            .checked_div(self.count)
+            .map_or(Duration::ZERO, |avg| to_duration(avg as usize))
+    }
+}
+
+/// Errors counted by kind.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorStats {
+    pub response_timeout: u64,
+    pub response: u64,
+    pub data_crc: u64,
+    pub data_timeout: u64,
+    pub fifo: u64,
+    pub hardware_lock: u64,
+    pub bit: u64,
+    pub wait_timeout: u64,
+    pub init: u64,
+    pub address: u64,
+    pub other: u64,
+}
+
+impl ErrorStats {
+    const fn new() -> Self {
+        Self {
+            response_timeout: 0,
+            response: 0,
+            data_crc: 0,
+            data_timeout: 0,
+            fifo: 0,
+            hardware_lock: 0,
+            bit: 0,
+            wait_timeout: 0,
+            init: 0,
+            address: 0,
+            other: 0,
+        }
+    }
+
+    fn record(&mut self, err: &CardError) {
+        match err {
+            CardError::InterruptErr(int) => match int {
+                Interrupt::ResponseTimeout => self.response_timeout += 1,
+                Interrupt::ResponseErr => self.response += 1,
+                Interrupt::DataCrc => self.data_crc += 1,
+                Interrupt::DataReadTimeout => self.data_timeout += 1,
+                Interrupt::Fifo => self.fifo += 1,
+                Interrupt::HardwareLock => self.hardware_lock += 1,
+                Interrupt::StartBitErr | Interrupt::EndBitErr => self.bit += 1,
+            },
+            CardError::TimeoutErr(_) => self.wait_timeout += 1,
+            CardError::DataTransferTimeout => self.data_timeout += 1,
+            CardError::CardInitErr | CardError::VoltagePattern => self.init += 1,
+            CardError::AddressOutOfRange => self.address += 1,
+            CardError::InvalidArgument
+            | CardError::UnsupportedCommand
+            | CardError::UnexpectedState(_)
+            | CardError::Cancelled => self.other += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.response_timeout
+            + self.response
+            + self.data_crc
+            + self.data_timeout
+            + self.fifo
+            + self.hardware_lock
+            + self.bit
+            + self.wait_timeout
+            + self.init
+            + self.address
+            + self.other
+    }
+}
+
+/// Snapshot of the device statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoStats {
+    pub commands: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Commands re-issued because of a hardware locked write error
+    pub retries: u64,
+    pub errors: ErrorStats,
+    pub read_latency: LatencyHistogram,
+    pub write_latency: LatencyHistogram,
+}
+
+impl IoStats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            commands: 0,
+            blocks_read: 0,
+            blocks_written: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            retries: 0,
+            errors: ErrorStats::new(),
+            read_latency: LatencyHistogram::new(),
+            write_latency: LatencyHistogram::new(),
+        }
+    }
+
+    pub(crate) fn command(&mut self) {
+        self.commands += 1;
+    }
+
+    pub(crate) fn retry(&mut self) {
+        self.retries += 1;
+    }
+
+    pub(crate) fn error(&mut self, err: &CardError) {
+        self.errors.record(err);
+    }
+
+    /// Tick counter to pass to [`read_done`](Self::read_done) / [`write_done`](Self::write_done).
+    pub(crate) fn start() -> usize {
+        read_tick()
+    }
+
+    pub(crate) fn read_done(&mut self, start: usize, blocks: usize, bytes: usize) {
+        self.blocks_read += blocks as u64;
+        self.bytes_read += bytes as u64;
+        self.read_latency.record(read_tick().wrapping_sub(start));
+    }
+
+    pub(crate) fn write_done(&mut self, start: usize, blocks: usize, bytes: usize) {
+        self.blocks_written += blocks as u64;
+        self.bytes_written += bytes as u64;
+        self.write_latency.record(read_tick().wrapping_sub(start));
+    }
+}
+
+impl<S: CardState> SdHost<S> {
+    /// Snapshot of the I/O counters and latency histograms.
+    pub fn stats(&self) -> IoStats {
+        self.stats
+    }
+
+    /// Clear the I/O counters and latency histograms.
+    pub fn reset_stats(&mut self) {
+        self.stats = IoStats::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd::err::Timeout;
+    use crate::sd::sd_reg::CurrentState;
+    use crate::sd::SdConfig;
+    use crate::timer::TIME_BASE;
+
+    /// Ticks in `us` microseconds.
+    fn ticks(us: usize) -> usize {
+        us * (TIME_BASE / 1_000_000)
+    }
+
+    #[test]
+    fn latency_buckets_are_powers_of_two() {
+        let mut histogram = LatencyHistogram::new();
+        for us in [0, 1, 2, 3, 4, 1000, 1023, 1024, 1 << 30] {
+            histogram.record(ticks(us));
+        }
+        let mut expected = [0; LATENCY_BUCKETS];
+        // below 1 us, [1, 2), [2, 4) twice, [4, 8), [512, 1024) twice,
+        // [1024, 2048), and the overflow bucket
+        for bucket in [0, 1, 2, 2, 3, 10, 10, 11, LATENCY_BUCKETS - 1] {
+            expected[bucket] += 1;
+        }
+        assert_eq!(histogram.buckets, expected);
+        assert_eq!(histogram.count, 9);
+        assert_eq!(histogram.min(), Duration::ZERO);
+        assert_eq!(histogram.max(), Duration::from_micros(1 << 30));
+    }
+
+    #[test]
+    fn latency_min_avg_max() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.avg(), Duration::ZERO);
+        for us in [300, 100, 200] {
+            histogram.record(ticks(us));
+        }
+        assert_eq!(histogram.min(), Duration::from_micros(100));
+        assert_eq!(histogram.avg(), Duration::from_micros(200));
+        assert_eq!(histogram.max(), Duration::from_micros(300));
+    }
+
+    #[test]
+    fn errors_are_counted_by_kind() {
+        let mut errors = ErrorStats::new();
+        for err in [
+            Interrupt::ResponseTimeout.into(),
+            Interrupt::ResponseErr.into(),
+            Interrupt::DataCrc.into(),
+            Interrupt::DataReadTimeout.into(),
+            CardError::DataTransferTimeout,
+            Interrupt::Fifo.into(),
+            Interrupt::HardwareLock.into(),
+            Interrupt::StartBitErr.into(),
+            Interrupt::EndBitErr.into(),
+            Timeout::WaitDataLine.into(),
+            CardError::VoltagePattern,
+            CardError::AddressOutOfRange,
+            CardError::UnexpectedState(CurrentState::Standby),
+            CardError::Cancelled,
+        ] {
+            errors.record(&err);
+        }
+        assert_eq!(errors.response_timeout, 1);
+        assert_eq!(errors.response, 1);
+        assert_eq!(errors.data_crc, 1);
+        assert_eq!(errors.data_timeout, 2);
+        assert_eq!(errors.fifo, 1);
+        assert_eq!(errors.hardware_lock, 1);
+        assert_eq!(errors.bit, 2);
+        assert_eq!(errors.wait_timeout, 1);
+        assert_eq!(errors.init, 1);
+        assert_eq!(errors.address, 1);
+        assert_eq!(errors.other, 2);
+        assert_eq!(errors.total(), 14);
+    }
+
+    #[test]
+    fn reset_clears_every_counter() {
+        // SAFETY: the registers are never touched
+        let mut host = unsafe { SdHost::new(0, SdConfig::default()) };
+        host.stats.command();
+        host.stats.retry();
+        host.stats.error(&CardError::Cancelled);
+        host.stats.read_latency.record(ticks(5));
+        let stats = host.stats();
+        assert_eq!((stats.commands, stats.retries), (1, 1));
+        assert_eq!(stats.errors.total(), 1);
+        assert_eq!(stats.read_latency.count, 1);
+
+        host.reset_stats();
+        let stats = host.stats();
+        assert_eq!((stats.commands, stats.retries), (0, 0));
+        assert_eq!(stats.errors.total(), 0);
+        assert_eq!(stats.read_latency.count, 0);
+        assert_eq!(stats.read_latency.buckets, [0; LATENCY_BUCKETS]);
+    }
+}
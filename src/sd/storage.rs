@@ -0,0 +1,151 @@
+//! `embedded-storage` integration.
+//!
+//! The cards are exposed as byte addressed storage on top of `read_at` and
+//! `write_at`, so partial blocks are read-modify-written. Offsets are `u32`
+//! in `embedded-storage`, only the first 4 GiB of a card are reachable.
+//! Errors are [`BlockError`]s, card errors come wrapped in them.
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::block::{BlockDevice, BlockError};
+
+use super::rmw;
+use super::spi::{SpiBus, SpiCard};
+use super::{SdCard, Transfer};
+
+fn check_range(capacity: u64, offset: u32, len: usize) -> Result<(), BlockError> {
+    match u64::from(offset).checked_add(len as u64) {
+        Some(end) if end <= capacity => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// The part of a card reachable through `u32` offsets.
+fn reachable(card_size: u64) -> usize {
+    card_size
+        .min(u64::from(u32::MAX) + 1)
+        .min(usize::MAX as u64) as usize
+}
+
+/// Size of `dev` in bytes.
+fn device_size<D: BlockDevice>(dev: &D) -> u64 {
+    dev.block_count() * dev.block_size() as u64
+}
+
+fn storage_read<D: BlockDevice>(
+    dev: &mut D,
+    offset: u32,
+    bytes: &mut [u8],
+) -> Result<(), BlockError> {
+    check_range(device_size(dev), offset, bytes.len())?;
+    rmw::read_at(dev, u64::from(offset), bytes)
+}
+
+fn storage_write<D: BlockDevice>(dev: &mut D, offset: u32, bytes: &[u8]) -> Result<(), BlockError> {
+    check_range(device_size(dev), offset, bytes.len())?;
+    rmw::write_at(dev, u64::from(offset), bytes)
+}
+
+impl ReadStorage for SdCard<Transfer> {
+    type Error = BlockError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        storage_read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        reachable(device_size(self))
+    }
+}
+
+impl Storage for SdCard<Transfer> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        storage_write(self, offset, bytes)
+    }
+}
+
+impl<B: SpiBus> ReadStorage for SpiCard<B> {
+    type Error = BlockError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        storage_read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        reachable(device_size(self))
+    }
+}
+
+impl<B: SpiBus> Storage for SpiCard<B> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        storage_write(self, offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ram::RamDisk;
+    use crate::sd::info::CardInfo;
+    use crate::sd::sd_reg::Csd;
+    use crate::sd::{SdConfig, SdHost};
+
+    const DISK: usize = 4 * 512;
+
+    fn out_of_range(ret: Result<(), BlockError>) -> bool {
+        matches!(ret, Err(BlockError::OutOfRange))
+    }
+
+    #[test]
+    fn ranges_end_at_the_device_size() {
+        let mut image = [0u8; DISK];
+        let mut disk = RamDisk::new(&mut image);
+        let mut buf = [0u8; 64];
+        assert!(storage_read(&mut disk, 0, &mut [0; DISK]).is_ok());
+        assert!(storage_read(&mut disk, DISK as u32 - 64, &mut buf).is_ok());
+        assert!(out_of_range(storage_read(
+            &mut disk,
+            DISK as u32 - 63,
+            &mut buf
+        )));
+        assert!(out_of_range(storage_write(&mut disk, u32::MAX, &buf)));
+        assert!(storage_write(&mut disk, DISK as u32, &[]).is_ok());
+        assert!(check_range(u64::from(u32::MAX) + 1, u32::MAX, 1).is_ok());
+        assert!(out_of_range(check_range(u64::MAX, u32::MAX, usize::MAX)));
+    }
+
+    #[test]
+    fn partial_blocks_round_trip() {
+        let mut image = [0u8; DISK];
+        let mut disk = RamDisk::new(&mut image);
+        storage_write(&mut disk, 510, b"across").unwrap();
+        let mut buf = [0u8; 10];
+        storage_read(&mut disk, 508, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0across\0\0");
+        assert_eq!(reachable(device_size(&disk)), DISK);
+    }
+
+    #[test]
+    fn capacity_is_clamped_to_u32_offsets() {
+        assert_eq!(reachable(2_022_703_104), 2_022_703_104);
+        assert_eq!(reachable(1 << 32), 1 << 32);
+        assert_eq!(reachable(31_914_983_424), 1 << 32);
+
+        // SAFETY: the registers are never touched
+        let host = unsafe { SdHost::new(0, SdConfig::default()) };
+        let mut card = host.into_state::<Transfer>();
+        assert_eq!(card.capacity(), 0);
+        // a 32 GB SDHC card
+        card.info = Some(CardInfo {
+            csd: Csd::from(0x400e_0032_5b59_0000_edc8_7f80_0a40_4001),
+            ..Default::default()
+        });
+        assert_eq!(card.capacity(), 1 << 32);
+        // a 2 GB SDSC card, the range is checked before any command is sent
+        card.info = Some(CardInfo {
+            csd: Csd::from(0x0026_0032_5f5a_83c4_6db7_ff9f_9640_0001),
+            ..Default::default()
+        });
+        assert_eq!(card.capacity(), 2_022_703_104);
+        assert!(out_of_range(card.read(2_022_703_100, &mut [0; 8])));
+    }
+}
@@ -0,0 +1,250 @@
+//! Command trace ring buffer.
+//!
+//! Every command issued to the card is recorded into a fixed-size ring buffer
+//! of its [`SdHost`] so that intermittent failures can be inspected after the
+//! fact with [`SdHost::dump_trace`] or [`SdHost::copy_trace`], instead of
+//! enabling `debug` logging on the UART.
+use core::fmt::{Debug, Display};
+
+use log::info;
+
+use crate::timer::{read_tick, to_duration};
+
+use super::cmd::{Command, Response};
+use super::err::CardError;
+use super::{CardState, SdHost};
+
+/// Number of commands kept in the trace, older entries are overwritten.
+pub const TRACE_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Default)]
+pub struct TraceEntry {
+    /// Command index (CMDx / ACMDx)
+    pub index: u8,
+    /// Command argument
+    pub arg: u32,
+    /// Value written to the CMD register
+    pub flags: u32,
+    /// Response words, RESP0..RESP3
+    pub resp: [u32; 4],
+    /// RINTSTS snapshot taken when the command completed
+    pub rintsts: u32,
+    /// STATUS snapshot taken when the command completed
+    pub status: u32,
+    /// Tick counter when the command was issued
+    pub timestamp: usize,
+    /// Ticks spent between issue and completion
+    pub duration: usize,
+    /// Error returned to the caller, if any
+    pub error: Option<CardError>,
+}
+
+impl Debug for TraceEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TraceEntry")
+            .field("index", &self.index)
+            .field("arg", &format_args!("{:#010x}", self.arg))
+            .field("flags", &format_args!("{:#010x}", self.flags))
+            .field("resp", &self.resp)
+            .field("rintsts", &format_args!("{:#010x}", self.rintsts))
+            .field("status", &format_args!("{:#010x}", self.status))
+            .field("timestamp", &self.timestamp)
+            .field("duration", &to_duration(self.duration))
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[{:>12}] CMD{:<2} arg={:#010x} flags={:#010x} resp=[{:#010x} {:#010x} {:#010x} {:#010x}] rintsts={:#010x} status={:#010x} {:?}",
+            self.timestamp,
+            self.index,
+            self.arg,
+            self.flags,
+            self.resp[0],
+            self.resp[1],
+            self.resp[2],
+            self.resp[3],
+            self.rintsts,
+            self.status,
+            to_duration(self.duration),
+        )?;
+        if let Some(err) = self.error {
+            write!(f, " err={err:?}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Trace {
+    entries: [TraceEntry; TRACE_DEPTH],
+    /// Next slot to be written
+    head: usize,
+    /// Number of valid entries
+    len: usize,
+    /// Entry of the command currently in flight
+    pending: TraceEntry,
+    enabled: bool,
+}
+
+impl Trace {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: [TraceEntry::default(); TRACE_DEPTH],
+            head: 0,
+            len: 0,
+            pending: TraceEntry::default(),
+            enabled: true,
+        }
+    }
+
+    fn get(&self, n: usize) -> &TraceEntry {
+        let oldest = (self.head + TRACE_DEPTH - self.len) % TRACE_DEPTH;
+        &self.entries[(oldest + n) % TRACE_DEPTH]
+    }
+
+    /// Start recording `cmd`, called right before it is written to the CMD register.
+    pub(crate) fn begin(&mut self, cmd: &Command) {
+        self.pending = TraceEntry {
+            index: (cmd.to_cmd() & 0x3F) as u8,
+            arg: cmd.arg(),
+            flags: cmd.to_cmd(),
+            timestamp: read_tick(),
+            ..Default::default()
+        };
+    }
+
+    /// Snapshot the controller state once the command is done.
+    pub(crate) fn snapshot(&mut self, rintsts: u32, status: u32) {
+        self.pending.rintsts = rintsts;
+        self.pending.status = status;
+    }
+
+    /// Finish the pending entry and push it into the ring.
+    pub(crate) fn end(&mut self, ret: &Result<Response, CardError>) {
+        if !self.enabled {
+            return;
+        }
+        let mut entry = self.pending;
+        entry.duration = read_tick().wrapping_sub(entry.timestamp);
+        match ret {
+            Ok(Response::R48(r)) => entry.resp[0] = *r,
+            Ok(Response::R136((r0, r1, r2, r3))) => entry.resp = [*r0, *r1, *r2, *r3],
+            Ok(Response::Rz) => {}
+            Err(err) => entry.error = Some(*err),
+        }
+        self.push(entry);
+    }
+
+    fn push(&mut self, entry: TraceEntry) {
+        self.entries[self.head] = entry;
+        self.head = (self.head + 1) % TRACE_DEPTH;
+        self.len = (self.len + 1).min(TRACE_DEPTH);
+    }
+}
+
+impl<S: CardState> SdHost<S> {
+    /// Enable or disable command tracing, the recorded entries are kept.
+    pub fn set_trace_enabled(&mut self, enabled: bool) {
+        self.trace.enabled = enabled;
+    }
+
+    /// Drop every recorded command.
+    pub fn clear_trace(&mut self) {
+        self.trace.head = 0;
+        self.trace.len = 0;
+    }
+
+    /// Number of commands currently recorded.
+    pub fn trace_len(&self) -> usize {
+        self.trace.len
+    }
+
+    /// Copy the recorded commands, oldest first, into `buf`.
+    ///
+    /// Returns the number of entries written. When `buf` is shorter than the
+    /// trace, the most recent entries are kept.
+    pub fn copy_trace(&self, buf: &mut [TraceEntry]) -> usize {
+        let trace = &self.trace;
+        let n = trace.len.min(buf.len());
+        let skip = trace.len - n;
+        for (i, slot) in buf.iter_mut().take(n).enumerate() {
+            *slot = *trace.get(skip + i);
+        }
+        n
+    }
+
+    /// Print the recorded commands, oldest first, through the serial logger.
+    pub fn dump_trace(&self) {
+        info!(
+            "sd command trace ({:#x}): {} entries",
+            self.base, self.trace.len
+        );
+        for i in 0..self.trace.len {
+            info!("{}", self.trace.get(i));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd::SdConfig;
+
+    fn entry(arg: u32) -> TraceEntry {
+        TraceEntry {
+            index: 17,
+            arg,
+            ..Default::default()
+        }
+    }
+
+    fn traced(pushed: u32) -> SdHost {
+        // SAFETY: the registers are never touched
+        let mut host = unsafe { SdHost::new(0, SdConfig::default()) };
+        for arg in 0..pushed {
+            host.trace.push(entry(arg));
+        }
+        host
+    }
+
+    fn args(entries: &[TraceEntry]) -> impl Iterator<Item = u32> + '_ {
+        entries.iter().map(|entry| entry.arg)
+    }
+
+    #[test]
+    fn ring_keeps_the_latest_entries() {
+        let host = traced(TRACE_DEPTH as u32 + 6);
+        assert_eq!(host.trace_len(), TRACE_DEPTH);
+        let mut buf = [TraceEntry::default(); TRACE_DEPTH + 1];
+        assert_eq!(host.copy_trace(&mut buf), TRACE_DEPTH);
+        assert!(args(&buf[..TRACE_DEPTH]).eq(6..TRACE_DEPTH as u32 + 6));
+    }
+
+    #[test]
+    fn copy_into_a_short_buffer_keeps_the_latest_entries() {
+        let host = traced(10);
+        let mut buf = [TraceEntry::default(); 4];
+        assert_eq!(host.copy_trace(&mut buf), 4);
+        assert!(args(&buf).eq(6..10));
+
+        // wrapped, oldest first across the end of the ring
+        let host = traced(TRACE_DEPTH as u32 + 2);
+        assert_eq!(host.copy_trace(&mut buf), 4);
+        assert!(args(&buf).eq(TRACE_DEPTH as u32 - 2..TRACE_DEPTH as u32 + 2));
+    }
+
+    #[test]
+    fn clear_and_disable() {
+        let mut host = traced(3);
+        host.clear_trace();
+        assert_eq!(host.trace_len(), 0);
+        assert_eq!(host.copy_trace(&mut [TraceEntry::default(); 2]), 0);
+        host.set_trace_enabled(false);
+        host.trace.end(&Ok(Response::R48(0x900)));
+        assert_eq!(host.trace_len(), 0);
+    }
+}
@@ -1,6 +1,6 @@
 /// Baud rate 115200
-/// Baud Rate	|   Divisor (in decimal)	|   Divisor Latch High Byte	|   Divisor Latch Low Byte
-/// 115200	    |   1	                    |   $00	                    |   $01
+/// Baud Rate    |   Divisor (in decimal)    |   Divisor Latch High Byte    |   Divisor Latch Low Byte
+/// 115200        |   1                        |   $00                        |   $01
 pub const DIVISOR: u8 = 13;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -107,20 +107,20 @@ pub fn init_log(level: log::LevelFilter) -> Result<(), log::SetLoggerError> {
 #[macro_export]
 macro_rules! println {
     () => {
-        writeln!(unsafe{$crate::serial::UART.as_mut().unwrap()}).unwrap();
+        writeln!(unsafe{(*core::ptr::addr_of_mut!($crate::serial::UART)).as_mut().unwrap()}).unwrap();
     };
     ($($arg:tt)*) => {
-        writeln!(unsafe{$crate::serial::UART.as_mut().unwrap()},$($arg)*).unwrap();
+        writeln!(unsafe{(*core::ptr::addr_of_mut!($crate::serial::UART)).as_mut().unwrap()},$($arg)*).unwrap();
     };
 }
 
 #[macro_export]
 macro_rules! print {
     () => {
-        write!(unsafe{$crate::serial::UART.as_mut().unwrap()}).unwrap();
+        write!(unsafe{(*core::ptr::addr_of_mut!($crate::serial::UART)).as_mut().unwrap()}).unwrap();
     };
     ($($arg:tt)*) => {
-        write!(unsafe{$crate::serial::UART.as_mut().unwrap()},$($arg)*).unwrap();
+        write!(unsafe{(*core::ptr::addr_of_mut!($crate::serial::UART)).as_mut().unwrap()},$($arg)*).unwrap();
     };
 }
 
@@ -0,0 +1,36 @@
+#!/bin/sh
+# Regenerate ext4.img, the ext4 fixture of the fs::ext4 unit tests.
+#
+# 256 KiB, 1 KiB blocks, metadata_csum, no journal. /lib/modules is big
+# enough for an htree index, /boot/sparse.bin has more extents than fit in
+# the inode. Trailing zero blocks are cut off, the tests pad the image back.
+# Needs e2fsprogs 1.47.
+set -e
+out=$(cd "$(dirname "$0")" && pwd)/ext4.img
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+cd "$tmp"
+mkdir -p root/boot root/lib/modules
+python3 - <<'PY'
+open('root/boot/Image', 'wb').write(bytes((i * 31 + 7) % 251 for i in range(12000)))
+with open('root/boot/sparse.bin', 'wb') as f:
+    for i in range(0, 12, 2):
+        f.seek(i * 1024)
+        f.write(bytes([i + 1]) * 1024)
+    f.truncate(12 * 1024)
+for i in range(80):
+    name = f'root/lib/modules/module-{i:03d}-with-a-longer-name.ko'
+    open(name, 'wb').write(f'mod {i}\n'.encode() if i % 20 == 0 else b'')
+PY
+ln -s boot/Image root/vmlinuz
+ln -s ../../boot/Image root/lib/modules/kernel
+ln -s ../../../boot/../boot/./../boot/Image-with-a-very-long-target-name-over-sixty \
+    root/lib/modules/long
+export E2FSPROGS_FAKE_TIME=1700000000
+mkfs.ext4 -q -b 1024 -N 112 -O ^has_journal,^resize_inode -L vf2test \
+    -U 01234567-89ab-cdef-0123-456789abcdef \
+    -E hash_seed=fedcba98-7654-3210-fedc-ba9876543210,root_owner=0:0,lazy_itable_init=0 \
+    -d root img 256k
+# index the directories
+e2fsck -fyD img >/dev/null || [ $? -eq 1 ]
+python3 -c "import sys; d = open('img', 'rb').read(); open(sys.argv[1], 'wb').write(d.rstrip(b'\0'))" "$out"